@@ -0,0 +1,47 @@
+use std::{env, process::Command};
+
+/// Runs `program` and returns its trimmed stdout, if it succeeded
+fn output_of(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+fn main() {
+    // Record provenance information for `workspaces --version`
+    let commit =
+        output_of("git", &["rev-parse", "--short=12", "HEAD"]).map(|commit| {
+            match output_of("git", &["status", "--porcelain", "--untracked-files=no"]) {
+                Some(status) if !status.is_empty() => format!("{}-dirty", commit),
+                _ => commit,
+            }
+        });
+    let build_date = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => output_of(
+            "date",
+            &["-u", "-d", &format!("@{}", epoch), "+%Y-%m-%dT%H:%M:%SZ"],
+        ),
+        Err(_) => output_of("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"]),
+    };
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+
+    println!(
+        "cargo:rustc-env=WORKSPACES_GIT_COMMIT={}",
+        commit.as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "cargo:rustc-env=WORKSPACES_BUILD_DATE={}",
+        build_date.as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "cargo:rustc-env=WORKSPACES_RUSTC_VERSION={}",
+        output_of(&rustc, &["--version"])
+            .as_deref()
+            .unwrap_or("unknown")
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
@@ -0,0 +1,225 @@
+use std::{collections::HashMap, error::Error};
+
+use chrono::{DateTime, Duration, Local, Utc};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{clock, config, notify, to_volume_string};
+
+/// Time between reports sent by `workspaces daemon`
+pub const REPORT_INTERVAL_DAYS: i64 = 7;
+
+/// When the last report was sent, if ever
+pub fn last_sent(conn: &Connection) -> rusqlite::Result<Option<DateTime<Utc>>> {
+    conn.query_row(
+        "SELECT timestamp FROM admin_reports ORDER BY id DESC LIMIT 1",
+        (),
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Whether the weekly report is due
+pub fn is_due(conn: &Connection) -> rusqlite::Result<bool> {
+    Ok(last_sent(conn)?
+        .is_none_or(|last| last + Duration::days(REPORT_INTERVAL_DAYS) <= clock::now()))
+}
+
+/// Emails the admins a summary of what happened since the last report
+///
+/// Covers new and deleted workspaces, the ones which grew the most, how full
+/// the filesystems' roots are, failures and the largest deletions coming up
+/// within the next week.  Without a previous report, the last week is covered.
+/// Nothing is recorded unless the report reached at least one recipient.
+pub fn send(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    smtp: &config::SmtpConfig,
+    report: &config::AdminReportConfig,
+) -> Result<(), Box<dyn Error>> {
+    let now = clock::now();
+    let since = last_sent(conn)?.unwrap_or(now - Duration::days(REPORT_INTERVAL_DAYS));
+    let host = hostname::get()?.to_string_lossy().to_string();
+    let mut sections = Vec::new();
+
+    for (kind, title) in [
+        ("create", "New workspaces"),
+        ("delete", "Deleted workspaces"),
+        ("failure", "Failures"),
+    ] {
+        let events: Vec<String> = conn
+            .prepare(
+                "SELECT time, filesystem, user, name, detail FROM events \
+                    WHERE kind = ?1 AND unixepoch(time) > unixepoch(?2) \
+                    ORDER BY id",
+            )?
+            .query_map((kind, since), |row| {
+                let time: DateTime<Utc> = row.get(0)?;
+                let detail: Option<String> = row.get(4)?;
+                Ok(format!(
+                    "{} {}/{}/{}{}",
+                    time.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    detail
+                        .map(|detail| format!(": {}", detail))
+                        .unwrap_or_default()
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        sections.push(section(title, &events, report.entries));
+    }
+
+    // Current sizes, compared to the ones of the last report
+    let mut growth = Vec::new();
+    let mut deletions = Vec::new();
+    let mut sizes = Vec::new();
+    {
+        let mut statement = conn.prepare(
+            "SELECT w.id, w.filesystem, w.user, w.name, w.expiration_time, w.root, w.dataset, \
+                    w.deletion_held, r.used \
+                FROM workspaces w LEFT JOIN reported_sizes r ON r.workspace_id = w.id",
+        )?;
+        let mut rows = statement.query(())?;
+        while let Some(row) = rows.next()? {
+            let workspace_id: i64 = row.get(0)?;
+            let filesystem_name: String = row.get(1)?;
+            let user: String = row.get(2)?;
+            let name: String = row.get(3)?;
+            let expiration_time: DateTime<Utc> = row.get(4)?;
+            let root: Option<String> = row.get(5)?;
+            let dataset: String = row.get(6)?;
+            let deletion_held: bool = row.get(7)?;
+            let reported: Option<u64> = row.get(8)?;
+            let Some(filesystem) = filesystems.get(&filesystem_name) else {
+                continue;
+            };
+            let volume = to_volume_string(filesystem.root(root.as_deref()), &dataset);
+            let used: u64 = match filesystem.storage().get_property(&volume, "used") {
+                Ok(used) => used,
+                Err(e) => {
+                    log::warn!("Failed to get the size of {} for the report: {}", volume, e);
+                    continue;
+                }
+            };
+            sizes.push((workspace_id, used));
+            let workspace = format!("{}/{}/{}", filesystem_name, user, name);
+
+            // Without a size from the last report there is nothing to compare to
+            if let Some(reported) = reported
+                && used > reported
+            {
+                growth.push((used - reported, used, workspace.clone()));
+            }
+            let deletion_time = expiration_time + filesystem.expired_retention;
+            if !deletion_held && deletion_time < now + Duration::days(REPORT_INTERVAL_DAYS) {
+                deletions.push((used, deletion_time, workspace));
+            }
+        }
+    }
+
+    growth.sort_by(|a, b| b.cmp(a));
+    let growth: Vec<String> = growth
+        .into_iter()
+        .map(|(grown, used, workspace)| {
+            format!("{}: +{} (now {})", workspace, gib(grown), gib(used))
+        })
+        .collect();
+    sections.push(section("Largest growth", &growth, report.entries));
+
+    let mut roots = Vec::new();
+    for (filesystem_name, filesystem) in filesystems {
+        for root in &filesystem.roots {
+            match filesystem.storage().space(root) {
+                Ok((used, available)) if used + available > 0 => {
+                    let percent = 100 * used / (used + available);
+                    roots.push((percent, filesystem_name, root, used, used + available));
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Failed to get the space of {} for the report: {}", root, e),
+            }
+        }
+    }
+    roots.sort_by(|a, b| b.cmp(a));
+    let roots: Vec<String> = roots
+        .into_iter()
+        .map(|(percent, filesystem_name, root, used, total)| {
+            format!(
+                "{} ({}): {}% ({} of {})",
+                filesystem_name,
+                root,
+                percent,
+                gib(used as u64),
+                gib(total as u64)
+            )
+        })
+        .collect();
+    sections.push(section("Filesystems by utilization", &roots, usize::MAX));
+
+    deletions.sort_by(|a, b| b.cmp(a));
+    let deletions: Vec<String> = deletions
+        .into_iter()
+        .map(|(used, deletion_time, workspace)| {
+            format!(
+                "{}: {}, deleted {}",
+                workspace,
+                gib(used),
+                deletion_time.with_timezone(&Local).format("%Y-%m-%d")
+            )
+        })
+        .collect();
+    sections.push(section(
+        "Largest deletions within the next week",
+        &deletions,
+        report.entries,
+    ));
+
+    let subject = format!("Weekly workspaces report for {}", host);
+    let body = format!(
+        "Hello,\n\nThis is what happened to the workspaces on {} since {}.\n\n{}",
+        host,
+        since.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+        sections.join("\n")
+    );
+    let mut delivered = 0;
+    for recipient in &report.recipients {
+        match notify::send_email(smtp, recipient.clone(), subject.clone(), body.clone()) {
+            Ok(()) => delivered += 1,
+            Err(e) => log::warn!("Failed to send the admin report to {}: {}", recipient, e),
+        }
+    }
+    // So that the next run retries, covering the same period
+    if delivered == 0 {
+        return Err("The admin report couldn't be delivered to any recipient".into());
+    }
+
+    conn.execute("DELETE FROM reported_sizes", ())?;
+    for (workspace_id, used) in sizes {
+        conn.execute(
+            "INSERT INTO reported_sizes(workspace_id, used) VALUES(?1, ?2)",
+            (workspace_id, used),
+        )?;
+    }
+    conn.execute("INSERT INTO admin_reports(timestamp) VALUES(?1)", (now,))?;
+    Ok(())
+}
+
+/// A size in GiB, with one decimal so growth of small workspaces shows
+fn gib(bytes: u64) -> String {
+    format!("{:.1}G", bytes as f64 / (1u64 << 30) as f64)
+}
+
+/// A titled list of at most `entries` lines
+fn section(title: &str, lines: &[String], entries: usize) -> String {
+    let mut section = format!("{} ({}):\n", title, lines.len());
+    if lines.is_empty() {
+        section.push_str("  none\n");
+    }
+    for line in lines.iter().take(entries) {
+        section.push_str(&format!("  {}\n", line));
+    }
+    if lines.len() > entries {
+        section.push_str(&format!("  ... and {} more\n", lines.len() - entries));
+    }
+    section
+}
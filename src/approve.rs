@@ -0,0 +1,226 @@
+use std::{collections::HashMap, error::Error};
+
+use chrono::{DateTime, Duration, Local, Utc};
+use prettytable::{
+    Attr, Cell, Row, Table,
+    format::{Alignment, FormatBuilder},
+};
+use rusqlite::Connection;
+
+use crate::{
+    cli::CommandFormat,
+    config,
+    create::create,
+    error::WorkspacesError,
+    notify::{self, NotificationKind, Notifiers},
+};
+
+/// A workspace creation awaiting approval
+struct Request {
+    filesystem_name: String,
+    user: String,
+    name: String,
+    /// Days the workspace is created for
+    duration: i64,
+    modulefile: bool,
+    visible_snapshots: bool,
+    profile_name: Option<String>,
+    quota: Option<u64>,
+    group: Option<String>,
+}
+
+/// Approves or rejects a pending creation request
+///
+/// Approving creates the requested workspace and notifies its owner,
+/// rejecting it notifies them of that.  Without a request id, all pending
+/// requests are listed instead.
+pub fn approve(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    request_id: Option<i64>,
+    reject: bool,
+    notifiers: &Notifiers,
+    modulefiles: &Option<config::ModulefilesConfig>,
+    profiles: &HashMap<String, config::Profile>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(request_id) = request_id else {
+        return list_pending(conn);
+    };
+
+    let request = match conn
+        .prepare(
+            "SELECT filesystem, user, name, duration, modulefile, visible_snapshots, profile, \
+                    quota, owner_group \
+                FROM pending_requests WHERE id = ?1",
+        )?
+        .query_row((request_id,), |row| {
+            Ok(Request {
+                filesystem_name: row.get(0)?,
+                user: row.get(1)?,
+                name: row.get(2)?,
+                duration: row.get(3)?,
+                modulefile: row.get(4)?,
+                visible_snapshots: row.get(5)?,
+                profile_name: row.get(6)?,
+                quota: row.get(7)?,
+                group: row.get(8)?,
+            })
+        }) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::UnknownId(format!(
+                "There is no pending request with id {}",
+                request_id
+            ))
+            .into());
+        }
+        res => res,
+    }?;
+    let Request {
+        filesystem_name,
+        user,
+        name,
+        duration,
+        modulefile,
+        visible_snapshots,
+        profile_name,
+        quota,
+        group,
+    } = request;
+
+    let Some(filesystem) = filesystems.get(&filesystem_name) else {
+        if !reject {
+            return Err(WorkspacesError::NotConfigured(format!(
+                "The request's filesystem {} isn't configured anymore; reject it instead",
+                filesystem_name
+            ))
+            .into());
+        }
+        // Without the filesystem's notification channels to tell its owner over
+        remove(conn, request_id)?;
+        println!(
+            "Rejected request for workspace {} of {} on {}, which isn't configured anymore",
+            name, user, filesystem_name
+        );
+        return Ok(());
+    };
+
+    if reject {
+        remove(conn, request_id)?;
+        println!(
+            "Rejected request for workspace {} of {} on {}",
+            name, user, filesystem_name
+        );
+        let host = hostname::get()?.to_string_lossy().to_string();
+        let subject = format!(
+            "Your request for workspace {} on {} was rejected",
+            name, host
+        );
+        let body = format!(
+            "Hello,\n\nYour request for the workspace \"{}\" on {} has been rejected by an \
+                admin.\nFilesystem: {}\n",
+            name, host, filesystem_name
+        );
+        if let Err(e) = notify::notify(
+            notifiers,
+            filesystem,
+            NotificationKind::Rejected,
+            &user,
+            &[
+                ("workspace", &name),
+                ("user", &user),
+                ("filesystem", &filesystem_name),
+                ("host", &host),
+            ],
+            subject,
+            body,
+        ) {
+            log::warn!("Failed to notify {} of the rejection: {}", user, e);
+        }
+        return Ok(());
+    }
+
+    let profile = match &profile_name {
+        Some(profile_name) => {
+            let Some(profile) = profiles.get(profile_name) else {
+                return Err(WorkspacesError::NotConfigured(format!(
+                    "The request's profile {} does not exist anymore; \
+                    reject it or add the profile back",
+                    profile_name
+                ))
+                .into());
+            };
+            Some((profile_name.as_str(), profile))
+        }
+        None => None,
+    };
+    create(
+        conn,
+        &filesystem_name,
+        filesystem,
+        &user,
+        &name,
+        &Duration::days(duration),
+        quota,
+        group.as_deref(),
+        notifiers,
+        true,
+        modulefiles.as_ref().filter(|_| modulefile),
+        visible_snapshots,
+        profile,
+        None,
+        None,
+        CommandFormat::Text,
+    )?;
+    conn.execute("DELETE FROM pending_requests WHERE id = ?1", (request_id,))?;
+
+    Ok(())
+}
+
+/// Removes a pending request, exiting if another admin handled it meanwhile
+fn remove(conn: &Connection, request_id: i64) -> Result<(), Box<dyn Error>> {
+    if conn.execute("DELETE FROM pending_requests WHERE id = ?1", (request_id,))? == 0 {
+        return Err(WorkspacesError::UnknownId(format!(
+            "There is no pending request with id {}",
+            request_id
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Prints all creation requests awaiting approval
+fn list_pending(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["ID", "NAME", "USER", "FS", "DURATION", "REQUESTED"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    let mut statement = conn.prepare(
+        "SELECT id, name, user, filesystem, duration, request_time \
+            FROM pending_requests ORDER BY request_time",
+    )?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let request_time: DateTime<Utc> = row.get(5)?;
+        table.add_row(Row::new(vec![
+            Cell::new_align(&row.get::<_, i64>(0)?.to_string(), Alignment::RIGHT),
+            Cell::new(&row.get::<_, String>(1)?),
+            Cell::new(&row.get::<_, String>(2)?),
+            Cell::new(&row.get::<_, String>(3)?),
+            Cell::new_align(&format!("{}d", row.get::<_, i64>(4)?), Alignment::RIGHT),
+            Cell::new(
+                &request_time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+            ),
+        ]));
+    }
+
+    table.printstd();
+    Ok(())
+}
@@ -0,0 +1,890 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{self, Child, ChildStdin, Command, Output, Stdio},
+    thread,
+    time::{self, Instant},
+};
+
+use chrono::{DateTime, Duration, Local, Utc};
+use prettytable::{
+    Attr, Cell, Row, Table,
+    format::{Alignment, FormatBuilder},
+};
+use rusqlite::{Connection, TransactionBehavior};
+
+use crate::{
+    clock, config,
+    error::WorkspacesError,
+    events::{self, EventKind},
+    journal,
+    notify::{self, NotificationKind},
+    storage, to_volume_string,
+};
+
+/// Streams are uploaded in parts of this size, each an object of its own, so
+/// an interrupted upload carries on from the last part uploaded
+const PART_SIZE: u64 = 1 << 30;
+
+/// How often downloading a part is tried before giving up on it
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Builds an `aws` CLI invocation authenticated for the archive's bucket
+fn aws_command(s3: &config::S3Archive) -> Command {
+    let mut command = storage::system_command("aws");
+    command
+        .env("AWS_ACCESS_KEY_ID", &s3.access_key_id)
+        .env("AWS_SECRET_ACCESS_KEY", &s3.secret_access_key);
+    if let Some(region) = &s3.region {
+        command.env("AWS_DEFAULT_REGION", region);
+    }
+    if let Some(endpoint_url) = &s3.endpoint_url {
+        command.args(["--endpoint-url", endpoint_url]);
+    }
+    command
+}
+
+/// Parses the checksum printed by `sha256sum`
+pub fn parse_sha256(output: &Output) -> Result<String, Box<dyn Error>> {
+    if !output.status.success() {
+        return Err(format!(
+            "sha256sum failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    match output.split_whitespace().next() {
+        Some(sha256) if sha256.len() == 64 => Ok(sha256.to_owned()),
+        _ => Err(format!("unexpected output of sha256sum: {}", output.trim()).into()),
+    }
+}
+
+/// A part of an archive's stream, recorded once it was uploaded
+struct Part {
+    number: i64,
+    size: u64,
+    sha256: String,
+}
+
+/// The parts recorded for an archive, in order
+///
+/// Archives uploaded before streams were split into parts have none, their
+/// stream is a single object at their URL.
+fn parts(conn: &Connection, archive_id: i64) -> rusqlite::Result<Vec<Part>> {
+    conn.prepare(
+        "SELECT number, size, sha256 FROM archive_parts WHERE archive_id = ?1 ORDER BY number",
+    )?
+    .query_map((archive_id,), |row| {
+        Ok(Part {
+            number: row.get(0)?,
+            size: row.get::<_, i64>(1)? as u64,
+            sha256: row.get(2)?,
+        })
+    })?
+    .collect()
+}
+
+/// The object a part of the stream archived at `url` is stored in
+fn part_url(url: &str, number: i64) -> String {
+    format!("{}.{:06}", url, number)
+}
+
+/// Keeps a transfer to at most `bwlimit` bytes per second on average
+struct Throttle {
+    start: Instant,
+    copied: u64,
+    bwlimit: Option<u64>,
+}
+
+impl Throttle {
+    fn new(bwlimit: Option<u64>) -> Self {
+        Throttle {
+            start: Instant::now(),
+            copied: 0,
+            bwlimit,
+        }
+    }
+
+    /// Waits as long as the transfer is ahead, after `bytes` more were copied
+    fn pace(&mut self, bytes: u64) {
+        self.copied += bytes;
+        if let Some(bwlimit) = self.bwlimit
+            && let Some(ahead) = time::Duration::from_secs_f64(self.copied as f64 / bwlimit as f64)
+                .checked_sub(self.start.elapsed())
+        {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+/// Copies up to `limit` bytes from `reader` to each of `writers`, returning
+/// how many there were
+fn copy(
+    reader: &mut impl Read,
+    writers: &mut [&mut dyn Write],
+    limit: u64,
+    throttle: &mut Throttle,
+) -> io::Result<u64> {
+    let mut copied = 0;
+    let mut buffer = vec![0; 1 << 16];
+    while copied < limit {
+        let wanted = (limit - copied).min(buffer.len() as u64) as usize;
+        let read = match reader.read(&mut buffer[..wanted]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for writer in writers.iter_mut() {
+            writer.write_all(&buffer[..read])?;
+        }
+        copied += read as u64;
+        throttle.pace(read as u64);
+    }
+    Ok(copied)
+}
+
+/// Spawns `sha256sum`, hashing what is written to its stdin until it's waited
+/// for with `wait_with_output`
+fn hasher() -> io::Result<Child> {
+    storage::system_command("sha256sum")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+}
+
+/// Where to write what a `hasher()` is to hash
+fn hasher_stdin(hasher: &mut Child) -> &mut ChildStdin {
+    hasher.stdin.as_mut().expect("stdin should be piped")
+}
+
+/// Uploads a final snapshot of a workspace to the filesystem's object storage
+///
+/// The upload is recorded in the database part by part, and with the stream's
+/// SHA-256 once complete, so it can be restored and verified later.  An
+/// upload which was interrupted carries on where it left off, as long as the
+/// workspace wasn't extended or recreated meanwhile, which starts it over.
+/// Workspaces uploaded since they were created already aren't uploaded again.
+#[allow(clippy::too_many_arguments)]
+pub fn archive(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    s3: &config::S3Archive,
+    root: &str,
+    dataset: &str,
+    user: &str,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    // Archives are ZFS streams
+    if filesystem.backend != config::Backend::Zfs {
+        return Err("archiving to object storage requires the zfs backend".into());
+    }
+    let archived: bool = conn.query_row(
+        "SELECT EXISTS(SELECT * FROM archives JOIN workspaces USING(filesystem, user, name) \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3 AND archives.complete \
+                AND unixepoch(archives.archive_time) >= unixepoch(workspaces.created_at))",
+        (filesystem_name, user, name),
+        |row| row.get(0),
+    )?;
+    if archived {
+        return Ok(());
+    }
+    let (expiration_time, created_at): (DateTime<Utc>, Option<DateTime<Utc>>) = conn.query_row(
+        "SELECT expiration_time, created_at FROM workspaces \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name),
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let volume = to_volume_string(root, dataset);
+    let storage = filesystem.storage();
+    let snapshots = storage.list_snapshots(&volume)?;
+    // Uploads interrupted before, of which the one of the workspace as it
+    // still is is resumed, from its snapshot
+    let mut resumed = None;
+    let incomplete: Vec<(i64, String, Option<String>)> = conn
+        .prepare(
+            "SELECT id, url, snapshot, archive_time, expiration_time FROM archives \
+                WHERE filesystem = ?1 AND user = ?2 AND name = ?3 AND NOT complete",
+        )?
+        .query_map((filesystem_name, user, name), |row| {
+            let archive_time: DateTime<Utc> = row.get(3)?;
+            let archived_expiration: Option<DateTime<Utc>> = row.get(4)?;
+            let snapshot = row.get::<_, Option<String>>(2)?.filter(|_| {
+                created_at.is_none_or(|created_at| archive_time >= created_at)
+                    && archived_expiration == Some(expiration_time)
+            });
+            Ok((row.get(0)?, row.get(1)?, snapshot))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    for (archive_id, url, snapshot) in incomplete {
+        match snapshot {
+            Some(snapshot) if resumed.is_none() && snapshots.contains(&snapshot) => {
+                resumed = Some((archive_id, url, snapshot));
+            }
+            _ => discard(conn, s3, archive_id, &url)?,
+        }
+    }
+    // Left behind by uploads which can't be resumed
+    for snapshot in &snapshots {
+        if snapshot.starts_with("archive-")
+            && resumed
+                .as_ref()
+                .is_none_or(|(_, _, resumed)| resumed != snapshot)
+        {
+            storage.destroy(&format!("{}@{}", volume, snapshot))?;
+        }
+    }
+
+    let (archive_id, url, snapshot_name) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let now = Utc::now();
+            let snapshot_name = format!("archive-{}", now.format("%Y-%m-%dT%H%M%S"));
+            let url = format!(
+                "s3://{}/{}{}/{}/{}-{}.zfs",
+                s3.bucket,
+                s3.prefix,
+                filesystem_name,
+                user,
+                name,
+                now.format("%Y%m%dT%H%M%S")
+            );
+            storage.snapshot_named(&volume, &snapshot_name)?;
+            conn.execute(
+                "INSERT INTO archives(filesystem, user, name, root, url, size, archive_time, \
+                        complete, snapshot, expiration_time) \
+                    VALUES(?1, ?2, ?3, ?4, ?5, 0, ?6, 0, ?7, ?8)",
+                (
+                    filesystem_name,
+                    user,
+                    name,
+                    root,
+                    &url,
+                    now,
+                    &snapshot_name,
+                    expiration_time,
+                ),
+            )?;
+            (conn.last_insert_rowid(), url, snapshot_name)
+        }
+    };
+    let snapshot = format!("{}@{}", volume, snapshot_name);
+    let (size, sha256) = upload(conn, filesystem, s3, archive_id, &url, &snapshot)?;
+    check_uploaded(conn, s3, archive_id, &url)?;
+    conn.execute(
+        "UPDATE archives SET complete = 1, size = ?2, sha256 = ?3, snapshot = NULL WHERE id = ?1",
+        (archive_id, size as i64, &sha256),
+    )?;
+
+    // The next upload takes a new one, e.g. of a workspace restored meanwhile
+    if let Err(e) = storage.destroy(&snapshot) {
+        log::warn!("Failed to destroy snapshot {}: {}", snapshot, e);
+    }
+    Ok(())
+}
+
+/// Streams `snapshot` to the parts of `url`, recording each once uploaded
+///
+/// Parts recorded for `archive_id` already aren't uploaded again, but read
+/// back from the stream, to check it's still the same.  Returns the size and
+/// SHA-256 of the whole stream.  Unlike other commands on the filesystem's
+/// host, not subject to its `command_timeout`, as streaming large workspaces
+/// takes a while.
+fn upload(
+    conn: &Connection,
+    filesystem: &config::Filesystem,
+    s3: &config::S3Archive,
+    archive_id: i64,
+    url: &str,
+    snapshot: &str,
+) -> Result<(u64, String), Box<dyn Error>> {
+    let uploaded = parts(conn, archive_id)?;
+    let differs = |number: i64| -> Result<(), Box<dyn Error>> {
+        conn.execute(
+            "DELETE FROM archive_parts WHERE archive_id = ?1 AND number >= ?2",
+            (archive_id, number),
+        )?;
+        Err(format!(
+            "The stream of {} differs from the one uploaded before from part {} on, which is \
+                uploaded again next time",
+            snapshot, number
+        )
+        .into())
+    };
+
+    let mut whole = hasher()?;
+    let mut send = filesystem.storage().command("zfs", &["send", snapshot]);
+    let mut source = send.stdout(Stdio::piped()).spawn()?;
+    let mut stream = BufReader::new(source.stdout.take().expect("stdout should be piped"));
+    let mut throttle = Throttle::new(s3.bwlimit);
+    let mut size = 0;
+    let mut number = 0;
+    let mut streamed = || -> Result<(), Box<dyn Error>> {
+        while !stream.fill_buf()?.is_empty() {
+            let mut hashed = hasher()?;
+            let part_size = if let Some(part) = uploaded.get(number as usize) {
+                let part_size = copy(
+                    &mut stream,
+                    &mut [hasher_stdin(&mut whole), hasher_stdin(&mut hashed)],
+                    PART_SIZE,
+                    &mut throttle,
+                )?;
+                if part_size != part.size
+                    || parse_sha256(&hashed.wait_with_output()?)? != part.sha256
+                {
+                    return differs(number);
+                }
+                part_size
+            } else {
+                let part_url = part_url(url, number);
+                let mut sink = aws_command(s3)
+                    .args(["s3", "cp", "--only-show-errors", "--expected-size"])
+                    .arg(PART_SIZE.to_string())
+                    .arg("-")
+                    .arg(&part_url)
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                let copied = copy(
+                    &mut stream,
+                    &mut [
+                        sink.stdin.as_mut().expect("stdin should be piped"),
+                        hasher_stdin(&mut whole),
+                        hasher_stdin(&mut hashed),
+                    ],
+                    PART_SIZE,
+                    &mut throttle,
+                );
+                drop(sink.stdin.take());
+                let status = sink.wait()?;
+                let part_size = copied?;
+                // Not the command, its environment has the bucket's credentials
+                if !status.success() {
+                    return Err(format!("Uploading {} failed: {}", part_url, status).into());
+                }
+                // Shorter than the others when it's the last one, or when the
+                // stream broke off, which it mustn't be recorded for
+                if part_size < PART_SIZE {
+                    let status = source.wait()?;
+                    if !status.success() {
+                        return Err(format!("{:?} failed: {}", send, status).into());
+                    }
+                }
+                conn.execute(
+                    "INSERT INTO archive_parts(archive_id, number, size, sha256) \
+                        VALUES(?1, ?2, ?3, ?4)",
+                    (
+                        archive_id,
+                        number,
+                        part_size as i64,
+                        parse_sha256(&hashed.wait_with_output()?)?,
+                    ),
+                )?;
+                part_size
+            };
+            size += part_size;
+            number += 1;
+        }
+        if (number as usize) < uploaded.len() {
+            return differs(number);
+        }
+        Ok(())
+    };
+    let streamed = streamed();
+    // Closing it lets `zfs send` finish, or fail, rather than block
+    drop(stream);
+    let status = source.wait()?;
+    streamed?;
+    if !status.success() {
+        return Err(format!("{:?} failed: {}", send, status).into());
+    }
+    Ok((size, parse_sha256(&whole.wait_with_output()?)?))
+}
+
+/// Checks that the object storage has each part recorded for `archive_id`, at
+/// the size it was uploaded with
+///
+/// Parts from the first one missing on are uploaded again next time.
+fn check_uploaded(
+    conn: &Connection,
+    s3: &config::S3Archive,
+    archive_id: i64,
+    url: &str,
+) -> Result<(), Box<dyn Error>> {
+    let output = aws_command(s3)
+        .args(["s3", "ls", &format!("{}.", url)])
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "Listing the parts of {} failed: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    // Lines of date, time, size and key, relative to the URL's directory
+    let listed: HashMap<String, u64> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(2);
+            let size = fields.next()?.parse().ok()?;
+            Some((fields.next()?.to_owned(), size))
+        })
+        .collect();
+    let (_, object) = url.rsplit_once('/').unwrap_or(("", url));
+    for part in parts(conn, archive_id)? {
+        if listed.get(&part_url(object, part.number)) != Some(&part.size) {
+            conn.execute(
+                "DELETE FROM archive_parts WHERE archive_id = ?1 AND number >= ?2",
+                (archive_id, part.number),
+            )?;
+            return Err(format!(
+                "Part {} of {} is missing from the object storage, so it's uploaded again next \
+                    time",
+                part.number, url
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Removes an upload which can't be resumed, along with the parts it got to
+fn discard(
+    conn: &Connection,
+    s3: &config::S3Archive,
+    archive_id: i64,
+    url: &str,
+) -> Result<(), Box<dyn Error>> {
+    // Including the one which may have been uploading when interrupted
+    for number in 0..=parts(conn, archive_id)?.len() as i64 {
+        let part_url = part_url(url, number);
+        let output = aws_command(s3)
+            .args(["s3", "rm", "--only-show-errors", &part_url])
+            .output()?;
+        if !output.status.success() {
+            log::warn!(
+                "Failed to remove {}: {}",
+                part_url,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    conn.execute(
+        "DELETE FROM archive_parts WHERE archive_id = ?1",
+        (archive_id,),
+    )?;
+    conn.execute("DELETE FROM archives WHERE id = ?1", (archive_id,))?;
+    Ok(())
+}
+
+/// Downloads one object to each of `writers`, returning its size
+///
+/// A failed download is tried again, skipping what was written already, as
+/// objects don't change once uploaded.  Failing to write isn't, as that's the
+/// other end giving up, e.g. `zfs receive`.
+fn download_object(
+    s3: &config::S3Archive,
+    url: &str,
+    writers: &mut [&mut dyn Write],
+    throttle: &mut Throttle,
+) -> Result<u64, Box<dyn Error>> {
+    let mut written = 0;
+    for attempt in 1.. {
+        let mut source = aws_command(s3)
+            .args(["s3", "cp", "--only-show-errors", url, "-"])
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdout = source.stdout.take().expect("stdout should be piped");
+        let skipped = io::copy(&mut (&mut stdout).take(written), &mut io::sink()).unwrap_or(0);
+        let copied = if skipped == written {
+            copy(&mut stdout, writers, u64::MAX, throttle)
+        } else {
+            Ok(0)
+        };
+        drop(stdout);
+        let status = source.wait()?;
+        written += copied?;
+        if status.success() {
+            break;
+        }
+        if attempt == DOWNLOAD_ATTEMPTS {
+            return Err(format!("Downloading {} failed: {}", url, status).into());
+        }
+        log::warn!("Downloading {} failed, trying again: {}", url, status);
+        thread::sleep(time::Duration::from_secs(10));
+    }
+    Ok(written)
+}
+
+/// Downloads the archive at `url` to each of `writers`, at most the bucket's
+/// `bwlimit` bytes per second, returning the SHA-256 of its stream
+///
+/// Each part is checked against what was recorded when uploading it.
+fn download(
+    s3: &config::S3Archive,
+    url: &str,
+    parts: &[Part],
+    writers: &mut [&mut dyn Write],
+) -> Result<String, Box<dyn Error>> {
+    let mut whole = hasher()?;
+    let mut throttle = Throttle::new(s3.bwlimit);
+    if parts.is_empty() {
+        let mut writers: Vec<&mut dyn Write> = writers.iter_mut().map(|w| &mut **w as _).collect();
+        writers.push(hasher_stdin(&mut whole));
+        download_object(s3, url, &mut writers, &mut throttle)?;
+    }
+    for part in parts {
+        let mut hashed = hasher()?;
+        let mut writers: Vec<&mut dyn Write> = writers.iter_mut().map(|w| &mut **w as _).collect();
+        writers.push(hasher_stdin(&mut whole));
+        writers.push(hasher_stdin(&mut hashed));
+        let size = download_object(s3, &part_url(url, part.number), &mut writers, &mut throttle)?;
+        drop(writers);
+        let sha256 = parse_sha256(&hashed.wait_with_output()?)?;
+        if size != part.size || sha256 != part.sha256 {
+            return Err(format!(
+                "Part {} of {} is damaged, its SHA-256 is {} but {} was recorded",
+                part.number, url, sha256, part.sha256
+            )
+            .into());
+        }
+    }
+    parse_sha256(&whole.wait_with_output()?)
+}
+
+/// An archive as recorded in the database
+struct Archived {
+    filesystem_name: String,
+    user: String,
+    name: String,
+    root: String,
+    url: String,
+    /// Unknown for archives uploaded before checksums were recorded
+    sha256: Option<String>,
+    parts: Vec<Part>,
+}
+
+/// Looks up an archive, along with its filesystem and the object storage
+/// configured for it, failing if there is no such archive
+fn find<'a>(
+    conn: &Connection,
+    filesystems: &'a HashMap<String, config::Filesystem>,
+    archive_id: i64,
+) -> Result<(Archived, &'a config::Filesystem, &'a config::S3Archive), Box<dyn Error>> {
+    let mut archived = match conn
+        .prepare(
+            "SELECT filesystem, user, name, root, url, sha256 FROM archives \
+                WHERE id = ?1 AND complete",
+        )?
+        .query_row((archive_id,), |row| {
+            Ok(Archived {
+                filesystem_name: row.get(0)?,
+                user: row.get(1)?,
+                name: row.get(2)?,
+                root: row.get(3)?,
+                url: row.get(4)?,
+                sha256: row.get(5)?,
+                parts: Vec::new(),
+            })
+        }) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::UnknownId(format!(
+                "There is no archive with id {}",
+                archive_id
+            ))
+            .into());
+        }
+        res => res,
+    }?;
+    archived.parts = parts(conn, archive_id)?;
+    let filesystem = filesystems
+        .get(&archived.filesystem_name)
+        .expect("found archive in database without corresponding config entry");
+    let Some(s3) = &filesystem.s3_archive else {
+        return Err(WorkspacesError::NotConfigured(format!(
+            "Filesystem {} has no object storage configured anymore",
+            archived.filesystem_name
+        ))
+        .into());
+    };
+    Ok((archived, filesystem, s3))
+}
+
+/// Restores an archived workspace from object storage
+///
+/// Without an archive id, all archives are listed instead.  The archive is
+/// received into a temporary dataset, without holding the write lock, and
+/// only becomes the workspace once it matches its checksum, if that's known.
+pub fn restore_archive(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    archive_id: Option<i64>,
+    duration: Option<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(archive_id) = archive_id else {
+        return list_archives(conn);
+    };
+
+    let (archived, filesystem, s3) = find(conn, filesystems, archive_id)?;
+    let Archived {
+        filesystem_name,
+        user,
+        name,
+        root,
+        url,
+        sha256,
+        parts,
+    } = archived;
+    let duration = duration.unwrap_or(
+        filesystem
+            .default_duration
+            .unwrap_or(filesystem.max_duration),
+    );
+    let exists = || {
+        WorkspacesError::WorkspaceExists(format!(
+            "A workspace {} of {} already exists on {}. Please rename it first.",
+            name, user, filesystem_name
+        ))
+    };
+
+    // Restored as a new workspace, named as per the filesystem's current layout.
+    // Both are checked again once received, but that takes a while.
+    let dataset = filesystem.layout.dataset(&user, &name)?;
+    let volume = to_volume_string(&root, &dataset);
+    let storage = filesystem.storage();
+    let taken: bool = conn.query_row(
+        "SELECT EXISTS(SELECT * FROM workspaces \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3)",
+        (&filesystem_name, &user, &name),
+        |row| row.get(0),
+    )?;
+    if taken {
+        return Err(exists().into());
+    }
+    if storage.exists(&volume)? {
+        return Err(WorkspacesError::WorkspaceExists(format!(
+            "There already is a dataset {}, but no workspace for it.{}",
+            volume,
+            filesystem.contact_hint()
+        ))
+        .into());
+    }
+
+    // Next to the workspaces, so it is renamed within the pool
+    let temporary_volume = format!("{}/.receive-{}-{}", root, archive_id, process::id());
+    let entry = journal::begin_receive(conn, &filesystem_name, &volume, &temporary_volume)?;
+    let mut restore = || -> Result<DateTime<Utc>, Box<dyn Error>> {
+        receive(
+            filesystem,
+            s3,
+            &url,
+            &parts,
+            sha256.as_deref(),
+            filesystem.mountpoint(&filesystem_name, &user, &name),
+            &temporary_volume,
+        )?;
+        storage.rename(&temporary_volume, &volume)?;
+        let mountpoint: PathBuf = storage.get_property(&volume, "mountpoint")?;
+
+        let expiration_time = clock::now() + duration;
+        let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        match transaction.execute(
+            "INSERT INTO workspaces(filesystem, user, name, expiration_time, root, dataset, \
+                    mountpoint, created_at) \
+                VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            (
+                &filesystem_name,
+                &user,
+                &name,
+                expiration_time,
+                &root,
+                &dataset,
+                mountpoint.to_string_lossy(),
+                clock::now(),
+            ),
+        ) {
+            Ok(_) => {}
+            Err(rusqlite::Error::SqliteFailure(
+                libsqlite3_sys::Error {
+                    code: libsqlite3_sys::ErrorCode::ConstraintViolation,
+                    ..
+                },
+                _,
+            )) => return Err(exists().into()),
+            Err(err) => return Err(err.into()),
+        }
+        // Like a newly created workspace, so its owner isn't warned right away
+        notify::record(
+            &transaction,
+            transaction.last_insert_rowid(),
+            NotificationKind::Created,
+            &[],
+            clock::now(),
+        )?;
+        events::record(
+            &transaction,
+            EventKind::Create,
+            &filesystem_name,
+            &user,
+            &name,
+            Some(expiration_time),
+            Some(&format!("restored from archive {}", archive_id)),
+        )?;
+        journal::remove(&transaction, &entry)?;
+        transaction.commit()?;
+        Ok(expiration_time)
+    };
+    let expiration_time = match restore() {
+        Ok(expiration_time) => expiration_time,
+        Err(e) => {
+            if let Err(e) = journal::resolve(conn, filesystem, &entry) {
+                log::warn!(
+                    "Failed to clean up after restoring archive {}: {}",
+                    archive_id,
+                    e
+                );
+            }
+            return Err(e);
+        }
+    };
+
+    println!(
+        "Restored workspace {} of {} on {}, expiring on {}",
+        name,
+        user,
+        filesystem_name,
+        expiration_time
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+    );
+    Ok(())
+}
+
+/// Receives the archive at `url` into `volume`, failing if it doesn't match
+/// `sha256`
+///
+/// What was received is left for the caller to destroy on failure.  An
+/// interrupted download of a part is tried again, but `zfs receive` can't
+/// carry on from a stream stored elsewhere, so restoring otherwise starts
+/// over.
+fn receive(
+    filesystem: &config::Filesystem,
+    s3: &config::S3Archive,
+    url: &str,
+    parts: &[Part],
+    sha256: Option<&str>,
+    mountpoint: Option<String>,
+    volume: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mountpoint = mountpoint.map(|mountpoint| format!("mountpoint={}", mountpoint));
+    let mut receive = vec!["receive"];
+    if let Some(mountpoint) = &mountpoint {
+        receive.extend(["-o", mountpoint]);
+    }
+    receive.push(volume);
+    let mut command = filesystem.storage().command("zfs", &receive);
+    let mut sink = command.stdin(Stdio::piped()).spawn()?;
+    let mut stdin = sink.stdin.take().expect("stdin should be piped");
+    let downloaded = download(s3, url, parts, &mut [&mut stdin]);
+    // Closing it lets `zfs receive` finish, or fail on a stream cut short
+    drop(stdin);
+    let status = sink.wait()?;
+    let downloaded = downloaded?;
+    if !status.success() {
+        return Err(format!("{:?} failed: {}", command, status).into());
+    }
+    if let Some(sha256) = sha256
+        && downloaded != sha256
+    {
+        return Err(format!(
+            "The downloaded archive doesn't match its checksum, its SHA-256 is {} but {} was \
+                recorded",
+            downloaded, sha256
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Downloads an archive to check it still matches the checksum recorded when
+/// uploading it
+///
+/// Without an archive id, all archives are listed instead.
+pub fn verify_archive(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    archive_id: Option<i64>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(archive_id) = archive_id else {
+        return list_archives(conn);
+    };
+    let (archived, _, s3) = find(conn, filesystems, archive_id)?;
+    let Some(recorded) = archived.sha256 else {
+        return Err(format!(
+            "Archive {} was uploaded before checksums were recorded, so it can't be verified",
+            archive_id
+        )
+        .into());
+    };
+
+    let downloaded = download(s3, &archived.url, &archived.parts, &mut [])?;
+    if downloaded != recorded {
+        return Err(format!(
+            "Archive {} is damaged, its SHA-256 is {} but {} was recorded",
+            archive_id, downloaded, recorded
+        )
+        .into());
+    }
+    println!(
+        "Archive {} is intact, its SHA-256 is {}",
+        archive_id, recorded
+    );
+    Ok(())
+}
+
+/// Prints all archived workspaces
+fn list_archives(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["ID", "NAME", "USER", "FS", "SIZE", "ARCHIVED", "URL"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    let mut statement = conn.prepare(
+        "SELECT id, name, user, filesystem, size, archive_time, url \
+            FROM archives WHERE complete ORDER BY archive_time",
+    )?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let archive_time: DateTime<Utc> = row.get(5)?;
+        table.add_row(Row::new(vec![
+            Cell::new_align(&row.get::<_, i64>(0)?.to_string(), Alignment::RIGHT),
+            Cell::new(&row.get::<_, String>(1)?),
+            Cell::new(&row.get::<_, String>(2)?),
+            Cell::new(&row.get::<_, String>(3)?),
+            Cell::new_align(
+                &format!("{}G", row.get::<_, i64>(4)? / (1 << 30)),
+                Alignment::RIGHT,
+            ),
+            Cell::new(
+                &archive_time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+            ),
+            Cell::new(&row.get::<_, String>(6)?),
+        ]));
+    }
+
+    table.printstd();
+    Ok(())
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use chrono::{DateTime, Duration, Local, Utc};
+use prettytable::{
+    Attr, Cell, Row, Table,
+    format::{Alignment, FormatBuilder},
+};
+use rusqlite::Connection;
+
+use crate::{
+    cli::CommandFormat,
+    config::{self, BackupTool},
+    create::create,
+    error::WorkspacesError,
+    notify::Notifiers,
+    storage, workspace_volume,
+};
+
+/// Builds an invocation of the backup tool for the configured repository
+fn tool_command(backup: &config::Backup) -> Command {
+    match backup.tool {
+        BackupTool::Restic => {
+            let mut command = storage::system_command("restic");
+            command
+                .arg("--repo")
+                .arg(&backup.repository)
+                .arg("--password-file")
+                .arg(&backup.password_file);
+            command
+        }
+        BackupTool::Borg => {
+            let mut command = storage::system_command("borg");
+            command
+                .env("BORG_REPO", &backup.repository)
+                // Split like a shell would, so the path has to be quoted
+                .env(
+                    "BORG_PASSCOMMAND",
+                    format!(
+                        "cat {}",
+                        storage::shell_quote(&backup.password_file.to_string_lossy())
+                    ),
+                )
+                // We run unattended, so there's no one to answer prompts
+                .env("BORG_RELOCATED_REPO_ACCESS_IS_OK", "no")
+                .env("BORG_UNKNOWN_UNENCRYPTED_REPO_ACCESS_IS_OK", "no");
+            command
+        }
+    }
+}
+
+/// Runs `command`, returning its stdout if it succeeded
+fn run(mut command: Command) -> Result<String, Box<dyn Error>> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Backs up a workspace with the filesystem's backup tool and verifies the result
+///
+/// The resulting snapshot is recorded in the database, so it can be restored
+/// later.  Workspaces backed up since they were created already, e.g. before
+/// a later step of deleting them failed, aren't backed up again.
+pub fn backup(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    backup: &config::Backup,
+    volume: &str,
+    user: &str,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    let backed_up: bool = conn.query_row(
+        "SELECT EXISTS(SELECT * FROM backups JOIN workspaces USING(filesystem, user, name) \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3 \
+                AND unixepoch(backups.backup_time) >= unixepoch(workspaces.created_at))",
+        (filesystem_name, user, name),
+        |row| row.get(0),
+    )?;
+    if backed_up {
+        return Ok(());
+    }
+
+    let mountpoint: PathBuf = filesystem.storage().get_property(volume, "mountpoint")?;
+    let now = Utc::now();
+
+    let snapshot_id = match backup.tool {
+        BackupTool::Restic => {
+            let mut command = tool_command(backup);
+            command.args([
+                "backup",
+                "--json",
+                "--tag",
+                "workspaces",
+                "--tag",
+                &format!("filesystem={},user={},name={}", filesystem_name, user, name),
+            ]);
+            command.arg(&mountpoint);
+            let output = run(command)?;
+            let id = restic_snapshot_id(&output).ok_or("restic did not report a snapshot id")?;
+
+            let mut verify = tool_command(backup);
+            verify.args(["ls", &id]);
+            run(verify)?;
+            id
+        }
+        BackupTool::Borg => {
+            let archive = format!(
+                "{}-{}-{}-{}",
+                filesystem_name,
+                user,
+                name,
+                now.format("%Y-%m-%dT%H%M%S")
+            );
+            let mut command = tool_command(backup);
+            command.arg("create").arg(format!("::{}", archive));
+            // Store paths relative to the mountpoint's parent
+            command.current_dir(mountpoint.parent().unwrap_or(Path::new("/")));
+            command.arg(mountpoint.file_name().ok_or("mountpoint has no name")?);
+            run(command)?;
+
+            let mut verify = tool_command(backup);
+            verify
+                .args(["check", "--archives-only"])
+                .arg(format!("::{}", archive));
+            run(verify)?;
+            archive
+        }
+    };
+
+    conn.execute(
+        "INSERT INTO backups(filesystem, user, name, tool, repository, snapshot_id, backup_time) \
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (
+            filesystem_name,
+            user,
+            name,
+            backup.tool.to_string(),
+            &backup.repository,
+            &snapshot_id,
+            now,
+        ),
+    )?;
+    Ok(())
+}
+
+/// Extracts the snapshot id from the summary `restic backup --json` emits last
+fn restic_snapshot_id(output: &str) -> Option<String> {
+    const KEY: &str = "\"snapshot_id\":\"";
+    let line = output.lines().rev().find(|line| line.contains(KEY))?;
+    let start = line.find(KEY)? + KEY.len();
+    let end = start + line[start..].find('"')?;
+    Some(line[start..end].to_owned())
+}
+
+/// Recreates a workspace from one of its backups
+///
+/// Without a backup id, all backups are listed instead.
+pub fn restore_backup(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    backup_id: Option<i64>,
+    duration: Option<Duration>,
+    notifiers: &Notifiers,
+) -> Result<(), Box<dyn Error>> {
+    let Some(backup_id) = backup_id else {
+        return list_backups(conn);
+    };
+
+    let (filesystem_name, user, name, snapshot_id): (String, String, String, String) = match conn
+        .prepare("SELECT filesystem, user, name, snapshot_id FROM backups WHERE id = ?1")?
+        .query_row((backup_id,), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        }) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::UnknownId(format!(
+                "There is no backup with id {}",
+                backup_id
+            ))
+            .into());
+        }
+        res => res,
+    }?;
+    let filesystem = filesystems
+        .get(&filesystem_name)
+        .expect("found backup in database without corresponding config entry");
+    let Some(backup) = &filesystem.backup else {
+        return Err(WorkspacesError::NotConfigured(format!(
+            "Filesystem {} has no backup tool configured anymore",
+            filesystem_name
+        ))
+        .into());
+    };
+    let duration = duration.unwrap_or(
+        filesystem
+            .default_duration
+            .unwrap_or(filesystem.max_duration),
+    );
+
+    create(
+        conn,
+        &filesystem_name,
+        filesystem,
+        &user,
+        &name,
+        &duration,
+        None,
+        None,
+        notifiers,
+        true,
+        None,
+        false,
+        None,
+        None,
+        None,
+        CommandFormat::Text,
+    )?;
+
+    let volume = workspace_volume(conn, &filesystem_name, filesystem, &user, &name)?;
+    let mountpoint: PathBuf = filesystem.storage().get_property(&volume, "mountpoint")?;
+
+    let mut command = tool_command(backup);
+    match backup.tool {
+        BackupTool::Restic => {
+            // Restore the backed-up mountpoint's contents into the new one
+            command
+                .arg("restore")
+                .arg(format!("{}:{}", snapshot_id, mountpoint.display()))
+                .arg("--target")
+                .arg(&mountpoint);
+        }
+        BackupTool::Borg => {
+            command
+                .arg("extract")
+                .arg("--strip-components")
+                .arg("1")
+                .arg(format!("::{}", snapshot_id))
+                .current_dir(&mountpoint);
+        }
+    }
+    run(command)?;
+
+    println!(
+        "Restored backup {} into {}",
+        snapshot_id,
+        mountpoint.display()
+    );
+    Ok(())
+}
+
+/// Prints all recorded backups
+fn list_backups(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["ID", "NAME", "USER", "FS", "TOOL", "BACKED UP", "SNAPSHOT"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    let mut statement = conn.prepare(
+        "SELECT id, name, user, filesystem, tool, backup_time, snapshot_id \
+            FROM backups ORDER BY backup_time",
+    )?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let backup_time: DateTime<Utc> = row.get(5)?;
+        table.add_row(Row::new(vec![
+            Cell::new_align(&row.get::<_, i64>(0)?.to_string(), Alignment::RIGHT),
+            Cell::new(&row.get::<_, String>(1)?),
+            Cell::new(&row.get::<_, String>(2)?),
+            Cell::new(&row.get::<_, String>(3)?),
+            Cell::new(&row.get::<_, String>(4)?),
+            Cell::new(
+                &backup_time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+            ),
+            Cell::new(&row.get::<_, String>(6)?),
+        ]));
+    }
+
+    table.printstd();
+    Ok(())
+}
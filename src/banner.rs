@@ -0,0 +1,55 @@
+use std::{error::Error, path::PathBuf};
+
+use chrono::{DateTime, Local, Utc};
+
+use crate::{clock, config, metadata};
+
+/// Name of the expiry banner placed at the root of soon-to-expire workspaces
+pub const BANNER_FILE_NAME: &str = "EXPIRES_SOON.txt";
+
+/// Places, refreshes or removes a workspace's expiry banner
+///
+/// The banner exists while the workspace is within its filesystem's
+/// `expiry_banner_days` of expiring.
+pub fn update(
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    volume: &str,
+    name: &str,
+    expiration_time: DateTime<Utc>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(window) = filesystem.expiry_banner_days else {
+        return Ok(());
+    };
+    let mountpoint: PathBuf = filesystem.storage().get_property(volume, "mountpoint")?;
+    let path = mountpoint.join(BANNER_FILE_NAME);
+
+    let until_expiry = expiration_time - clock::now();
+    if until_expiry > window {
+        return metadata::remove_file(filesystem, &path);
+    }
+
+    metadata::replace_file(
+        filesystem,
+        &path,
+        &format!(
+            "This workspace ({}) expires on {}, in {} days.\n\
+            Afterwards it becomes read-only, and it will be deleted on {}.\n\
+            \n\
+            To keep it, run:\n\
+            \x20   workspaces extend -f {} {}\n\
+            \n\
+            This file is removed once the workspace has been extended.\n",
+            name,
+            expiration_time
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M"),
+            until_expiry.num_days(),
+            (expiration_time + filesystem.expired_retention)
+                .with_timezone(&Local)
+                .format("%Y-%m-%d"),
+            filesystem_name,
+            name,
+        ),
+    )
+}
@@ -0,0 +1,368 @@
+use crate::storage::{Error, Host, Result, Storage, parse, snapshot_name};
+use std::{
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Directory next to the subvolumes, holding a directory of read-only
+/// snapshots for each of them
+///
+/// It's in the root-owned parent rather than the user-owned subvolume, where
+/// users could swap it for a symlink or take it along when moving things.
+const SNAPSHOTS: &str = ".snapshots";
+
+/// Manages a filesystem's workspaces as btrfs subvolumes
+///
+/// Volumes are the paths of the subvolumes, which are also their mountpoints.
+/// Sizes and quotas come from qgroups, so quotas have to be enabled on the
+/// filesystem (`btrfs quota enable`).
+pub struct Btrfs<'a> {
+    host: Host<'a>,
+}
+
+/// The sizes btrfs keeps track of for a subvolume's qgroup
+struct Qgroup {
+    /// Bytes referenced by the subvolume
+    referenced: usize,
+    /// Bytes only the subvolume references, and no snapshot of it
+    exclusive: usize,
+    /// Limit on the referenced bytes, 0 if there is none
+    limit: usize,
+}
+
+impl<'a> Btrfs<'a> {
+    pub fn new(host: Host<'a>) -> Self {
+        Btrfs { host }
+    }
+
+    /// Runs `program` with `args`, failing with its error message if it doesn't succeed
+    fn run(&self, program: &str, args: &[&str]) -> Result<process::Output> {
+        self.host.checked_output(program, args, Error::BtrfsStatus)
+    }
+
+    /// Path of the directory holding `volume`'s snapshots
+    fn snapshots(volume: &str) -> String {
+        let volume = Path::new(volume);
+        volume
+            .parent()
+            .unwrap_or(Path::new("/"))
+            .join(SNAPSHOTS)
+            .join(volume.file_name().unwrap_or_default())
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Creates the parent of the directory holding `volume`'s snapshots
+    fn create_snapshots_parent(&self, volume: &str) -> Result<()> {
+        if let Some(parent) = Path::new(&Self::snapshots(volume)).parent() {
+            self.run("mkdir", &["-p", &parent.to_string_lossy()])?;
+        }
+        Ok(())
+    }
+
+    /// Creates the parent directories of `volume`
+    fn create_parent(&self, volume: &str) -> Result<()> {
+        if let Some(parent) = Path::new(volume).parent() {
+            self.run("mkdir", &["-p", &parent.to_string_lossy()])?;
+        }
+        Ok(())
+    }
+
+    /// The qgroup of the subvolume at `volume`
+    fn qgroup(&self, volume: &str) -> Result<Qgroup> {
+        let output = self.run("btrfs", &["qgroup", "show", "--raw", "-r", "-f", volume])?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // Headers are followed by a line like `0/257  16384  16384  none`
+        let fields: Vec<&str> = stdout
+            .lines()
+            .find(|line| line.starts_with("0/"))
+            .ok_or_else(|| Error::Unsupported(format!("{} has no qgroup", volume)))?
+            .split_whitespace()
+            .collect();
+        if fields.len() < 4 {
+            return Err(Error::PropertyParse(
+                format!("unexpected qgroup of {}: {}", volume, fields.join(" ")).into(),
+            ));
+        }
+        Ok(Qgroup {
+            referenced: parse(fields[1])?,
+            exclusive: parse(fields[2])?,
+            limit: match fields[3] {
+                "none" => 0,
+                limit => parse(limit)?,
+            },
+        })
+    }
+
+    /// Bytes held on to by the snapshots of `volume` only
+    fn used_by_snapshots(&self, volume: &str) -> Result<usize> {
+        let mut used = 0;
+        for snapshot in self.list_snapshots(volume)? {
+            used += self
+                .qgroup(&format!("{}/{}", Self::snapshots(volume), snapshot))?
+                .exclusive;
+        }
+        Ok(used)
+    }
+
+    /// Bytes available on the btrfs filesystem holding `path`
+    fn available(&self, path: &str) -> Result<usize> {
+        let output = self.run("df", &["-B1", "--output=avail", path])?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match stdout.lines().nth(1) {
+            Some(available) => parse(available.trim()),
+            None => Err(Error::PropertyParse(
+                format!("unexpected output of df: {}", stdout.trim()).into(),
+            )),
+        }
+    }
+}
+
+impl Storage for Btrfs<'_> {
+    fn host(&self) -> &Host<'_> {
+        &self.host
+    }
+
+    /// Creates a subvolume, removing it again if a property can't be set
+    fn create(&self, volume: &str, properties: &[(&str, &str)]) -> Result<()> {
+        self.create_parent(volume)?;
+        self.create_snapshots_parent(volume)?;
+        // Without `-p`, so snapshots left behind under the name aren't taken over
+        self.run("mkdir", &["-m", "700", &Self::snapshots(volume)])?;
+        let configured = self
+            .run("btrfs", &["subvolume", "create", volume])
+            .and_then(|_| {
+                properties
+                    .iter()
+                    .try_for_each(|(property, value)| self.set_property(volume, property, value))
+                    .or_else(|e| {
+                        // Don't leave a workspace behind which is missing e.g. its quota
+                        self.run("btrfs", &["subvolume", "delete", volume])?;
+                        Err(e)
+                    })
+            });
+        if let Err(e) = configured {
+            self.run("rmdir", &[&Self::snapshots(volume)])?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Whether a subvolume exists, failing if `btrfs` fails for another reason
+    fn exists(&self, volume: &str) -> Result<bool> {
+        let output = self.host.output("btrfs", &["subvolume", "show", volume])?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match output.status.success() {
+            true => Ok(true),
+            false if stderr.contains("No such file or directory") => Ok(false),
+            false => Err(Error::BtrfsStatus(output.status, stderr.trim().to_owned())),
+        }
+    }
+
+    /// Deletes a subvolume, after its snapshots
+    fn destroy(&self, volume: &str) -> Result<()> {
+        let snapshots = Self::snapshots(volume);
+        for snapshot in self.list_snapshots(volume)? {
+            self.run(
+                "btrfs",
+                &[
+                    "subvolume",
+                    "delete",
+                    &format!("{}/{}", snapshots, snapshot),
+                ],
+            )?;
+        }
+        self.run("rmdir", &[&snapshots])?;
+        self.run("btrfs", &["subvolume", "delete", volume])?;
+        Ok(())
+    }
+
+    /// Moves a subvolume along with its snapshots
+    fn rename(&self, src_volume: &str, dest_volume: &str) -> Result<()> {
+        let (src_snapshots, dest_snapshots) =
+            (Self::snapshots(src_volume), Self::snapshots(dest_volume));
+        self.create_parent(dest_volume)?;
+        self.create_snapshots_parent(dest_volume)?;
+        self.run("mv", &["-T", &src_snapshots, &dest_snapshots])?;
+        if let Err(e) = self.run("mv", &["-T", src_volume, dest_volume]) {
+            self.run("mv", &["-T", &dest_snapshots, &src_snapshots])?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Retrieves the btrfs counterpart of a ZFS property
+    fn property(&self, volume: &str, property: &str) -> Result<String> {
+        Ok(match property {
+            "mountpoint" => volume.to_owned(),
+            "referenced" => self.qgroup(volume)?.referenced.to_string(),
+            "used" => {
+                (self.qgroup(volume)?.referenced + self.used_by_snapshots(volume)?).to_string()
+            }
+            "available" => self.available(volume)?.to_string(),
+            "usedbysnapshots" => self.used_by_snapshots(volume)?.to_string(),
+            "quota" => self.qgroup(volume)?.limit.to_string(),
+            "readonly" => {
+                let output = self.run("btrfs", &["property", "get", "-ts", volume, "ro"])?;
+                match String::from_utf8_lossy(&output.stdout).trim() {
+                    "ro=true" => "on",
+                    _ => "off",
+                }
+                .to_owned()
+            }
+            "snapdir" => {
+                let output = self.run("stat", &["-c", "%a", &Self::snapshots(volume)])?;
+                let mode = u32::from_str_radix(String::from_utf8_lossy(&output.stdout).trim(), 8)
+                    .map_err(|e| Error::PropertyParse(Box::new(e)))?;
+                match mode & 0o005 {
+                    0 => "hidden",
+                    _ => "visible",
+                }
+                .to_owned()
+            }
+            _ => return Err(Error::Unsupported(format!("property {}", property))),
+        })
+    }
+
+    /// Sets the btrfs counterpart of a ZFS property
+    fn set_property(&self, volume: &str, property: &str, value: &str) -> Result<()> {
+        match (property, value) {
+            ("readonly", "on") => {
+                self.run("btrfs", &["property", "set", "-ts", volume, "ro", "true"])?;
+            }
+            ("readonly", "off") => {
+                self.run("btrfs", &["property", "set", "-ts", volume, "ro", "false"])?;
+            }
+            ("quota", limit) => {
+                self.run("btrfs", &["qgroup", "limit", limit, volume])?;
+            }
+            ("compression", "off") => {
+                self.run("btrfs", &["property", "set", volume, "compression", "none"])?;
+            }
+            ("compression", algorithm) => {
+                self.run(
+                    "btrfs",
+                    &["property", "set", volume, "compression", algorithm],
+                )?;
+            }
+            ("snapdir", "visible") => {
+                self.run("chmod", &["755", &Self::snapshots(volume)])?;
+            }
+            ("snapdir", "hidden") => {
+                self.run("chmod", &["700", &Self::snapshots(volume)])?;
+            }
+            // Subvolumes are always mounted where they are
+            ("mountpoint", mountpoint) if mountpoint == volume => {}
+            (property, value) => {
+                return Err(Error::Unsupported(format!("{}={}", property, value)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the names of a subvolume's snapshots, oldest first
+    fn list_snapshots(&self, volume: &str) -> Result<Vec<String>> {
+        let output = self.run(
+            "find",
+            &[
+                &Self::snapshots(volume),
+                "-mindepth",
+                "1",
+                "-maxdepth",
+                "1",
+                "-printf",
+                "%B@ %T@ %f\\n",
+            ],
+        )?;
+        let mut snapshots = Vec::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let mut fields = line.splitn(3, ' ');
+            let (Some(born), Some(modified), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            // Old kernels report no birth time, the modification time is the next best thing
+            let created: f64 = match born.parse() {
+                Ok(born) if born >= 0.0 => born,
+                _ => parse(modified)?,
+            };
+            snapshots.push((created, name.to_owned()));
+        }
+        snapshots.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        Ok(snapshots.into_iter().map(|(_, name)| name).collect())
+    }
+
+    fn snapshot_named(&self, volume: &str, snapshot_name: &str) -> Result<()> {
+        let snapshots = Self::snapshots(volume);
+        self.run("mkdir", &["-p", "-m", "700", &snapshots])?;
+        self.run(
+            "btrfs",
+            &[
+                "subvolume",
+                "snapshot",
+                "-r",
+                volume,
+                &format!("{}/{}", snapshots, snapshot_name),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Snapshots all subvolumes below `volume`
+    fn snapshot(&self, volume: &str, prefix: &str) -> Result<()> {
+        self.snapshot_each(&self.list_descendants(volume)?, prefix)
+    }
+
+    /// Snapshots several subvolumes one after another; unlike with ZFS, not atomically
+    fn snapshot_each(&self, volumes: &[String], prefix: &str) -> Result<()> {
+        let name = snapshot_name(prefix);
+        for volume in volumes {
+            self.snapshot_named(volume, &name)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the outermost subvolumes below `volume`, without `volume` itself
+    fn list_descendants(&self, volume: &str) -> Result<Vec<String>> {
+        // The root directory of a subvolume always has inode number 256
+        let output = self.run(
+            "find",
+            &[
+                volume,
+                "-mindepth",
+                "1",
+                "-name",
+                SNAPSHOTS,
+                "-prune",
+                "-o",
+                "-type",
+                "d",
+                "-inum",
+                "256",
+                "-print",
+                "-prune",
+            ],
+        )?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    /// Bytes used by the subvolumes below `root` and their snapshots, and
+    /// available on the btrfs filesystem
+    ///
+    /// Roots sharing a btrfs filesystem would all report its usage as a whole.
+    fn space(&self, root: &str) -> Result<(usize, usize)> {
+        let mut used = 0;
+        for volume in self.list_descendants(root)? {
+            used += self.qgroup(&volume)?.referenced + self.used_by_snapshots(&volume)?;
+        }
+        Ok((used, self.available(root)?))
+    }
+
+    fn snapshot_dir(&self, mountpoint: &Path) -> PathBuf {
+        PathBuf::from(Self::snapshots(&mountpoint.to_string_lossy()))
+    }
+}
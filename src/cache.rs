@@ -0,0 +1,205 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{Connection, DatabaseName, OptionalExtension};
+
+use crate::{config, storage};
+
+/// Recently fetched ZFS properties, so interactive commands stay fast
+///
+/// Stale entries are served nonetheless and refreshed by a background process,
+/// so only properties never seen before are queried synchronously.
+pub struct PropertyCache<'a> {
+    conn: &'a Connection,
+    ttl: Duration,
+    /// Whether a stale entry has been served
+    stale: Cell<bool>,
+    /// Values of volume and property fetched by [`Self::prefetch`]
+    fetched: RefCell<HashMap<(String, String), String>>,
+}
+
+impl<'a> PropertyCache<'a> {
+    pub fn new(conn: &'a Connection, ttl: Duration) -> Self {
+        PropertyCache {
+            conn,
+            ttl,
+            stale: Cell::new(false),
+            fetched: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Fetches `properties` of the `volumes` below `root` with a single query,
+    /// for those not cached yet
+    ///
+    /// Fetching a few volumes one by one is cheaper than listing all of the
+    /// root, so this only queries if more than one volume is missing.  If it
+    /// fails, [`Self::get_property`] falls back to querying them one by one.
+    pub fn prefetch(
+        &self,
+        filesystem_name: &str,
+        filesystem: &config::Filesystem,
+        root: &str,
+        volumes: &[String],
+        properties: &[&str],
+    ) {
+        let missing: HashSet<&str> = volumes
+            .iter()
+            .filter(|volume| {
+                properties
+                    .iter()
+                    .any(|property| self.cached(volume, property).is_none())
+            })
+            .map(String::as_str)
+            .collect();
+        if missing.len() < 2 {
+            return;
+        }
+
+        let values = match filesystem.storage().properties_below(root, properties) {
+            Ok(values) => values,
+            Err(e) => {
+                log::debug!("Failed to fetch the properties below {}: {}", root, e);
+                return;
+            }
+        };
+        let mut fetched = self.fetched.borrow_mut();
+        for (volume, property, value) in values {
+            if !missing.contains(volume.as_str()) {
+                continue;
+            }
+            if self.ttl > Duration::zero() {
+                store(self.conn, filesystem_name, &volume, &property, &value);
+            }
+            fetched.insert((volume, property), value);
+        }
+    }
+
+    /// The cached value of a property and when it was fetched, if caching at all
+    fn cached(&self, volume: &str, property: &str) -> Option<(String, DateTime<Utc>)> {
+        if self.ttl <= Duration::zero() {
+            return None;
+        }
+        // Failing to access the cache shouldn't fail the command
+        self.conn
+            .query_row(
+                "SELECT value, fetch_time FROM property_cache \
+                    WHERE volume = ?1 AND property = ?2",
+                (volume, property),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .unwrap_or(None)
+    }
+
+    /// Retrieves a ZFS property, preferably from the cache
+    pub fn get_property<F: FromStr>(
+        &self,
+        filesystem_name: &str,
+        filesystem: &config::Filesystem,
+        volume: &str,
+        property: &str,
+    ) -> Result<F, storage::Error>
+    where
+        <F as FromStr>::Err: std::error::Error + 'static,
+    {
+        let fetched = self
+            .fetched
+            .borrow()
+            .get(&(volume.to_owned(), property.to_owned()))
+            .cloned();
+        if let Some(value) = fetched {
+            return storage::parse(&value);
+        }
+        if self.ttl <= Duration::zero() {
+            return filesystem.storage().get_property(volume, property);
+        }
+
+        let value = match self.cached(volume, property) {
+            Some((value, fetch_time)) => {
+                if fetch_time + self.ttl < Utc::now() {
+                    self.stale.set(true);
+                }
+                value
+            }
+            None => {
+                let value: String = filesystem.storage().get_property(volume, property)?;
+                store(self.conn, filesystem_name, volume, property, &value);
+                value
+            }
+        };
+        value
+            .parse()
+            .map_err(|e| storage::Error::PropertyParse(Box::new(e)))
+    }
+
+    /// Spawns a process refreshing the cache if stale entries were served
+    pub fn refresh_in_background(&self) {
+        // Read-only connections mustn't cause writes either
+        if !self.stale.replace(false) || self.conn.is_readonly(DatabaseName::Main).unwrap_or(true) {
+            return;
+        }
+        let Ok(exe) = env::current_exe() else {
+            return;
+        };
+        // We don't wait for it; the cache will simply be fresher next time
+        let _ = Command::new(exe)
+            .arg("refresh-cache")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+    }
+}
+
+fn store(conn: &Connection, filesystem_name: &str, volume: &str, property: &str, value: &str) {
+    let _ = conn.execute(
+        "INSERT OR REPLACE INTO property_cache(filesystem, volume, property, value, fetch_time) \
+            VALUES(?1, ?2, ?3, ?4, ?5)",
+        (filesystem_name, volume, property, value, Utc::now()),
+    );
+}
+
+/// Re-fetches all cached properties older than `ttl`
+///
+/// Entries which cannot be fetched anymore, e.g. of deleted workspaces, are dropped.
+pub fn refresh(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    ttl: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let stale: Vec<(String, String, String)> = conn
+        .prepare(
+            "SELECT filesystem, volume, property FROM property_cache \
+                WHERE unixepoch(fetch_time) < unixepoch(?1)",
+        )?
+        .query_map([Utc::now() - ttl], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    for (filesystem_name, volume, property) in stale {
+        let value = filesystems.get(&filesystem_name).map(|filesystem| {
+            filesystem
+                .storage()
+                .get_property::<String>(&volume, &property)
+        });
+        match value {
+            Some(Ok(value)) => store(conn, &filesystem_name, &volume, &property, &value),
+            _ => {
+                conn.execute(
+                    "DELETE FROM property_cache WHERE volume = ?1 AND property = ?2",
+                    (&volume, &property),
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
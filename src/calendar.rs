@@ -0,0 +1,285 @@
+use std::{collections::HashMap, error::Error};
+
+use chrono::{DateTime, Duration, Local, Utc};
+use lettre::message::Mailbox;
+use prettytable::{
+    Attr, Cell, Row, Table,
+    format::{Alignment, FormatBuilder},
+};
+use rusqlite::Connection;
+use users::get_current_uid;
+
+use crate::{
+    cache::PropertyCache, cli, clock, config, error::WorkspacesError, list, notify,
+    to_volume_string,
+};
+
+/// Something about to happen to a workspace
+struct Entry {
+    time: DateTime<Utc>,
+    /// Whether the workspace is deleted, rather than expiring
+    deletion: bool,
+    id: i64,
+    filesystem_name: String,
+    user: String,
+    name: String,
+    /// Bytes used by the workspace, including its snapshots
+    used: Option<usize>,
+}
+
+impl Entry {
+    fn event(&self) -> &'static str {
+        match self.deletion {
+            true => "deleted",
+            false => "expires",
+        }
+    }
+}
+
+/// Prints the expiries and deletions within the next `days`, oldest first
+///
+/// Deletions which are overdue, i.e. will happen on the next `maintain` run,
+/// are included.  With `email`, the schedule is sent there instead.
+#[allow(clippy::too_many_arguments)]
+pub fn calendar(
+    conn: &Connection,
+    cache: &PropertyCache,
+    filesystems: &HashMap<String, config::Filesystem>,
+    days: i64,
+    filter_users: &Option<Vec<String>>,
+    filter_filesystems: &Option<Vec<String>>,
+    format: cli::CalendarFormat,
+    email: Option<&str>,
+    smtp: Option<&config::SmtpConfig>,
+    private_listing: bool,
+) -> Result<(), Box<dyn Error>> {
+    let recipient = match email {
+        None => None,
+        Some(_) if get_current_uid() != 0 => {
+            return Err(WorkspacesError::not_allowed().into());
+        }
+        Some(address) => {
+            let Some(smtp) = smtp else {
+                return Err(WorkspacesError::NotConfigured(
+                    "Sending emails requires [smtp] to be configured".to_owned(),
+                )
+                .into());
+            };
+            let Ok(mailbox) = address.parse::<Mailbox>() else {
+                return Err(WorkspacesError::InvalidArgument(format!(
+                    "Invalid email address: {}",
+                    address
+                ))
+                .into());
+            };
+            Some((smtp, mailbox))
+        }
+    };
+
+    let now = clock::now();
+    // Far enough to cover everything
+    let end = Duration::try_days(days)
+        .and_then(|days| now.checked_add_signed(days))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    let mut entries = Vec::new();
+    let mut statement = conn.prepare(
+        "SELECT id, filesystem, user, name, expiration_time, root, dataset, deletion_held \
+            FROM workspaces",
+    )?;
+    let visible = if private_listing && get_current_uid() != 0 {
+        Some(list::visible_workspaces(conn)?)
+    } else {
+        None
+    };
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        let filesystem_name: String = row.get(1)?;
+        let user: String = row.get(2)?;
+        let name: String = row.get(3)?;
+        let expiration_time: DateTime<Utc> = row.get(4)?;
+        let root: Option<String> = row.get(5)?;
+        let dataset: String = row.get(6)?;
+        let deletion_held: bool = row.get(7)?;
+        if visible
+            .as_ref()
+            .is_some_and(|visible| !visible.contains(&id))
+            || !filter_users.as_ref().is_none_or(|us| us.contains(&user))
+            || !filter_filesystems
+                .as_ref()
+                .is_none_or(|fs| fs.contains(&filesystem_name))
+        {
+            continue;
+        }
+        let filesystem = filesystems
+            .get(&filesystem_name)
+            .expect("found workspace in database without corresponding config entry");
+
+        let deletion_time = expiration_time + filesystem.expired_retention;
+        let expires = expiration_time > now && expiration_time <= end;
+        let deleted = !deletion_held && deletion_time <= end;
+        if !expires && !deleted {
+            continue;
+        }
+        let volume = to_volume_string(filesystem.root(root.as_deref()), &dataset);
+        let used = cache
+            .get_property::<usize>(&filesystem_name, filesystem, &volume, "used")
+            .ok();
+        for (time, deletion, due) in [
+            (expiration_time, false, expires),
+            (deletion_time, true, deleted),
+        ] {
+            if due {
+                entries.push(Entry {
+                    time,
+                    deletion,
+                    id,
+                    filesystem_name: filesystem_name.clone(),
+                    user: user.clone(),
+                    name: name.clone(),
+                    used,
+                });
+            }
+        }
+    }
+    cache.refresh_in_background();
+    entries.sort_by_key(|entry| entry.time);
+
+    let output = match format {
+        cli::CalendarFormat::Table => table(&entries),
+        cli::CalendarFormat::Ics => ics(&entries)?,
+    };
+    match recipient {
+        None => print!("{}", output),
+        Some((smtp, mailbox)) => {
+            let host = hostname::get()?.to_string_lossy().to_string();
+            notify::send_email(
+                smtp,
+                mailbox,
+                format!(
+                    "Workspaces expiring or deleted on {} within {} days",
+                    host, days
+                ),
+                output,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The entries as a table, followed by how much space the deletions free
+fn table(entries: &[Entry]) -> String {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["Date", "Event", "Name", "User", "Filesystem", "Size"]
+            .iter()
+            .map(|title| Cell::new(title).with_style(Attr::Bold))
+            .collect(),
+    ));
+    for entry in entries {
+        table.add_row(Row::new(vec![
+            Cell::new(
+                &entry
+                    .time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+            ),
+            Cell::new(entry.event()),
+            Cell::new(&entry.name),
+            Cell::new(&entry.user),
+            Cell::new(&entry.filesystem_name),
+            match entry.used {
+                Some(used) => Cell::new_align(&format!("{}G", used / (1 << 30)), Alignment::RIGHT),
+                None => Cell::new_align("?", Alignment::RIGHT),
+            },
+        ]));
+    }
+    let freed: usize = entries
+        .iter()
+        .filter(|entry| entry.deletion)
+        .filter_map(|entry| entry.used)
+        .sum();
+    format!(
+        "{}\nDeletions free {}G in total.\n",
+        table,
+        freed / (1 << 30)
+    )
+}
+
+/// The entries as an iCalendar file, one event per entry
+fn ics(entries: &[Entry]) -> Result<String, Box<dyn Error>> {
+    let host = hostname::get()?.to_string_lossy().to_string();
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    let mut ics = String::new();
+    for line in [
+        "BEGIN:VCALENDAR",
+        "VERSION:2.0",
+        "PRODID:-//workspaces//calendar//EN",
+    ] {
+        push_line(&mut ics, line);
+    }
+    for entry in entries {
+        push_line(&mut ics, "BEGIN:VEVENT");
+        push_line(
+            &mut ics,
+            &format!("UID:{}-{}@{}", entry.event(), entry.id, host),
+        );
+        push_line(&mut ics, &format!("DTSTAMP:{}", stamp));
+        push_line(
+            &mut ics,
+            &format!("DTSTART:{}", entry.time.format("%Y%m%dT%H%M%SZ")),
+        );
+        push_line(
+            &mut ics,
+            &format!(
+                "SUMMARY:{}",
+                ics_escape(&format!(
+                    "Workspace {} of {} on {} {}",
+                    entry.name,
+                    entry.user,
+                    entry.filesystem_name,
+                    entry.event()
+                ))
+            ),
+        );
+        push_line(
+            &mut ics,
+            &format!(
+                "DESCRIPTION:{}",
+                ics_escape(&match entry.used {
+                    Some(used) => format!("Size: {}G", used / (1 << 30)),
+                    None => "Size: unknown".to_owned(),
+                })
+            ),
+        );
+        push_line(&mut ics, "END:VEVENT");
+    }
+    push_line(&mut ics, "END:VCALENDAR");
+    Ok(ics)
+}
+
+/// Appends a content line, folded so no line is longer than 75 octets
+///
+/// Continuation lines start with a space, and characters aren't split.
+fn push_line(ics: &mut String, line: &str) {
+    let mut length = 0;
+    for character in line.chars() {
+        if length + character.len_utf8() > 75 {
+            ics.push_str("\r\n ");
+            length = 1;
+        }
+        ics.push(character);
+        length += character.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+/// Escapes text for an iCalendar property value
+fn ics_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    io::{self, IsTerminal, Write},
+};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, TransactionBehavior};
+use users::get_current_uid;
+
+use crate::{
+    clock, config,
+    error::WorkspacesError,
+    maintain::{self, Expired},
+    to_volume_string,
+};
+
+/// A workspace `clean` considers
+struct Candidate {
+    workspace_id: i32,
+    filesystem_name: String,
+    username: String,
+    workspace_name: String,
+    root: Option<String>,
+    dataset: String,
+    expiration_time: DateTime<Utc>,
+    deletion_held: bool,
+}
+
+/// Asks whether to delete the `volumes` right away
+fn confirm(volumes: &[String]) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        eprintln!("Refusing to delete workspaces without confirmation; pass --force to confirm");
+        return Ok(false);
+    }
+
+    for volume in volumes {
+        eprintln!("    {}", volume);
+    }
+    eprint!(
+        "These {} workspaces will be DESTROYED right away.\n\
+        This cannot be undone.\n\
+        Type \"yes\" to continue: ",
+        volumes.len()
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
+/// Whether the workspace still expired before `cutoff` and its deletion isn't held
+fn still_due(
+    conn: &Connection,
+    workspace_id: i32,
+    cutoff: DateTime<Utc>,
+) -> rusqlite::Result<bool> {
+    match conn.query_row(
+        "SELECT expiration_time, deletion_held FROM workspaces WHERE id = ?1",
+        (workspace_id,),
+        |row| Ok((row.get::<_, DateTime<Utc>>(0)?, row.get::<_, bool>(1)?)),
+    ) {
+        Ok((expiration_time, deletion_held)) => Ok(expiration_time < cutoff && !deletion_held),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Deletes expired workspaces right away, instead of on the next `maintain` run
+///
+/// Deletes the `workspace` given as filesystem, user and name, which has to
+/// have expired, or otherwise all workspaces past their retention period, on
+/// `filesystem_name` only if given.
+pub fn clean(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    modulefiles: &Option<config::ModulefilesConfig>,
+    workspace: Option<(&str, &str, &str)>,
+    filesystem_name: Option<&str>,
+    force: bool,
+) -> Result<(), WorkspacesError> {
+    if get_current_uid() != 0 {
+        return Err(WorkspacesError::not_allowed());
+    }
+    if let Some(filesystem_name) = filesystem_name
+        && !filesystems.contains_key(filesystem_name)
+    {
+        return Err(WorkspacesError::InvalidArgument(format!(
+            "There is no filesystem {}",
+            filesystem_name
+        )));
+    }
+
+    let candidates = conn
+        .prepare(
+            "SELECT id, filesystem, user, name, root, dataset, expiration_time, deletion_held \
+                FROM workspaces ORDER BY filesystem, user, name",
+        )?
+        .query_map([], |row| {
+            Ok(Candidate {
+                workspace_id: row.get(0)?,
+                filesystem_name: row.get(1)?,
+                username: row.get(2)?,
+                workspace_name: row.get(3)?,
+                root: row.get(4)?,
+                dataset: row.get(5)?,
+                expiration_time: row.get(6)?,
+                deletion_held: row.get(7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let (candidates, unconfigured): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|candidate| filesystems.contains_key(&candidate.filesystem_name));
+    // Only of concern when cleaning up every filesystem
+    if workspace.is_none() && filesystem_name.is_none() {
+        for candidate in &unconfigured {
+            log::warn!(
+                "Skipping workspace {} of {}, its filesystem {} is no longer configured",
+                candidate.workspace_name,
+                candidate.username,
+                candidate.filesystem_name
+            );
+        }
+    }
+
+    let doomed: Vec<Candidate> = match workspace {
+        Some((filesystem_name, user, name)) => {
+            let Some(candidate) = candidates.into_iter().find(|candidate| {
+                candidate.filesystem_name == filesystem_name
+                    && candidate.username == user
+                    && candidate.workspace_name == name
+            }) else {
+                return Err(WorkspacesError::unknown_workspace(
+                    filesystem_name,
+                    user,
+                    name,
+                ));
+            };
+            if candidate.expiration_time >= clock::now() {
+                return Err(WorkspacesError::InvalidArgument(format!(
+                    "Workspace {} of {} hasn't expired yet, expire it first",
+                    name, user
+                )));
+            }
+            if candidate.deletion_held {
+                return Err(WorkspacesError::InvalidArgument(format!(
+                    "The deletion of {} is on hold, release it with \
+                        `workspaces pending-deletions --release {} -u {} -f {}` first",
+                    name, name, user, filesystem_name
+                )));
+            }
+            vec![candidate]
+        }
+        None => candidates
+            .into_iter()
+            .filter(|candidate| {
+                filesystem_name.is_none_or(|name| candidate.filesystem_name == name)
+                    && !candidate.deletion_held
+                    && candidate.expiration_time
+                        < clock::now() - filesystems[&candidate.filesystem_name].expired_retention
+            })
+            .collect(),
+    };
+    if doomed.is_empty() {
+        println!("No workspaces are past their retention period");
+        return Ok(());
+    }
+
+    let volumes: Vec<String> = doomed
+        .iter()
+        .map(|candidate| {
+            let filesystem = &filesystems[&candidate.filesystem_name];
+            to_volume_string(
+                filesystem.root(candidate.root.as_deref()),
+                &candidate.dataset,
+            )
+        })
+        .collect();
+    if !force && !confirm(&volumes)? {
+        return Err(WorkspacesError::NotConfirmed(
+            "Aborted, no workspace was deleted".to_owned(),
+        ));
+    }
+
+    let mut kept = 0;
+    for (candidate, volume) in doomed.iter().zip(&volumes) {
+        let filesystem = &filesystems[&candidate.filesystem_name];
+        // A single workspace only has to have expired
+        let cutoff = match workspace {
+            Some(_) => clock::now(),
+            None => clock::now() - filesystem.expired_retention,
+        };
+        let expired = Expired {
+            workspace_id: candidate.workspace_id,
+            filesystem_name: &candidate.filesystem_name,
+            username: &candidate.username,
+            workspace_name: &candidate.workspace_name,
+            root: candidate.root.as_deref(),
+            dataset: &candidate.dataset,
+            expiration_time: candidate.expiration_time,
+        };
+        // Archiving takes a while, so it's done before taking the write lock
+        if !maintain::preserve(conn, filesystem, &expired)? {
+            kept += 1;
+            continue;
+        }
+        // Nothing may extend the workspace between checking it's due and deleting it
+        let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if !still_due(&transaction, candidate.workspace_id, cutoff)? {
+            println!("Kept {}, it was extended or held meanwhile", volume);
+            kept += 1;
+            continue;
+        }
+        if maintain::delete(&transaction, filesystem, modulefiles, &expired)? {
+            println!("Deleted {}", volume);
+        } else {
+            kept += 1;
+        }
+        transaction.commit()?;
+    }
+
+    if kept > 0 {
+        return Err(format!("{} of the workspaces could not be deleted", kept).into());
+    }
+    Ok(())
+}
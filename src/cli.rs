@@ -1,13 +1,50 @@
-use chrono::Duration;
+use chrono::{DateTime, Duration, Local, NaiveDate, Utc};
 use clap::{Parser, Subcommand, ValueEnum};
-use std::{error::Error, fmt, num::ParseIntError};
+use std::{error::Error, fmt, num::ParseIntError, path::PathBuf};
 use users::get_current_username;
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    about,
+    long_about = None,
+    disable_version_flag = true,
+    arg_required_else_help = true
+)]
 pub struct Args {
     #[command(subcommand)]
-    pub command: Command,
+    pub command: Option<Command>,
+
+    /// Print version and build information
+    #[arg(short = 'V', long, conflicts_with = "read_only")]
+    pub version: bool,
+
+    /// Print the version information as JSON
+    #[arg(long, requires = "version")]
+    pub json: bool,
+
+    /// Refuse commands which could change anything
+    ///
+    /// Only commands inspecting state (list, info, filesystems, ...) are
+    /// allowed, and the database is never upgraded or written to.  Meant for
+    /// monitoring accounts and tooling.
+    #[arg(long, global = true)]
+    pub read_only: bool,
+
+    /// Don't print reminders, e.g. about a missing email address
+    #[arg(long, global = true)]
+    pub no_hints: bool,
+
+    /// Pretend it is TIMESTAMP, deciding expiry and notifications as of then
+    /// (admins only)
+    ///
+    /// An RFC 3339 timestamp or a date, which means local midnight.  For
+    /// tests, and to see what e.g. `maintain --simulate` will do next week.
+    /// Only for `maintain --simulate` and `--dry-run`, with --read-only, and
+    /// for commands which only show things.  Given before the subcommand, as
+    /// `expire --now` means something else.
+    #[arg(long, hide = true, value_name = "TIMESTAMP", value_parser = parse_timestamp)]
+    pub now: Option<DateTime<Utc>>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -17,15 +54,18 @@ pub enum Command {
     Create {
         /// Name of the workspace
         ///
-        /// Must entirely consist of the characters [A-Za-z0-9_-].
+        /// Must entirely consist of the characters [A-Za-z0-9._-] and not start with a dot.
+        /// Workspaces can't be nested, so `/` is not allowed.
         #[arg(value_parser = parse_pathsafe)]
         workspace_name: String,
 
         /// Duration in days to extend the workspace to
         ///
         /// Must be less or equal to the DURATION given in `workspaces filesystems`.
-        #[arg(short, long, value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
-        duration: Duration,
+        ///
+        /// Defaults to the profile's duration, if it has one.
+        #[arg(short, long, required_unless_present = "profile", value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
+        duration: Option<Duration>,
 
         /// User the workspace belongs to
         #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
@@ -34,19 +74,71 @@ pub enum Command {
         /// Filesystem to create the workspace in
         #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
         filesystem_name: Option<String>,
+
+        /// Generate an environment modulefile for the workspace
+        ///
+        /// Afterwards, `module load workspace/<NAME>` sets `WORKSPACE_DIR`.
+        #[arg(long)]
+        modulefile: bool,
+
+        /// Make the workspace's snapshots accessible in its `.zfs/snapshot` directory
+        ///
+        /// This allows restoring files without an administrator.
+        /// May already be the default for the filesystem.
+        #[arg(long)]
+        visible_snapshots: bool,
+
+        /// Apply a profile's settings, see `workspaces profiles`
+        ///
+        /// Explicitly given options take precedence.
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// ZFS quota of the workspace, e.g. "500G"
+        ///
+        /// Defaults to the profile's or filesystem's quota, if any.
+        /// Must be at most the filesystem's maximum quota.
+        #[arg(long, value_name = "SIZE", value_parser = crate::config::parse_size)]
+        quota: Option<u64>,
+
+        /// Unix group owning the workspace along with USER
+        ///
+        /// The workspace is shared with the group (mode 2770) and any of its
+        /// members may extend, rename, expire or restore it, passing `-u USER`.
+        /// You have to be a member of the group.
+        #[arg(short, long, value_parser = parse_pathsafe)]
+        group: Option<String>,
+
+        /// Don't notify the owner about the new workspace
+        ///
+        /// Meant for pipelines creating many short-lived workspaces.
+        /// Warnings about its expiry are still sent.
+        #[arg(long)]
+        no_notify: bool,
+
+        /// Text to include in the owner's notification, e.g. what the
+        /// workspace is meant for
+        ///
+        /// Workspaces created for other users always tell them who did.
+        #[arg(long, conflicts_with = "no_notify")]
+        message: Option<String>,
+
+        #[command(flatten)]
+        format: FormatArgs,
     },
     /// Rename an already existing workspace
     #[clap(alias = "mv")]
     Rename {
-        /// Original name of the workspace
+        /// Original name of the workspace, or the new one with `--id`
         #[arg(value_parser = parse_pathsafe)]
         src_workspace_name: String,
 
         /// New name of the workspace
         ///
-        /// Must entirely consist of the characters [A-Za-z0-9_-].
-        #[arg(value_parser = parse_pathsafe)]
-        dest_workspace_name: String,
+        /// Must entirely consist of the characters [A-Za-z0-9._-] and not start with a dot.
+        /// Workspaces can't be nested, so `/` is not allowed.
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        dest_workspace_name: Option<String>,
 
         /// User the workspace belongs to
         #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
@@ -55,6 +147,19 @@ pub enum Command {
         /// Filesystem to create the workspace in
         #[arg(short, long = "filesystem")]
         filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its original name, user and filesystem
+        #[arg(long, conflicts_with_all = ["dest_workspace_name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// Also rename expired / read-only workspaces
+        ///
+        /// They are made writable for the rename and read-only again afterwards.
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        format: FormatArgs,
     },
     /// List workspaces
     #[clap(alias = "ls")]
@@ -76,20 +181,128 @@ pub enum Command {
         /// Can be specified multiple times
         #[arg(short, long, value_name = "COLUMN")]
         output: Option<Vec<WorkspacesColumns>>,
+
+        /// Sort workspaces by COLUMN
+        ///
+        /// Example: `--sort size --reverse --limit 20` shows the 20 largest workspaces
+        #[arg(short, long, value_name = "COLUMN")]
+        sort: Option<WorkspacesColumns>,
+
+        /// Reverse the order of the workspaces
+        #[arg(short, long)]
+        reverse: bool,
+
+        /// Show at most N workspaces
+        #[arg(short = 'n', long, value_name = "N")]
+        limit: Option<usize>,
+
+        /// Skip the first N workspaces
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        offset: usize,
+
+        /// Re-render the list every SECONDS seconds until interrupted
+        #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "2")]
+        watch: Option<u64>,
+
+        /// Output format
+        ///
+        /// `jsonl` prints one JSON object with all columns per workspace as soon as
+        /// it is known, e.g. for piping into `jq`. `json` prints them as a single
+        /// array once all are known. `--output` only affects tables.
+        #[arg(long, value_enum, default_value_t = ListFormat::Table, conflicts_with = "watch")]
+        format: ListFormat,
+
+        /// Show the workspaces in sections per user or filesystem, with subtotals
+        ///
+        /// With `--format jsonl` or `json`, one object per group is printed,
+        /// containing its workspaces.
+        #[arg(long, value_name = "COLUMN")]
+        group_by: Option<ListGroupBy>,
+
+        /// Only show workspaces published with `workspaces publish`
+        ///
+        /// Shows their descriptions by default.
+        #[arg(long)]
+        public: bool,
+    },
+    /// Show when workspaces expire and get deleted, in chronological order
+    ///
+    /// Lists the expiries and deletions within the next days with the sizes of
+    /// the workspaces, e.g. to anticipate a lot of space being freed.
+    Calendar {
+        /// Days ahead to show
+        #[arg(short, long, default_value_t = 30, value_parser = clap::value_parser!(i64).range(1..))]
+        days: i64,
+
+        /// Only show workspaces belonging to USER
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 'u', long = "user", value_name = "USER", value_parser = parse_pathsafe)]
+        filter_users: Option<Vec<String>>,
+
+        /// Only show workspaces on FILESYSTEM
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 'f', long = "filesystem", value_name = "FILESYSTEM", value_parser = parse_pathsafe)]
+        filter_filesystems: Option<Vec<String>>,
+
+        /// Output format
+        ///
+        /// `ics` prints an iCalendar file, e.g. to be served to calendar apps.
+        #[arg(long, value_enum, default_value_t = CalendarFormat::Table)]
+        format: CalendarFormat,
+
+        /// Email the schedule to ADDRESS instead of printing it (admins only)
+        #[arg(long, value_name = "ADDRESS")]
+        email: Option<String>,
+    },
+    /// Show detailed information about a workspace
+    Info {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// Also show the sizes of the workspace's top-level directories
+        ///
+        /// Only available to the workspace's owners and co-owners.
+        /// Large workspaces may take a while; gives up after 30 seconds.
+        #[arg(long)]
+        du: bool,
     },
     /// Postpone the expiry date of an already existing workspace
     #[clap(alias = "ex")]
     Extend {
         /// Name of the workspace
-        #[arg(value_parser = parse_pathsafe)]
-        name: String,
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
 
-        /// Duration in days to extend the workspace until
+        /// Duration in days from now to extend the workspace for
         ///
         /// If this is fewer than the current days until expiry,
         /// no action will be taken.
-        #[arg(short, long, value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
-        duration: Duration,
+        /// If omitted, the filesystem's default duration is used,
+        /// which is the maximum DURATION unless configured otherwise.
+        #[arg(short, long, visible_alias = "for", value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
+        duration: Option<Duration>,
+
+        /// Days to add to the current expiry date instead
+        ///
+        /// Counted from now if the workspace expired already.  The workspace
+        /// may still expire at most the maximum DURATION from now.
+        #[arg(long, value_name = "DAYS", conflicts_with = "duration", value_parser = clap::value_parser!(i64).range(1..))]
+        by: Option<i64>,
 
         /// User the workspace belongs to
         #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
@@ -98,12 +311,43 @@ pub enum Command {
         /// Filesystem of the workspace
         #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
         filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// Don't notify the owner about the extension
+        #[arg(long)]
+        no_notify: bool,
+
+        #[command(flatten)]
+        format: FormatArgs,
+    },
+    /// Make an expired workspace writable again before it is deleted
+    ///
+    /// The workspace expires again after a grace period set by the filesystem.
+    Restore {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
     },
     /// Expire a workspace
     Expire {
         /// Name of the workspace
-        #[arg(value_parser = parse_pathsafe)]
-        name: String,
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
         /// User the workspace belongs to
         #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
         user: String,
@@ -112,12 +356,153 @@ pub enum Command {
         #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
         filesystem_name: Option<String>,
 
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
         /// Delete this dataset on next cleanup
         ///
         /// No deletion will take place until the next time `maintain` is called.
         /// Be aware that this may happen due to another user / cronjob.
         #[arg(long = "now")]
         delete_on_next_clean: bool,
+
+        /// Don't ask for confirmation before scheduling the deletion
+        ///
+        /// Required for `--now` when not running interactively.
+        #[arg(short, long, requires = "delete_on_next_clean")]
+        yes: bool,
+
+        /// Why the workspace is expired, e.g. "pool nearly full"
+        ///
+        /// Recorded in the event log and included in the owner's notification.
+        #[arg(long)]
+        reason: Option<String>,
+
+        #[command(flatten)]
+        format: FormatArgs,
+    },
+    /// Write a tarball of a workspace's contents, e.g. to take them along when leaving
+    Export {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// File to write the tarball to, or "-" for stdout
+        #[arg(long, value_name = "PATH")]
+        to: PathBuf,
+
+        /// Compress the tarball with zstd
+        #[arg(long)]
+        zstd: bool,
+
+        /// Export a consistent copy from SNAPSHOT instead of the live contents
+        ///
+        /// Without SNAPSHOT, one is taken for the export and destroyed afterwards.
+        #[arg(long, value_name = "SNAPSHOT", num_args = 0..=1, default_missing_value = "")]
+        from_snapshot: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+    },
+    /// Hand the workspaces of a user leaving over (admin only)
+    ///
+    /// Lists all of USER's workspaces, reassigns the ones given with
+    /// `--reassign` to the successor and expires the rest.
+    Offboard {
+        /// User who is leaving
+        #[arg(value_parser = parse_pathsafe)]
+        user: String,
+
+        /// User to hand workspaces over to
+        #[arg(short, long, value_parser = parse_pathsafe)]
+        successor: Option<String>,
+
+        /// Name of a workspace to reassign to the successor; can be repeated
+        #[arg(short, long, value_name = "NAME", requires = "successor", value_parser = parse_pathsafe)]
+        reassign: Vec<String>,
+
+        /// Days until the expired workspaces are deleted
+        ///
+        /// Defaults to each filesystem's retention, which can only be shortened.
+        #[arg(long, value_name = "DAYS", value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
+        retention: Option<Duration>,
+
+        /// Address to send a summary to
+        ///
+        /// Defaults to the successor's address, if known.
+        #[arg(long, value_name = "ADDRESS")]
+        notify: Option<String>,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Transfer a workspace to another user
+    ///
+    /// Admins transfer it right away.  Otherwise its owner offers it to the
+    /// new owner, who takes it over with `--accept`.  The dataset moves into
+    /// the new owner's tree, and the previous owner's files in it are handed
+    /// over as well.
+    Transfer {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User to transfer the workspace to
+        ///
+        /// With --id, the only argument given.
+        #[arg(value_parser = parse_pathsafe, required_unless_present_any = ["accept", "id"])]
+        to: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// Take over a workspace offered to you
+        #[arg(long, conflicts_with = "to")]
+        accept: bool,
+    },
+    /// Print shell-exportable variables describing a workspace
+    ///
+    /// Example: `eval "$(workspaces env my-workspace)"`
+    Env {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// Syntax to print the variables in
+        #[arg(long, value_enum, default_value_t = Shell::Bash)]
+        shell: Shell,
     },
     /// List all existing filesystems
     #[clap(alias = "fi")]
@@ -127,6 +512,73 @@ pub enum Command {
         /// Can be specified multiple times
         #[arg(short, long, value_name = "COLUMN")]
         output: Option<Vec<FilesystemsColumns>>,
+
+        /// Re-render the table every SECONDS seconds until interrupted
+        ///
+        /// Adds a CHANGE column with how much the used space grew or shrank
+        /// since the previous refresh.
+        #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "2")]
+        watch: Option<u64>,
+
+        /// Output format
+        ///
+        /// The JSON formats contain all columns, with sizes in bytes and
+        /// durations in days.  `--output` only affects tables.
+        #[arg(long, value_enum, default_value_t = ListFormat::Table, conflicts_with = "watch")]
+        format: ListFormat,
+    },
+    /// Set up workspaces for yourself
+    ///
+    /// Sets up the email address notifications are sent to, shows the
+    /// filesystems available and, if running interactively, offers to create
+    /// a first workspace.
+    Init,
+    /// List the profiles workspaces can be created with
+    Profiles,
+    /// Print what happens to workspaces as JSON lines
+    ///
+    /// Covers creations, extensions, restores, expiries, renames, deletions and
+    /// notifications, oldest first.  Each event has an increasing `id`.
+    Events {
+        /// Only print events after the one with this id, e.g. to resume
+        #[arg(long, value_name = "ID")]
+        since: Option<i64>,
+
+        /// Keep waiting for and printing new events until interrupted
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Explain what an exit code of this program means
+    Explain {
+        /// Exit code to explain; all of them if omitted
+        code: Option<i32>,
+    },
+    /// Print a completion script for a shell
+    ///
+    /// E.g. `source <(workspaces completions bash)` in ~/.bashrc.  Filesystem
+    /// and workspace names are completed too.
+    Completions {
+        #[arg(value_enum, required_unless_present = "names")]
+        shell: Option<CompletionShell>,
+
+        /// Print the names of filesystems or of your workspaces instead, for
+        /// the completion scripts
+        #[arg(long, value_enum, hide = true, conflicts_with = "shell")]
+        names: Option<CompletedNames>,
+    },
+    /// Show how much space workspaces use, per filesystem or per user
+    Du {
+        /// Sum up the space per user instead, with a column per filesystem
+        ///
+        /// Users are sorted by the space they use, largest first.
+        #[arg(long)]
+        by_user: bool,
+
+        /// Only count workspaces on FILESYSTEM
+        ///
+        /// Can be specified multiple times
+        #[arg(short = 'f', long = "filesystem", value_name = "FILESYSTEM", value_parser = parse_pathsafe)]
+        filter_filesystems: Option<Vec<String>>,
     },
     /// Do maintainance work
     ///
@@ -135,7 +587,397 @@ pub enum Command {
     /// Expired workspaces will be made read-only.
     /// Snapshots will be created.
     /// Notification emails may be sent.
-    Maintain,
+    Maintain {
+        /// Only print warnings and errors, e.g. when run from cron
+        ///
+        /// On a terminal, progress and a timing summary are shown otherwise.
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Afterwards, email the admins in [admin_report] a summary of the past week
+        ///
+        /// Covers new and deleted workspaces, the largest growth, utilization,
+        /// failures and upcoming large deletions.  `workspaces daemon` sends
+        /// it weekly on its own.
+        #[arg(long)]
+        weekly_report: bool,
+
+        /// Only print which workspaces would be set read-only or deleted,
+        /// and who would be notified
+        ///
+        /// Nothing is changed, in ZFS or the database, and nothing is sent.
+        #[arg(long, conflicts_with = "weekly_report")]
+        dry_run: bool,
+
+        /// Like --dry-run, but against a copy of the database, without
+        /// touching any storage
+        ///
+        /// Every workspace is taken to exist and to use no space, so new
+        /// notification days or retention periods in the configuration can
+        /// be tried out before deploying them, e.g. on another host.
+        #[arg(long, conflicts_with = "weekly_report")]
+        simulate: bool,
+
+        /// Database to copy for --simulate, instead of `db_path`
+        #[arg(long, value_name = "PATH", requires = "simulate")]
+        db: Option<PathBuf>,
+
+        #[command(flatten)]
+        bwlimit: BwLimitArgs,
+    },
+    /// Keep running, doing the work of `maintain` periodically (admins only)
+    ///
+    /// Runs are `maintain_interval` apart, the first one right away.
+    /// SIGTERM or SIGINT stop the daemon, after letting a run in progress
+    /// finish.  Meant as a service instead of maintain-workspaces.timer.
+    Daemon,
+    /// Take the snapshots which are due (admins only)
+    ///
+    /// Meant to be run more often than `maintain`, e.g. every few minutes,
+    /// for filesystems with `snapshot_interval_hours`.
+    Snapshot,
+    /// List workspaces the next `maintain` run will delete
+    ///
+    /// Admins can hold off the deletion of individual workspaces, e.g. to
+    /// review large deletions first.
+    PendingDeletions {
+        /// Hold off the deletion of workspace NAME until released (admins only)
+        #[arg(long, value_name = "NAME", value_parser = parse_pathsafe, conflicts_with = "release")]
+        hold: Option<String>,
+
+        /// Allow the deletion of workspace NAME again (admins only)
+        #[arg(long, value_name = "NAME", value_parser = parse_pathsafe)]
+        release: Option<String>,
+
+        /// User the held / released workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the held / released workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+    },
+    /// Delete expired workspaces right away, instead of on the next
+    /// `maintain` run (admins only)
+    ///
+    /// Either the expired workspace NAME, even within its retention period,
+    /// or with --all every workspace past its retention period whose deletion
+    /// isn't held.  They are archived and backed up like `maintain` does, but
+    /// their owners aren't notified.
+    Clean {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present_any = ["id", "all"])]
+        name: Option<String>,
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace, or the one to clean up with --all
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// Delete all workspaces past their retention period
+        #[arg(long, conflicts_with_all = ["name", "user", "id"])]
+        all: bool,
+
+        /// Don't ask for confirmation before deleting
+        ///
+        /// Required when not running interactively.
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        bwlimit: BwLimitArgs,
+    },
+    /// Cross-check the database against the datasets (admins only)
+    ///
+    /// Reports datasets no workspace is recorded for, workspaces whose
+    /// dataset is missing, and workspaces mounted elsewhere than recorded,
+    /// e.g. after restoring either from a backup.  Exits with an error while
+    /// any of them remain.
+    Fsck {
+        /// Only check this filesystem
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Record orphaned datasets as workspaces, and mountpoints as they are
+        ///
+        /// The owner and name of a dataset are told from its path, and it
+        /// gets the filesystem's default duration.
+        #[arg(long)]
+        adopt: bool,
+
+        /// Remove workspaces whose dataset is missing from the database
+        #[arg(long)]
+        prune: bool,
+    },
+    /// Approve a pending workspace creation request (admins only)
+    ///
+    /// Without an ID, lists all pending requests.
+    Approve {
+        /// ID of the request, as shown when listing pending requests
+        id: Option<i64>,
+
+        /// Reject the request instead of approving it
+        #[arg(long, requires = "id")]
+        reject: bool,
+    },
+    /// Restore a workspace archived to object storage (admins only)
+    ///
+    /// Lists all archives if no ID is given.
+    RestoreArchive {
+        /// ID of the archive, as shown when listing archives
+        id: Option<i64>,
+
+        /// Duration in days until the restored workspace expires again
+        ///
+        /// Defaults to the filesystem's default duration.
+        #[arg(short, long, requires = "id", value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
+        duration: Option<Duration>,
+
+        #[command(flatten)]
+        bwlimit: BwLimitArgs,
+    },
+    /// Recreate a workspace from its restic / borg backup (admins only)
+    ///
+    /// Lists all backups if no ID is given.
+    RestoreBackup {
+        /// ID of the backup, as shown when listing backups
+        id: Option<i64>,
+
+        /// Duration in days until the restored workspace expires again
+        ///
+        /// Defaults to the filesystem's default duration.
+        #[arg(short, long, requires = "id", value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
+        duration: Option<Duration>,
+    },
+    /// Recreate a deleted workspace from its archived tarball (admins only)
+    ///
+    /// Uses the most recent archive, as packed by `maintain` into the
+    /// filesystem's `archive_path`.  Deleted workspaces have no id anymore,
+    /// so unlike other commands this one takes no --id.
+    RecoverArchive {
+        /// Name of the deleted workspace
+        #[arg(value_parser = parse_pathsafe)]
+        name: String,
+
+        /// User the workspace belonged to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem the workspace was on
+        #[arg(short, long = "filesystem")]
+        filesystem_name: Option<String>,
+
+        /// Duration in days until the recovered workspace expires again
+        ///
+        /// Defaults to the filesystem's default duration.
+        #[arg(short, long, value_parser = |arg: &str| -> Result<Duration, ParseIntError> {Ok(Duration::days(arg.parse()?))})]
+        duration: Option<Duration>,
+    },
+    /// Check an archive against its checksum (admins only)
+    ///
+    /// Downloads an archive from object storage, or reads a tarball packed
+    /// into `archive_path` with --tarball, and compares its SHA-256 to the one
+    /// recorded when archiving the workspace.  Lists all archives or tarballs
+    /// if no ID is given.
+    VerifyArchive {
+        /// ID of the archive, as shown when listing archives
+        id: Option<i64>,
+
+        /// Verify the tarball with this ID instead
+        #[arg(long)]
+        tarball: bool,
+    },
+    /// Refresh stale entries of the ZFS property cache
+    #[command(hide = true)]
+    RefreshCache,
+    /// Manage your notification settings
+    Email {
+        #[command(subcommand)]
+        command: EmailCommand,
+    },
+    /// List or change the co-owners of a workspace
+    ///
+    /// Co-owners may extend, expire, rename and restore it just like its
+    /// owner, and get its notifications as well.  Only the owner and admins
+    /// may change them.
+    Owner {
+        #[command(subcommand)]
+        command: OwnerCommand,
+    },
+    /// Grant another user limited rights on a workspace
+    ///
+    /// With `extend` they may keep it alive with `workspaces extend`, with
+    /// `status` only inspect it, e.g. with `workspaces info --du`.  Unlike
+    /// co-owners they can't expire or rename it.  Granting again replaces the
+    /// previous right.
+    Grant {
+        /// User to grant the right to
+        #[arg(value_parser = parse_pathsafe)]
+        grantee: String,
+
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// What the grantee may do
+        #[arg(long, value_enum, default_value_t = GrantRight::Extend)]
+        right: GrantRight,
+
+        /// Revoke the grantee's right instead
+        #[arg(long, conflicts_with = "right")]
+        revoke: bool,
+    },
+    /// Show or change your notification preferences
+    ///
+    /// Shows them without a subcommand.
+    Prefs {
+        #[command(subcommand)]
+        command: Option<PrefsCommand>,
+    },
+    /// Manage the system configuration (admins only)
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Show or change the days before expiry a workspace's owner is warned on
+    ///
+    /// Example: `--days 30,14,7,0` for extra-early warnings about a large
+    /// workspace that takes a while to copy out.
+    NotifySchedule {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// Comma-separated days before expiry to warn on, replacing the filesystem's
+        ///
+        /// Negative days fall into the retention period after expiry.  At most
+        /// ten years either way.
+        #[arg(long, value_name = "DAYS", value_delimiter = ',', allow_hyphen_values = true, conflicts_with = "reset", value_parser = clap::value_parser!(i64).range(-crate::schedule::MAX_DAYS..=crate::schedule::MAX_DAYS))]
+        days: Option<Vec<i64>>,
+
+        /// Go back to the filesystem's schedule
+        #[arg(long)]
+        reset: bool,
+    },
+    /// Leave a workspace out of its filesystem's regular snapshots
+    ///
+    /// Meant for large, short-lived data which would make snapshots grow a
+    /// lot.  Files deleted from it can't be restored from snapshots anymore.
+    ExcludeSnapshots {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// Include the workspace in snapshots again
+        #[arg(long)]
+        undo: bool,
+    },
+    /// List a workspace to everyone with `list --public`
+    ///
+    /// Meant for shared data, e.g. reference datasets, others should be able
+    /// to discover.  Only its name, owner, filesystem, mountpoint and
+    /// description are shown.
+    Publish {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// What the workspace contains, shown next to it
+        #[arg(short, long)]
+        description: Option<String>,
+
+        /// Stop listing the workspace publicly
+        #[arg(long, conflicts_with = "description")]
+        undo: bool,
+    },
+    /// Attach an operator note to a workspace (admins only)
+    ///
+    /// Notes are shown to admins by `info` and `list`, but never sent to the
+    /// workspace's owner.
+    Note {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+
+        /// Text of the note, e.g. "user contacted 2024-05-02, migration planned"
+        #[arg(
+            short,
+            long,
+            required_unless_present = "delete",
+            conflicts_with = "delete"
+        )]
+        message: Option<String>,
+
+        /// Delete the note with this number instead, as shown by `info`
+        #[arg(long, value_name = "NOTE")]
+        delete: Option<i64>,
+    },
     /// Send a one-off test notification email (admins only)
     NotifyTest {
         /// User whose ~/.config/workspaces.toml holds the recipient email
@@ -154,8 +996,352 @@ pub enum Command {
     },
 }
 
+impl Command {
+    /// Whether the command only inspects state, and can thus run against a
+    /// read-only database
+    pub fn is_inspection(&self) -> bool {
+        matches!(
+            self,
+            Command::List { .. }
+                | Command::Info { .. }
+                | Command::Env { .. }
+                | Command::Filesystems { .. }
+                | Command::Profiles
+                | Command::Du { .. }
+                | Command::PendingDeletions {
+                    hold: None,
+                    release: None,
+                    ..
+                }
+                | Command::Events { .. }
+                | Command::Explain { .. }
+                | Command::Completions { .. }
+                | Command::Fsck {
+                    adopt: false,
+                    prune: false,
+                    ..
+                }
+                | Command::Calendar { email: None, .. }
+                | Command::VerifyArchive { .. }
+                | Command::Prefs {
+                    command: None | Some(PrefsCommand::Show),
+                }
+                | Command::Owner {
+                    command: OwnerCommand::List { .. },
+                }
+        )
+    }
+}
+
+/// Arguments of commands streaming workspaces to and from object storage
+#[derive(clap::Args, Debug)]
+pub struct BwLimitArgs {
+    /// Limit streams to and from object storage to RATE bytes per second,
+    /// e.g. 50M, instead of the filesystems' `bwlimit`
+    #[arg(long, value_name = "RATE", value_parser = crate::config::parse_size)]
+    pub bwlimit: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum EmailCommand {
+    /// Set the address your notifications are sent to
+    ///
+    /// Stored in your ~/.config/workspaces.toml, or the workspaces database if
+    /// the site keeps preferences there.
+    SetMine {
+        /// Email address, e.g. "you@example.org" or "Your Name <you@example.org>"
+        address: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum OwnerCommand {
+    /// List the co-owners of a workspace
+    List {
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+    },
+    /// Make another user co-owner of a workspace
+    Add {
+        /// The new co-owner
+        #[arg(value_parser = parse_pathsafe)]
+        co_owner: String,
+
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+    },
+    /// Take a co-owner's rights on a workspace away
+    Remove {
+        /// The co-owner to remove
+        #[arg(value_parser = parse_pathsafe)]
+        co_owner: String,
+
+        /// Name of the workspace
+        #[arg(value_parser = parse_pathsafe, required_unless_present = "id")]
+        name: Option<String>,
+
+        /// User the workspace belongs to
+        #[arg(short, long, default_value_t = get_current_username().unwrap().to_string_lossy().to_string(), value_parser = parse_pathsafe)]
+        user: String,
+
+        /// Filesystem of the workspace
+        #[arg(short, long = "filesystem", value_name = "FILESYSTEM")]
+        filesystem_name: Option<String>,
+
+        /// Id of the workspace, instead of its name, user and filesystem
+        #[arg(long, conflicts_with_all = ["name", "user", "filesystem_name"])]
+        id: Option<i64>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PrefsCommand {
+    /// Show your preferences and where they are kept
+    Show,
+    /// Change a preference
+    ///
+    /// Example: `workspaces prefs set digest-frequency weekly`
+    Set { key: PrefsKey, value: String },
+    /// Go back to the site's default for a preference
+    Unset { key: PrefsKey },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum PrefsKey {
+    /// Address notifications are sent to, or several separated by commas
+    Email,
+    /// Addresses copied on notifications, separated by commas
+    Cc,
+    /// How often created / extended emails are sent: immediate, daily or weekly
+    DigestFrequency,
+}
+
+impl PrefsKey {
+    /// All preferences, in the order they are shown
+    pub const ALL: [PrefsKey; 3] = [PrefsKey::Email, PrefsKey::Cc, PrefsKey::DigestFrequency];
+
+    /// The key of the preference in the stored TOML
+    pub fn toml_key(self) -> &'static str {
+        match self {
+            PrefsKey::Email => "email",
+            PrefsKey::Cc => "cc",
+            PrefsKey::DigestFrequency => "digest_frequency",
+        }
+    }
+}
+
+impl fmt::Display for PrefsKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                PrefsKey::Email => "email",
+                PrefsKey::Cc => "cc",
+                PrefsKey::DigestFrequency => "digest-frequency",
+            }
+        )
+    }
+}
+
+/// What a user was granted on someone else's workspace, each including the ones before
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum GrantRight {
+    /// Inspect the workspace
+    Status,
+    /// Extend the workspace, and inspect it
+    Extend,
+}
+
+impl fmt::Display for GrantRight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                GrantRight::Status => "status",
+                GrantRight::Extend => "extend",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Rewrite a configuration for an older version in the current format
+    ///
+    /// Renamed keys are updated in place, keeping comments.  The old file is
+    /// kept with a `.bak` suffix.
+    Upgrade {
+        /// Print the upgraded configuration instead of writing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
 #[derive(Clone, Debug, ValueEnum)]
+pub enum Shell {
+    /// `export KEY='value'` lines, also understood by sh and zsh
+    Bash,
+    /// `set -gx KEY 'value'` lines
+    Fish,
+    /// A single JSON object
+    Json,
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Shell::Bash => "bash",
+                Shell::Fish => "fish",
+                Shell::Json => "json",
+            }
+        )
+    }
+}
+
+/// Arguments of commands creating or changing a workspace
+#[derive(clap::Args, Debug)]
+pub struct FormatArgs {
+    /// Output format
+    ///
+    /// `json` prints a single object describing the workspace afterwards
+    /// instead, e.g. its id, mountpoint and expiration time, and what was done.
+    #[arg(long, value_enum, default_value_t = CommandFormat::Text)]
+    pub format: CommandFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CommandFormat {
+    /// Messages for people
+    Text,
+    /// A single JSON object
+    Json,
+}
+
+impl fmt::Display for CommandFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                CommandFormat::Text => "text",
+                CommandFormat::Json => "json",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum CompletedNames {
+    Filesystems,
+    Workspaces,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ListGroupBy {
+    User,
+    Filesystem,
+}
+
+impl fmt::Display for ListGroupBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ListGroupBy::User => "user",
+                ListGroupBy::Filesystem => "filesystem",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// An aligned table
+    Table,
+    /// One JSON object per line
+    Jsonl,
+    /// A single JSON array
+    Json,
+}
+
+impl fmt::Display for ListFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ListFormat::Table => "table",
+                ListFormat::Jsonl => "jsonl",
+                ListFormat::Json => "json",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CalendarFormat {
+    /// An aligned table
+    Table,
+    /// An iCalendar file with one event per expiry or deletion
+    Ics,
+}
+
+impl fmt::Display for CalendarFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                CalendarFormat::Table => "table",
+                CalendarFormat::Ics => "ics",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, ValueEnum)]
 pub enum WorkspacesColumns {
+    /// Numeric id of the workspace, which stays the same across renames
+    Id,
     /// Name of the workspace
     Name,
     /// Owner of the workspace
@@ -166,8 +1352,14 @@ pub enum WorkspacesColumns {
     Size,
     /// Days until expiry / deletion
     Expiry,
+    /// Date the workspace was created
+    Created,
     /// Mountpoint of the workspace
     Mountpoint,
+    /// Description of a published workspace
+    Description,
+    /// Latest operator note (admins only)
+    Notes,
 }
 
 impl fmt::Display for WorkspacesColumns {
@@ -176,12 +1368,16 @@ impl fmt::Display for WorkspacesColumns {
             f,
             "{}",
             match self {
+                WorkspacesColumns::Id => "ID",
                 WorkspacesColumns::Name => "NAME",
                 WorkspacesColumns::User => "USER",
                 WorkspacesColumns::Fs => "FS",
                 WorkspacesColumns::Size => "SIZE",
                 WorkspacesColumns::Expiry => "EXPIRY",
+                WorkspacesColumns::Created => "CREATED",
                 WorkspacesColumns::Mountpoint => "MOUNTPOINT",
+                WorkspacesColumns::Description => "DESCRIPTION",
+                WorkspacesColumns::Notes => "NOTES",
             }
         )
     }
@@ -220,28 +1416,45 @@ impl fmt::Display for FilesystemsColumns {
     }
 }
 
-/// String contains characters which are not [A-Za-z0-9_-]
+/// String contains characters which are not [A-Za-z0-9._-], or starts with a
+/// dot or a dash
 #[derive(Debug)]
-struct NotPathsafeError {
+pub(crate) struct NotPathsafeError {
     str: String,
 }
 impl fmt::Display for NotPathsafeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "`{}` must contain only the characters [A-Za-z0-9_-]",
-            self.str
-        )
+        if self.str.contains('/') {
+            // Would otherwise silently become a nested dataset
+            write!(
+                f,
+                "`{}` must not contain `/`, workspaces can't be nested",
+                self.str
+            )
+        } else {
+            write!(
+                f,
+                "`{}` must contain only the characters [A-Za-z0-9._-] and not start with a dot \
+                    or a dash",
+                self.str
+            )
+        }
     }
 }
 impl Error for NotPathsafeError {}
 
-/// Ensures string only contains the characters [A-Za-z0-9_-]
-fn parse_pathsafe(ident: &str) -> Result<String, NotPathsafeError> {
+/// Ensures string only contains the characters [A-Za-z0-9._-] and doesn't
+/// start with a dot or a dash
+///
+/// These are safe as ZFS dataset names and path components alike; a leading
+/// dot is refused so names can't be `.`, `..` or hidden, and a leading dash
+/// so they can't be taken for options by the commands they are passed to.
+pub(crate) fn parse_pathsafe(ident: &str) -> Result<String, NotPathsafeError> {
     if !ident.is_empty()
+        && !ident.starts_with(['.', '-'])
         && ident
             .chars()
-            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
     {
         Ok(ident.to_string())
     } else {
@@ -250,3 +1463,62 @@ fn parse_pathsafe(ident: &str) -> Result<String, NotPathsafeError> {
         })
     }
 }
+
+/// Parses an RFC 3339 timestamp, or a date meaning local midnight
+fn parse_timestamp(arg: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(arg, "%Y-%m-%d") {
+        return date
+            .and_hms_opt(0, 0, 0)
+            .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+            .map(|midnight| midnight.with_timezone(&Utc))
+            .ok_or_else(|| format!("{} has no local midnight", arg));
+    }
+    DateTime::parse_from_rfc3339(arg)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|e| {
+            format!(
+                "`{}` is neither a date nor an RFC 3339 timestamp: {}",
+                arg, e
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pathsafe_names_pass() {
+        for name in ["run-2024.03", "a_b", "x", "trailing-", "a--b"] {
+            assert_eq!(parse_pathsafe(name).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn nested_names_are_refused() {
+        let error = parse_pathsafe("a/b").unwrap_err();
+        assert!(error.to_string().contains("can't be nested"));
+        assert!(parse_pathsafe("/").is_err());
+    }
+
+    #[test]
+    fn dot_names_are_refused() {
+        for name in [".", "..", ".hidden"] {
+            assert!(parse_pathsafe(name).is_err(), "{} passed", name);
+        }
+    }
+
+    #[test]
+    fn option_like_names_are_refused() {
+        for name in ["-", "--", "-rf", "--help"] {
+            assert!(parse_pathsafe(name).is_err(), "{} passed", name);
+        }
+    }
+
+    #[test]
+    fn empty_and_other_characters_are_refused() {
+        for name in ["", "a b", "a:b", "ä", "a\n"] {
+            assert!(parse_pathsafe(name).is_err(), "{:?} passed", name);
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use chrono::{DateTime, Local, Utc};
+use std::sync::OnceLock;
+
+/// Time `--now` pretends it is, instead of the system's
+static NOW: OnceLock<DateTime<Utc>> = OnceLock::new();
+
+/// Pretends it is `now` from now on, e.g. to replay what a `maintain` run
+/// next week will do
+pub fn set(now: DateTime<Utc>) {
+    let _ = NOW.set(now);
+}
+
+/// The current time expiry and notifications are decided by
+///
+/// That's the system's, unless `--now` overrides it.
+pub fn now() -> DateTime<Utc> {
+    NOW.get().copied().unwrap_or_else(Utc::now)
+}
+
+/// [`now`] in the local timezone
+pub fn local_now() -> DateTime<Local> {
+    now().with_timezone(&Local)
+}
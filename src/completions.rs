@@ -0,0 +1,357 @@
+use std::{collections::HashMap, error::Error};
+
+use clap::{Arg, Command, CommandFactory};
+use rusqlite::Connection;
+use users::{get_current_uid, get_current_username};
+
+use crate::{
+    cli::{self, CompletedNames, CompletionShell},
+    config,
+};
+
+/// Positional arguments naming one of the user's existing workspaces
+const WORKSPACE_ARGS: &[&str] = &["name", "src_workspace_name"];
+
+/// What the value of an argument is completed with
+enum Values {
+    Filesystems,
+    Workspaces,
+    Users,
+    Choices(String),
+    Files,
+}
+
+impl Values {
+    fn of(arg: &Arg) -> Self {
+        let choices: Vec<_> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name().to_owned())
+            .collect();
+        if !choices.is_empty() {
+            Values::Choices(choices.join(" "))
+        } else if arg.get_id() == "filesystem_name" {
+            Values::Filesystems
+        } else if arg.is_positional() && WORKSPACE_ARGS.contains(&arg.get_id().as_str()) {
+            Values::Workspaces
+        } else if arg.get_id() == "user" {
+            Values::Users
+        } else {
+            Values::Files
+        }
+    }
+}
+
+/// A (sub)command, with the words leading to it, e.g. `workspaces owner add`
+struct Level<'a> {
+    path: String,
+    command: &'a Command,
+}
+
+/// The command and all of its visible subcommands, parents first
+fn levels<'a>(path: String, command: &'a Command, all: &mut Vec<Level<'a>>) {
+    all.push(Level {
+        path: path.clone(),
+        command,
+    });
+    for subcommand in visible_subcommands(command) {
+        levels(
+            format!("{} {}", path, subcommand.get_name()),
+            subcommand,
+            all,
+        );
+    }
+}
+
+fn visible_subcommands(command: &Command) -> impl Iterator<Item = &Command> {
+    command
+        .get_subcommands()
+        .filter(|subcommand| !subcommand.is_hide_set())
+}
+
+fn visible_options(command: &Command) -> impl Iterator<Item = &Arg> {
+    command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set() && !arg.is_positional())
+}
+
+/// `-f` and `--filesystem-name`, say
+fn flags(arg: &Arg) -> Vec<String> {
+    let short = arg.get_short().map(|short| format!("-{}", short));
+    let long = arg.get_long().map(|long| format!("--{}", long));
+    short.into_iter().chain(long).collect()
+}
+
+/// The first line of what the help says about a command or argument
+fn summary(help: Option<&clap::builder::StyledStr>) -> String {
+    help.map(|help| {
+        help.to_string()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    })
+    .unwrap_or_default()
+}
+
+/// Prints a completion script for `shell`
+pub fn completions(shell: CompletionShell) -> Result<(), Box<dyn Error>> {
+    print!("{}", script(shell));
+    Ok(())
+}
+
+/// The completion script for `shell`
+///
+/// Written by hand rather than by `clap_complete`, which isn't available where
+/// workspaces is built.
+fn script(shell: CompletionShell) -> String {
+    let mut command = cli::Args::command();
+    // Adds the global and help arguments to the subcommands
+    command.build();
+    let mut all = Vec::new();
+    levels(command.get_name().to_owned(), &command, &mut all);
+
+    match shell {
+        CompletionShell::Bash => bash(&all),
+        // zsh understands bash's completion functions well enough
+        CompletionShell::Zsh => format!(
+            "#compdef workspaces\nautoload -U +X bashcompinit && bashcompinit\n{}",
+            bash(&all)
+        ),
+        CompletionShell::Fish => fish(&all),
+    }
+}
+
+/// Switches `path` to the subcommand a word names, for each of its names
+fn transitions(all: &[Level]) -> Vec<(Vec<String>, String)> {
+    all.iter()
+        .flat_map(|level| {
+            visible_subcommands(level.command).map(|subcommand| {
+                let words = std::iter::once(subcommand.get_name())
+                    .chain(subcommand.get_all_aliases())
+                    .map(|word| format!("{}:{}", level.path, word))
+                    .collect();
+                (words, format!("{} {}", level.path, subcommand.get_name()))
+            })
+        })
+        .collect()
+}
+
+fn bash(all: &[Level]) -> String {
+    let names = |kind| format!("$(workspaces completions --names {} 2>/dev/null)", kind);
+    let mut script = String::from(
+        "_workspaces() {\n    \
+            local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n    \
+            local path=workspaces words i\n    \
+            for ((i = 1; i < COMP_CWORD; i++)); do\n        \
+                case \"$path:${COMP_WORDS[i]}\" in\n",
+    );
+    for (words, path) in transitions(all) {
+        let patterns: Vec<_> = words.iter().map(|word| format!("'{}'", word)).collect();
+        script += &format!("            {}) path='{}' ;;\n", patterns.join("|"), path);
+    }
+    script += "        esac\n    done\n\n    case \"$path:$prev\" in\n";
+    for level in all {
+        for arg in visible_options(level.command) {
+            if !arg.get_action().takes_values() {
+                continue;
+            }
+            let patterns: Vec<_> = flags(arg)
+                .iter()
+                .map(|flag| format!("'{}:{}'", level.path, flag))
+                .collect();
+            let reply = match Values::of(arg) {
+                Values::Filesystems => format!("compgen -W \"{}\"", names("filesystems")),
+                Values::Workspaces => format!("compgen -W \"{}\"", names("workspaces")),
+                Values::Users => "compgen -u".to_owned(),
+                Values::Choices(choices) => format!("compgen -W '{}'", choices),
+                Values::Files => "compgen -f".to_owned(),
+            };
+            script += &format!(
+                "        {})\n            COMPREPLY=($({} -- \"$cur\"))\n            return ;;\n",
+                patterns.join("|"),
+                reply
+            );
+        }
+    }
+    script += "    esac\n\n    case \"$path\" in\n";
+    for level in all {
+        let mut words: Vec<String> = visible_subcommands(level.command)
+            .map(|subcommand| subcommand.get_name().to_owned())
+            .collect();
+        words.extend(visible_options(level.command).flat_map(flags));
+        for arg in level.command.get_positionals() {
+            match Values::of(arg) {
+                Values::Workspaces => words.push(names("workspaces")),
+                Values::Choices(choices) => words.push(choices),
+                _ => {}
+            }
+        }
+        script += &format!(
+            "        '{}') words=\"{}\" ;;\n",
+            level.path,
+            words.join(" ")
+        );
+    }
+    script += "    esac\n    \
+        COMPREPLY=($(compgen -W \"$words\" -- \"$cur\"))\n\
+        }\n\
+        complete -F _workspaces workspaces\n";
+    script
+}
+
+/// Quotes `text` for fish
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(all: &[Level]) -> String {
+    let names = |kind| format!("'(workspaces completions --names {} 2>/dev/null)'", kind);
+    let mut script = String::from(
+        "function __workspaces_path\n    \
+            set -l path workspaces\n    \
+            for word in (commandline -opc)\n        \
+                switch \"$path:$word\"\n",
+    );
+    for (words, path) in transitions(all) {
+        let patterns: Vec<_> = words.iter().map(|word| fish_quote(word)).collect();
+        script += &format!(
+            "            case {}\n                set path {}\n",
+            patterns.join(" "),
+            fish_quote(&path)
+        );
+    }
+    script += "        end\n    end\n    echo $path\nend\n\n\
+        function __workspaces_at\n    test (__workspaces_path) = $argv[1]\nend\n\n\
+        complete -c workspaces -f\n";
+    for level in all {
+        let condition = format!("-n \"__workspaces_at '{}'\"", level.path);
+        for subcommand in visible_subcommands(level.command) {
+            script += &format!(
+                "complete -c workspaces {} -a {} -d {}\n",
+                condition,
+                subcommand.get_name(),
+                fish_quote(&summary(subcommand.get_about()))
+            );
+        }
+        for arg in visible_options(level.command) {
+            let mut line = format!("complete -c workspaces {}", condition);
+            if let Some(short) = arg.get_short() {
+                line += &format!(" -s {}", short);
+            }
+            if let Some(long) = arg.get_long() {
+                line += &format!(" -l {}", long);
+            }
+            if arg.get_action().takes_values() {
+                line += &match Values::of(arg) {
+                    Values::Filesystems => format!(" -x -a {}", names("filesystems")),
+                    Values::Workspaces => format!(" -x -a {}", names("workspaces")),
+                    Values::Users => " -x -a '(__fish_complete_users)'".to_owned(),
+                    Values::Choices(choices) => format!(" -x -a {}", fish_quote(&choices)),
+                    Values::Files => " -r -F".to_owned(),
+                };
+            }
+            line += &format!(" -d {}\n", fish_quote(&summary(arg.get_help())));
+            script += &line;
+        }
+        for arg in level.command.get_positionals() {
+            let values = match Values::of(arg) {
+                Values::Workspaces => names("workspaces"),
+                Values::Choices(choices) => fish_quote(&choices),
+                _ => continue,
+            };
+            script += &format!("complete -c workspaces {} -a {}\n", condition, values);
+        }
+    }
+    script
+}
+
+/// Prints the names completed for `kind`, one per line
+///
+/// Workspaces are those the user owns or co-owns, or all of them for admins.
+pub fn names(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    kind: CompletedNames,
+) -> Result<(), Box<dyn Error>> {
+    let mut names: Vec<String> = match kind {
+        CompletedNames::Filesystems => filesystems.keys().cloned().collect(),
+        CompletedNames::Workspaces if get_current_uid() == 0 => conn
+            .prepare("SELECT DISTINCT name FROM workspaces")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?,
+        CompletedNames::Workspaces => {
+            let me = get_current_username()
+                .ok_or("couldn't get username")?
+                .to_string_lossy()
+                .into_owned();
+            conn.prepare(
+                "SELECT DISTINCT name FROM workspaces \
+                    WHERE user = ?1 \
+                        OR id IN (SELECT workspace_id FROM co_owners WHERE user = ?1)",
+            )?
+            .query_map((me,), |row| row.get(0))?
+            .collect::<Result<_, _>>()?
+        }
+    };
+    names.sort();
+    for name in names {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        process::{Command, Stdio},
+    };
+
+    use super::*;
+
+    /// Whether `shell` parses `script` without complaints, if it is installed
+    fn parses(shell: &str, script: &str) -> io::Result<Option<bool>> {
+        let mut child = match Command::new(shell)
+            .arg("-n")
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        child
+            .stdin
+            .take()
+            .expect("stdin should be piped")
+            .write_all(script.as_bytes())?;
+        let output = child.wait_with_output()?;
+        Ok(Some(output.status.success() && output.stderr.is_empty()))
+    }
+
+    #[test]
+    fn scripts_parse() {
+        for (shell, name) in [
+            (CompletionShell::Bash, "bash"),
+            (CompletionShell::Zsh, "zsh"),
+            (CompletionShell::Fish, "fish"),
+        ] {
+            let parsed = parses(name, &script(shell)).unwrap();
+            assert_ne!(parsed, Some(false), "{} fails to parse its script", name);
+        }
+    }
+
+    #[test]
+    fn bash_script_completes_subcommands() {
+        let script = format!(
+            "{}\nCOMP_WORDS=(workspaces owner a)\nCOMP_CWORD=2\n_workspaces\n\
+                echo \"${{COMPREPLY[@]}}\"\n",
+            script(CompletionShell::Bash)
+        );
+        let output = Command::new("bash").arg("-c").arg(script).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "add");
+    }
+}
@@ -1,9 +1,20 @@
+use crate::{
+    btrfs::Btrfs,
+    error::WorkspacesError,
+    simulated::Simulated,
+    storage::{Host, Storage},
+    template,
+    zfs::Zfs,
+};
 use chrono::Duration;
-use lettre::message::Mailbox;
+use lettre::message::{Mailbox, Mailboxes};
 use serde::de::{self, Unexpected};
 use serde::{Deserialize, Deserializer};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read};
 use std::path::PathBuf;
+use users::{get_user_by_name, get_user_groups};
 
 /// Path of the configuration file
 pub const CONFIG_PATH: &str = "/etc/workspaces/workspaces.toml";
@@ -35,11 +46,82 @@ pub struct Config {
     #[serde(default)]
     pub smtp: Option<SmtpConfig>,
 
+    /// Webhook notifications are POSTed to
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+
+    /// Maximum number of notifications `maintain` sends at once
+    #[serde(default = "default_max_concurrent_notifications")]
+    pub max_concurrent_notifications: usize,
+
+    /// How often created / extended emails are sent, unless users choose
+    /// themselves
+    #[serde(default)]
+    pub digest_frequency: DigestFrequency,
+
+    /// Central reporting of crashes and errors
+    #[serde(default)]
+    pub telemetry: Option<TelemetryConfig>,
+
+    /// Environment modulefile generation
+    #[serde(default)]
+    pub modulefiles: Option<ModulefilesConfig>,
+
+    /// Handling of workspaces whose owner's account is gone or expired
+    #[serde(default)]
+    pub departed_users: Option<DepartedUsersConfig>,
+
+    /// Weekly summary emailed to admins
+    #[serde(default)]
+    pub admin_report: Option<AdminReportConfig>,
+
+    /// Seconds ZFS properties shown by `list` and `info` may be cached for.
+    /// 0 disables the cache.
+    #[serde(
+        default = "default_property_cache_ttl",
+        deserialize_with = "from_seconds"
+    )]
+    pub property_cache_ttl: Duration,
+
+    /// Seconds between the maintenance runs of `workspaces daemon`
+    #[serde(
+        default = "default_maintain_interval",
+        deserialize_with = "from_seconds"
+    )]
+    pub maintain_interval: Duration,
+
+    /// Where users' notification preferences are kept
+    #[serde(default)]
+    pub user_preferences: UserPreferences,
+
+    /// Whether to print hints, e.g. about a missing email address, when
+    /// stderr is a terminal
+    #[serde(default = "default_true")]
+    pub hints: bool,
+
+    /// Whether users only see their own workspaces in `list`, `calendar`, `du`,
+    /// `pending-deletions` and `events`, and those shared with or published to
+    /// them, instead of everyone's
+    #[serde(default)]
+    pub private_listing: bool,
+
+    /// Least severe messages logged, to stderr or, for `maintain` and
+    /// `daemon`, to syslog
+    #[serde(default)]
+    pub log_level: LogLevel,
+
+    /// Notification templates of the filesystems which don't have their own
+    #[serde(default)]
+    pub notification_templates: NotificationTemplates,
+
     /// Default filesystem to use in CLI
     pub default_filesystem: Option<String>,
     /// Workspace filesystem definitions
     #[serde(default)]
     pub filesystems: HashMap<String, Filesystem>,
+    /// Named settings for `workspaces create --profile`
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
 }
 
 fn default_db_path() -> PathBuf {
@@ -64,11 +146,41 @@ fn default_db_path() -> PathBuf {
     PathBuf::from("/usr/local/lib/workspaces/workspaces.db")
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_concurrent_notifications() -> usize {
+    4
+}
+
+fn default_property_cache_ttl() -> Duration {
+    Duration::seconds(60)
+}
+
+fn default_maintain_interval() -> Duration {
+    Duration::days(1)
+}
+
 /// A filesystem workspaces can be created in
 #[derive(Debug, Deserialize)]
 pub struct Filesystem {
-    /// ZFS filesystem / volume which will act as the root for the datasets
-    pub root: String,
+    /// ZFS filesystems / volumes which will act as the roots for the datasets.
+    /// Either a single root or a list of them, e.g. across several pools.
+    /// Workspaces created before a root was added live on the first one.
+    #[serde(rename = "root", deserialize_with = "one_or_many_roots")]
+    pub roots: Vec<String>,
+    /// How new workspaces are distributed among multiple roots
+    #[serde(default)]
+    pub placement: Placement,
+    /// How the datasets of new workspaces are named below their root.
+    /// Existing workspaces keep theirs.
+    #[serde(default)]
+    pub layout: Layout,
+    /// Template for the mountpoints of new workspaces, e.g.
+    /// `/workspaces/{user}/{name}`.  Inherited from the root if unset.
+    #[serde(default)]
+    pub mountpoint: Option<String>,
 
     /// Maximum number of days a workspace may exist
     #[serde(deserialize_with = "from_days")]
@@ -76,6 +188,24 @@ pub struct Filesystem {
     /// Days after which an expired dataset will be removed
     #[serde(deserialize_with = "from_days")]
     pub expired_retention: Duration,
+    /// Days a workspace is extended by if no duration is given.
+    /// Defaults to `max_duration`.
+    #[serde(default, deserialize_with = "from_opt_days")]
+    pub default_duration: Option<Duration>,
+    /// ZFS quota of new workspaces unless given explicitly, e.g. "500G"
+    #[serde(default, deserialize_with = "from_opt_size")]
+    pub default_quota: Option<u64>,
+    /// Largest quota users may give their workspaces.  Workspaces without
+    /// any other quota get this one.
+    #[serde(default, deserialize_with = "from_opt_size")]
+    pub max_quota: Option<u64>,
+    /// Most unexpired workspaces a user may have on the filesystem
+    #[serde(default)]
+    pub max_workspaces_per_user: Option<usize>,
+    /// Most space a user's unexpired workspaces may take up together, e.g.
+    /// "10T", counting their quotas, or their size for those without one
+    #[serde(default, deserialize_with = "from_opt_size")]
+    pub max_total_bytes_per_user: Option<u64>,
 
     /// Days relative to the expiration time the user will be notified.
     /// Negative durations will lead to messages being sent after expiry,
@@ -83,13 +213,521 @@ pub struct Filesystem {
     #[serde(default = "Vec::new", deserialize_with = "from_days_list")]
     pub expiry_notifications_on_days: Vec<Duration>,
 
+    /// Days before expiry from which on an `EXPIRES_SOON.txt` is kept
+    /// in the workspace.  Disabled if unset.
+    #[serde(default, deserialize_with = "from_opt_days")]
+    pub expiry_banner_days: Option<Duration>,
+
+    /// Days a workspace is extended by, once, when its first expiry warning
+    /// is due while it is still in use.  Disabled if unset.
+    #[serde(default, deserialize_with = "from_opt_days")]
+    pub grace_extension_days: Option<Duration>,
+    /// Days within which a file must have been modified for a workspace to
+    /// count as in use for `grace_extension_days`
+    #[serde(
+        default = "default_grace_activity_days",
+        deserialize_with = "from_days"
+    )]
+    pub grace_activity_days: Duration,
+
+    /// What `maintain` does about mountpoints no longer owned by their workspace's owner
+    #[serde(default)]
+    pub ownership_drift: OwnershipDrift,
+
+    /// Days an expired workspace is given again when restored with
+    /// `workspaces restore`
+    #[serde(default = "default_restore_grace_days", deserialize_with = "from_days")]
+    pub restore_grace_days: Duration,
+
+    /// Channels notifications are sent over
+    #[serde(default = "default_notification_channels")]
+    pub notification_channels: Vec<NotificationChannel>,
+
+    /// Templates replacing the built-in notification texts
+    #[serde(default)]
+    pub notification_templates: NotificationTemplates,
+
     /// Snapshot
     #[serde(default)]
     pub snapshot: bool,
+    /// Hours between snapshots, taken by `workspaces snapshot` as well as
+    /// `maintain` once due.  Unset, every `maintain` run takes one.
+    #[serde(default, deserialize_with = "from_opt_hours")]
+    pub snapshot_interval_hours: Option<Duration>,
+    /// Prepended to the snapshots' RFC 3339 names, e.g. "hourly-"
+    #[serde(default)]
+    pub snapshot_prefix: String,
+    /// Fraction of a workspace's quota (or, without one, its size) its
+    /// snapshots may pin before `maintain` warns its owner.  Disabled if unset.
+    #[serde(default)]
+    pub snapshot_space_warning: Option<f64>,
+
+    /// Permissions of new workspaces, e.g. `0o2770` for group-shared ones
+    #[serde(default = "default_mode", deserialize_with = "from_mode")]
+    pub mode: u32,
+    /// Group owning new workspaces.  Defaults to their owner's primary group.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Default ACL entries of new workspaces, in `setfacl` syntax
+    #[serde(default)]
+    pub default_acl: Vec<String>,
+
+    /// Whether users can access their workspaces' snapshots in `.zfs/snapshot`
+    /// (`.snapshots/<workspace>` next to them on btrfs)
+    #[serde(default)]
+    pub snapdir_visible: bool,
 
     /// Whether datasets can be created / extended
     #[serde(default)]
     pub disabled: bool,
+
+    /// Whether workspaces created by non-admins have to be approved first
+    #[serde(default)]
+    pub requires_approval: bool,
+
+    /// Users who may create and extend workspaces on the filesystem, besides
+    /// the members of `allowed_groups`.  Everyone may if both are empty.
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+
+    /// Groups whose members may create and extend workspaces on the filesystem
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
+
+    /// Whom users should ask about the filesystem, e.g. an email address,
+    /// mentioned in errors and notifications
+    #[serde(default)]
+    pub admin_contact: Option<String>,
+
+    /// How the filesystem's workspaces are stored
+    #[serde(default)]
+    pub backend: Backend,
+
+    /// Host to run ZFS commands on, if the pool is not local
+    #[serde(default)]
+    pub ssh: Option<SshRemote>,
+    /// Seconds `zfs` and other commands run on the filesystem's host may take
+    /// before they are terminated.  0 disables the timeout.  Streaming whole
+    /// workspaces, to archives or `export`, isn't limited.
+    #[serde(default = "default_command_timeout", deserialize_with = "from_seconds")]
+    pub command_timeout: Duration,
+
+    /// Object storage expired workspaces are uploaded to before their deletion
+    #[serde(default)]
+    pub s3_archive: Option<S3Archive>,
+
+    /// Backup tool expired workspaces are backed up with before their deletion
+    #[serde(default)]
+    pub backup: Option<Backup>,
+
+    /// Directory on the filesystem's host expired workspaces are packed into
+    /// as zstd-compressed tarballs before their deletion
+    #[serde(default)]
+    pub archive_path: Option<PathBuf>,
+
+    /// Days tarballs are kept in `archive_path`; forever if unset
+    #[serde(default, deserialize_with = "from_opt_days")]
+    pub archive_retention: Option<Duration>,
+
+    /// Whether to keep a machine-readable `.workspace.json` in each workspace
+    #[serde(default)]
+    pub metadata_file: bool,
+}
+
+/// Settings for a common kind of workspace, applied on creation
+#[derive(Debug, Deserialize)]
+pub struct Profile {
+    /// Shown by `workspaces profiles`
+    #[serde(default)]
+    pub description: String,
+    /// Filesystem used unless one is given explicitly
+    #[serde(default)]
+    pub filesystem: Option<String>,
+    /// Days the workspace is created for unless given explicitly
+    #[serde(default, deserialize_with = "from_opt_days")]
+    pub duration: Option<Duration>,
+    /// ZFS quota of the dataset, e.g. "500G"
+    #[serde(default, deserialize_with = "from_opt_size")]
+    pub quota: Option<u64>,
+    /// Further ZFS properties of the dataset
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
+    /// Directory whose contents are copied into new workspaces
+    #[serde(default)]
+    pub skeleton: Option<PathBuf>,
+}
+
+/// Strategy for choosing the root a new workspace is created on
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Placement {
+    /// The root with the smallest fraction of its space used
+    #[default]
+    LeastUsed,
+    /// A root determined by the workspace's user and name
+    Hash,
+}
+
+/// How the datasets of new workspaces are named below their root
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub enum Layout {
+    /// `<root>/<user>/<name>`
+    #[default]
+    #[serde(rename = "user/name")]
+    UserName,
+    /// `<root>/<name>--<user>`
+    #[serde(rename = "name--user")]
+    NameUser,
+    /// `<root>/<uuid>`, which stays the same across renames
+    #[serde(rename = "flat")]
+    Flat,
+}
+
+impl Layout {
+    /// The dataset of a new workspace, relative to its root
+    pub fn dataset(self, user: &str, name: &str) -> Result<String, WorkspacesError> {
+        Ok(match self {
+            Layout::UserName => format!("{}/{}", user, name),
+            Layout::NameUser => name_user_dataset(user, name)?,
+            Layout::Flat => random_uuid()?,
+        })
+    }
+}
+
+/// `<name>--<user>`, refusing names and users it wouldn't tell apart
+pub fn name_user_dataset(user: &str, name: &str) -> Result<String, WorkspacesError> {
+    if name.contains("--") {
+        return Err(WorkspacesError::InvalidArgument(
+            "Workspace names on this filesystem can't contain `--`".to_owned(),
+        ));
+    }
+    if user.contains("--") {
+        return Err(WorkspacesError::InvalidArgument(format!(
+            "{} can't have workspaces on this filesystem, as their name contains `--`",
+            user
+        )));
+    }
+    Ok(format!("{}--{}", name, user))
+}
+
+/// A random (version 4) UUID
+fn random_uuid() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+/// A way of notifying users
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationChannel {
+    /// Email to the address in the user's `~/.config/workspaces.toml`
+    Email,
+    /// JSON POST to the site's `[webhook]`
+    Webhook,
+}
+
+impl std::fmt::Display for NotificationChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotificationChannel::Email => write!(f, "email"),
+            NotificationChannel::Webhook => write!(f, "webhook"),
+        }
+    }
+}
+
+fn default_command_timeout() -> Duration {
+    Duration::hours(1)
+}
+
+fn default_mode() -> u32 {
+    0o750
+}
+
+fn default_grace_activity_days() -> Duration {
+    Duration::days(7)
+}
+
+fn default_restore_grace_days() -> Duration {
+    Duration::days(7)
+}
+
+fn default_notification_channels() -> Vec<NotificationChannel> {
+    vec![NotificationChannel::Email]
+}
+
+/// Paths to notification templates, one per kind of notification
+///
+/// The first line of a template is the subject, the rest the body.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationTemplates {
+    pub created: Option<PathBuf>,
+    pub extended: Option<PathBuf>,
+    pub expired: Option<PathBuf>,
+    pub deleted: Option<PathBuf>,
+    pub expiry_warning: Option<PathBuf>,
+    pub deletion_warning: Option<PathBuf>,
+    pub snapshot_space_warning: Option<PathBuf>,
+    pub rejected: Option<PathBuf>,
+}
+
+impl NotificationTemplates {
+    /// Fills in the templates not set with those of `defaults`
+    fn inherit(&mut self, defaults: &NotificationTemplates) {
+        for (template, default) in [
+            (&mut self.created, &defaults.created),
+            (&mut self.extended, &defaults.extended),
+            (&mut self.expired, &defaults.expired),
+            (&mut self.deleted, &defaults.deleted),
+            (&mut self.expiry_warning, &defaults.expiry_warning),
+            (&mut self.deletion_warning, &defaults.deletion_warning),
+            (
+                &mut self.snapshot_space_warning,
+                &defaults.snapshot_space_warning,
+            ),
+            (&mut self.rejected, &defaults.rejected),
+        ] {
+            if template.is_none() {
+                template.clone_from(default);
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Gives the filesystems the site-wide notification templates they don't
+    /// override
+    pub fn inherit_notification_templates(&mut self) {
+        for filesystem in self.filesystems.values_mut() {
+            filesystem
+                .notification_templates
+                .inherit(&self.notification_templates);
+        }
+    }
+}
+
+impl Filesystem {
+    /// The storage the filesystem's workspaces live on
+    pub fn storage(&self) -> Box<dyn Storage + '_> {
+        let timeout = self
+            .command_timeout
+            .to_std()
+            .ok()
+            .filter(|timeout| !timeout.is_zero());
+        let host = Host::new(self.ssh.as_ref(), timeout);
+        match self.backend {
+            Backend::Zfs => Box::new(Zfs::new(host)),
+            Backend::Btrfs => Box::new(Btrfs::new(host)),
+            Backend::Simulated => Box::new(Simulated::new(host)),
+        }
+    }
+
+    /// Whether `allowed_users` and `allowed_groups` let `user` create and
+    /// extend workspaces on the filesystem
+    pub fn allows_user(&self, user: &str) -> bool {
+        if self.allowed_users.is_empty() && self.allowed_groups.is_empty() {
+            return true;
+        }
+        if self.allowed_users.iter().any(|allowed| allowed == user) {
+            return true;
+        }
+        let Some(account) = get_user_by_name(user) else {
+            return false;
+        };
+        get_user_groups(user, account.primary_group_id()).is_some_and(|groups| {
+            groups.iter().any(|group| {
+                self.allowed_groups
+                    .iter()
+                    .any(|allowed| group.name() == allowed.as_str())
+            })
+        })
+    }
+
+    /// A sentence pointing users to the filesystem's admin contact, if it has one,
+    /// to be appended to messages
+    pub fn contact_hint(&self) -> String {
+        match &self.admin_contact {
+            Some(contact) => format!(" For help, contact {}.", contact),
+            None => String::new(),
+        }
+    }
+
+    /// The root a workspace lives on, given the one recorded in the database
+    pub fn root<'a>(&'a self, recorded: Option<&'a str>) -> &'a str {
+        recorded.unwrap_or(&self.roots[0])
+    }
+
+    /// The mountpoint a workspace should have, if not inherited from its root
+    pub fn mountpoint(&self, filesystem_name: &str, user: &str, name: &str) -> Option<String> {
+        self.mountpoint.as_ref().map(|template| {
+            template::render(
+                template,
+                &[
+                    ("filesystem", filesystem_name),
+                    ("user", user),
+                    ("name", name),
+                ],
+            )
+        })
+    }
+}
+
+/// A host reached via SSH to run ZFS commands on
+#[derive(Debug, Deserialize)]
+pub struct SshRemote {
+    pub host: String,
+    /// User to log in as.  Defaults to ssh's choice.
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    /// Private key to authenticate with
+    pub identity_file: Option<PathBuf>,
+}
+
+/// An S3-compatible bucket workspaces are archived to
+#[derive(Debug, Deserialize)]
+pub struct S3Archive {
+    pub bucket: String,
+    /// Prepended to the archives' object keys
+    #[serde(default)]
+    pub prefix: String,
+    /// Endpoint of S3-compatible storage other than AWS
+    pub endpoint_url: Option<String>,
+    pub region: Option<String>,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Bytes per second streams to and from the bucket are limited to
+    #[serde(default, deserialize_with = "from_opt_size")]
+    pub bwlimit: Option<u64>,
+}
+
+/// A backup repository workspaces are saved to
+#[derive(Debug, Deserialize)]
+pub struct Backup {
+    pub tool: BackupTool,
+    /// Repository location, as understood by the tool
+    pub repository: String,
+    /// File containing the repository's password
+    pub password_file: PathBuf,
+}
+
+/// An external backup tool
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupTool {
+    Restic,
+    Borg,
+}
+
+impl std::fmt::Display for BackupTool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupTool::Restic => write!(f, "restic"),
+            BackupTool::Borg => write!(f, "borg"),
+        }
+    }
+}
+
+fn one_or_many_roots<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let roots = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(root) => vec![root],
+        OneOrMany::Many(roots) => roots,
+    };
+    if roots.is_empty() {
+        return Err(de::Error::invalid_length(0, &"at least one root"));
+    }
+    Ok(roots)
+}
+
+fn from_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let seconds: i64 = Deserialize::deserialize(deserializer)?;
+    Ok(Duration::seconds(seconds))
+}
+
+fn from_mode<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mode: u32 = Deserialize::deserialize(deserializer)?;
+    if mode > 0o7777 {
+        return Err(de::Error::invalid_value(
+            Unexpected::Unsigned(mode.into()),
+            &"a file mode, e.g. 0o750",
+        ));
+    }
+    Ok(mode)
+}
+
+/// Parses a size like ZFS does, e.g. "500G" or "1.5T", into bytes
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, unit) = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .map_or((size, ""), |i| size.split_at(i));
+    let shift = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        "P" => 50,
+        _ => return Err(format!("`{}` is not a size like 500G", size)),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("`{}` is not a size like 500G", size))?;
+    let bytes = (number * (1u64 << shift) as f64) as u64;
+    // ZFS takes a quota of 0 for none at all
+    if bytes == 0 {
+        return Err(format!(
+            "`{}` is no size at all; leave it out for none",
+            size
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Formats a size in bytes in the largest unit it is a whole multiple of, e.g. "500G"
+pub fn format_size(bytes: u64) -> String {
+    ["P", "T", "G", "M", "K"]
+        .iter()
+        .zip([50, 40, 30, 20, 10])
+        .find(|(_, shift)| bytes != 0 && bytes.is_multiple_of(1u64 << shift))
+        .map_or(bytes.to_string(), |(unit, shift)| {
+            format!("{}{}", bytes >> shift, unit)
+        })
+}
+
+fn from_opt_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let size: Option<String> = Deserialize::deserialize(deserializer)?;
+    size.map(|size| parse_size(&size).map_err(de::Error::custom))
+        .transpose()
 }
 
 fn from_days<'de, D>(deserializer: D) -> Result<Duration, D::Error>
@@ -100,6 +738,22 @@ where
     Ok(Duration::days(days))
 }
 
+fn from_opt_days<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let days: Option<i64> = Deserialize::deserialize(deserializer)?;
+    Ok(days.map(Duration::days))
+}
+
+fn from_opt_hours<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let hours: Option<i64> = Deserialize::deserialize(deserializer)?;
+    Ok(hours.map(Duration::hours))
+}
+
 fn from_days_list<'de, D>(deserializer: D) -> Result<Vec<Duration>, D::Error>
 where
     D: Deserializer<'de>,
@@ -126,20 +780,195 @@ pub struct SmtpConfig {
     pub auth: Option<AuthMethod>,
 }
 
+/// Where and how to write environment modulefiles for workspaces
+#[derive(Deserialize, Debug)]
+pub struct ModulefilesConfig {
+    /// Directory the modulefiles are written to.
+    /// `{user}` and `{filesystem}` will be substituted.
+    pub dir: String,
+    /// Modulefile template. `{name}`, `{user}`, `{filesystem}` and
+    /// `{mountpoint}` will be substituted verbatim.  Defaults to a Tcl
+    /// modulefile.
+    pub template: Option<PathBuf>,
+}
+
+/// Where to report panics and non-recoverable errors
+#[derive(Deserialize, Debug)]
+pub struct TelemetryConfig {
+    /// URL a JSON error report is POSTed to
+    pub error_webhook: String,
+}
+
+/// Endpoint for webhook notifications
+#[derive(Deserialize, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+}
+
+/// What `maintain` does about workspaces of users who left
+#[derive(Deserialize, Debug)]
+pub struct DepartedUsersConfig {
+    #[serde(default)]
+    pub action: DepartedUsersAction,
+    /// Admins told about newly departed owners (requires [smtp])
+    #[serde(default, deserialize_with = "deserialize_mailboxes")]
+    pub notify: Vec<Mailbox>,
+    /// Share of the owners which may newly turn out to have left in a single
+    /// run; more are taken for an outage of the user directory
+    #[serde(default = "default_max_departed_fraction")]
+    pub max_departed_fraction: f64,
+}
+
+fn default_max_departed_fraction() -> f64 {
+    0.1
+}
+
+/// Who gets the weekly admin report, and how long its lists are
+#[derive(Deserialize, Debug)]
+pub struct AdminReportConfig {
+    /// Admins the report is emailed to (requires [smtp])
+    #[serde(deserialize_with = "deserialize_mailboxes")]
+    pub recipients: Vec<Mailbox>,
+    /// Entries shown per list, e.g. of the workspaces which grew the most
+    #[serde(default = "default_admin_report_entries")]
+    pub entries: usize,
+}
+
+fn default_admin_report_entries() -> usize {
+    10
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DepartedUsersAction {
+    /// Only report the workspaces
+    #[default]
+    Flag,
+    /// Also expire them, so they are deleted after their retention
+    Expire,
+}
+
+/// What `maintain` does about drifted ownership of mountpoints
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OwnershipDrift {
+    /// Don't check
+    Ignore,
+    /// Report it and record a `drift` event
+    #[default]
+    Flag,
+    /// Also restore the owner, group and permissions
+    Fix,
+}
+
+/// Storage backend of a filesystem
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// ZFS datasets below the roots
+    #[default]
+    Zfs,
+    /// btrfs subvolumes below the roots, which are directories
+    Btrfs,
+    /// Nothing, for `maintain --simulate`
+    #[serde(skip)]
+    Simulated,
+}
+
+/// Where users' notification preferences are kept
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UserPreferences {
+    /// In each user's `~/.config/workspaces.toml`
+    #[default]
+    Home,
+    /// In the workspaces database, for home directories root can't read
+    Database,
+}
+
+/// Least severe messages which are logged
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+        }
+    }
+}
+
+/// How often emails about created and extended workspaces are sent
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestFrequency {
+    /// Right away, one email per event
+    #[default]
+    Immediate,
+    /// Batched into a digest sent by `maintain` about once a day
+    Daily,
+    /// Batched into a digest sent by `maintain` about once a week
+    Weekly,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UserConfig {
-    #[serde(deserialize_with = "deserialize_mailbox")]
-    pub email: Mailbox,
+    /// Addresses notifications are sent to
+    #[serde(deserialize_with = "deserialize_recipients")]
+    pub email: Vec<Mailbox>,
+    /// Addresses copied on notifications, e.g. a PI or a lab's mailing list
+    #[serde(default, deserialize_with = "deserialize_address_list")]
+    pub cc: Vec<Mailbox>,
+    /// Overrides the site's digest frequency
+    #[serde(default)]
+    pub digest_frequency: Option<DigestFrequency>,
+}
+
+/// A list of addresses, or a single string of comma-separated ones
+fn deserialize_address_list<'de, D>(deserializer: D) -> Result<Vec<Mailbox>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum AddressList {
+        One(String),
+        Many(Vec<String>),
+    }
+    let emails = match AddressList::deserialize(deserializer)? {
+        AddressList::One(s) => vec![s],
+        AddressList::Many(emails) => emails,
+    };
+    let mut mailboxes = Vec::new();
+    for s in &emails {
+        let parsed: Mailboxes = s
+            .parse()
+            .map_err(|_| de::Error::invalid_value(Unexpected::Str(s), &"valid email addresses"))?;
+        mailboxes.extend(parsed);
+    }
+    Ok(mailboxes)
 }
 
-fn deserialize_mailbox<'de, D>(deserializer: D) -> Result<Mailbox, D::Error>
+/// Like [`deserialize_address_list`], but with at least one address
+fn deserialize_recipients<'de, D>(deserializer: D) -> Result<Vec<Mailbox>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let email_str: String = Deserialize::deserialize(deserializer)?;
-    email_str.parse().map_err(|_| {
-        de::Error::invalid_value(Unexpected::Str(&email_str), &"a valid email address string")
-    })
+    let mailboxes = deserialize_address_list(deserializer)?;
+    if mailboxes.is_empty() {
+        return Err(de::Error::invalid_length(0, &"at least one email address"));
+    }
+    Ok(mailboxes)
 }
 
 fn deserialize_opt_mailbox<'de, D>(deserializer: D) -> Result<Option<Mailbox>, D::Error>
@@ -154,3 +983,18 @@ where
         None => Ok(None),
     }
 }
+
+fn deserialize_mailboxes<'de, D>(deserializer: D) -> Result<Vec<Mailbox>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let emails: Vec<String> = Deserialize::deserialize(deserializer)?;
+    emails
+        .iter()
+        .map(|s| {
+            s.parse().map_err(|_| {
+                de::Error::invalid_value(Unexpected::Str(s), &"a valid email address string")
+            })
+        })
+        .collect()
+}
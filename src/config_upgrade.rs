@@ -0,0 +1,541 @@
+use std::{cell::RefCell, collections::HashSet, error::Error, fs, path::Path, sync::OnceLock};
+
+use serde::{Deserialize, Deserializer, de};
+
+use crate::{config, error::WorkspacesError};
+
+/// Version of the configuration format, as given by `config_version`.
+/// Configurations without one are version 1.  It goes up whenever a key is
+/// renamed or dropped, see [`DEPRECATIONS`].
+pub const CONFIG_VERSION: i64 = 1;
+
+/// A key older versions of the configuration format used
+struct Deprecation {
+    /// Last version of the format the key was used in
+    until: i64,
+    /// Table the key is in, e.g. "filesystems.*" for any filesystem
+    table: &'static str,
+    key: &'static str,
+    /// Key it was renamed to, if its value carries over unchanged
+    renamed_to: Option<&'static str>,
+    /// How to replace it by hand otherwise
+    hint: &'static str,
+}
+
+/// Keys older versions of the configuration format used
+///
+/// Each entry names the commit which renamed or dropped its key.  No key has
+/// been so far.
+const DEPRECATIONS: &[Deprecation] = &[];
+
+/// Whether the table at `path` is matched by the `pattern` of a [`Deprecation`]
+fn matches(pattern: &str, path: &[String]) -> bool {
+    if pattern.is_empty() {
+        return path.is_empty();
+    }
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    pattern.len() == path.len()
+        && pattern
+            .iter()
+            .zip(path)
+            .all(|(pattern, segment)| *pattern == "*" || pattern == segment)
+}
+
+/// Calls `f` with every table below `table`, and the keys leading to it
+fn each_table(
+    table: &mut toml::Table,
+    path: &mut Vec<String>,
+    f: &mut dyn FnMut(&mut toml::Table, &[String]),
+) {
+    f(table, path);
+    for (key, value) in table.iter_mut() {
+        if let toml::Value::Table(child) = value {
+            path.push(key.clone());
+            each_table(child, path, f);
+            path.pop();
+        }
+    }
+}
+
+/// Brings a parsed configuration up to the current format
+///
+/// Renamed keys are carried over, returning a note for each.  Keys which
+/// can't be carried over, and configurations for a newer version of
+/// workspaces, are errors.
+fn upgrade(config: &mut toml::Table) -> Result<Vec<String>, String> {
+    let version = match config.remove("config_version") {
+        None => 1,
+        Some(toml::Value::Integer(version)) => version,
+        Some(value) => return Err(format!("config_version must be a number, not {}", value)),
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "config_version {} is newer than the {} this version of workspaces understands",
+            version, CONFIG_VERSION
+        ));
+    }
+
+    let mut notes = Vec::new();
+    let mut errors = Vec::new();
+    each_table(config, &mut Vec::new(), &mut |table, path| {
+        for deprecation in DEPRECATIONS {
+            if deprecation.until < version
+                || !matches(deprecation.table, path)
+                || !table.contains_key(deprecation.key)
+            {
+                continue;
+            }
+            let mut key = path.to_vec();
+            key.push(deprecation.key.to_owned());
+            let key = key.join(".");
+            match deprecation.renamed_to {
+                Some(new) if table.contains_key(new) => errors.push(format!(
+                    "`{}` has been renamed to `{}`, which is set as well; remove one of them",
+                    key, new
+                )),
+                Some(new) => {
+                    let value = table
+                        .remove(deprecation.key)
+                        .expect("key should be present");
+                    table.insert(new.to_owned(), value);
+                    notes.push(format!("`{}` has been renamed to `{}`", key, new));
+                }
+                None => errors.push(format!(
+                    "`{}` is no longer supported; {}",
+                    key, deprecation.hint
+                )),
+            }
+        }
+    });
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(notes)
+}
+
+/// Top-level keys not read into [`config::Config`]
+const FORMAT_KEYS: &[&str] = &["config_version", "strict_config"];
+
+/// What [`Probe`] found out about the configuration's tables so far
+#[derive(Default)]
+struct Probed {
+    /// Tables and the keys they may contain, as returned by [`schema`]
+    tables: Vec<(String, &'static [&'static str])>,
+    /// Values which aren't tables, or which failed to deserialize for that
+    /// reason, as they are left out from then on
+    skipped: HashSet<String>,
+    /// The innermost value which failed to deserialize this time
+    failed: Option<String>,
+}
+
+/// A deserializer handing out tables, and nothing but tables
+///
+/// Deserializing [`config::Config`] from it records the keys of each struct
+/// on the way, as their derived implementations hand them to
+/// `deserialize_struct`.  Anything else is an error, which ends the attempt,
+/// so [`schema`] tries again without what failed until nothing does.
+struct Probe<'a> {
+    /// The keys leading to the value, with `*` for names of maps' entries
+    path: String,
+    probed: &'a RefCell<Probed>,
+}
+
+impl Probe<'_> {
+    /// Hands the value's `keys` to `visitor`, each with a probe of its own
+    fn visit_keys<'de, V: de::Visitor<'de>>(
+        self,
+        keys: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, de::value::Error> {
+        let probed = self.probed.borrow();
+        let keys = keys
+            .iter()
+            .map(|key| match self.path.is_empty() {
+                true => (*key, key.to_string()),
+                false => (*key, format!("{}.{}", self.path, key)),
+            })
+            .filter(|(_, path)| !probed.skipped.contains(path))
+            .collect::<Vec<_>>();
+        drop(probed);
+        visitor.visit_map(ProbeKeys {
+            keys: keys.into_iter(),
+            path: None,
+            probed: self.probed,
+        })
+    }
+}
+
+impl<'de> Deserializer<'de> for Probe<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a table"))
+    }
+
+    fn deserialize_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let mut probed = self.probed.borrow_mut();
+        if !probed.tables.iter().any(|(path, _)| *path == self.path) {
+            probed.tables.push((self.path.clone(), fields));
+        }
+        drop(probed);
+        self.visit_keys(fields, visitor)
+    }
+
+    fn deserialize_map<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.visit_keys(&["*"], visitor)
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct enum identifier
+        ignored_any
+    }
+}
+
+/// The keys of a table [`Probe`] hands out, with their paths
+struct ProbeKeys<'a> {
+    keys: std::vec::IntoIter<(&'static str, String)>,
+    /// The path of the key handed out last
+    path: Option<String>,
+    probed: &'a RefCell<Probed>,
+}
+
+impl<'de> de::MapAccess<'de> for ProbeKeys<'_> {
+    type Error = de::value::Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some((key, path)) = self.keys.next() else {
+            return Ok(None);
+        };
+        self.path = Some(path);
+        seed.deserialize(de::value::StrDeserializer::new(key))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let path = self.path.take().expect("a key should come first");
+        seed.deserialize(Probe {
+            path: path.clone(),
+            probed: self.probed,
+        })
+        .inspect_err(|_| {
+            self.probed.borrow_mut().failed.get_or_insert(path);
+        })
+    }
+}
+
+/// Tables of the configuration and the keys they may contain, with `*` for
+/// any name.  Tables not listed, like ZFS properties, may contain any key.
+fn schema() -> &'static [(String, &'static [&'static str])] {
+    static SCHEMA: OnceLock<Vec<(String, &'static [&'static str])>> = OnceLock::new();
+    SCHEMA.get_or_init(|| {
+        let probed = RefCell::new(Probed::default());
+        loop {
+            let _ = config::Config::deserialize(Probe {
+                path: String::new(),
+                probed: &probed,
+            });
+            let mut probed = probed.borrow_mut();
+            // Every key is either a table whose keys are known now, or skipped
+            let Some(failed) = probed.failed.take() else {
+                break;
+            };
+            probed.skipped.insert(failed);
+        }
+        probed.into_inner().tables
+    })
+}
+
+/// Number of single-character edits turning `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + usize::from(a != *b))
+                .min(row[j] + 1)
+                .min(above + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Describes the keys of the configuration which workspaces doesn't know,
+/// with the line they are on and the known key they are most likely a typo of
+fn unknown_keys(config: &toml::Table, toml_str: &str) -> Vec<String> {
+    fn walk(
+        table: &toml::Table,
+        path: &mut Vec<String>,
+        toml_str: &str,
+        unknown: &mut Vec<String>,
+    ) {
+        let known = schema()
+            .iter()
+            .find(|(pattern, _)| matches(pattern, path))
+            .map(|(_, fields)| *fields);
+        for (key, value) in table {
+            if let Some(known) = known
+                && !known.contains(&key.as_str())
+                && !(path.is_empty() && FORMAT_KEYS.contains(&key.as_str()))
+            {
+                let mut message = match path.is_empty() {
+                    true => format!("unknown key `{}` at the top level", key),
+                    false => format!("unknown key `{}` in [{}]", key, path.join(".")),
+                };
+                if let Some(line) = line_of(toml_str, path, key) {
+                    message.push_str(&format!(" (line {})", line));
+                }
+                if let Some(suggestion) = known
+                    .iter()
+                    .map(|known| (edit_distance(key, known), known))
+                    .filter(|(distance, _)| *distance <= 2.max(key.len() / 4))
+                    .min()
+                {
+                    message.push_str(&format!(", did you mean `{}`?", suggestion.1));
+                }
+                unknown.push(message);
+                continue;
+            }
+            if let toml::Value::Table(child) = value {
+                path.push(key.clone());
+                walk(child, path, toml_str, unknown);
+                path.pop();
+            }
+        }
+    }
+
+    let mut unknown = Vec::new();
+    walk(config, &mut Vec::new(), toml_str, &mut unknown);
+    unknown
+}
+
+/// The line number `key` of the table at `path` is set on, if it can be found
+fn line_of(toml_str: &str, path: &[String], key: &str) -> Option<usize> {
+    let mut table_path = Vec::new();
+    for (number, line) in toml_str.lines().enumerate() {
+        if let Some(header) = header_path(line) {
+            // A table of its own, like `[filesystems.bulk.s3archive]`
+            if header.len() > path.len() && header.starts_with(path) && header[path.len()] == key {
+                return Some(number + 1);
+            }
+            table_path = header;
+        } else if table_path == path && assigned_key(line) == Some(key) {
+            return Some(number + 1);
+        }
+    }
+    None
+}
+
+/// Parses the configuration, carrying over keys of older formats with a warning
+///
+/// Unknown keys, e.g. misspelled ones, are warned about, or errors with
+/// `strict_config = true`.
+pub fn load(toml_str: &str) -> Result<config::Config, String> {
+    let mut table: toml::Table = toml::from_str(toml_str).map_err(|e| e.to_string())?;
+    let outdated = table
+        .get("config_version")
+        .is_none_or(|version| version.as_integer() != Some(CONFIG_VERSION));
+    let strict = match table.get("strict_config") {
+        None => false,
+        Some(toml::Value::Boolean(strict)) => *strict,
+        Some(value) => {
+            return Err(format!(
+                "strict_config must be true or false, not {}",
+                value
+            ));
+        }
+    };
+    let notes = upgrade(&mut table)?;
+    for note in &notes {
+        eprintln!(
+            "DEPRECATION WARNING: {} in `{}`.  Run `workspaces config upgrade` to update it.",
+            note,
+            config::CONFIG_PATH
+        );
+    }
+    let unknown = unknown_keys(&table, toml_str);
+
+    let config = match notes.is_empty() {
+        // Parsing the text again points to where errors are
+        true => toml::from_str::<config::Config>(toml_str),
+        false => toml::Value::Table(table).try_into(),
+    };
+    let config = config.map_err(|e| {
+        let mut message = e.to_string();
+        // Missing keys are often misspelled ones
+        for unknown in &unknown {
+            message.push_str(&format!("\n{}", unknown));
+        }
+        if outdated {
+            message.push_str(
+                "\nThe configuration may be written for an older version of workspaces; \
+                    see `workspaces config upgrade`.",
+            );
+        }
+        message
+    })?;
+
+    if strict && !unknown.is_empty() {
+        return Err(format!(
+            "strict_config forbids unknown keys:\n{}",
+            unknown.join("\n")
+        ));
+    }
+    for unknown in unknown {
+        eprintln!("Warning in {}: {}", config::CONFIG_PATH, unknown);
+    }
+    Ok(config)
+}
+
+/// The keys of a table header line like `[filesystems.bulk]`, if it is one
+fn header_path(line: &str) -> Option<Vec<String>> {
+    let trimmed = line.trim_start();
+    if !trimmed.starts_with('[') {
+        return None;
+    }
+    // Let toml deal with quoting and comments
+    let header = trimmed.replace("[[", "[").replace("]]", "]");
+    let mut table: toml::Table = toml::from_str(&header).ok()?;
+    let mut path = Vec::new();
+    while table.len() == 1 {
+        let (key, value) = table.into_iter().next().expect("table has one entry");
+        path.push(key);
+        match value {
+            toml::Value::Table(child) => table = child,
+            toml::Value::Array(mut tables) => match tables.pop() {
+                Some(toml::Value::Table(child)) => table = child,
+                _ => break,
+            },
+            _ => break,
+        }
+    }
+    Some(path)
+}
+
+/// The bare key a `key = value` line assigns, if it is one
+fn assigned_key(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start();
+    let end = trimmed.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))?;
+    (end > 0 && trimmed[end..].trim_start().starts_with('=')).then(|| &trimmed[..end])
+}
+
+/// Rewrites the configuration file in the current format, keeping comments
+/// and the order of keys
+///
+/// The old file is kept next to it with a `.bak` suffix.  With `dry_run`, the
+/// upgraded configuration is printed instead.
+pub fn upgrade_file(path: &Path, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let toml_str = fs::read_to_string(path)?;
+    let mut table: toml::Table = toml::from_str(&toml_str)?;
+    let current = table.get("config_version").and_then(|v| v.as_integer()) == Some(CONFIG_VERSION);
+    let notes = match upgrade(&mut table) {
+        Ok(notes) => notes,
+        Err(e) => {
+            return Err(WorkspacesError::InvalidConfig(format!(
+                "{}\nPlease change this in {} by hand first.",
+                e,
+                path.display()
+            ))
+            .into());
+        }
+    };
+    if current && notes.is_empty() {
+        println!("{} is up to date", path.display());
+        return Ok(());
+    }
+
+    let mut upgraded = String::new();
+    let mut table_path = Vec::new();
+    let mut has_version = false;
+    for line in toml_str.lines() {
+        if let Some(path) = header_path(line) {
+            table_path = path;
+        }
+        let mut line = line.to_owned();
+        if let Some(key) = assigned_key(&line).map(str::to_owned) {
+            if table_path.is_empty() && key == "config_version" {
+                line = format!("config_version = {}", CONFIG_VERSION);
+                has_version = true;
+            } else if let Some(new) = DEPRECATIONS
+                .iter()
+                .filter(|d| d.key == key && matches(d.table, &table_path))
+                .find_map(|d| d.renamed_to)
+            {
+                line = line.replacen(&key, new, 1);
+            }
+        }
+        upgraded.push_str(&line);
+        upgraded.push('\n');
+    }
+    if !has_version {
+        // Top-level keys have to come before the first table, so put it on top,
+        // below the comments introducing the file
+        let offset = toml_str
+            .lines()
+            .take_while(|line| line.trim().is_empty() || line.trim_start().starts_with('#'))
+            .map(|line| line.len() + 1)
+            .sum::<usize>()
+            .min(upgraded.len());
+        upgraded.insert_str(offset, &format!("config_version = {}\n\n", CONFIG_VERSION));
+    }
+
+    // Keys in inline tables or dotted keys aren't rewritten, so make sure
+    // nothing was left behind
+    let mut check: toml::Table = toml::from_str(&upgraded)?;
+    check.remove("config_version");
+    if !upgrade(&mut check).is_ok_and(|notes| notes.is_empty()) {
+        let mut message = format!(
+            "Could not upgrade {} automatically, please rename these keys by hand:",
+            path.display()
+        );
+        for note in notes {
+            message.push_str(&format!("\n  {}", note));
+        }
+        return Err(WorkspacesError::InvalidConfig(message).into());
+    }
+
+    if dry_run {
+        print!("{}", upgraded);
+        return Ok(());
+    }
+    let backup = path.with_extension("toml.bak");
+    fs::copy(path, &backup)?;
+    fs::write(path, upgraded)?;
+    for note in notes {
+        println!("{}", note);
+    }
+    println!(
+        "Upgraded {} to config_version {}, the old one is kept as {}",
+        path.display(),
+        CONFIG_VERSION,
+        backup.display()
+    );
+    Ok(())
+}
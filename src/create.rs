@@ -1,16 +1,28 @@
-use crate::{config, to_volume_string, zfs, ExitCodes};
-use chrono::{Duration, Utc};
+use crate::{
+    cli::CommandFormat,
+    clock, config, digest,
+    error::WorkspacesError,
+    events::{self, EventKind},
+    is_group_member, journal, json, metadata, modulefile,
+    notify::{self, NotificationKind, Notifiers},
+    to_volume_string, workspace_volume,
+};
+use chrono::Duration;
 use rusqlite::Connection;
 use std::{
+    env,
     error::Error,
     fs,
-    os::unix::fs::PermissionsExt,
-    path::PathBuf,
-    process::{self, Command},
+    hash::{DefaultHasher, Hash, Hasher},
+    os::unix::{self, fs::PermissionsExt},
+    path::{Path, PathBuf},
+};
+use users::{
+    get_current_uid, get_current_username, get_group_by_gid, get_group_by_name, get_user_by_name,
 };
-use users::{get_current_uid, get_current_username};
 
 /// Creates a new workspace
+#[allow(clippy::too_many_arguments)]
 pub fn create(
     conn: &mut Connection,
     filesystem_name: &str,
@@ -18,87 +30,628 @@ pub fn create(
     user: &str,
     name: &str,
     duration: &Duration,
-    smtp: &Option<config::SmtpConfig>, // <-- added parameter
-) -> Result<(), Box<dyn Error>> {
+    quota: Option<u64>,
+    group: Option<&str>,
+    notifiers: &Notifiers,
+    notify_owner: bool,
+    modulefiles: Option<&config::ModulefilesConfig>,
+    visible_snapshots: bool,
+    profile: Option<(&str, &config::Profile)>,
+    created_by: Option<&str>,
+    message: Option<&str>,
+    format: CommandFormat,
+) -> Result<(), WorkspacesError> {
     if get_current_username().expect("couldn't get username") != user && get_current_uid() != 0 {
-        eprintln!("You are not allowed to execute this operation");
-        process::exit(ExitCodes::InsufficientPrivileges as i32);
+        return Err(WorkspacesError::not_allowed());
+    }
+    if let Some(group) = group {
+        if get_group_by_name(group).is_none() {
+            return Err(WorkspacesError::InvalidArgument(format!(
+                "Group {} does not exist",
+                group
+            )));
+        }
+        if get_current_uid() != 0 && !is_group_member(group) {
+            return Err(WorkspacesError::InsufficientPrivileges(
+                "You can only create workspaces for groups you are a member of".to_owned(),
+            ));
+        }
     }
     if filesystem.disabled && get_current_uid() != 0 {
-        eprintln!("Filesystem is disabled. Please try another filesystem.");
-        process::exit(ExitCodes::FsDisabled as i32);
+        return Err(WorkspacesError::FsDisabled(format!(
+            "Filesystem is disabled. Please try another filesystem.{}",
+            filesystem.contact_hint()
+        )));
+    }
+    if !filesystem.allows_user(user) && get_current_uid() != 0 {
+        return Err(WorkspacesError::InsufficientPrivileges(format!(
+            "Filesystem {} is reserved for certain users and groups. \
+            Please try another filesystem.{}",
+            filesystem_name,
+            filesystem.contact_hint()
+        )));
     }
     if duration > &filesystem.max_duration && get_current_uid() != 0 {
-        eprintln!(
-            "Duration can be at most {} days",
-            filesystem.max_duration.num_days()
-        );
-        process::exit(ExitCodes::TooHighDuration as i32);
-    }
-
-    conn.transaction().inspect(
-        |transaction| {
-            match transaction.execute(
-                "INSERT INTO workspaces(filesystem, user, name, expiration_time) \
-                    VALUES(?1, ?2, ?3, ?4)",
-                (filesystem_name, user, name, Utc::now() + *duration),
-            ) {
-                Ok(_) => {}
-                Err(rusqlite::Error::SqliteFailure(
-                    libsqlite3_sys::Error {
-                        code: libsqlite3_sys::ErrorCode::ConstraintViolation,
-                        ..
-                    },
-                    _,
-                )) => {
-                    eprintln!(
-                        "This workspace already exists. You can extend it using `workspaces extend`."
-                    );
-                    process::exit(ExitCodes::WorkspaceExists as i32);
-                }
-                Err(_) => unreachable!(),
-            };
+        return Err(WorkspacesError::TooHighDuration(format!(
+            "Duration can be at most {} days.{}",
+            filesystem.max_duration.num_days(),
+            filesystem.contact_hint()
+        )));
+    }
+    let requested_quota = quota;
+    let quota = quota
+        .or(profile.and_then(|(_, profile)| profile.quota))
+        .or(filesystem.default_quota)
+        .or(filesystem.max_quota);
+    if let (Some(quota), Some(max_quota)) = (quota, filesystem.max_quota)
+        && quota > max_quota
+        && get_current_uid() != 0
+    {
+        return Err(WorkspacesError::TooHighQuota(format!(
+            "Quota can be at most {}.{}",
+            config::format_size(max_quota),
+            filesystem.contact_hint()
+        )));
+    }
+    if get_current_uid() != 0 {
+        // A workspace without a quota could take up any amount of space
+        if quota.is_none() && filesystem.max_total_bytes_per_user.is_some() {
+            return Err(WorkspacesError::InvalidArgument(format!(
+                "Space per user is limited on {}, so please set a quota with --quota.{}",
+                filesystem_name,
+                filesystem.contact_hint()
+            )));
+        }
+        check_user_limits(conn, filesystem_name, filesystem, user, quota.unwrap_or(0))?;
+    }
 
-            // Act like there was a notification sent just now
-            // so the user doesn't immediately get spammed with them
-            transaction.execute(
-                "INSERT INTO notifications(workspace_id, timestamp) VALUES (?1, ?2)",
-                (transaction.last_insert_rowid(), Utc::now()),
-            ).unwrap();
+    if filesystem.requires_approval && get_current_uid() != 0 {
+        match conn.execute(
+            "INSERT INTO pending_requests(filesystem, user, name, duration, modulefile, \
+                    visible_snapshots, request_time, profile, quota, owner_group) \
+                VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            (
+                filesystem_name,
+                user,
+                name,
+                duration.num_days(),
+                modulefiles.is_some(),
+                visible_snapshots,
+                clock::now(),
+                profile.map(|(profile_name, _)| profile_name),
+                requested_quota,
+                group,
+            ),
+        ) {
+            Ok(_) => {}
+            Err(rusqlite::Error::SqliteFailure(
+                libsqlite3_sys::Error {
+                    code: libsqlite3_sys::ErrorCode::ConstraintViolation,
+                    ..
+                },
+                _,
+            )) => {
+                return Err(WorkspacesError::WorkspaceExists(format!(
+                    "You already requested this workspace. It is awaiting approval.{}",
+                    filesystem.contact_hint()
+                )));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if format == CommandFormat::Json {
+            json::print_line(&json::object([
+                ("action", "create".into()),
+                ("filesystem", filesystem_name.into()),
+                ("user", user.into()),
+                ("name", name.into()),
+                ("pending_approval", true.into()),
+                ("actions", json::Value::Array(vec!["requested".into()])),
+            ]))?;
+        } else {
+            println!(
+                "Workspaces on {} require approval. Your request has been recorded \
+                and the workspace will be created once an administrator approves it.{}",
+                filesystem_name,
+                filesystem.contact_hint()
+            );
         }
-    )?.commit()?;
+        return Ok(());
+    }
+
+    let root = choose_root(filesystem, user, name)?;
+
+    let dataset = filesystem.layout.dataset(user, name)?;
+    let volume = to_volume_string(root, &dataset);
+    // `zfs create -p` succeeds for datasets which already exist, which would
+    // hand someone else's data to the user
+    if filesystem.storage().exists(&volume)? {
+        return Err(WorkspacesError::WorkspaceExists(format!(
+            "There already is a dataset {}, but no workspace for it.{}",
+            volume,
+            filesystem.contact_hint()
+        )));
+    }
+
+    let expiration_time = clock::now() + *duration;
+    let transaction = conn.transaction()?;
+    match transaction.execute(
+        "INSERT INTO workspaces(filesystem, user, name, expiration_time, root, dataset, \
+                owner_group, created_at) \
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        (
+            filesystem_name,
+            user,
+            name,
+            expiration_time,
+            root,
+            &dataset,
+            group,
+            clock::now(),
+        ),
+    ) {
+        Ok(_) => {}
+        Err(rusqlite::Error::SqliteFailure(
+            libsqlite3_sys::Error {
+                code: libsqlite3_sys::ErrorCode::ConstraintViolation,
+                ..
+            },
+            _,
+        )) => {
+            return Err(WorkspacesError::WorkspaceExists(
+                "This workspace already exists. You can extend it using `workspaces extend`."
+                    .to_owned(),
+            ));
+        }
+        Err(_) => unreachable!(),
+    };
+    let workspace_id = transaction.last_insert_rowid();
 
-    let volume = to_volume_string(&filesystem.root, user, name);
+    // Act like there was a notification sent just now
+    // so the user doesn't immediately get spammed with them
+    notify::record(
+        &transaction,
+        workspace_id,
+        NotificationKind::Created,
+        &[],
+        clock::now(),
+    )?;
+    let entry = journal::begin(
+        &transaction,
+        workspace_id,
+        filesystem_name,
+        &volume,
+        journal::Operation::Create { visible_snapshots },
+    )?;
+    transaction.commit()?;
 
-    zfs::create(&volume)?;
+    let templated_mountpoint = filesystem.mountpoint(filesystem_name, user, name);
+    let mut properties = Vec::new();
+    if let Some(mountpoint) = &templated_mountpoint {
+        properties.push(("mountpoint", mountpoint.as_str()));
+    }
+    let quota = quota.map(|quota| quota.to_string());
+    if let Some(quota) = &quota {
+        properties.push(("quota", quota.as_str()));
+    }
+    if let Some((_, profile)) = profile {
+        properties.extend(
+            profile
+                .properties
+                .iter()
+                .map(|(property, value)| (property.as_str(), value.as_str())),
+        );
+    }
+    let failure = match filesystem.storage().create(&volume, &properties) {
+        Err(e) => {
+            journal::abandon_create(conn, &entry)?;
+            Some(format!("failed to create {}: {}", volume, e))
+        }
+        Ok(()) => (journal::resolve(conn, filesystem, &entry)? == journal::Resolution::RolledBack)
+            .then(|| format!("{} is missing right after creating it", volume)),
+    };
+    if let Some(message) = failure {
+        events::record(
+            conn,
+            EventKind::Failure,
+            filesystem_name,
+            user,
+            name,
+            None,
+            Some(&message),
+        )?;
+        return Err(message.into());
+    }
 
     // Explicitly request PathBuf so .display() works
-    let mountpoint: PathBuf = zfs::get_property::<PathBuf>(&volume, "mountpoint")?;
+    let mountpoint: PathBuf = filesystem
+        .storage()
+        .get_property::<PathBuf>(&volume, "mountpoint")?;
+    conn.execute(
+        "UPDATE workspaces SET mountpoint = ?2 WHERE id = ?1",
+        (workspace_id, mountpoint.to_string_lossy()),
+    )?;
+    events::record(
+        conn,
+        EventKind::Create,
+        filesystem_name,
+        user,
+        name,
+        Some(expiration_time),
+        profile.map(|(profile_name, _)| profile_name),
+    )?;
 
-    let mut permissions = fs::metadata(&mountpoint)?.permissions();
-    permissions.set_mode(0o750);
-    fs::set_permissions(&mountpoint, permissions)?;
+    if let Some((profile_name, profile)) = profile
+        && let Some(skeleton) = &profile.skeleton
+        && let Err(e) = copy_skeleton(filesystem, skeleton, &mountpoint, group)
+    {
+        log::warn!(
+            "Failed to copy the skeleton of profile {}: {}",
+            profile_name,
+            e
+        );
+    }
+
+    if let Err(e) = metadata::write(conn, filesystem_name, filesystem, user, name) {
+        log::warn!("Failed to write workspace metadata file: {}", e);
+    }
+
+    // What was done, for `--format json`
+    let mut actions = vec!["created"];
+    if format == CommandFormat::Text {
+        println!("Created workspace at {}", mountpoint.display());
+    }
 
-    let status = Command::new("chown")
-        .args([&format!("{}:{}", user, user), &mountpoint.to_string_lossy().to_string()])
-        .status()?;
-    assert!(status.success(), "failed to change owner on dataset");
+    let mut modulefile = None;
+    if let Some(modulefiles) = modulefiles {
+        match modulefile::write(modulefiles, filesystem_name, user, name, &mountpoint) {
+            Ok(path) => {
+                if format == CommandFormat::Text {
+                    println!("Created modulefile at {}", path.display());
+                }
+                actions.push("modulefile");
+                modulefile = Some(path);
+            }
+            Err(e) => log::warn!("Failed to write modulefile: {}", e),
+        }
+    }
 
-    println!("Created workspace at {}", mountpoint.display());
+    if notifiers.any() && !notify_owner {
+        events::record(
+            conn,
+            EventKind::Notify,
+            filesystem_name,
+            user,
+            name,
+            Some(expiration_time),
+            Some("created suppressed by --no-notify"),
+        )?;
+    }
 
-    // Send "created" email (best-effort)
-    if let Some(smtp_cfg) = smtp.as_ref() {
+    // Send "created" notification (best-effort)
+    if notifiers.any() && notify_owner {
         let host = hostname::get()?.to_string_lossy().to_string();
         let subject = format!("Workspace {} created on {}", name, host);
         let expiry_days = duration.num_days();
+        let mut note = String::new();
+        if let Some(creator) = created_by {
+            note.push_str(&format!("{} created it for you.\n", creator));
+        }
+        if let Some(message) = message {
+            note.push_str(&format!("\n{}\n", message));
+        }
+        if !note.is_empty() {
+            note.push('\n');
+        }
         let body = format!(
-            "Hello,\n\nYour workspace \"{}\" has been created on {}.\nFilesystem: {}\nMountpoint: {}\nInitial expiry: in {} days.\n\nYou can extend it with:\n  workspaces extend -f {} -d <days> {}\n",
-            name, host, filesystem_name, mountpoint.display(), expiry_days, filesystem_name, name
+            "Hello,\n\nYour workspace \"{}\" has been created on {}.\n{}Filesystem: {}\nMountpoint: {}\nInitial expiry: in {} days.\n\nYou can extend it with:\n  workspaces extend -f {} -d <days> {}\n",
+            name,
+            host,
+            note,
+            filesystem_name,
+            mountpoint.display(),
+            expiry_days,
+            filesystem_name,
+            name
         );
-        if let Err(e) = crate::maintain::notify_event(user, smtp_cfg, subject, body) {
-            eprintln!("Failed to send 'created' email: {}", e);
+        match digest::notify(
+            conn,
+            notifiers,
+            filesystem,
+            NotificationKind::Created,
+            workspace_id,
+            user,
+            &[
+                ("workspace", name),
+                ("user", user),
+                ("filesystem", filesystem_name),
+                ("host", &host),
+                ("days", &expiry_days.to_string()),
+                ("mountpoint", &mountpoint.to_string_lossy()),
+                ("created_by", created_by.unwrap_or_default()),
+                ("message", message.unwrap_or_default()),
+            ],
+            subject,
+            body,
+        ) {
+            Ok(channels) if !channels.is_empty() => {
+                notify::record(
+                    conn,
+                    workspace_id,
+                    NotificationKind::Created,
+                    &channels,
+                    clock::now(),
+                )?;
+                actions.push("notified");
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to send 'created' email: {}", e),
+        }
+    }
+
+    if format == CommandFormat::Json {
+        json::print_line(&json::object([
+            ("action", "create".into()),
+            ("id", workspace_id.into()),
+            ("filesystem", filesystem_name.into()),
+            ("user", user.into()),
+            ("name", name.into()),
+            (
+                "mountpoint",
+                mountpoint.to_string_lossy().into_owned().into(),
+            ),
+            ("expiration_time", expiration_time.to_rfc3339().into()),
+            (
+                "modulefile",
+                modulefile
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .into(),
+            ),
+            ("pending_approval", false.into()),
+            (
+                "actions",
+                json::Value::Array(actions.into_iter().map(Into::into).collect()),
+            ),
+        ]))?;
+    }
+    Ok(())
+}
+
+/// Who runs workspaces, naming admins by their own account when they run it
+/// with sudo, and users without a name by their uid
+pub fn acting_user() -> String {
+    match env::var("SUDO_USER") {
+        Ok(sudo_user) if get_current_uid() == 0 && !sudo_user.is_empty() => sudo_user,
+        _ => get_current_username()
+            .map(|me| me.to_string_lossy().into_owned())
+            .unwrap_or_else(|| get_current_uid().to_string()),
+    }
+}
+
+/// Number of unexpired workspaces `user` has on the filesystem, and the space
+/// they take up: their quotas, or their size for those without one
+fn user_usage(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+) -> Result<(usize, u64), Box<dyn Error>> {
+    let mut statement = conn.prepare(
+        "SELECT name FROM workspaces \
+            WHERE filesystem = ?1 AND user = ?2 \
+                AND unixepoch(expiration_time) > unixepoch(?3)",
+    )?;
+    let names = statement
+        .query_map((filesystem_name, user, clock::now()), |row| {
+            row.get::<_, String>(0)
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut bytes = 0;
+    if filesystem.max_total_bytes_per_user.is_some() {
+        for name in &names {
+            let volume = workspace_volume(conn, filesystem_name, filesystem, user, name)?;
+            bytes += footprint(filesystem, &volume)?;
         }
     }
+    Ok((names.len(), bytes))
+}
 
+/// Space a workspace counts with against `max_total_bytes_per_user`: its
+/// quota, or its size if it has none
+pub fn footprint(filesystem: &config::Filesystem, volume: &str) -> Result<u64, Box<dyn Error>> {
+    let storage = filesystem.storage();
+    Ok(match storage.get_property::<u64>(volume, "quota")? {
+        0 => storage.get_property::<u64>(volume, "used")?,
+        quota => quota,
+    })
+}
+
+/// Refuses unless `user` may have another unexpired workspace on the
+/// filesystem, taking up `bytes` more
+pub fn check_user_limits(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    bytes: u64,
+) -> Result<(), WorkspacesError> {
+    if filesystem.max_workspaces_per_user.is_none() && filesystem.max_total_bytes_per_user.is_none()
+    {
+        return Ok(());
+    }
+    let (count, used) = user_usage(conn, filesystem_name, filesystem, user)?;
+    if let Some(max_workspaces) = filesystem.max_workspaces_per_user
+        && count >= max_workspaces
+    {
+        return Err(WorkspacesError::TooManyWorkspaces(format!(
+            "You may have at most {} workspaces on {}.{}",
+            max_workspaces,
+            filesystem_name,
+            filesystem.contact_hint()
+        )));
+    }
+    if let Some(max_total) = filesystem.max_total_bytes_per_user
+        && used + bytes > max_total
+    {
+        return Err(WorkspacesError::TooHighQuota(format!(
+            "Your workspaces on {} may take up at most {} together, {}G are in use already.{}",
+            filesystem_name,
+            config::format_size(max_total),
+            used / (1 << 30),
+            filesystem.contact_hint()
+        )));
+    }
     Ok(())
 }
+
+/// Permissions of a new workspace, setgid and shared with the group for group-owned ones
+fn mode(filesystem: &config::Filesystem, group: Option<&str>) -> u32 {
+    match group {
+        Some(_) => 0o2770,
+        None => filesystem.mode,
+    }
+}
+
+/// Prepares a freshly created dataset for its owner, and the group owning it if any
+pub fn set_up(
+    filesystem: &config::Filesystem,
+    volume: &str,
+    user: &str,
+    owner_group: Option<&str>,
+    visible_snapshots: bool,
+) -> Result<(), Box<dyn Error>> {
+    if filesystem.snapdir_visible || visible_snapshots {
+        filesystem
+            .storage()
+            .set_property(volume, "snapdir", "visible")?;
+    }
+
+    let mountpoint: PathBuf = filesystem
+        .storage()
+        .get_property::<PathBuf>(volume, "mountpoint")?;
+
+    let owner = get_user_by_name(user).ok_or("workspace owner does not exist")?;
+    let group = match owner_group.or(filesystem.group.as_deref()) {
+        Some(group) => {
+            get_group_by_name(group).ok_or_else(|| format!("group {} does not exist", group))?
+        }
+        None => get_group_by_gid(owner.primary_group_id())
+            .ok_or("primary group of workspace owner does not exist")?,
+    };
+
+    if filesystem.storage().is_remote() {
+        // The dataset may only be available locally via a root-squashed network share
+        let status = filesystem.storage().status(
+            "chown",
+            &[
+                &format!("{}:{}", user, group.name().to_string_lossy()),
+                &mountpoint.to_string_lossy(),
+            ],
+        )?;
+        if !status.success() {
+            return Err("failed to change owner on dataset".into());
+        }
+        let status = filesystem.storage().status(
+            "chmod",
+            &[
+                &format!("{:o}", mode(filesystem, owner_group)),
+                &mountpoint.to_string_lossy(),
+            ],
+        )?;
+        if !status.success() {
+            return Err("failed to change permissions on dataset".into());
+        }
+    } else {
+        // Changing the owner may clear the setgid bit, so set the mode afterwards
+        unix::fs::chown(&mountpoint, Some(owner.uid()), Some(group.gid()))?;
+        fs::set_permissions(
+            &mountpoint,
+            fs::Permissions::from_mode(mode(filesystem, owner_group)),
+        )?;
+    }
+
+    if !filesystem.default_acl.is_empty() {
+        let status = filesystem.storage().status(
+            "setfacl",
+            &[
+                "-d",
+                "-m",
+                &filesystem.default_acl.join(","),
+                &mountpoint.to_string_lossy(),
+            ],
+        )?;
+        if !status.success() {
+            return Err("failed to set default ACL on dataset".into());
+        }
+    }
+    Ok(())
+}
+
+/// Copies the contents of `skeleton` into a new workspace, owned like the workspace itself
+fn copy_skeleton(
+    filesystem: &config::Filesystem,
+    skeleton: &Path,
+    mountpoint: &Path,
+    group: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mountpoint = mountpoint.to_string_lossy();
+    let status = filesystem.storage().status(
+        "cp",
+        &[
+            "-R",
+            "--preserve=mode,timestamps",
+            "-T",
+            &skeleton.to_string_lossy(),
+            &mountpoint,
+        ],
+    )?;
+    if !status.success() {
+        return Err("copying failed".into());
+    }
+    let status = filesystem.storage().status(
+        "chown",
+        &["-R", &format!("--reference={}", mountpoint), &mountpoint],
+    )?;
+    if !status.success() {
+        return Err("failed to change owner of the copies".into());
+    }
+    // `cp -T` gave the workspace itself the skeleton's mode
+    let status = filesystem.storage().status(
+        "chmod",
+        &[&format!("{:o}", mode(filesystem, group)), &mountpoint],
+    )?;
+    if !status.success() {
+        return Err("failed to restore the permissions of the workspace".into());
+    }
+    Ok(())
+}
+
+/// Picks the root a new workspace is created on
+fn choose_root<'a>(
+    filesystem: &'a config::Filesystem,
+    user: &str,
+    name: &str,
+) -> Result<&'a str, Box<dyn Error>> {
+    if let [root] = filesystem.roots.as_slice() {
+        return Ok(root);
+    }
+
+    match filesystem.placement {
+        config::Placement::Hash => {
+            let mut hasher = DefaultHasher::new();
+            (user, name).hash(&mut hasher);
+            let index = hasher.finish() % filesystem.roots.len() as u64;
+            Ok(&filesystem.roots[index as usize])
+        }
+        config::Placement::LeastUsed => {
+            let mut least_used = (&filesystem.roots[0], f64::INFINITY);
+            for root in &filesystem.roots {
+                let (used, available) = filesystem.storage().space(root)?;
+                let fraction = used as f64 / (used + available) as f64;
+                if fraction < least_used.1 {
+                    least_used = (root, fraction);
+                }
+            }
+            Ok(least_used.0)
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    error::Error,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rusqlite::Connection;
+
+use crate::{admin_report, config, maintain, notify::Notifiers, telemetry};
+
+/// How often a sleeping daemon checks whether it was asked to stop
+const STOP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set by the signal handler once the daemon should stop
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// Runs `maintain` every `maintain_interval` until SIGTERM or SIGINT
+///
+/// With [admin_report] configured, the weekly admin report is sent after the
+/// first successful run a week after the last one.
+///
+/// A run in progress is always finished before stopping.  Runs overlapping
+/// with a `workspaces maintain` started by hand are skipped.  Errors are
+/// logged and reported, but don't stop the daemon.
+pub fn daemon(
+    conn: &mut Connection,
+    config: &config::Config,
+    notifiers: &Notifiers,
+) -> Result<(), Box<dyn Error>> {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        unsafe { libc::signal(signal, request_stop as *const () as libc::sighandler_t) };
+    }
+    let interval = config.maintain_interval.to_std()?;
+    log::info!("Started, maintaining every {}s", interval.as_secs());
+
+    while !STOP.load(Ordering::SeqCst) {
+        let start = Instant::now();
+        match maintain::try_lock(&config.db_path)? {
+            None => log::info!("Skipping run, another `workspaces maintain` is still in progress"),
+            Some(_lock) => {
+                log::info!("Starting maintenance run");
+                match maintain::maintain(
+                    conn,
+                    &config.filesystems,
+                    notifiers,
+                    &config.modulefiles,
+                    &config.departed_users,
+                    true,
+                    false,
+                ) {
+                    Ok(()) => {
+                        log::info!("Finished maintenance run in {}s", start.elapsed().as_secs());
+                        if let (Some(report), Some(smtp)) = (&config.admin_report, &config.smtp)
+                            && admin_report::is_due(conn).unwrap_or_else(|e| {
+                                log::error!(
+                                    "Failed to tell whether the admin report is due: {}",
+                                    e
+                                );
+                                false
+                            })
+                        {
+                            match admin_report::send(conn, &config.filesystems, smtp, report) {
+                                Ok(()) => log::info!("Sent the weekly admin report"),
+                                Err(e) => log::error!("Failed to send the admin report: {}", e),
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Maintenance run failed: {}", e);
+                        telemetry::report(&config.telemetry, &e.to_string());
+                    }
+                }
+            }
+        }
+
+        let next = start + interval;
+        while !STOP.load(Ordering::SeqCst) && Instant::now() < next {
+            thread::sleep(STOP_CHECK_INTERVAL.min(next.saturating_duration_since(Instant::now())));
+        }
+    }
+
+    log::info!("Stopped");
+    Ok(())
+}
@@ -2,7 +2,10 @@ use std::error::Error;
 
 use rusqlite::Connection;
 
-pub const UPDATE_DB: &[fn(&mut Connection) -> Result<(), Box<dyn Error>>] = &[
+/// A procedure upgrading the database by exactly one schema version
+type UpdateProc = fn(&mut Connection) -> Result<(), Box<dyn Error>>;
+
+pub const UPDATE_DB: &[UpdateProc] = &[
     |conn| {
         // Create initial database
         let transaction = conn.transaction()?;
@@ -60,6 +63,572 @@ pub const UPDATE_DB: &[fn(&mut Connection) -> Result<(), Box<dyn Error>>] = &[
         transaction.pragma_update(None, "user_version", 2)?;
         Ok(transaction.commit()?)
     },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Creation requests on filesystems requiring approval
+        transaction.execute(
+            "CREATE TABLE pending_requests( \
+                id           INTEGER  NOT NULL PRIMARY KEY, \
+                filesystem   TEXT     NOT NULL, \
+                user         TEXT     NOT NULL, \
+                name         TEXT     NOT NULL, \
+                duration     INTEGER  NOT NULL, \
+                modulefile   BOOLEAN  NOT NULL, \
+                request_time DATETIME NOT NULL, \
+                UNIQUE(filesystem, user, name) \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 3)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Root a workspace was created on, for filesystems with multiple roots.
+        // NULL refers to the filesystem's first root.
+        transaction.execute("ALTER TABLE workspaces ADD COLUMN root TEXT", ())?;
+
+        transaction.pragma_update(None, "user_version", 4)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Recently fetched ZFS properties
+        transaction.execute(
+            "CREATE TABLE property_cache( \
+                filesystem TEXT     NOT NULL, \
+                volume     TEXT     NOT NULL, \
+                property   TEXT     NOT NULL, \
+                value      TEXT     NOT NULL, \
+                fetch_time DATETIME NOT NULL, \
+                PRIMARY KEY(volume, property) \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 5)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        transaction.execute(
+            "ALTER TABLE pending_requests \
+                ADD COLUMN visible_snapshots BOOLEAN NOT NULL DEFAULT FALSE",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 6)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Workspaces uploaded to object storage before their deletion
+        transaction.execute(
+            "CREATE TABLE archives( \
+                id           INTEGER  NOT NULL PRIMARY KEY, \
+                filesystem   TEXT     NOT NULL, \
+                user         TEXT     NOT NULL, \
+                name         TEXT     NOT NULL, \
+                root         TEXT     NOT NULL, \
+                url          TEXT     NOT NULL, \
+                size         INTEGER  NOT NULL, \
+                archive_time DATETIME NOT NULL \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 7)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Workspaces backed up with an external tool before their deletion
+        transaction.execute(
+            "CREATE TABLE backups( \
+                id          INTEGER  NOT NULL PRIMARY KEY, \
+                filesystem  TEXT     NOT NULL, \
+                user        TEXT     NOT NULL, \
+                name        TEXT     NOT NULL, \
+                tool        TEXT     NOT NULL, \
+                repository  TEXT     NOT NULL, \
+                snapshot_id TEXT     NOT NULL, \
+                backup_time DATETIME NOT NULL \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 8)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Workspaces an admin wants to review before they are deleted
+        transaction.execute(
+            "ALTER TABLE workspaces ADD COLUMN deletion_held BOOLEAN NOT NULL DEFAULT FALSE",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 9)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // What a notification was about, and the channels it was delivered over.
+        // Without a channel, nothing was sent: the entry only holds off warnings,
+        // e.g. right after the workspace was created.
+        transaction.execute(
+            "ALTER TABLE notifications ADD COLUMN kind TEXT NOT NULL DEFAULT 'unknown'",
+            (),
+        )?;
+        transaction.execute("ALTER TABLE notifications ADD COLUMN channel TEXT", ())?;
+
+        transaction.pragma_update(None, "user_version", 10)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Comma-separated days overriding the filesystem's expiry_notifications_on_days
+        transaction.execute(
+            "ALTER TABLE workspaces ADD COLUMN expiry_notifications TEXT",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 11)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Whether the one-time grace extension was already granted
+        transaction.execute(
+            "ALTER TABLE workspaces ADD COLUMN grace_granted BOOLEAN NOT NULL DEFAULT FALSE",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 12)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // ZFS changes whose database side was already committed.
+        // `flag` is the workspace's snapdir visibility for creations,
+        // whether it was read-only for renames, and the new value for
+        // readonly changes.
+        transaction.execute(
+            "CREATE TABLE journal( \
+                id INTEGER PRIMARY KEY, \
+                operation TEXT NOT NULL, \
+                workspace_id INTEGER NOT NULL, \
+                filesystem TEXT NOT NULL, \
+                volume TEXT NOT NULL, \
+                previous_volume TEXT, \
+                previous_name TEXT, \
+                flag BOOLEAN NOT NULL, \
+                started TIMESTAMP NOT NULL \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 13)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Where the workspace was mounted when last checked;
+        // filled in for existing workspaces by `workspaces maintain`
+        transaction.execute("ALTER TABLE workspaces ADD COLUMN mountpoint TEXT", ())?;
+
+        transaction.pragma_update(None, "user_version", 14)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Profile the workspace was requested with, applied on approval
+        transaction.execute("ALTER TABLE pending_requests ADD COLUMN profile TEXT", ())?;
+
+        transaction.pragma_update(None, "user_version", 15)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // What happened to workspaces, for `workspaces events`.  Workspaces
+        // are referenced by name, as events outlive their deletion.
+        transaction.execute(
+            "CREATE TABLE events( \
+                id INTEGER PRIMARY KEY AUTOINCREMENT, \
+                time TIMESTAMP NOT NULL, \
+                kind TEXT NOT NULL, \
+                filesystem TEXT NOT NULL, \
+                user TEXT NOT NULL, \
+                name TEXT NOT NULL, \
+                expiration_time TIMESTAMP, \
+                detail TEXT)",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 16)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // When each filesystem was last snapshotted, for `snapshot_interval_hours`
+        transaction.execute(
+            "CREATE TABLE snapshots( \
+                filesystem TEXT PRIMARY KEY, \
+                time TIMESTAMP NOT NULL)",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 17)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Workspaces left out of their filesystem's regular snapshots
+        transaction.execute(
+            "ALTER TABLE workspaces ADD COLUMN snapshots_excluded BOOLEAN NOT NULL DEFAULT FALSE",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 18)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // When `maintain` found the owner's account gone or expired
+        transaction.execute(
+            "ALTER TABLE workspaces ADD COLUMN owner_departed TIMESTAMP",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 19)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Dataset of each workspace below its root, as named by its filesystem's layout
+        transaction.execute("ALTER TABLE workspaces ADD COLUMN dataset TEXT", ())?;
+        transaction.execute("UPDATE workspaces SET dataset = user || '/' || name", ())?;
+
+        transaction.pragma_update(None, "user_version", 20)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Created / extended emails held back for their recipient's digest
+        transaction.execute(
+            "CREATE TABLE digest_entries( \
+                id           INTEGER  NOT NULL PRIMARY KEY, \
+                workspace_id INTEGER  NOT NULL, \
+                user         TEXT     NOT NULL, \
+                kind         TEXT     NOT NULL, \
+                subject      TEXT     NOT NULL, \
+                body         TEXT     NOT NULL, \
+                timestamp    DATETIME NOT NULL \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 21)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Notes operators attached to workspaces, never shown to their owners
+        transaction.execute(
+            "CREATE TABLE notes( \
+                id           INTEGER  NOT NULL PRIMARY KEY, \
+                workspace_id INTEGER  NOT NULL, \
+                author       TEXT     NOT NULL, \
+                timestamp    DATETIME NOT NULL, \
+                text         TEXT     NOT NULL, \
+                FOREIGN KEY(workspace_id) REFERENCES workspaces(id) ON DELETE CASCADE \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 22)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Quota given with the creation request, in bytes
+        transaction.execute("ALTER TABLE pending_requests ADD COLUMN quota INTEGER", ())?;
+
+        transaction.pragma_update(None, "user_version", 23)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // When weekly admin reports were sent
+        transaction.execute(
+            "CREATE TABLE admin_reports( \
+                id        INTEGER  NOT NULL PRIMARY KEY, \
+                timestamp DATETIME NOT NULL \
+            )",
+            (),
+        )?;
+        // Sizes of the workspaces as of the last report, to tell how much they grew
+        transaction.execute(
+            "CREATE TABLE reported_sizes( \
+                workspace_id INTEGER NOT NULL PRIMARY KEY, \
+                used         INTEGER NOT NULL, \
+                FOREIGN KEY(workspace_id) REFERENCES workspaces(id) ON DELETE CASCADE \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 24)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Unix group whose members may manage the workspace alongside its owner
+        transaction.execute("ALTER TABLE workspaces ADD COLUMN owner_group TEXT", ())?;
+        transaction.execute(
+            "ALTER TABLE pending_requests ADD COLUMN owner_group TEXT",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 25)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Workspaces packed into a tarball before their deletion
+        transaction.execute(
+            "CREATE TABLE tarballs( \
+                id           INTEGER  NOT NULL PRIMARY KEY, \
+                filesystem   TEXT     NOT NULL, \
+                user         TEXT     NOT NULL, \
+                name         TEXT     NOT NULL, \
+                path         TEXT     NOT NULL, \
+                size         INTEGER  NOT NULL, \
+                archive_time DATETIME NOT NULL \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 26)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // When workspaces were created, taken from their latest creation event
+        // for existing ones
+        transaction.execute("ALTER TABLE workspaces ADD COLUMN created_at DATETIME", ())?;
+        transaction.execute(
+            "UPDATE workspaces SET created_at = ( \
+                SELECT MAX(time) FROM events \
+                    WHERE kind = 'create' \
+                        AND events.filesystem = workspaces.filesystem \
+                        AND events.user = workspaces.user \
+                        AND events.name = workspaces.name \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 27)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Users' preferences as TOML, for sites keeping them out of home directories
+        transaction.execute(
+            "CREATE TABLE user_preferences( \
+                user        TEXT NOT NULL PRIMARY KEY, \
+                preferences TEXT NOT NULL \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 28)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // When users were last reminded of something, so they aren't on every command
+        transaction.execute(
+            "CREATE TABLE hints( \
+                user     TEXT NOT NULL, \
+                hint     TEXT NOT NULL, \
+                shown_at TEXT NOT NULL, \
+                PRIMARY KEY(user, hint) \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 29)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Users sharing a workspace's ownership, besides its owner
+        transaction.execute(
+            "CREATE TABLE co_owners( \
+                workspace_id INTEGER NOT NULL, \
+                user         TEXT    NOT NULL, \
+                PRIMARY KEY(workspace_id, user), \
+                FOREIGN KEY(workspace_id) REFERENCES workspaces(id) ON DELETE CASCADE \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 30)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Limited rights other users were granted on a workspace
+        transaction.execute(
+            "CREATE TABLE grants( \
+                workspace_id INTEGER NOT NULL, \
+                user         TEXT    NOT NULL, \
+                access       TEXT    NOT NULL, \
+                PRIMARY KEY(workspace_id, user), \
+                FOREIGN KEY(workspace_id) REFERENCES workspaces(id) ON DELETE CASCADE \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 31)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Workspaces their owners offered to someone else, until they accept
+        transaction.execute(
+            "CREATE TABLE transfer_offers( \
+                workspace_id INTEGER PRIMARY KEY, \
+                recipient    TEXT    NOT NULL, \
+                offered_at   TEXT    NOT NULL, \
+                FOREIGN KEY(workspace_id) REFERENCES workspaces(id) ON DELETE CASCADE \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 32)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Workspaces listed to everyone with `list --public`, e.g. reference data
+        transaction.execute(
+            "ALTER TABLE workspaces ADD COLUMN public INTEGER NOT NULL DEFAULT 0",
+            (),
+        )?;
+        transaction.execute("ALTER TABLE workspaces ADD COLUMN description TEXT", ())?;
+
+        transaction.pragma_update(None, "user_version", 33)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // SHA-256 of the uploaded stream and of the tarball, to verify them
+        // against later; unknown for ones archived before
+        transaction.execute("ALTER TABLE archives ADD COLUMN sha256 TEXT", ())?;
+        transaction.execute("ALTER TABLE tarballs ADD COLUMN sha256 TEXT", ())?;
+
+        transaction.pragma_update(None, "user_version", 34)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Receiving an archive is journaled before its workspace is inserted,
+        // by a process which has to be gone before the entry is resolved
+        transaction.execute("ALTER TABLE journal RENAME TO journal_old", ())?;
+        transaction.execute(
+            "CREATE TABLE journal( \
+                id INTEGER PRIMARY KEY, \
+                operation TEXT NOT NULL, \
+                workspace_id INTEGER, \
+                filesystem TEXT NOT NULL, \
+                volume TEXT NOT NULL, \
+                previous_volume TEXT, \
+                previous_name TEXT, \
+                flag BOOLEAN NOT NULL, \
+                started TIMESTAMP NOT NULL, \
+                pid INTEGER \
+            )",
+            (),
+        )?;
+        transaction.execute(
+            "INSERT INTO journal(id, operation, workspace_id, filesystem, volume, \
+                    previous_volume, previous_name, flag, started) \
+                SELECT id, operation, workspace_id, filesystem, volume, \
+                    previous_volume, previous_name, flag, started \
+                FROM journal_old",
+            (),
+        )?;
+        transaction.execute("DROP TABLE journal_old", ())?;
+
+        transaction.pragma_update(None, "user_version", 35)?;
+        Ok(transaction.commit()?)
+    },
+    |conn| {
+        let transaction = conn.transaction()?;
+
+        // Streams are uploaded in parts, recorded as they are, so an upload
+        // which was interrupted is resumed from its snapshot, as long as the
+        // workspace wasn't extended meanwhile.  Archives uploaded before are a
+        // single object, without parts.
+        transaction.execute(
+            "ALTER TABLE archives ADD COLUMN complete INTEGER NOT NULL DEFAULT 1",
+            (),
+        )?;
+        transaction.execute("ALTER TABLE archives ADD COLUMN snapshot TEXT", ())?;
+        transaction.execute(
+            "ALTER TABLE archives ADD COLUMN expiration_time DATETIME",
+            (),
+        )?;
+        transaction.execute(
+            "CREATE TABLE archive_parts( \
+                archive_id INTEGER NOT NULL, \
+                number     INTEGER NOT NULL, \
+                size       INTEGER NOT NULL, \
+                sha256     TEXT    NOT NULL, \
+                PRIMARY KEY(archive_id, number), \
+                FOREIGN KEY(archive_id) REFERENCES archives(id) ON DELETE CASCADE \
+            )",
+            (),
+        )?;
+
+        transaction.pragma_update(None, "user_version", 36)?;
+        Ok(transaction.commit()?)
+    },
 ];
 
 pub const NEWEST_DB_VERSION: usize = UPDATE_DB.len();
@@ -0,0 +1,137 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+
+use chrono::{Duration, NaiveDate};
+use lettre::message::Mailbox;
+
+use crate::{clock, config, notify, storage};
+
+/// Why `username` counts as having left, if they do
+///
+/// Users without an account have left, as have those whose account expired
+/// according to `getent shadow`, which also consults LDAP if the site uses it.
+/// Failing to look up the account, e.g. while LDAP is unreachable, is an error
+/// rather than a departure.
+pub fn departure(username: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let output = storage::system_command("getent")
+        .args(["passwd", username])
+        .output()?;
+    match output.status.code() {
+        Some(0) => {}
+        // "One or more supplied key could not be found in the database"
+        Some(2) => return Ok(Some("the account no longer exists".to_owned())),
+        _ => {
+            return Err(format!(
+                "getent passwd {} failed with {}: {}",
+                username,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+    }
+
+    let output = storage::system_command("getent")
+        .args(["shadow", username])
+        .output()?;
+    // Accounts without shadow entry, e.g. from directories not exposing them
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let entry = String::from_utf8_lossy(&output.stdout);
+    // The 8th field holds the expiry in days since the epoch, if any
+    let Some(days) = entry
+        .trim_end()
+        .split(':')
+        .nth(7)
+        .and_then(|days| days.parse::<i64>().ok())
+    else {
+        return Ok(None);
+    };
+    let expiry = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap() + Duration::days(days);
+    if expiry <= clock::now().date_naive() {
+        Ok(Some(format!("the account expired on {}", expiry)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Looks up why each of `usernames` counts as having left, if they do
+///
+/// Owners whose account can't be looked up are taken to still be there.  If
+/// more than one owner and more than `max_fraction` of them newly turn out to
+/// have left, i.e. aren't in `known_departed`, that is rather taken for an
+/// outage of the user directory, and nothing is returned.
+pub fn departures(
+    usernames: &HashSet<String>,
+    known_departed: &HashSet<String>,
+    max_fraction: f64,
+) -> Result<HashMap<String, Option<String>>, String> {
+    let departures: HashMap<String, Option<String>> = usernames
+        .iter()
+        .map(|username| {
+            let reason = departure(username).unwrap_or_else(|e| {
+                log::warn!("Failed to check whether {} has left: {}", username, e);
+                None
+            });
+            (username.clone(), reason)
+        })
+        .collect();
+    let newly_departed = departures
+        .iter()
+        .filter(|(username, reason)| reason.is_some() && !known_departed.contains(*username))
+        .count();
+    if newly_departed > 1 && newly_departed as f64 > max_fraction * usernames.len() as f64 {
+        return Err(format!(
+            "{} of {} owners seem to have left at once, which looks like an outage of \
+                the user directory; not acting on any departures",
+            newly_departed,
+            usernames.len()
+        ));
+    }
+    Ok(departures)
+}
+
+/// Tells the site's admins about workspaces whose owners newly turned out to have left
+///
+/// `departed` holds the volumes with the reason their owner counts as departed.
+pub fn notify_admins(
+    smtp: &config::SmtpConfig,
+    admins: &[Mailbox],
+    action: config::DepartedUsersAction,
+    departed: &[(String, String)],
+) {
+    let host = match hostname::get() {
+        Ok(host) => host.to_string_lossy().to_string(),
+        Err(_) => "unknown host".to_owned(),
+    };
+    let body = format!(
+        "Hello,\n\nThe owners of these workspaces on {} have left:\n\n{}\n\n{}\n",
+        host,
+        departed
+            .iter()
+            .map(|(volume, reason)| format!("{}: {}", volume, reason))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        match action {
+            config::DepartedUsersAction::Flag => {
+                "They were left untouched; `workspaces offboard` hands them over or expires them."
+            }
+            config::DepartedUsersAction::Expire => {
+                "They were expired and will be deleted once their retention period is over."
+            }
+        }
+    );
+    for admin in admins {
+        if let Err(e) = notify::send_email(
+            smtp,
+            admin.clone(),
+            format!("Workspaces of departed users on {}", host),
+            body.clone(),
+        ) {
+            log::warn!("Failed to notify {} about departed users: {}", admin, e);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::error::Error;
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::Connection;
+
+use crate::{
+    clock,
+    config::{self, DigestFrequency, NotificationChannel},
+    events,
+    notify::{self, NotificationKind, Notifiers},
+};
+
+/// How often `username` gets emails about created and extended workspaces
+pub fn frequency(notifiers: &Notifiers, username: &str) -> DigestFrequency {
+    notify::user_config(username)
+        .ok()
+        .and_then(|user_config| user_config.digest_frequency)
+        .unwrap_or(notifiers.digest_frequency)
+}
+
+/// Notifies a workspace's owner about it being created or extended
+///
+/// Like [`notify::notify`], but if the owner gets a digest, the email is held
+/// back for it instead of being sent right away.  Succeeds with the channels
+/// the notification was delivered over right away.
+#[allow(clippy::too_many_arguments)]
+pub fn notify(
+    conn: &Connection,
+    notifiers: &Notifiers,
+    filesystem: &config::Filesystem,
+    kind: NotificationKind,
+    workspace_id: i64,
+    username: &str,
+    values: &[(&str, &str)],
+    subject: String,
+    body: String,
+) -> Result<Vec<NotificationChannel>, Box<dyn Error>> {
+    if notifiers.smtp.is_none()
+        || !filesystem
+            .notification_channels
+            .contains(&NotificationChannel::Email)
+        || frequency(notifiers, username) == DigestFrequency::Immediate
+    {
+        return Ok(notify::notify(
+            notifiers, filesystem, kind, username, values, subject, body,
+        )?);
+    }
+
+    let (subject, body) = notify::render(filesystem, kind, values, subject, body)?;
+    conn.execute(
+        "INSERT INTO digest_entries(workspace_id, user, kind, subject, body, timestamp) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        (
+            workspace_id,
+            username,
+            kind.to_string(),
+            &subject,
+            &body,
+            clock::now(),
+        ),
+    )?;
+    // The other channels aren't batched
+    let immediate = Notifiers {
+        smtp: None,
+        ..*notifiers
+    };
+    Ok(notify::notify(
+        &immediate, filesystem, kind, username, values, subject, body,
+    )?)
+}
+
+/// Sends the digests which are due
+///
+/// A user's digest is due once the oldest email held back for it is older
+/// than their digest frequency, or right away if they no longer want one.
+pub fn send_due(
+    conn: &Connection,
+    notifiers: &Notifiers,
+    host: &str,
+) -> Result<(), Box<dyn Error>> {
+    let Some(smtp) = notifiers.smtp else {
+        return Ok(());
+    };
+
+    let users: Vec<(String, DateTime<Utc>)> = conn
+        .prepare("SELECT user, MIN(timestamp) FROM digest_entries GROUP BY user")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    for (username, oldest) in users {
+        let period = match frequency(notifiers, &username) {
+            DigestFrequency::Immediate => Duration::zero(),
+            DigestFrequency::Daily => Duration::days(1),
+            DigestFrequency::Weekly => Duration::weeks(1),
+        };
+        // Leave some slack for `maintain` not running at exactly the same time each day
+        if oldest > clock::now() - period + Duration::hours(1) {
+            continue;
+        }
+
+        let entries: Vec<(i64, i64, String, String, String)> = conn
+            .prepare(
+                "SELECT id, workspace_id, kind, subject, body FROM digest_entries \
+                    WHERE user = ?1 ORDER BY timestamp",
+            )?
+            .query_map((&username,), |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        let subject = format!("{} updates to your workspaces on {}", entries.len(), host);
+        let body = format!(
+            "Hello,\n\nThis is your digest of what happened to your workspaces on {}.\n\n{}",
+            host,
+            entries
+                .iter()
+                .map(|(_, _, _, subject, body)| format!(
+                    "{}\n{}\n\n{}\n",
+                    subject,
+                    "-".repeat(subject.chars().count()),
+                    body.trim_end()
+                ))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+
+        match notify::user_recipients(&username)
+            .and_then(|to| notify::send_email(smtp, to, subject, body))
+        {
+            Ok(()) => {
+                for (id, workspace_id, kind, _, _) in &entries {
+                    if let Ok(kind) = kind.parse::<NotificationKind>() {
+                        events::record_notification(
+                            conn,
+                            *workspace_id,
+                            kind,
+                            &[NotificationChannel::Email],
+                        )?;
+                    }
+                    conn.execute("DELETE FROM digest_entries WHERE id = ?1", (id,))?;
+                }
+            }
+            Err(e) => log::warn!("Failed to send the digest to {}: {}", username, e),
+        }
+    }
+    Ok(())
+}
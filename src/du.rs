@@ -0,0 +1,198 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+};
+
+use prettytable::{
+    Attr, Cell, Row, Table,
+    format::{Alignment, FormatBuilder},
+};
+use rusqlite::Connection;
+use users::get_current_uid;
+
+use crate::{cache::PropertyCache, config, list, to_volume_string};
+
+/// Space used by a group of workspaces
+#[derive(Default)]
+struct Usage {
+    workspaces: usize,
+    referenced: usize,
+}
+
+impl Usage {
+    fn add(&mut self, referenced: usize) {
+        self.workspaces += 1;
+        self.referenced += referenced;
+    }
+}
+
+/// Prints the space referenced by workspaces, summed per filesystem or per user
+///
+/// With `private_listing`, users only get the workspaces `list` shows them.
+pub fn du(
+    conn: &Connection,
+    cache: &PropertyCache,
+    filesystems: &HashMap<String, config::Filesystem>,
+    filter_filesystems: &Option<Vec<String>>,
+    by_user: bool,
+    private_listing: bool,
+) -> Result<(), Box<dyn Error>> {
+    // (user, filesystem) -> usage
+    let mut usage: BTreeMap<(String, String), Usage> = BTreeMap::new();
+    let mut failed = 0;
+
+    let visible = if private_listing && get_current_uid() != 0 {
+        Some(list::visible_workspaces(conn)?)
+    } else {
+        None
+    };
+    let mut statement =
+        conn.prepare("SELECT filesystem, user, root, dataset, id FROM workspaces")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let filesystem_name: String = row.get(0)?;
+        let user: String = row.get(1)?;
+        let root: Option<String> = row.get(2)?;
+        let dataset: String = row.get(3)?;
+        let id: i64 = row.get(4)?;
+        if visible
+            .as_ref()
+            .is_some_and(|visible| !visible.contains(&id))
+            || !filter_filesystems
+                .as_ref()
+                .is_none_or(|fs| fs.contains(&filesystem_name))
+        {
+            continue;
+        }
+        let filesystem = filesystems
+            .get(&filesystem_name)
+            .expect("found workspace in database without corresponding config entry");
+        let volume = to_volume_string(filesystem.root(root.as_deref()), &dataset);
+        match cache.get_property::<usize>(&filesystem_name, filesystem, &volume, "referenced") {
+            Ok(referenced) => usage
+                .entry((user, filesystem_name))
+                .or_default()
+                .add(referenced),
+            Err(_) => failed += 1,
+        }
+    }
+
+    if by_user {
+        print_by_user(&usage);
+    } else {
+        print_by_filesystem(&usage);
+    }
+    cache.refresh_in_background();
+
+    if failed > 0 {
+        eprintln!(
+            "Warning: could not query ZFS for {} workspace(s); they are not included above",
+            failed
+        );
+    }
+    Ok(())
+}
+
+fn print_by_filesystem(usage: &BTreeMap<(String, String), Usage>) {
+    let mut per_filesystem: BTreeMap<&str, Usage> = BTreeMap::new();
+    for ((_, filesystem_name), u) in usage {
+        let entry = per_filesystem.entry(filesystem_name).or_default();
+        entry.workspaces += u.workspaces;
+        entry.referenced += u.referenced;
+    }
+    let mut per_filesystem: Vec<_> = per_filesystem.into_iter().collect();
+    per_filesystem.sort_by_key(|(_, u)| std::cmp::Reverse(u.referenced));
+
+    let mut table = new_table(&["FS", "WORKSPACES", "SIZE"]);
+    let mut total = Usage::default();
+    for (filesystem_name, u) in per_filesystem {
+        table.add_row(Row::new(vec![
+            Cell::new(filesystem_name),
+            count_cell(u.workspaces),
+            size_cell(u.referenced),
+        ]));
+        total.workspaces += u.workspaces;
+        total.referenced += u.referenced;
+    }
+    table.add_row(total_row(vec![
+        Cell::new("TOTAL"),
+        count_cell(total.workspaces),
+        size_cell(total.referenced),
+    ]));
+    table.printstd();
+}
+
+fn print_by_user(usage: &BTreeMap<(String, String), Usage>) {
+    let mut filesystem_names: Vec<&str> = usage.keys().map(|(_, fs)| fs.as_str()).collect();
+    filesystem_names.sort();
+    filesystem_names.dedup();
+
+    // user -> (total, per filesystem)
+    let mut per_user: BTreeMap<&str, (Usage, HashMap<&str, usize>)> = BTreeMap::new();
+    for ((user, filesystem_name), u) in usage {
+        let (total, per_filesystem) = per_user.entry(user).or_default();
+        total.workspaces += u.workspaces;
+        total.referenced += u.referenced;
+        per_filesystem.insert(filesystem_name, u.referenced);
+    }
+    let mut per_user: Vec<_> = per_user.into_iter().collect();
+    per_user.sort_by_key(|(_, (total, _))| std::cmp::Reverse(total.referenced));
+
+    let mut titles = vec!["USER".to_owned(), "WORKSPACES".to_owned()];
+    titles.extend(filesystem_names.iter().map(|fs| fs.to_uppercase()));
+    titles.push("SIZE".to_owned());
+    let mut table = new_table(&titles);
+
+    let mut total = Usage::default();
+    let mut filesystem_totals: HashMap<&str, usize> = HashMap::new();
+    for (user, (u, per_filesystem)) in per_user {
+        let mut cells = vec![Cell::new(user), count_cell(u.workspaces)];
+        for filesystem_name in &filesystem_names {
+            let referenced = per_filesystem.get(filesystem_name).copied().unwrap_or(0);
+            *filesystem_totals.entry(filesystem_name).or_default() += referenced;
+            cells.push(size_cell(referenced));
+        }
+        cells.push(size_cell(u.referenced));
+        table.add_row(Row::new(cells));
+        total.workspaces += u.workspaces;
+        total.referenced += u.referenced;
+    }
+
+    let mut cells = vec![Cell::new("TOTAL"), count_cell(total.workspaces)];
+    for filesystem_name in &filesystem_names {
+        cells.push(size_cell(filesystem_totals[filesystem_name]));
+    }
+    cells.push(size_cell(total.referenced));
+    table.add_row(total_row(cells));
+    table.printstd();
+}
+
+fn new_table<S: AsRef<str>>(titles: &[S]) -> Table {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    // bold title row
+    table.set_titles(Row::new(
+        titles
+            .iter()
+            .map(|h| Cell::new(h.as_ref()).with_style(Attr::Bold))
+            .collect(),
+    ));
+    table
+}
+
+fn total_row(cells: Vec<Cell>) -> Row {
+    Row::new(
+        cells
+            .into_iter()
+            .map(|c| c.with_style(Attr::Bold))
+            .collect(),
+    )
+}
+
+fn count_cell(count: usize) -> Cell {
+    Cell::new_align(&count.to_string(), Alignment::RIGHT)
+}
+
+fn size_cell(referenced: usize) -> Cell {
+    Cell::new_align(&format!("{}G", referenced / (1 << 30)), Alignment::RIGHT)
+}
@@ -0,0 +1,30 @@
+use std::error::Error;
+
+use lettre::message::Mailbox;
+use users::get_current_username;
+
+use crate::{error::WorkspacesError, prefs};
+
+/// Sets the notification address in the current user's preferences
+///
+/// Other settings are kept.
+pub fn set_mine(address: &str) -> Result<(), Box<dyn Error>> {
+    if address.parse::<Mailbox>().is_err() {
+        return Err(WorkspacesError::InvalidArgument(format!(
+            "`{}` is not a valid email address, e.g. \"you@example.org\" or \
+            \"Your Name <you@example.org>\"",
+            address
+        ))
+        .into());
+    }
+
+    let username = get_current_username()
+        .ok_or("could not look up the current user")?
+        .to_string_lossy()
+        .into_owned();
+    let mut user_config = prefs::table(&username)?;
+    user_config.insert("email".to_owned(), address.into());
+    prefs::write(&username, &toml::to_string(&user_config)?)?;
+    println!("Notifications will be sent to {}", address);
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+
+use crate::{cli::Shell, config, error::WorkspacesError, json, to_volume_string};
+
+/// Prints shell-exportable variables describing a workspace
+pub fn env(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    shell: &Shell,
+) -> Result<(), WorkspacesError> {
+    let (expiration_time, root, dataset): (DateTime<Utc>, Option<String>, String) = match conn
+        .prepare(
+            "SELECT expiration_time, root, dataset FROM workspaces \
+                WHERE filesystem = ?1 \
+                    AND user = ?2 \
+                    AND name = ?3",
+        )?
+        .query_row((filesystem_name, user, name), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        }) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                name,
+            ));
+        }
+        res => res,
+    }?;
+
+    let volume = to_volume_string(filesystem.root(root.as_deref()), &dataset);
+    let mountpoint: PathBuf = filesystem.storage().get_property(&volume, "mountpoint")?;
+
+    let vars = [
+        ("WORKSPACE_DIR", mountpoint.to_string_lossy().into_owned()),
+        (
+            "WORKSPACE_EXPIRY_EPOCH",
+            expiration_time.timestamp().to_string(),
+        ),
+        ("WORKSPACE_NAME", name.to_owned()),
+    ];
+
+    match shell {
+        Shell::Bash => {
+            for (key, value) in vars {
+                println!("export {}='{}'", key, value.replace('\'', r"'\''"));
+            }
+        }
+        Shell::Fish => {
+            for (key, value) in vars {
+                println!(
+                    "set -gx {} '{}'",
+                    key,
+                    value.replace('\\', r"\\").replace('\'', r"\'")
+                );
+            }
+        }
+        Shell::Json => {
+            println!(
+                "{}",
+                json::object(vars.map(|(key, value)| (key, value.into())))
+            );
+        }
+    }
+
+    Ok(())
+}
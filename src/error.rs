@@ -0,0 +1,122 @@
+use std::{error::Error, fmt, io};
+
+use crate::{ExitCodes, storage};
+
+/// Why an operation on a workspace was refused, or failed
+///
+/// Refusals carry the message for the user; `main` prints it and exits with
+/// the matching [`ExitCodes`].
+#[derive(Debug)]
+pub enum WorkspacesError {
+    InsufficientPrivileges(String),
+    FsDisabled(String),
+    TooHighDuration(String),
+    UnknownWorkspace(String),
+    WorkspaceExists(String),
+    NotConfirmed(String),
+    WorkspaceExpired(String),
+    InvalidArgument(String),
+    TooHighQuota(String),
+    TooManyWorkspaces(String),
+    UnknownId(String),
+    NotConfigured(String),
+    InvalidConfig(String),
+    /// Anything else, e.g. a failed ZFS command or database access
+    Failure(Box<dyn Error>),
+}
+
+impl WorkspacesError {
+    /// The refusal for users changing workspaces they have no rights on
+    pub fn not_allowed() -> Self {
+        WorkspacesError::InsufficientPrivileges(
+            "You are not allowed to execute this operation".to_owned(),
+        )
+    }
+
+    /// The refusal for a workspace which doesn't exist
+    pub fn unknown_workspace(filesystem_name: &str, user: &str, name: &str) -> Self {
+        WorkspacesError::UnknownWorkspace(format!(
+            "Could not find a matching filesystem={}, user={}, name={}",
+            filesystem_name, user, name
+        ))
+    }
+
+    pub fn exit_code(&self) -> ExitCodes {
+        match self {
+            WorkspacesError::InsufficientPrivileges(_) => ExitCodes::InsufficientPrivileges,
+            WorkspacesError::FsDisabled(_) => ExitCodes::FsDisabled,
+            WorkspacesError::TooHighDuration(_) => ExitCodes::TooHighDuration,
+            WorkspacesError::UnknownWorkspace(_) => ExitCodes::UnknownWorkspace,
+            WorkspacesError::WorkspaceExists(_) => ExitCodes::WorkspaceExists,
+            WorkspacesError::NotConfirmed(_) => ExitCodes::NotConfirmed,
+            WorkspacesError::WorkspaceExpired(_) => ExitCodes::WorkspaceExpired,
+            WorkspacesError::InvalidArgument(_) => ExitCodes::InvalidArgument,
+            WorkspacesError::TooHighQuota(_) => ExitCodes::TooHighQuota,
+            WorkspacesError::TooManyWorkspaces(_) => ExitCodes::TooManyWorkspaces,
+            WorkspacesError::UnknownId(_) => ExitCodes::UnknownId,
+            WorkspacesError::NotConfigured(_) => ExitCodes::NotConfigured,
+            WorkspacesError::InvalidConfig(_) => ExitCodes::InvalidConfig,
+            WorkspacesError::Failure(_) => ExitCodes::Failure,
+        }
+    }
+}
+
+impl fmt::Display for WorkspacesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WorkspacesError::InsufficientPrivileges(message)
+            | WorkspacesError::FsDisabled(message)
+            | WorkspacesError::TooHighDuration(message)
+            | WorkspacesError::UnknownWorkspace(message)
+            | WorkspacesError::WorkspaceExists(message)
+            | WorkspacesError::NotConfirmed(message)
+            | WorkspacesError::WorkspaceExpired(message)
+            | WorkspacesError::InvalidArgument(message)
+            | WorkspacesError::TooHighQuota(message)
+            | WorkspacesError::TooManyWorkspaces(message)
+            | WorkspacesError::UnknownId(message)
+            | WorkspacesError::NotConfigured(message)
+            | WorkspacesError::InvalidConfig(message) => f.write_str(message),
+            WorkspacesError::Failure(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for WorkspacesError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WorkspacesError::Failure(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for WorkspacesError {
+    fn from(e: Box<dyn Error>) -> Self {
+        WorkspacesError::Failure(e)
+    }
+}
+
+impl From<String> for WorkspacesError {
+    fn from(message: String) -> Self {
+        WorkspacesError::Failure(message.into())
+    }
+}
+
+impl From<rusqlite::Error> for WorkspacesError {
+    fn from(e: rusqlite::Error) -> Self {
+        WorkspacesError::Failure(e.into())
+    }
+}
+
+impl From<io::Error> for WorkspacesError {
+    fn from(e: io::Error) -> Self {
+        WorkspacesError::Failure(e.into())
+    }
+}
+
+impl From<storage::Error> for WorkspacesError {
+    fn from(e: storage::Error) -> Self {
+        WorkspacesError::Failure(e.into())
+    }
+}
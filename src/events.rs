@@ -0,0 +1,199 @@
+use std::{collections::HashSet, error::Error, fmt, thread, time::Duration as StdDuration};
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use users::{get_current_uid, get_current_username};
+
+use crate::{clock, config, json, list, notify::NotificationKind};
+
+/// How often `workspaces events --follow` looks for new events
+const FOLLOW_INTERVAL: StdDuration = StdDuration::from_secs(1);
+
+/// Something that happened to a workspace
+#[derive(Debug, Clone, Copy)]
+pub enum EventKind {
+    Create,
+    Extend,
+    /// The expired workspace was made writable again by `workspaces restore`
+    Restore,
+    /// The workspace expired, either on its own or by `workspaces expire`
+    Expire,
+    Rename,
+    /// The workspace was handed over to another user
+    Reassign,
+    /// The workspace was permanently deleted
+    Delete,
+    /// Its owner was notified about it
+    Notify,
+    /// Changing its dataset failed; the detail has the error, including what ZFS printed
+    Failure,
+    /// Its mountpoint no longer belonged to its owner, according to `maintain`;
+    /// the detail says how, and whether it was fixed
+    Drift,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                EventKind::Create => "create",
+                EventKind::Extend => "extend",
+                EventKind::Restore => "restore",
+                EventKind::Expire => "expire",
+                EventKind::Rename => "rename",
+                EventKind::Reassign => "reassign",
+                EventKind::Delete => "delete",
+                EventKind::Notify => "notify",
+                EventKind::Failure => "failure",
+                EventKind::Drift => "drift",
+            }
+        )
+    }
+}
+
+/// Records an event for `workspaces events`
+///
+/// `detail` depends on the kind, e.g. the previous name of a renamed workspace.
+pub fn record(
+    conn: &Connection,
+    kind: EventKind,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    expiration_time: Option<DateTime<Utc>>,
+    detail: Option<&str>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO events(time, kind, filesystem, user, name, expiration_time, detail) \
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (
+            clock::now(),
+            kind.to_string(),
+            filesystem_name,
+            user,
+            name,
+            expiration_time,
+            detail,
+        ),
+    )?;
+    Ok(())
+}
+
+/// Records that the owner of workspace `workspace_id` was notified
+pub fn record_notification(
+    conn: &Connection,
+    workspace_id: i64,
+    kind: NotificationKind,
+    channels: &[config::NotificationChannel],
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO events(time, kind, filesystem, user, name, expiration_time, detail) \
+            SELECT ?2, ?3, filesystem, user, name, expiration_time, ?4 \
+                FROM workspaces WHERE id = ?1",
+        (
+            workspace_id,
+            clock::now(),
+            EventKind::Notify.to_string(),
+            notification_detail(kind, channels),
+        ),
+    )?;
+    Ok(())
+}
+
+/// Describes a notification as the `detail` of its event, e.g. "expiry-warning via email"
+pub fn notification_detail(
+    kind: NotificationKind,
+    channels: &[config::NotificationChannel],
+) -> String {
+    format!(
+        "{} via {}",
+        kind,
+        channels
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// A workspace's filesystem, owner and name, which is all events have of it
+type WorkspaceKey = (String, String, String);
+
+/// Workspaces `list` shows the current user with `private_listing`, along
+/// with their own deleted ones
+fn visible_names(conn: &Connection) -> Result<HashSet<WorkspaceKey>, Box<dyn Error>> {
+    let me = get_current_username()
+        .ok_or("could not look up the current user")?
+        .to_string_lossy()
+        .into_owned();
+    let visible = list::visible_workspaces(conn)?;
+    let mut names = HashSet::new();
+    let mut statement = conn.prepare(
+        "SELECT id, filesystem, user, name FROM workspaces \
+            UNION SELECT NULL, filesystem, user, name FROM events WHERE user = ?1",
+    )?;
+    let mut rows = statement.query((&me,))?;
+    while let Some(row) = rows.next()? {
+        let id: Option<i64> = row.get(0)?;
+        if id.is_none_or(|id| visible.contains(&id)) {
+            names.insert((row.get(1)?, row.get(2)?, row.get(3)?));
+        }
+    }
+    Ok(names)
+}
+
+/// Prints the events after `since` as JSON lines, waiting for new ones if `follow`
+///
+/// With `private_listing`, users only get the events of workspaces `list`
+/// shows them, and of their own.
+pub fn events(
+    conn: &Connection,
+    since: Option<i64>,
+    follow: bool,
+    private_listing: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut last_id = since.unwrap_or(0);
+    loop {
+        // Looked up anew every time, as workspaces get shared and renamed
+        let visible = if private_listing && get_current_uid() != 0 {
+            Some(visible_names(conn)?)
+        } else {
+            None
+        };
+        let mut statement = conn.prepare_cached(
+            "SELECT id, time, kind, filesystem, user, name, expiration_time, detail \
+                FROM events WHERE id > ?1 ORDER BY id",
+        )?;
+        let mut rows = statement.query([last_id])?;
+        while let Some(row) = rows.next()? {
+            last_id = row.get(0)?;
+            let (filesystem, user, name): WorkspaceKey = (row.get(3)?, row.get(4)?, row.get(5)?);
+            if visible.as_ref().is_some_and(|visible| {
+                !visible.contains(&(filesystem.clone(), user.clone(), name.clone()))
+            }) {
+                continue;
+            }
+            json::print_line(&json::object([
+                ("id", last_id.into()),
+                ("time", row.get::<_, DateTime<Utc>>(1)?.to_rfc3339().into()),
+                ("event", row.get::<_, String>(2)?.into()),
+                ("filesystem", filesystem.into()),
+                ("user", user.into()),
+                ("workspace", name.into()),
+                (
+                    "expiration_time",
+                    row.get::<_, Option<DateTime<Utc>>>(6)?
+                        .map(|time| time.to_rfc3339())
+                        .into(),
+                ),
+                ("detail", row.get::<_, Option<String>>(7)?.into()),
+            ]))?;
+        }
+        if !follow {
+            return Ok(());
+        }
+        thread::sleep(FOLLOW_INTERVAL);
+    }
+}
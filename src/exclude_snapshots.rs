@@ -0,0 +1,36 @@
+use rusqlite::Connection;
+
+use crate::{error::WorkspacesError, may_change};
+
+/// Leaves a workspace out of its filesystem's regular snapshots, or includes it again
+pub fn exclude_snapshots(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    exclude: bool,
+) -> Result<(), WorkspacesError> {
+    if !may_change(conn, filesystem_name, user, name) {
+        return Err(WorkspacesError::not_allowed());
+    }
+
+    let updated = conn.execute(
+        "UPDATE workspaces SET snapshots_excluded = ?4 \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name, exclude),
+    )?;
+    if updated == 0 {
+        return Err(WorkspacesError::unknown_workspace(
+            filesystem_name,
+            user,
+            name,
+        ));
+    }
+
+    if exclude {
+        println!("Workspace {} is left out of further snapshots", name);
+    } else {
+        println!("Workspace {} is included in snapshots again", name);
+    }
+    Ok(())
+}
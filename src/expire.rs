@@ -1,11 +1,57 @@
-use std::{error::Error, process};
+use std::io::{self, IsTerminal, Write};
 
-use chrono::Utc;
-use rusqlite::Connection;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, TransactionBehavior};
 use users::{get_current_uid, get_current_username};
 
-use crate::{config, to_volume_string, zfs, ExitCodes};
+use crate::{
+    cli::CommandFormat,
+    clock, config,
+    error::WorkspacesError,
+    events::{self, EventKind},
+    grants, journal, json, may_change, metadata,
+    notify::{self, NotificationKind, Notifiers},
+    owners, storage, workspace_volume,
+};
 
+/// Snapshots a workspace as it expires
+///
+/// This allows recovering data written just before the workspace became
+/// read-only during its retention period.
+pub fn final_snapshot(filesystem: &config::Filesystem, volume: &str) -> Result<(), storage::Error> {
+    filesystem.storage().snapshot_named(
+        volume,
+        &format!("expired-{}", Utc::now().format("%Y-%m-%dT%H%M%S")),
+    )
+}
+
+/// Asks whether the workspace should really be destroyed
+///
+/// Fails closed if there is no terminal to ask on.
+fn confirm_deletion(filesystem_name: &str, user: &str, name: &str) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        eprintln!(
+            "Refusing to schedule the deletion of workspace {} of {} on {} without confirmation; \
+            pass --yes to confirm",
+            name, user, filesystem_name
+        );
+        return Ok(false);
+    }
+
+    eprint!(
+        "Workspace {} of {} on {} will be DESTROYED on the next `workspaces maintain` run, \
+        skipping its retention period.\n\
+        This may happen at any moment and cannot be undone.\n\
+        Type \"yes\" to continue: ",
+        name, user, filesystem_name
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn expire(
     conn: &mut Connection,
     filesystem_name: &str,
@@ -13,22 +59,37 @@ pub fn expire(
     user: &str,
     name: &str,
     delete_on_next_clean: bool,
-    smtp: &Option<config::SmtpConfig>,
-) -> Result<(), Box<dyn Error>> {
-    if get_current_username().unwrap() != user && get_current_uid() != 0 {
-        eprintln!("You are not allowed to execute this operation");
-        process::exit(ExitCodes::InsufficientPrivileges as i32);
+    yes: bool,
+    reason: Option<&str>,
+    notifiers: &Notifiers,
+    format: CommandFormat,
+) -> Result<(), WorkspacesError> {
+    if !may_change(conn, filesystem_name, user, name) {
+        if let Some(right) = grants::granted(conn, filesystem_name, user, name) {
+            return Err(WorkspacesError::InsufficientPrivileges(format!(
+                "You were only granted {} rights on this workspace, ask its owner {} to expire it",
+                right, user
+            )));
+        }
+        return Err(WorkspacesError::not_allowed());
+    }
+
+    if delete_on_next_clean && !yes && !confirm_deletion(filesystem_name, user, name)? {
+        return Err(WorkspacesError::NotConfirmed(
+            "Aborted, the workspace was left untouched".to_owned(),
+        ));
     }
 
     let expiration_time = if delete_on_next_clean {
         // Set the expiration time sufficiently far in the past
         // for it to get cleaned up soon
-        Utc::now() - filesystem.expired_retention
+        clock::now() - filesystem.expired_retention
     } else {
-        Utc::now()
+        clock::now()
     };
 
-    let transaction = conn.transaction()?;
+    // Take the write lock right away, so nothing changes the workspace in between
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
     // Get workspace id
     let workspace_id: i64 = match transaction
         .prepare(
@@ -40,13 +101,13 @@ pub fn expire(
         .query_row((filesystem_name, user, name), |row| row.get(0))
     {
         Err(rusqlite::Error::QueryReturnedNoRows) => {
-            eprintln!(
-                "Could not find a matching filesystem={}, user={}, name={}",
-                filesystem_name, user, name
-            );
-            process::exit(ExitCodes::UnknownWorkspace as i32);
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                name,
+            ));
         }
-        res @ _ => res,
+        res => res,
     }?;
 
     transaction.execute(
@@ -58,44 +119,154 @@ pub fn expire(
 
     if get_current_username().unwrap() == user && get_current_uid() != 0 {
         // The user just expired their workspace, so they don't want deletion notices.
-        // We disable them by creating a faux notification in the future.
-        // TODO refactor this into a separate column in workspaces.
-        transaction.execute(
-            "INSERT INTO notifications(workspace_id, timestamp) VALUES (?1, ?2)",
-            (workspace_id, expiration_time),
+        // An undelivered `expired` notification at the expiration time silences
+        // them until the workspace is extended.
+        notify::record(
+            &transaction,
+            workspace_id,
+            NotificationKind::Expired,
+            &[],
+            expiration_time,
         )?;
     }
 
+    let volume = workspace_volume(&transaction, filesystem_name, filesystem, user, name)?;
+    let entry = journal::begin(
+        &transaction,
+        workspace_id,
+        filesystem_name,
+        &volume,
+        journal::Operation::Readonly(true),
+    )?;
+    let detail = match (delete_on_next_clean, reason) {
+        (true, Some(reason)) => Some(format!("deletion scheduled: {}", reason)),
+        (true, None) => Some("deletion scheduled".to_owned()),
+        (false, reason) => reason.map(ToOwned::to_owned),
+    };
+    events::record(
+        &transaction,
+        EventKind::Expire,
+        filesystem_name,
+        user,
+        name,
+        Some(expiration_time),
+        detail.as_deref(),
+    )?;
     transaction.commit()?;
 
-    zfs::set_property(
-        &to_volume_string(&filesystem.root, user, name),
-        "readonly",
-        "on",
-    )?;
+    // Has to happen before the workspace becomes read-only
+    if let Err(e) = metadata::write(conn, filesystem_name, filesystem, user, name) {
+        log::warn!("Failed to write workspace metadata file: {}", e);
+    }
+
+    // What was done, for `--format json`
+    let mut actions = vec![if delete_on_next_clean {
+        "deletion_scheduled"
+    } else {
+        "expired"
+    }];
+    match final_snapshot(filesystem, &volume) {
+        Ok(()) => actions.push("snapshot"),
+        Err(e) => log::warn!("Failed to snapshot the expiring workspace: {}", e),
+    }
+    journal::resolve(conn, filesystem, &entry)?;
 
-    if let Some(smtp_cfg) = smtp.as_ref() {
+    if notifiers.any() {
         let host = hostname::get()?.to_string_lossy().to_string();
         let subject = if delete_on_next_clean {
             format!("Workspace {} scheduled for deletion on {}", name, host)
         } else {
             format!("Workspace {} marked expired on {}", name, host)
         };
+        let reason_line = match reason {
+            Some(reason) => format!("Reason: {}\n", reason),
+            None => String::new(),
+        };
         let body = if delete_on_next_clean {
             format!(
-                "Hello,\n\nYour workspace \"{}\" on {} was marked for deletion on the next cleanup.\nFilesystem: {}\nIt will be removed during the next 'workspaces maintain' run.\n",
-                name, host, filesystem_name
+                "Hello,\n\nYour workspace \"{}\" on {} was marked for deletion on the next cleanup.\nFilesystem: {}\n{}It will be removed during the next 'workspaces maintain' run.\n",
+                name, host, filesystem_name, reason_line
             )
         } else {
             format!(
-                "Hello,\n\nYour workspace \"{}\" on {} has been marked expired and set read-only.\nFilesystem: {}\nYou can still re-enable it by extending:\n  workspaces extend -f {} -d <days> {}\n",
-                name, host, filesystem_name, filesystem_name, name
+                "Hello,\n\nYour workspace \"{}\" on {} has been marked expired and set read-only.\nFilesystem: {}\n{}You can still re-enable it by extending:\n  workspaces extend -f {} -d <days> {}\n",
+                name, host, filesystem_name, reason_line, filesystem_name, name
             )
         };
-        if let Err(e) = crate::maintain::notify_event(user, smtp_cfg, subject, body) {
-            eprintln!("Failed to send 'expired' email: {}", e);
+        let values = [
+            ("workspace", name),
+            ("user", user),
+            ("filesystem", filesystem_name),
+            ("host", &host),
+            ("reason", reason.unwrap_or_default()),
+        ];
+        for co_owner in owners::co_owners(conn, workspace_id)? {
+            if let Err(e) = notify::notify(
+                notifiers,
+                filesystem,
+                NotificationKind::Expired,
+                &co_owner,
+                &values,
+                subject.clone(),
+                owners::co_owner_body(user, &body),
+            ) {
+                log::warn!(
+                    "Failed to send 'expired' email to co-owner {}: {}",
+                    co_owner,
+                    e
+                );
+            }
+        }
+        match notify::notify(
+            notifiers,
+            filesystem,
+            NotificationKind::Expired,
+            user,
+            &values,
+            subject,
+            body,
+        ) {
+            Ok(channels) if !channels.is_empty() => {
+                notify::record(
+                    conn,
+                    workspace_id,
+                    NotificationKind::Expired,
+                    &channels,
+                    clock::now(),
+                )?;
+                actions.push("notified");
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to send 'expired' email: {}", e),
         }
     }
 
+    if format == CommandFormat::Json {
+        // Expiring never postpones the expiration time, so it may have been earlier
+        let (expiration_time, mountpoint): (DateTime<Utc>, Option<String>) = conn.query_row(
+            "SELECT expiration_time, mountpoint FROM workspaces WHERE id = ?1",
+            (workspace_id,),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        json::print_line(&json::object([
+            ("action", "expire".into()),
+            ("id", workspace_id.into()),
+            ("filesystem", filesystem_name.into()),
+            ("user", user.into()),
+            ("name", name.into()),
+            ("mountpoint", mountpoint.into()),
+            ("expiration_time", expiration_time.to_rfc3339().into()),
+            (
+                "deletion_time",
+                (expiration_time + filesystem.expired_retention)
+                    .to_rfc3339()
+                    .into(),
+            ),
+            (
+                "actions",
+                json::Value::Array(actions.into_iter().map(Into::into).collect()),
+            ),
+        ]))?;
+    }
     Ok(())
 }
@@ -0,0 +1,112 @@
+use std::error::Error;
+
+use crate::{ExitCodes, error::WorkspacesError};
+
+/// What an exit code means and how users typically get past it
+fn description(code: ExitCodes) -> (&'static str, &'static str) {
+    match code {
+        ExitCodes::InsufficientPrivileges => (
+            "You are not allowed to do this, e.g. touch another user's workspace.",
+            "Run it as the workspace's owner, or ask an administrator.",
+        ),
+        ExitCodes::FsDisabled => (
+            "The filesystem is disabled for new workspaces and extensions.",
+            "Pick another filesystem, see `workspaces filesystems`.",
+        ),
+        ExitCodes::TooHighDuration => (
+            "The requested duration exceeds the filesystem's maximum.",
+            "Ask for fewer days, see the DURATION column of `workspaces filesystems`.",
+        ),
+        ExitCodes::UnknownWorkspace => (
+            "There is no such workspace.",
+            "Check the name, filesystem and user with `workspaces list`.",
+        ),
+        ExitCodes::WorkspaceExists => (
+            "A workspace of that name already exists.",
+            "Extend the existing one, or choose another name.",
+        ),
+        ExitCodes::NoFilesystemSpecified => (
+            "No filesystem was given and there is no default one.",
+            "Pass one with `-f`, see `workspaces filesystems`.",
+        ),
+        ExitCodes::NotConfirmed => (
+            "A destructive operation was not confirmed.",
+            "Answer the prompt with \"yes\", or pass `--yes` in scripts.",
+        ),
+        ExitCodes::WorkspaceExpired => (
+            "The workspace is expired or read-only, or too long expired to be restored.",
+            "Extend it first, or pass `--force`.  Workspaces due for deletion can only be saved \
+            by an admin.",
+        ),
+        ExitCodes::UnknownFilesystem => (
+            "There is no filesystem of that name.",
+            "Check the name with `workspaces filesystems`.",
+        ),
+        ExitCodes::UnknownId => (
+            "There is no workspace, creation request, archive or backup with that id.",
+            "Run `workspaces list -o id -o name`, or `approve`, `restore-archive` or \
+            `restore-backup` without an id to list them.",
+        ),
+        ExitCodes::NotConfigured => (
+            "The operation needs something this site hasn't configured, e.g. SMTP.",
+            "Ask an administrator to set it up in the configuration file.",
+        ),
+        ExitCodes::InvalidArgument => (
+            "The command line or another input, e.g. an email address, is invalid.",
+            "See the error message and `workspaces help <COMMAND>`.",
+        ),
+        ExitCodes::Failure => (
+            "Something else went wrong, e.g. a ZFS command or the database failed.",
+            "See the error message; if it persists, contact an administrator.",
+        ),
+        ExitCodes::ReadOnly => (
+            "The command could change state, but --read-only was given.",
+            "Drop --read-only, or stick to commands like `list` and `info`.",
+        ),
+        ExitCodes::TooHighQuota => (
+            "The requested quota exceeds the filesystem's maximum, or the space you may use in \
+            total.",
+            "Ask for a smaller quota, delete workspaces you no longer need, or ask an \
+            administrator for more space.",
+        ),
+        ExitCodes::InvalidConfig => (
+            "The configuration file has errors, or is for a newer version of workspaces.",
+            "Ask an administrator; `workspaces config upgrade` updates older configurations.",
+        ),
+        ExitCodes::TooManyWorkspaces => (
+            "You already have as many workspaces on the filesystem as you may have.",
+            "Delete or let expire ones you no longer need, see `workspaces list`.",
+        ),
+    }
+}
+
+/// Prints what `code` means, or all codes if none is given
+pub fn explain(code: Option<i32>) -> Result<(), Box<dyn Error>> {
+    let Some(code) = code else {
+        println!("0: success");
+        for code in ExitCodes::ALL {
+            println!("{}: {:?} - {}", code as i32, code, description(code).0);
+        }
+        println!("101: the program crashed; please report this");
+        return Ok(());
+    };
+
+    match ExitCodes::ALL.into_iter().find(|c| *c as i32 == code) {
+        Some(c) => {
+            let (meaning, fixes) = description(c);
+            println!("{}: {:?}", code, c);
+            println!("{}", meaning);
+            println!("{}", fixes);
+        }
+        None if code == 0 => println!("0: success"),
+        None if code == 101 => println!("101: the program crashed; please report this"),
+        None => {
+            return Err(WorkspacesError::InvalidArgument(format!(
+                "{} is not an exit code of workspaces",
+                code
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use chrono::Utc;
+use rusqlite::Connection;
+use users::{get_current_gid, get_current_uid, get_user_by_name, switch};
+
+use crate::{config, error::WorkspacesError, may_change, workspace_volume};
+
+/// Streams a tarball of a workspace's contents to `to`, or stdout for "-"
+///
+/// With `from_snapshot`, the contents are taken from that snapshot, or from
+/// one taken just for the export if it's empty, so files changing meanwhile
+/// don't end up half-written in the tarball.
+#[allow(clippy::too_many_arguments)]
+pub fn export(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    to: &Path,
+    zstd: bool,
+    from_snapshot: Option<&str>,
+) -> Result<(), WorkspacesError> {
+    if !may_change(conn, filesystem_name, user, name) {
+        return Err(WorkspacesError::not_allowed());
+    }
+
+    let volume = match workspace_volume(conn, filesystem_name, filesystem, user, name) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                name,
+            ));
+        }
+        res => res,
+    }?;
+    let storage = filesystem.storage();
+    let mountpoint: PathBuf = storage.get_property(&volume, "mountpoint")?;
+
+    if let Some(snapshot) = from_snapshot
+        && !snapshot.is_empty()
+        && !storage
+            .list_snapshots(&volume)?
+            .iter()
+            .any(|s| s == snapshot)
+    {
+        return Err(WorkspacesError::InvalidArgument(format!(
+            "Workspace {} has no snapshot {}",
+            name, snapshot
+        )));
+    }
+
+    let stdout = if to == Path::new("-") {
+        if io::stdout().is_terminal() {
+            return Err(WorkspacesError::InvalidArgument(
+                "Refusing to write a tarball to a terminal, pipe it somewhere instead".to_owned(),
+            ));
+        }
+        Stdio::inherit()
+    } else {
+        // We run setuid root, so only create the tarball with the user's own privileges
+        let _guard = switch::switch_user_group(get_current_uid(), get_current_gid())?;
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(to)
+            .map_err(|e| format!("could not create {}: {}", to.display(), e))?
+            .into()
+    };
+
+    let temporary_snapshot = match from_snapshot {
+        Some("") => {
+            let snapshot = format!("export-{}", Utc::now().format("%Y-%m-%dT%H%M%S"));
+            storage.snapshot_named(&volume, &snapshot)?;
+            Some(snapshot)
+        }
+        _ => None,
+    };
+    let dir = match temporary_snapshot.as_deref().or(from_snapshot) {
+        Some(snapshot) => storage.snapshot_dir(&mountpoint).join(snapshot),
+        None => mountpoint,
+    };
+
+    // Like `info --du`, as the owner, so nothing is exported they couldn't read.
+    // Without the `command_timeout`, as the tarball is streamed at the pace of
+    // whatever reads it.
+    let owner =
+        get_user_by_name(user).ok_or_else(|| "workspace owner does not exist".to_owned())?;
+    let mut args = vec!["-cf", "-"];
+    if zstd {
+        args.insert(0, "--zstd");
+    }
+    args.push(".");
+    let status = storage
+        .command_as(
+            owner.uid(),
+            owner.primary_group_id(),
+            &dir.to_string_lossy(),
+            "tar",
+            &args,
+        )
+        .stdout(stdout)
+        .status();
+
+    if let Some(snapshot) = &temporary_snapshot
+        && let Err(e) = storage.destroy(&format!("{}@{}", volume, snapshot))
+    {
+        log::warn!("Failed to destroy snapshot {}@{}: {}", volume, snapshot, e);
+    }
+    let status = status?;
+    if !status.success() {
+        return Err(format!("tar failed with {}", status).into());
+    }
+    if to != Path::new("-") {
+        eprintln!("Exported workspace {} to {}", name, to.display());
+    }
+    Ok(())
+}
@@ -1,66 +1,164 @@
-use std::{error::Error, process};
-
-use chrono::{Duration, Utc};
-use rusqlite::Connection;
+use chrono::{DateTime, Duration, Local, Utc};
+use rusqlite::{Connection, TransactionBehavior};
 use users::{get_current_uid, get_current_username};
 
-use crate::{config, to_volume_string, zfs, ExitCodes};
+use crate::{
+    banner,
+    cli::{CommandFormat, GrantRight},
+    clock, config, create, digest,
+    error::WorkspacesError,
+    events::{self, EventKind},
+    grants, journal, json, metadata,
+    notify::{self, NotificationKind, Notifiers},
+    owners, workspace_volume,
+};
+
+/// How far a workspace is extended
+#[derive(Debug, Clone, Copy)]
+pub enum Extension {
+    /// Until this long from now, unless it expires later already
+    For(Duration),
+    /// This many days past its current expiry, or past now if it expired already
+    By(i64),
+}
 
+impl Extension {
+    /// The expiry of a workspace currently expiring at `expiration_time`
+    ///
+    /// None if that is beyond the dates which can be represented.
+    fn apply(self, expiration_time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Extension::For(duration) => clock::now()
+                .checked_add_signed(duration)
+                .map(|expiration| expiration.max(expiration_time)),
+            Extension::By(days) => expiration_time
+                .max(clock::now())
+                .checked_add_signed(Duration::try_days(days)?),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn extend(
     conn: &mut Connection,
     filesystem_name: &str,
     filesystem: &config::Filesystem,
     user: &str,
     name: &str,
-    duration: &Duration,
-    smtp: &Option<config::SmtpConfig>,
-) -> Result<(), Box<dyn Error>> {
-    if get_current_username().unwrap() != user && get_current_uid() != 0 {
-        eprintln!("You are not allowed to execute this operation");
-        process::exit(ExitCodes::InsufficientPrivileges as i32);
+    extension: Option<Extension>,
+    notifiers: &Notifiers,
+    notify_owner: bool,
+    format: CommandFormat,
+) -> Result<(), WorkspacesError> {
+    let extension = extension.unwrap_or(Extension::For(
+        filesystem
+            .default_duration
+            .unwrap_or(filesystem.max_duration),
+    ));
+
+    if !grants::allows(conn, filesystem_name, user, name, GrantRight::Extend) {
+        return Err(WorkspacesError::not_allowed());
     }
     if filesystem.disabled && get_current_uid() != 0 {
-        eprintln!("Filesystem is disabled. Please recreate workspace on another filesystem.");
-        process::exit(ExitCodes::FsDisabled as i32);
+        return Err(WorkspacesError::FsDisabled(format!(
+            "Filesystem is disabled. Please recreate workspace on another filesystem.{}",
+            filesystem.contact_hint()
+        )));
     }
-    if duration > &filesystem.max_duration && get_current_uid() != 0 {
-        eprintln!(
-            "Duration can be at most {} days",
-            filesystem.max_duration.num_days()
-        );
-        process::exit(ExitCodes::TooHighDuration as i32);
+    if get_current_uid() != 0
+        && !get_current_username().is_some_and(|me| filesystem.allows_user(&me.to_string_lossy()))
+    {
+        return Err(WorkspacesError::InsufficientPrivileges(format!(
+            "Filesystem {} is reserved for certain users and groups.{}",
+            filesystem_name,
+            filesystem.contact_hint()
+        )));
+    }
+    if let Extension::For(duration) = extension
+        && duration > filesystem.max_duration
+        && get_current_uid() != 0
+    {
+        return Err(WorkspacesError::TooHighDuration(format!(
+            "Duration can be at most {} days.{}",
+            filesystem.max_duration.num_days(),
+            filesystem.contact_hint()
+        )));
+    }
+    // Extending an expired workspace brings it back, so it counts against
+    // the user's limits again
+    if get_current_uid() != 0
+        && let Ok(expiration_time) = conn.query_row::<DateTime<Utc>, _, _>(
+            "SELECT expiration_time FROM workspaces \
+                WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+            (filesystem_name, user, name),
+            |row| row.get(0),
+        )
+        && expiration_time <= clock::now()
+    {
+        let bytes = match filesystem.max_total_bytes_per_user {
+            Some(_) => create::footprint(
+                filesystem,
+                &workspace_volume(conn, filesystem_name, filesystem, user, name)?,
+            )?,
+            None => 0,
+        };
+        create::check_user_limits(conn, filesystem_name, filesystem, user, bytes)?;
     }
 
-    conn.transaction()
-        .inspect(|transaction| {
+    // Take the write lock right away, so `maintain` can't delete the workspace
+    // between looking it up and extending it
+    let (entry, previous_expiration, new_expiration) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(WorkspacesError::from)
+        .and_then(|transaction| {
             // Get workspace id
-            let workspace_id: i64 = match transaction
+            let found = transaction
                 .prepare(
-                    "SELECT id FROM workspaces \
+                    "SELECT id, expiration_time FROM workspaces \
                         WHERE filesystem = ?1 \
                             AND user = ?2 \
                             AND name = ?3",
                 )
                 .unwrap()
-                .query_row((filesystem_name, user, name), |row| row.get(0))
-            {
+                .query_row((filesystem_name, user, name), |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                });
+            let (workspace_id, previous_expiration): (i64, DateTime<Utc>) = match found {
                 Err(rusqlite::Error::QueryReturnedNoRows) => {
-                    eprintln!(
-                        "Could not find a matching filesystem={}, user={}, name={}",
-                        filesystem_name, user, name
-                    );
-                    process::exit(ExitCodes::UnknownWorkspace as i32);
+                    return Err(WorkspacesError::unknown_workspace(
+                        filesystem_name,
+                        user,
+                        name,
+                    ));
                 }
-                res @ _ => res,
-            }
-            .unwrap();
+                res => res,
+            }?;
 
+            let Some(new_expiration) = extension.apply(previous_expiration) else {
+                return Err(WorkspacesError::TooHighDuration(format!(
+                    "Workspaces can expire at most {} days from now.{}",
+                    filesystem.max_duration.num_days(),
+                    filesystem.contact_hint()
+                )));
+            };
+            if matches!(extension, Extension::By(_))
+                && new_expiration > clock::now() + filesystem.max_duration
+                && get_current_uid() != 0
+            {
+                return Err(WorkspacesError::TooHighDuration(format!(
+                    "Workspaces can expire at most {} days from now, \
+                        this would be {} days.{}",
+                    filesystem.max_duration.num_days(),
+                    (new_expiration - clock::now()).num_days(),
+                    filesystem.contact_hint()
+                )));
+            }
             transaction
                 .execute(
                     "UPDATE workspaces \
-                        SET expiration_time = MAX(expiration_time, ?2) \
+                        SET expiration_time = ?2 \
                         WHERE id = ?1",
-                    (workspace_id, Utc::now() + *duration),
+                    (workspace_id, new_expiration),
                 )
                 .unwrap();
 
@@ -70,47 +168,187 @@ pub fn extend(
             transaction
                 .execute(
                     "DELETE FROM notifications \
-                        WHERE workspace_id = ?1 AND unixepoch(timestamp) > unixepoch(?2)",
-                    (workspace_id, Utc::now()),
+                        WHERE workspace_id = ?1 \
+                            AND channel IS NULL \
+                            AND unixepoch(timestamp) > unixepoch(?2)",
+                    (workspace_id, clock::now()),
                 )
                 .unwrap();
 
             if get_current_username().unwrap() == user && get_current_uid() != 0 {
                 // The user just acknowledged their workspaces status,
                 // so there's no need to notify them for the time being
-                transaction
-                    .execute(
-                        "INSERT INTO notifications(workspace_id, timestamp) VALUES (?1, ?2)",
-                        (workspace_id, Utc::now()),
-                    )
-                    .unwrap();
+                notify::record(
+                    &transaction,
+                    workspace_id,
+                    NotificationKind::Extended,
+                    &[],
+                    clock::now(),
+                )
+                .unwrap();
             }
-        })?
-        .commit()?;
-
-    zfs::set_property(
-        &to_volume_string(&filesystem.root, user, name),
-        "readonly",
-        "off",
-    )
-    .unwrap();
-
-    // Find the current (post-update) expiration_time
-    let new_expiration: chrono::DateTime<chrono::Utc> = conn
-        .prepare("SELECT expiration_time FROM workspaces WHERE filesystem=?1 AND user=?2 AND name=?3")?
-        .query_row((filesystem_name, user, name), |row| row.get(0))?;
-
-    if let Some(smtp_cfg) = smtp.as_ref() {
+
+            let volume =
+                workspace_volume(&transaction, filesystem_name, filesystem, user, name).unwrap();
+            let entry = journal::begin(
+                &transaction,
+                workspace_id,
+                filesystem_name,
+                &volume,
+                journal::Operation::Readonly(false),
+            )?;
+            transaction.commit()?;
+            Ok((entry, previous_expiration, new_expiration))
+        })?;
+
+    journal::resolve(conn, filesystem, &entry)?;
+
+    events::record(
+        conn,
+        EventKind::Extend,
+        filesystem_name,
+        user,
+        name,
+        Some(new_expiration),
+        None,
+    )?;
+
+    if let Err(e) = metadata::write(conn, filesystem_name, filesystem, user, name) {
+        log::warn!("Failed to write workspace metadata file: {}", e);
+    }
+    if let Err(e) = banner::update(
+        filesystem_name,
+        filesystem,
+        &workspace_volume(conn, filesystem_name, filesystem, user, name)?,
+        name,
+        new_expiration,
+    ) {
+        log::warn!("Failed to update expiry banner: {}", e);
+    }
+
+    // What was done, for `--format json`
+    let mut actions = vec!["extended"];
+    if format == CommandFormat::Text {
+        let date = |time: DateTime<Utc>| time.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+        match extension {
+            Extension::For(duration) if new_expiration == previous_expiration => println!(
+                "Workspace still expires on {}, already later than {} days from now",
+                date(new_expiration),
+                duration.num_days()
+            ),
+            _ => println!(
+                "Workspace expires on {}, instead of on {}",
+                date(new_expiration),
+                date(previous_expiration)
+            ),
+        }
+    }
+
+    if notifiers.any() && !notify_owner {
+        events::record(
+            conn,
+            EventKind::Notify,
+            filesystem_name,
+            user,
+            name,
+            Some(new_expiration),
+            Some("extended suppressed by --no-notify"),
+        )?;
+    }
+
+    if notifiers.any() && notify_owner {
         let host = hostname::get()?.to_string_lossy().to_string();
         let subject = format!("Workspace {} extended on {}", name, host);
         let body = format!(
             "Hello,\n\nYour workspace \"{}\" on {} was extended.\nFilesystem: {}\nNew expiry date: {}\n(days until expiry: {} days)\n",
-            name, host, filesystem_name, new_expiration, (new_expiration - chrono::Utc::now()).num_days()
+            name,
+            host,
+            filesystem_name,
+            new_expiration,
+            (new_expiration - clock::now()).num_days()
         );
-        if let Err(e) = crate::maintain::notify_event(user, smtp_cfg, subject, body) {
-            eprintln!("Failed to send 'extended' email: {}", e);
+        let workspace_id: i64 = conn.query_row(
+            "SELECT id FROM workspaces WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+            (filesystem_name, user, name),
+            |row| row.get(0),
+        )?;
+        let days = (new_expiration - clock::now()).num_days().to_string();
+        let values = [
+            ("workspace", name),
+            ("user", user),
+            ("filesystem", filesystem_name),
+            ("host", &host),
+            ("days", &days),
+        ];
+        for co_owner in owners::co_owners(conn, workspace_id)? {
+            if let Err(e) = digest::notify(
+                conn,
+                notifiers,
+                filesystem,
+                NotificationKind::Extended,
+                workspace_id,
+                &co_owner,
+                &values,
+                subject.clone(),
+                owners::co_owner_body(user, &body),
+            ) {
+                log::warn!(
+                    "Failed to send 'extended' email to co-owner {}: {}",
+                    co_owner,
+                    e
+                );
+            }
+        }
+        match digest::notify(
+            conn,
+            notifiers,
+            filesystem,
+            NotificationKind::Extended,
+            workspace_id,
+            user,
+            &values,
+            subject,
+            body,
+        ) {
+            Ok(channels) if !channels.is_empty() => {
+                notify::record(
+                    conn,
+                    workspace_id,
+                    NotificationKind::Extended,
+                    &channels,
+                    clock::now(),
+                )?;
+                actions.push("notified");
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to send 'extended' email: {}", e),
         }
     }
 
+    if format == CommandFormat::Json {
+        let (workspace_id, mountpoint): (i64, Option<String>) = conn.query_row(
+            "SELECT id, mountpoint FROM workspaces \
+                WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+            (filesystem_name, user, name),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        json::print_line(&json::object([
+            ("action", "extend".into()),
+            ("id", workspace_id.into()),
+            ("filesystem", filesystem_name.into()),
+            ("user", user.into()),
+            ("name", name.into()),
+            ("mountpoint", mountpoint.into()),
+            ("expiration_time", new_expiration.to_rfc3339().into()),
+            (
+                "previous_expiration_time",
+                previous_expiration.to_rfc3339().into(),
+            ),
+            (
+                "actions",
+                json::Value::Array(actions.into_iter().map(Into::into).collect()),
+            ),
+        ]))?;
+    }
     Ok(())
 }
@@ -1,20 +1,105 @@
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::HashMap,
+    error::Error,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+use users::{get_current_uid, get_current_username};
 
 use prettytable::{
-    color,
+    Attr, Cell, Row, Table, color,
     format::{Alignment, FormatBuilder},
-    Attr, Cell, Row, Table,
 };
 
 use crate::{
     cli::{self, FilesystemsColumns},
-    config, zfs,
+    config, json,
 };
 
 pub fn filesystems(
     filesystems: &HashMap<String, config::Filesystem>,
     output: Option<Vec<cli::FilesystemsColumns>>,
+    watch: Option<u64>,
+    format: cli::ListFormat,
 ) -> Result<(), Box<dyn Error>> {
+    if format != cli::ListFormat::Table {
+        return print_json(filesystems, format);
+    }
+
+    let Some(interval) = watch else {
+        render(filesystems, output, None)?;
+        return Ok(());
+    };
+
+    let interval = Duration::from_secs(interval.max(1));
+    let mut previous = HashMap::new();
+    loop {
+        let started = Instant::now();
+        // Clear the screen and move the cursor to the top left
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "Every {}s: workspaces filesystems    {}\n",
+            interval.as_secs(),
+            Local::now().format("%Y-%m-%d %H:%M:%S")
+        );
+        previous = render(filesystems, output.clone(), Some(&previous))?;
+        thread::sleep(interval.saturating_sub(started.elapsed()));
+    }
+}
+
+/// The filesystems the invoking user may create workspaces on, all of them for admins
+fn usable(
+    filesystems: &HashMap<String, config::Filesystem>,
+) -> impl Iterator<Item = (&String, &config::Filesystem)> {
+    let me = get_current_username().map(|me| me.to_string_lossy().into_owned());
+    filesystems.iter().filter(move |(_, filesystem)| {
+        get_current_uid() == 0 || me.as_deref().is_some_and(|me| filesystem.allows_user(me))
+    })
+}
+
+/// Prints all columns of the filesystems as JSON, with sizes in bytes and durations in days
+fn print_json(
+    filesystems: &HashMap<String, config::Filesystem>,
+    format: cli::ListFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut array = Vec::new();
+    for (name, info) in usable(filesystems) {
+        let (mut used, mut available) = (0, 0);
+        for root in &info.roots {
+            let (root_used, root_available) = info.storage().space(root)?;
+            used += root_used;
+            available += root_available;
+        }
+        let value = json::object([
+            ("name", name.as_str().into()),
+            ("used", used.into()),
+            ("free", available.into()),
+            ("total", (used + available).into()),
+            ("duration", info.max_duration.num_days().into()),
+            ("retention", info.expired_retention.num_days().into()),
+            ("disabled", info.disabled.into()),
+        ]);
+        match format {
+            cli::ListFormat::Json => array.push(value),
+            _ => json::print_line(&value)?,
+        }
+    }
+    if format == cli::ListFormat::Json {
+        json::print_line(&json::Value::Array(array))?;
+    }
+    Ok(())
+}
+
+/// Prints the table of filesystems, returning the space used on each
+///
+/// If the space previously used is given, a column with the change is added.
+fn render(
+    filesystems: &HashMap<String, config::Filesystem>,
+    output: Option<Vec<cli::FilesystemsColumns>>,
+    previous: Option<&HashMap<String, usize>>,
+) -> Result<HashMap<String, usize>, Box<dyn Error>> {
     // the default columns
     let output = output.unwrap_or(vec![
         FilesystemsColumns::Name,
@@ -32,14 +117,26 @@ pub fn filesystems(
     table.set_titles(Row::new(
         output
             .iter()
-            .map(|h| Cell::new(&h.to_string()).with_style(Attr::Bold))
+            .map(|h| h.to_string())
+            .chain(previous.map(|_| "CHANGE".to_owned()))
+            .map(|h| Cell::new(&h).with_style(Attr::Bold))
             .collect(),
     ));
 
-    for (name, info) in filesystems {
-        let used = zfs::get_property::<usize>(&info.root, "used")?;
-        let available = zfs::get_property::<usize>(&info.root, "available")?;
+    let mut current = HashMap::new();
+
+    for (name, info) in usable(filesystems) {
+        let (mut used, mut available) = (0, 0);
+        for root in &info.roots {
+            let (root_used, root_available) = info.storage().space(root)?;
+            used += root_used;
+            available += root_available;
+        }
         let total = used + available;
+        current.insert(name.clone(), used);
+        // Nothing to compare against on the first refresh
+        let change =
+            previous.map(|previous| used as i64 - *previous.get(name).unwrap_or(&used) as i64);
         table.add_row(Row::new(
             output
                 .iter()
@@ -65,6 +162,16 @@ pub fn filesystems(
                             .style_spec("r")
                     }
                 })
+                .chain(change.map(|change| {
+                    match change {
+                        0 => Cell::new(""),
+                        change => Cell::new_align(
+                            &format!("{:+.1}G", change as f64 / (1 << 30) as f64),
+                            Alignment::RIGHT,
+                        )
+                        .with_style(Attr::Bold),
+                    }
+                }))
                 .map(|c| {
                     // color if almost full
                     if used as f64 > total as f64 * 0.9 {
@@ -89,5 +196,5 @@ pub fn filesystems(
 
     table.printstd();
 
-    Ok(())
+    Ok(current)
 }
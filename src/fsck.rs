@@ -0,0 +1,305 @@
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{Connection, OptionalExtension};
+use users::{get_current_uid, get_user_by_name};
+
+use crate::{
+    cli::parse_pathsafe,
+    clock, config,
+    error::WorkspacesError,
+    events::{self, EventKind},
+    modulefile, to_volume_string,
+};
+
+/// A workspace as recorded in the database
+struct Recorded {
+    id: i64,
+    user: String,
+    name: String,
+    root: String,
+    volume: String,
+    mountpoint: Option<String>,
+    expiration_time: DateTime<Utc>,
+}
+
+/// The user and name of a workspace with `dataset`, as laid out by `create`
+///
+/// Flat datasets don't tell; neither do names which `create` would refuse,
+/// or users who don't exist.
+fn owner_and_name(dataset: &str) -> Option<(String, String)> {
+    let (user, name) = match dataset.split_once('/') {
+        Some((user, name)) => (user, name),
+        None => dataset.rsplit_once("--").map(|(name, user)| (user, name))?,
+    };
+    let name = parse_pathsafe(name).ok()?;
+    get_user_by_name(user)?;
+    Some((user.to_owned(), name))
+}
+
+/// Cross-checks the database against the datasets on the filesystems
+///
+/// Reports datasets with no workspace recorded for them, workspaces whose
+/// dataset is gone, and workspaces mounted elsewhere than recorded.  With
+/// `adopt`, the database follows the datasets: orphaned datasets become
+/// workspaces and mountpoints are recorded anew.  With `prune`, workspaces
+/// without a dataset are removed from the database.
+pub fn fsck(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    modulefiles: &Option<config::ModulefilesConfig>,
+    filesystem_name: Option<&str>,
+    adopt: bool,
+    prune: bool,
+) -> Result<(), WorkspacesError> {
+    if get_current_uid() != 0 {
+        return Err(WorkspacesError::not_allowed());
+    }
+    if let Some(filesystem_name) = filesystem_name
+        && !filesystems.contains_key(filesystem_name)
+    {
+        return Err(WorkspacesError::InvalidArgument(format!(
+            "There is no filesystem {}",
+            filesystem_name
+        )));
+    }
+    let all_roots: Vec<&str> = filesystems
+        .values()
+        .flat_map(|filesystem| filesystem.roots.iter().map(String::as_str))
+        .collect();
+
+    let mut filesystem_names: Vec<&String> = filesystems
+        .keys()
+        .filter(|name| filesystem_name.is_none_or(|only| only == name.as_str()))
+        .collect();
+    filesystem_names.sort();
+
+    let transaction = conn.transaction()?;
+    let mut unresolved = 0;
+    for filesystem_name in filesystem_names {
+        let filesystem = &filesystems[filesystem_name];
+        let storage = filesystem.storage();
+        let recorded = transaction
+            .prepare(
+                "SELECT id, user, name, root, dataset, mountpoint, expiration_time \
+                    FROM workspaces WHERE filesystem = ?1 ORDER BY user, name",
+            )?
+            .query_map((filesystem_name,), |row| {
+                let root: Option<String> = row.get(3)?;
+                let root = filesystem.root(root.as_deref()).to_owned();
+                let dataset: String = row.get(4)?;
+                Ok(Recorded {
+                    id: row.get(0)?,
+                    user: row.get(1)?,
+                    name: row.get(2)?,
+                    volume: to_volume_string(&root, &dataset),
+                    root,
+                    mountpoint: row.get(5)?,
+                    expiration_time: row.get(6)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Roots no longer configured may still hold workspaces
+        let roots: BTreeSet<&str> = filesystem
+            .roots
+            .iter()
+            .chain(recorded.iter().map(|workspace| &workspace.root))
+            .map(String::as_str)
+            .collect();
+
+        let mut listed = BTreeSet::new();
+        let mut unlisted_roots = BTreeSet::new();
+        for root in &roots {
+            match storage.list_descendants(root) {
+                Ok(volumes) => listed.extend(volumes),
+                Err(e) => {
+                    // Rather than taking all of its workspaces for gone
+                    eprintln!("Skipping {}, its datasets couldn't be listed: {}", root, e);
+                    unlisted_roots.insert(*root);
+                    unresolved += 1;
+                }
+            }
+        }
+
+        for workspace in &recorded {
+            if unlisted_roots.contains(workspace.root.as_str()) {
+                continue;
+            }
+            if !listed.contains(&workspace.volume) {
+                if !prune {
+                    println!(
+                        "{}: workspace {} of {} has no dataset {}",
+                        filesystem_name, workspace.name, workspace.user, workspace.volume
+                    );
+                    unresolved += 1;
+                    continue;
+                }
+                transaction.execute("DELETE FROM workspaces WHERE id = ?1", (workspace.id,))?;
+                events::record(
+                    &transaction,
+                    EventKind::Delete,
+                    filesystem_name,
+                    &workspace.user,
+                    &workspace.name,
+                    Some(workspace.expiration_time),
+                    Some(&format!("pruned by fsck, {} was missing", workspace.volume)),
+                )?;
+                if let Some(modulefiles) = modulefiles
+                    && let Err(e) = modulefile::remove(
+                        modulefiles,
+                        filesystem_name,
+                        &workspace.user,
+                        &workspace.name,
+                    )
+                {
+                    log::warn!("Failed to remove modulefile for {}: {}", workspace.name, e);
+                }
+                println!(
+                    "{}: removed workspace {} of {}, its dataset {} is gone",
+                    filesystem_name, workspace.name, workspace.user, workspace.volume
+                );
+                continue;
+            }
+
+            let Some(recorded_mountpoint) = &workspace.mountpoint else {
+                // `maintain` records it
+                continue;
+            };
+            let mountpoint = storage.property(&workspace.volume, "mountpoint")?;
+            if &mountpoint == recorded_mountpoint {
+                continue;
+            }
+            if adopt {
+                transaction.execute(
+                    "UPDATE workspaces SET mountpoint = ?2 WHERE id = ?1",
+                    (workspace.id, &mountpoint),
+                )?;
+                println!(
+                    "{}: recorded {} as the mountpoint of {} of {}",
+                    filesystem_name, mountpoint, workspace.name, workspace.user
+                );
+            } else {
+                println!(
+                    "{}: workspace {} of {} is mounted at {}, but {} is recorded",
+                    filesystem_name,
+                    workspace.name,
+                    workspace.user,
+                    mountpoint,
+                    recorded_mountpoint
+                );
+                unresolved += 1;
+            }
+        }
+
+        let known: Vec<&str> = recorded
+            .iter()
+            .map(|workspace| workspace.volume.as_str())
+            .collect();
+        for volume in &listed {
+            // Datasets belong to the innermost root they are below
+            let Some(root) = all_roots
+                .iter()
+                .chain(&roots)
+                .filter(|root| volume.starts_with(&format!("{}/", root)))
+                .max_by_key(|root| root.len())
+            else {
+                continue;
+            };
+            let is_parent = listed
+                .range(format!("{}/", volume)..)
+                .next()
+                .is_some_and(|next| next.starts_with(&format!("{}/", volume)));
+            if !roots.contains(root)
+                || is_parent
+                || known
+                    .iter()
+                    .any(|known| volume == known || volume.starts_with(&format!("{}/", known)))
+            {
+                continue;
+            }
+            let dataset = &volume[root.len() + 1..];
+            // The emptied parent of `<user>/<name>` datasets
+            if !dataset.contains('/') && get_user_by_name(dataset).is_some() {
+                continue;
+            }
+
+            let owner_and_name = owner_and_name(dataset);
+            if !adopt {
+                println!("{}: dataset {} has no workspace", filesystem_name, volume);
+                unresolved += 1;
+                continue;
+            }
+            let Some((user, name)) = owner_and_name else {
+                println!(
+                    "{}: dataset {} has no workspace, and its owner and name can't be told \
+                        from it",
+                    filesystem_name, volume
+                );
+                unresolved += 1;
+                continue;
+            };
+            let taken: Option<i64> = transaction
+                .query_row(
+                    "SELECT id FROM workspaces WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+                    (filesystem_name, &user, &name),
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if taken.is_some() {
+                println!(
+                    "{}: dataset {} has no workspace, but {} of {} is taken by another one",
+                    filesystem_name, volume, name, user
+                );
+                unresolved += 1;
+                continue;
+            }
+
+            let expiration_time = clock::now()
+                + filesystem
+                    .default_duration
+                    .unwrap_or(filesystem.max_duration);
+            let mountpoint = storage.property(volume, "mountpoint")?;
+            transaction.execute(
+                "INSERT INTO workspaces(filesystem, user, name, expiration_time, root, dataset, \
+                        mountpoint) \
+                    VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                (
+                    filesystem_name,
+                    &user,
+                    &name,
+                    expiration_time,
+                    root,
+                    dataset,
+                    &mountpoint,
+                ),
+            )?;
+            events::record(
+                &transaction,
+                EventKind::Create,
+                filesystem_name,
+                &user,
+                &name,
+                Some(expiration_time),
+                Some(&format!("adopted by fsck from {}", volume)),
+            )?;
+            println!(
+                "{}: adopted {} as workspace {} of {}, expiring {}",
+                filesystem_name,
+                volume,
+                name,
+                user,
+                expiration_time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+            );
+        }
+    }
+    transaction.commit()?;
+
+    if unresolved > 0 {
+        return Err(format!("{} problems remain", unresolved).into());
+    }
+    println!("The database matches the datasets");
+    Ok(())
+}
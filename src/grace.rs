@@ -0,0 +1,55 @@
+use std::{
+    error::Error,
+    path::PathBuf,
+    process::Stdio,
+    thread,
+    time::{Duration as StdDuration, Instant},
+};
+
+use chrono::Duration;
+
+use crate::config;
+
+/// Maximum time spent looking for recently modified files
+const ACTIVITY_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+
+/// Whether anything in a workspace was modified within `window`
+///
+/// Stops at the first such file.  Workspaces too large to check within
+/// [`ACTIVITY_TIMEOUT`] count as inactive.
+pub fn recently_active(
+    filesystem: &config::Filesystem,
+    volume: &str,
+    window: Duration,
+) -> Result<bool, Box<dyn Error>> {
+    let mountpoint: PathBuf = filesystem.storage().get_property(volume, "mountpoint")?;
+    let mut child = filesystem
+        .storage()
+        .command(
+            "find",
+            &[
+                &mountpoint.to_string_lossy(),
+                "-xdev",
+                "-mmin",
+                &format!("-{}", window.num_minutes()),
+                "-print",
+                "-quit",
+            ],
+        )
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let deadline = Instant::now() + ACTIVITY_TIMEOUT;
+    while child.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(false);
+        }
+        thread::sleep(StdDuration::from_millis(100));
+    }
+    let output = child.wait_with_output()?;
+    // find also fails on unreadable directories, even if it found something
+    Ok(!output.stdout.is_empty())
+}
@@ -0,0 +1,127 @@
+use clap::ValueEnum;
+use rusqlite::{Connection, OptionalExtension};
+use users::{get_current_username, get_user_by_name};
+
+use crate::{cli::GrantRight, error::WorkspacesError, may_change};
+
+/// The users granted rights on a workspace, with their rights
+pub fn grants(conn: &Connection, workspace_id: i64) -> rusqlite::Result<Vec<(String, GrantRight)>> {
+    conn.prepare("SELECT user, access FROM grants WHERE workspace_id = ?1 ORDER BY user")?
+        .query_map((workspace_id,), |row| {
+            Ok((row.get(0)?, row.get::<_, String>(1)?))
+        })?
+        .filter_map(|grant| match grant {
+            Ok((user, access)) => GrantRight::from_str(&access, true)
+                .ok()
+                .map(|right| Ok((user, right))),
+            Err(e) => Some(Err(e)),
+        })
+        .collect()
+}
+
+/// The right the current user was granted on the workspace `user` owns, if any
+pub fn granted(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> Option<GrantRight> {
+    let me = get_current_username()?;
+    conn.query_row(
+        "SELECT access FROM grants \
+            JOIN workspaces ON workspaces.id = grants.workspace_id \
+            WHERE filesystem = ?1 AND workspaces.user = ?2 AND name = ?3 \
+                AND grants.user = ?4",
+        (filesystem_name, user, name, me.to_string_lossy()),
+        |row| row.get::<_, String>(0),
+    )
+    .optional()
+    .ok()
+    .flatten()
+    .and_then(|access| GrantRight::from_str(&access, true).ok())
+}
+
+/// Whether the current user may do what `right` allows on the workspace,
+/// either through a grant or as one of its owners
+pub fn allows(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    right: GrantRight,
+) -> bool {
+    may_change(conn, filesystem_name, user, name)
+        || granted(conn, filesystem_name, user, name).is_some_and(|granted| granted >= right)
+}
+
+/// Grants `grantee` a right on a workspace, or revokes it
+///
+/// Only its owners and admins may do so.
+pub fn grant(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    grantee: &str,
+    right: GrantRight,
+    revoke: bool,
+) -> Result<(), WorkspacesError> {
+    let workspace_id: i64 = match conn.query_row(
+        "SELECT id FROM workspaces WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name),
+        |row| row.get(0),
+    ) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                name,
+            ));
+        }
+        res => res,
+    }?;
+    if !may_change(conn, filesystem_name, user, name) {
+        return Err(WorkspacesError::InsufficientPrivileges(
+            "Only the owners of a workspace may grant rights on it".to_owned(),
+        ));
+    }
+
+    if revoke {
+        let revoked = conn.execute(
+            "DELETE FROM grants WHERE workspace_id = ?1 AND user = ?2",
+            (workspace_id, grantee),
+        )?;
+        if revoked == 0 {
+            return Err(WorkspacesError::InvalidArgument(format!(
+                "{} has no rights on {}",
+                grantee, name
+            )));
+        }
+        println!("Revoked the rights of {} on {}", grantee, name);
+        return Ok(());
+    }
+
+    if get_user_by_name(grantee).is_none() {
+        return Err(WorkspacesError::InvalidArgument(format!(
+            "There is no user {}",
+            grantee
+        )));
+    }
+    if grantee == user {
+        return Err(WorkspacesError::InvalidArgument(format!(
+            "{} already owns {}",
+            grantee, name
+        )));
+    }
+    conn.execute(
+        "INSERT INTO grants(workspace_id, user, access) VALUES (?1, ?2, ?3) \
+            ON CONFLICT(workspace_id, user) DO UPDATE SET access = excluded.access",
+        (workspace_id, grantee, right.to_string()),
+    )?;
+    let verb = match right {
+        GrantRight::Status => "inspect",
+        GrantRight::Extend => "extend",
+    };
+    println!("{} may now {} {}", grantee, verb, name);
+    Ok(())
+}
@@ -0,0 +1,304 @@
+use std::{
+    error::Error,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::mpsc,
+    thread,
+    time::{Duration as StdDuration, Instant},
+};
+
+use chrono::{DateTime, Local, Utc};
+use prettytable::{
+    Attr, Cell, Row, Table,
+    format::{Alignment, FormatBuilder},
+};
+use rusqlite::Connection;
+use users::{get_current_uid, get_user_by_name};
+
+use crate::{
+    cache::PropertyCache, config, error::WorkspacesError, grants, may_change, note, owners,
+    schedule, to_volume_string,
+};
+
+/// Maximum time spent determining the disk usage breakdown
+///
+/// Used instead of the filesystem's `command_timeout`, which is meant for
+/// `maintain` rather than someone waiting for `info` to return.
+const DU_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+
+/// Shows detailed information about a single workspace
+pub fn info(
+    conn: &Connection,
+    cache: &PropertyCache,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    du: bool,
+) -> Result<(), WorkspacesError> {
+    // du runs as the owner, so grants don't extend to it
+    if du && !may_change(conn, filesystem_name, user, name) {
+        return Err(WorkspacesError::InsufficientPrivileges(
+            "Only the owners and co-owners of a workspace may inspect its contents".to_owned(),
+        ));
+    }
+
+    let (id, expiration_time, root, dataset, expiry_notifications, snapshots_excluded): (
+        i64,
+        DateTime<Utc>,
+        Option<String>,
+        String,
+        Option<String>,
+        bool,
+    ) = match conn
+        .prepare(
+            "SELECT id, expiration_time, root, dataset, expiry_notifications, snapshots_excluded \
+                FROM workspaces \
+                WHERE filesystem = ?1 \
+                    AND user = ?2 \
+                    AND name = ?3",
+        )?
+        .query_row((filesystem_name, user, name), |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
+        }) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                name,
+            ));
+        }
+        res => res,
+    }?;
+
+    let (group, public, description): (Option<String>, bool, Option<String>) = conn.query_row(
+        "SELECT owner_group, public, description FROM workspaces WHERE id = ?1",
+        (id,),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    let co_owners = owners::co_owners(conn, id)?;
+    let grants = grants::grants(conn, id)?;
+
+    let volume = to_volume_string(filesystem.root(root.as_deref()), &dataset);
+    let mountpoint: PathBuf =
+        cache.get_property(filesystem_name, filesystem, &volume, "mountpoint")?;
+    let referenced: usize =
+        cache.get_property(filesystem_name, filesystem, &volume, "referenced")?;
+    let deletion_time = expiration_time + filesystem.expired_retention;
+    let snapshots = filesystem.storage().list_snapshots(&volume)?;
+    let snapdir: String = cache.get_property(filesystem_name, filesystem, &volume, "snapdir")?;
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    let mut rows: Vec<(&str, String)> = vec![
+        ("ID", id.to_string()),
+        ("NAME", name.to_owned()),
+        ("USER", user.to_owned()),
+        ("GROUP", group.unwrap_or_else(|| "-".to_owned())),
+        (
+            "CO-OWNERS",
+            match co_owners.as_slice() {
+                [] => "-".to_owned(),
+                co_owners => co_owners.join(", "),
+            },
+        ),
+        (
+            "GRANTS",
+            match grants.as_slice() {
+                [] => "-".to_owned(),
+                grants => grants
+                    .iter()
+                    .map(|(grantee, right)| format!("{} ({})", grantee, right))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            },
+        ),
+        (
+            "PUBLIC",
+            match (public, description) {
+                (false, _) => "no".to_owned(),
+                (true, None) => "yes".to_owned(),
+                (true, Some(description)) => format!("yes: {}", description),
+            },
+        ),
+        ("FS", filesystem_name.to_owned()),
+        ("MOUNTPOINT", mountpoint.to_string_lossy().into_owned()),
+        ("SIZE", format!("{}G", referenced / (1 << 30))),
+        (
+            "EXPIRY",
+            expiration_time
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string(),
+        ),
+        (
+            "DELETION",
+            deletion_time
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string(),
+        ),
+        (
+            "WARNINGS",
+            match &expiry_notifications {
+                Some(days) => format!(
+                    "{} (set for this workspace)",
+                    schedule::describe(&schedule::from_db_string(days))
+                ),
+                None => schedule::describe(&filesystem.expiry_notifications_on_days),
+            },
+        ),
+        (
+            "SNAPSHOTS",
+            match (snapshots.len(), snapdir.as_str()) {
+                (0, _) => "none".to_owned(),
+                (n, "visible") => format!(
+                    "{} (browse them in {})",
+                    n,
+                    filesystem.storage().snapshot_dir(&mountpoint).display()
+                ),
+                (n, _) => format!("{} (ask an administrator to restore files)", n),
+            } + if snapshots_excluded && filesystem.snapshot {
+                ", no further ones are taken"
+            } else {
+                ""
+            },
+        ),
+    ];
+    // Operator notes are for admins only
+    if get_current_uid() == 0 {
+        for note in note::notes(conn, id)? {
+            rows.push(("NOTE", note.describe()));
+        }
+    }
+    for (key, value) in rows {
+        table.add_row(Row::new(vec![
+            Cell::new(key).with_style(Attr::Bold),
+            Cell::new(&value),
+        ]));
+    }
+    table.printstd();
+    cache.refresh_in_background();
+
+    if du {
+        let owner =
+            get_user_by_name(user).ok_or_else(|| "workspace owner does not exist".to_owned())?;
+        let DiskUsage {
+            mut entries,
+            complete,
+        } = first_level_usage(
+            filesystem,
+            &mountpoint,
+            owner.uid(),
+            owner.primary_group_id(),
+        )?;
+        entries.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+        println!();
+        let mut table = Table::new();
+        table.set_format(FormatBuilder::new().padding(0, 2).build());
+        table.set_titles(Row::new(vec![
+            Cell::new("SIZE").with_style(Attr::Bold),
+            Cell::new("PATH").with_style(Attr::Bold),
+        ]));
+        for (size, path) in entries {
+            table.add_row(Row::new(vec![
+                Cell::new_align(&format!("{}G", size / (1 << 30)), Alignment::RIGHT),
+                Cell::new(&path),
+            ]));
+        }
+        table.printstd();
+        if !complete {
+            eprintln!(
+                "Disk usage breakdown incomplete: gave up after {} seconds",
+                DU_TIMEOUT.as_secs()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+struct DiskUsage {
+    /// Sizes in bytes with their paths relative to the mountpoint
+    entries: Vec<(u64, String)>,
+    /// Whether `du` finished within [`DU_TIMEOUT`]
+    complete: bool,
+}
+
+/// Determines the sizes of the first-level entries below `mountpoint`
+///
+/// `du` is run on the filesystem's host as the given user, so no data is
+/// revealed the owner couldn't access anyway.
+fn first_level_usage(
+    filesystem: &config::Filesystem,
+    mountpoint: &Path,
+    uid: u32,
+    gid: u32,
+) -> Result<DiskUsage, Box<dyn Error>> {
+    let mountpoint_str = mountpoint.to_string_lossy();
+    let mut child = filesystem
+        .storage()
+        .command_as(
+            uid,
+            gid,
+            &mountpoint_str,
+            "du",
+            &["-x", "-d", "1", "-B1", "--", &mountpoint_str],
+        )
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // du emits lines as it finishes directories, so collect them as they come
+    let stdout = child.stdout.take().expect("stdout should be piped");
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + DU_TIMEOUT;
+    let mut entries = Vec::new();
+    let complete = loop {
+        let now = Instant::now();
+        if now >= deadline {
+            child.kill()?;
+            child.wait()?;
+            break false;
+        }
+        match receiver.recv_timeout(deadline - now) {
+            Ok(line) => {
+                let Some((size, path)) = line.split_once('\t') else {
+                    continue;
+                };
+                let Ok(size) = size.parse() else { continue };
+                let path = Path::new(path)
+                    .strip_prefix(mountpoint)
+                    .map_or(path.to_owned(), |p| p.to_string_lossy().into_owned());
+                // Skip the total of the mountpoint itself
+                if !path.is_empty() {
+                    entries.push((size, path));
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                child.wait()?;
+                break true;
+            }
+        }
+    };
+
+    Ok(DiskUsage { entries, complete })
+}
@@ -0,0 +1,151 @@
+use std::{
+    error::Error,
+    io::{self, IsTerminal, Write},
+};
+
+use chrono::Duration;
+use lettre::message::Mailbox;
+use rusqlite::Connection;
+use users::get_current_username;
+
+use crate::{cli, config, create, email, filesystems, notify, notify::Notifiers};
+
+/// Asks `question` on the terminal, returning the trimmed answer
+fn ask(question: &str) -> io::Result<String> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_owned())
+}
+
+fn ask_yes_no(question: &str) -> io::Result<bool> {
+    Ok(matches!(
+        ask(&format!("{} [y/N] ", question))?
+            .to_lowercase()
+            .as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// Walks the current user through setting up workspaces
+///
+/// Without a terminal, only reports what's missing.
+pub fn init(
+    conn: &mut Connection,
+    config: &config::Config,
+    notifiers: &Notifiers,
+) -> Result<(), Box<dyn Error>> {
+    let user = get_current_username()
+        .ok_or("could not look up the current user")?
+        .to_string_lossy()
+        .to_string();
+    let interactive = io::stdin().is_terminal();
+
+    println!("Notifications");
+    match notify::user_recipients(&user) {
+        Ok(recipients) => {
+            println!("  Sent to {}", recipients);
+            if interactive
+                && let Some(smtp) = &config.smtp
+                && ask_yes_no("  Send a test email to check it arrives?")?
+            {
+                notify::notify_test(&user, None, smtp)?;
+            }
+        }
+        Err(e) if !interactive => {
+            println!("  Not set up ({})", e);
+            println!("  Run `workspaces email set-mine you@example.org`");
+        }
+        Err(e) => {
+            println!("  Not set up ({})", e);
+            loop {
+                let address = ask("  Email address for notifications (empty to skip): ")?;
+                if address.is_empty() {
+                    break;
+                }
+                if address.parse::<Mailbox>().is_ok() {
+                    email::set_mine(&address)?;
+                    break;
+                }
+                println!("  `{}` is not a valid email address", address);
+            }
+        }
+    }
+
+    println!();
+    println!("Filesystems");
+    filesystems::filesystems(&config.filesystems, None, None, cli::ListFormat::Table)?;
+    if let Some(default) = &config.default_filesystem {
+        println!("Without -f, workspaces are created on {}.", default);
+    }
+
+    if !interactive || !ask_yes_no("\nCreate a first workspace now?")? {
+        println!("\nCreate workspaces with `workspaces create -f FILESYSTEM -d DAYS NAME`.");
+        return Ok(());
+    }
+
+    let name = loop {
+        match cli::parse_pathsafe(&ask("Name: ")?) {
+            Ok(name) => break name,
+            Err(e) => println!("{}", e),
+        }
+    };
+    let (filesystem_name, filesystem) = loop {
+        let answer = ask(&match &config.default_filesystem {
+            Some(default) => format!("Filesystem [{}]: ", default),
+            None => "Filesystem: ".to_owned(),
+        })?;
+        let filesystem_name = match (answer.is_empty(), &config.default_filesystem) {
+            (true, Some(default)) => default.clone(),
+            _ => answer,
+        };
+        match config.filesystems.get(&filesystem_name) {
+            Some(filesystem) if !filesystem.disabled => break (filesystem_name, filesystem),
+            Some(_) => println!("{} is disabled, please pick another one", filesystem_name),
+            None => println!("There is no filesystem {}", filesystem_name),
+        }
+    };
+    let default_duration = filesystem
+        .default_duration
+        .unwrap_or(filesystem.max_duration);
+    let duration = loop {
+        let answer = ask(&format!(
+            "Days until it expires (at most {}) [{}]: ",
+            filesystem.max_duration.num_days(),
+            default_duration.num_days()
+        ))?;
+        if answer.is_empty() {
+            break default_duration;
+        }
+        match answer.parse() {
+            Ok(days) if days > 0 && Duration::days(days) <= filesystem.max_duration => {
+                break Duration::days(days);
+            }
+            _ => println!(
+                "Please give a number of days between 1 and {}",
+                filesystem.max_duration.num_days()
+            ),
+        }
+    };
+
+    create::create(
+        conn,
+        &filesystem_name,
+        filesystem,
+        &user,
+        &name,
+        &duration,
+        None,
+        None,
+        notifiers,
+        true,
+        None,
+        false,
+        None,
+        None,
+        None,
+        cli::CommandFormat::Text,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,410 @@
+use std::{collections::HashMap, error::Error, process};
+
+use chrono::Utc;
+use rusqlite::{Connection, TransactionBehavior};
+
+use crate::{config, create, to_volume_string};
+
+/// A ZFS change belonging to a database change
+///
+/// The pool can't be changed atomically with the database.  Commands thus
+/// commit their database changes together with a journal entry describing
+/// the ZFS change still to be made, make it, and then [`resolve`] the entry.
+/// Entries left behind by a crash are resolved by `workspaces maintain`.
+#[derive(Debug)]
+pub struct Entry {
+    id: i64,
+    /// Unset for receives, whose workspace is only inserted once they completed
+    workspace_id: Option<i64>,
+    filesystem: String,
+    /// Dataset the operation is about after it completed
+    volume: String,
+    operation: Operation,
+}
+
+#[derive(Debug)]
+pub enum Operation {
+    /// The dataset of a newly inserted workspace is created
+    Create { visible_snapshots: bool },
+    /// The dataset is renamed from `previous_volume`
+    Rename {
+        previous_volume: String,
+        previous_name: String,
+        readonly: bool,
+    },
+    /// The dataset's `readonly` property is changed
+    Readonly(bool),
+    /// The dataset is moved from `previous_volume` to the workspace's new owner
+    Reassign {
+        previous_volume: String,
+        previous_user: String,
+    },
+    /// An archive is received into `temporary_volume`, which is then renamed
+    /// to the dataset of a workspace inserted afterwards
+    Receive { temporary_volume: String },
+}
+
+/// How an operation was brought to a consistent state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The ZFS change was made, or already had been
+    Completed,
+    /// The ZFS change didn't happen, so the database change was undone
+    RolledBack,
+}
+
+/// Records an operation on `volume`; has to be committed along with the
+/// database changes it belongs to
+pub fn begin(
+    conn: &Connection,
+    workspace_id: i64,
+    filesystem_name: &str,
+    volume: &str,
+    operation: Operation,
+) -> rusqlite::Result<Entry> {
+    insert(conn, Some(workspace_id), filesystem_name, volume, operation)
+}
+
+/// Records receiving an archive into `temporary_volume`, to become `volume`
+///
+/// Unlike other operations, it comes before the database change, so the
+/// write lock isn't held while receiving.  Once received, the entry is
+/// [`remove`]d along with inserting the workspace; until then, resolving it
+/// destroys what was received.
+pub fn begin_receive(
+    conn: &Connection,
+    filesystem_name: &str,
+    volume: &str,
+    temporary_volume: &str,
+) -> rusqlite::Result<Entry> {
+    insert(
+        conn,
+        None,
+        filesystem_name,
+        volume,
+        Operation::Receive {
+            temporary_volume: temporary_volume.to_owned(),
+        },
+    )
+}
+
+fn insert(
+    conn: &Connection,
+    workspace_id: Option<i64>,
+    filesystem_name: &str,
+    volume: &str,
+    operation: Operation,
+) -> rusqlite::Result<Entry> {
+    let (name, previous_volume, previous_name, flag) = match &operation {
+        Operation::Create { visible_snapshots } => ("create", None, None, *visible_snapshots),
+        Operation::Rename {
+            previous_volume,
+            previous_name,
+            readonly,
+        } => (
+            "rename",
+            Some(previous_volume),
+            Some(previous_name),
+            *readonly,
+        ),
+        Operation::Readonly(readonly) => ("readonly", None, None, *readonly),
+        Operation::Reassign {
+            previous_volume,
+            previous_user,
+        } => (
+            "reassign",
+            Some(previous_volume),
+            Some(previous_user),
+            false,
+        ),
+        Operation::Receive { temporary_volume } => ("receive", Some(temporary_volume), None, false),
+    };
+    conn.execute(
+        "INSERT INTO journal(operation, workspace_id, filesystem, volume, \
+                previous_volume, previous_name, flag, started, pid) \
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        (
+            name,
+            workspace_id,
+            filesystem_name,
+            volume,
+            previous_volume,
+            previous_name,
+            flag,
+            Utc::now(),
+            process::id(),
+        ),
+    )?;
+    Ok(Entry {
+        id: conn.last_insert_rowid(),
+        workspace_id,
+        filesystem: filesystem_name.to_owned(),
+        volume: volume.to_owned(),
+        operation,
+    })
+}
+
+/// Brings an operation to a consistent state, depending on the ZFS side
+///
+/// Readonly changes are always completed, i.e. resolving them is how they
+/// are made in the first place.  Creations, renames and reassignments are
+/// completed if the dataset exists under its new name and rolled back
+/// otherwise.  Receives are always rolled back, as their entry is gone once
+/// they completed.  The entry is kept if this fails, including when whether the
+/// dataset exists can't be told, to be retried by the next
+/// `workspaces maintain`.
+pub fn resolve(
+    conn: &mut Connection,
+    filesystem: &config::Filesystem,
+    entry: &Entry,
+) -> Result<Resolution, Box<dyn Error>> {
+    let storage = filesystem.storage();
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let resolution = match &entry.operation {
+        Operation::Create { visible_snapshots } => {
+            if storage.exists(&entry.volume)? {
+                let (user, group): (String, Option<String>) = transaction.query_row(
+                    "SELECT user, owner_group FROM workspaces WHERE id = ?1",
+                    (entry.workspace_id,),
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?;
+                create::set_up(
+                    filesystem,
+                    &entry.volume,
+                    &user,
+                    group.as_deref(),
+                    *visible_snapshots,
+                )?;
+                Resolution::Completed
+            } else {
+                transaction.execute(
+                    "DELETE FROM workspaces WHERE id = ?1",
+                    (entry.workspace_id,),
+                )?;
+                Resolution::RolledBack
+            }
+        }
+        Operation::Rename {
+            previous_volume,
+            previous_name,
+            readonly,
+        } => {
+            let (volume, resolution) = if storage.exists(&entry.volume)? {
+                (&entry.volume, Resolution::Completed)
+            } else if storage.exists(previous_volume)? {
+                transaction.execute(
+                    "UPDATE workspaces SET name = ?2 WHERE id = ?1",
+                    (entry.workspace_id, previous_name),
+                )?;
+                restore_dataset(
+                    &transaction,
+                    filesystem,
+                    entry.workspace_id,
+                    previous_volume,
+                )?;
+                (previous_volume, Resolution::RolledBack)
+            } else {
+                return Err(format!(
+                    "neither {} nor {} exist, cannot resolve their rename",
+                    previous_volume, entry.volume
+                )
+                .into());
+            };
+            if *readonly {
+                storage.set_property(volume, "readonly", "on")?;
+            }
+            resolution
+        }
+        Operation::Reassign {
+            previous_volume,
+            previous_user,
+        } => {
+            if storage.exists(&entry.volume)? {
+                Resolution::Completed
+            } else if storage.exists(previous_volume)? {
+                transaction.execute(
+                    "UPDATE workspaces SET user = ?2 WHERE id = ?1",
+                    (entry.workspace_id, previous_user),
+                )?;
+                restore_dataset(
+                    &transaction,
+                    filesystem,
+                    entry.workspace_id,
+                    previous_volume,
+                )?;
+                Resolution::RolledBack
+            } else {
+                return Err(format!(
+                    "neither {} nor {} exist, cannot resolve their reassignment",
+                    previous_volume, entry.volume
+                )
+                .into());
+            }
+        }
+        Operation::Readonly(readonly) => {
+            storage.set_property(
+                &entry.volume,
+                "readonly",
+                if *readonly { "on" } else { "off" },
+            )?;
+            Resolution::Completed
+        }
+        Operation::Receive { temporary_volume } => {
+            if storage.exists(temporary_volume)? {
+                storage.destroy(temporary_volume)?;
+            } else if storage.exists(&entry.volume)?
+                && !is_workspace_volume(&transaction, filesystem, &entry.filesystem, &entry.volume)?
+            {
+                // Renamed into place, but its workspace wasn't inserted
+                storage.destroy(&entry.volume)?;
+            }
+            Resolution::RolledBack
+        }
+    };
+    transaction.execute("DELETE FROM journal WHERE id = ?1", (entry.id,))?;
+    transaction.commit()?;
+    Ok(resolution)
+}
+
+/// Removes the entry of a receive, along with inserting its workspace
+pub fn remove(conn: &Connection, entry: &Entry) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM journal WHERE id = ?1", (entry.id,))?;
+    Ok(())
+}
+
+/// Whether `volume` is the dataset of a workspace on the filesystem
+fn is_workspace_volume(
+    conn: &Connection,
+    filesystem: &config::Filesystem,
+    filesystem_name: &str,
+    volume: &str,
+) -> rusqlite::Result<bool> {
+    let datasets = conn
+        .prepare("SELECT root, dataset FROM workspaces WHERE filesystem = ?1")?
+        .query_map((filesystem_name,), |row| {
+            Ok((row.get::<_, Option<String>>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(datasets.iter().any(|(root, dataset)| {
+        to_volume_string(filesystem.root(root.as_deref()), dataset) == volume
+    }))
+}
+
+/// Whether the process with `pid` is still around
+fn is_alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+/// Rolls back the creation of a workspace whose dataset couldn't be created
+///
+/// Unlike [`resolve`], this doesn't look at the ZFS side: a dataset under
+/// the workspace's name isn't the workspace's, e.g. one created since
+/// `create` checked or left behind by someone else.
+pub fn abandon_create(conn: &mut Connection, entry: &Entry) -> Result<(), Box<dyn Error>> {
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    transaction.execute(
+        "DELETE FROM workspaces WHERE id = ?1",
+        (entry.workspace_id,),
+    )?;
+    transaction.execute("DELETE FROM journal WHERE id = ?1", (entry.id,))?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Records `previous_volume` as a workspace's dataset again, after
+/// rolling back the move away from it
+fn restore_dataset(
+    conn: &Connection,
+    filesystem: &config::Filesystem,
+    workspace_id: Option<i64>,
+    previous_volume: &str,
+) -> Result<(), Box<dyn Error>> {
+    let root: Option<String> = conn.query_row(
+        "SELECT root FROM workspaces WHERE id = ?1",
+        (workspace_id,),
+        |row| row.get(0),
+    )?;
+    let root = filesystem.root(root.as_deref());
+    let dataset = previous_volume
+        .strip_prefix(root)
+        .and_then(|dataset| dataset.strip_prefix('/'))
+        .ok_or_else(|| format!("{} is not on root {}", previous_volume, root))?;
+    conn.execute(
+        "UPDATE workspaces SET dataset = ?2 WHERE id = ?1",
+        (workspace_id, dataset),
+    )?;
+    Ok(())
+}
+
+/// Resolves all operations interrupted before they were resolved
+pub fn recover(
+    conn: &mut Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+) -> Result<(), Box<dyn Error>> {
+    let entries = conn
+        .prepare(
+            "SELECT id, workspace_id, filesystem, volume, operation, \
+                    previous_volume, previous_name, flag, pid \
+                FROM journal ORDER BY id",
+        )?
+        .query_map((), |row| {
+            let flag = row.get(7)?;
+            let entry = Entry {
+                id: row.get(0)?,
+                workspace_id: row.get(1)?,
+                filesystem: row.get(2)?,
+                volume: row.get(3)?,
+                operation: match row.get::<_, String>(4)?.as_str() {
+                    "create" => Operation::Create {
+                        visible_snapshots: flag,
+                    },
+                    "rename" => Operation::Rename {
+                        previous_volume: row.get(5)?,
+                        previous_name: row.get(6)?,
+                        readonly: flag,
+                    },
+                    "reassign" => Operation::Reassign {
+                        previous_volume: row.get(5)?,
+                        previous_user: row.get(6)?,
+                    },
+                    "receive" => Operation::Receive {
+                        temporary_volume: row.get(5)?,
+                    },
+                    _ => Operation::Readonly(flag),
+                },
+            };
+            Ok((entry, row.get::<_, Option<u32>>(8)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (entry, pid) in entries {
+        // Unlike other operations, receives take hours rather than moments
+        if let Operation::Receive { .. } = entry.operation
+            && pid.is_some_and(is_alive)
+        {
+            continue;
+        }
+        let Some(filesystem) = filesystems.get(&entry.filesystem) else {
+            log::warn!(
+                "Cannot resolve interrupted operation on {}: unknown filesystem {}",
+                entry.volume,
+                entry.filesystem
+            );
+            continue;
+        };
+        match resolve(conn, filesystem, &entry) {
+            Ok(Resolution::Completed) => {
+                println!("Completed interrupted operation on {}", entry.volume)
+            }
+            Ok(Resolution::RolledBack) => {
+                println!("Rolled back interrupted operation on {}", entry.volume)
+            }
+            Err(e) => log::warn!(
+                "Failed to resolve interrupted operation on {}: {}",
+                entry.volume,
+                e
+            ),
+        }
+    }
+    Ok(())
+}
@@ -1,148 +1,645 @@
-use std::{collections::HashMap, error::Error, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration as StdDuration, Instant},
+};
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, Local, Utc};
 use prettytable::{
-    color,
+    Attr, Cell, Row, Table, color,
     format::{Alignment, FormatBuilder},
-    Attr, Cell, Row, Table,
 };
 use rusqlite::Connection;
+use users::{get_current_uid, get_current_username};
 
-use crate::{cli, config, to_volume_string, zfs};
+use crate::{
+    cache::PropertyCache,
+    cli, clock, config,
+    error::WorkspacesError,
+    is_group_member, json,
+    note::{self, Note},
+    to_volume_string,
+};
 
 #[derive(Debug)]
 struct WorkspacesRow {
+    id: i64,
     filesystem_name: String,
     user: String,
     name: String,
     expiration_time: DateTime<Utc>,
+    root: Option<String>,
+    dataset: String,
+    /// Unknown for workspaces created before this was recorded
+    created_at: Option<DateTime<Utc>>,
+    public: bool,
+    description: Option<String>,
+}
+
+/// Which part of the (sorted) list of workspaces to show
+pub struct Pagination {
+    /// Column to sort by
+    pub sort: Option<cli::WorkspacesColumns>,
+    /// Whether to reverse the order
+    pub reverse: bool,
+    /// Number of workspaces to skip
+    pub offset: usize,
+    /// Maximum number of workspaces to show
+    pub limit: Option<usize>,
+}
+
+impl Pagination {
+    fn paginate<T>(&self, rows: Vec<T>) -> Vec<T> {
+        rows.into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn list(
     conn: &Connection,
+    cache: &PropertyCache,
     filesystems: &HashMap<String, config::Filesystem>,
     filter_users: &Option<Vec<String>>,
     filter_filesystems: &Option<Vec<String>>,
     output: &Option<Vec<cli::WorkspacesColumns>>,
+    pagination: &Pagination,
+    watch: Option<u64>,
+    format: cli::ListFormat,
+    group_by: Option<cli::ListGroupBy>,
+    public: bool,
+    private_listing: bool,
 ) -> Result<(), Box<dyn Error>> {
-    use cli::WorkspacesColumns;
-    // the default columns
-    let output = output.clone().unwrap_or(vec![
-        WorkspacesColumns::Name,
-        WorkspacesColumns::User,
-        WorkspacesColumns::Fs,
-        WorkspacesColumns::Size,
-        WorkspacesColumns::Expiry,
-        WorkspacesColumns::Mountpoint,
-    ]);
+    if get_current_uid() != 0
+        && output
+            .as_ref()
+            .is_some_and(|output| output.contains(&cli::WorkspacesColumns::Notes))
+    {
+        return Err(WorkspacesError::InsufficientPrivileges(
+            "Only administrators may see operator notes".to_owned(),
+        )
+        .into());
+    }
 
-    let mut table = Table::new();
-    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    let Some(interval) = watch else {
+        render(
+            conn,
+            cache,
+            filesystems,
+            filter_users,
+            filter_filesystems,
+            output,
+            pagination,
+            format,
+            group_by,
+            public,
+            private_listing,
+        )?;
+        cache.refresh_in_background();
+        return Ok(());
+    };
 
-    // bold title row
-    table.set_titles(Row::new(
+    let interval = StdDuration::from_secs(interval.max(1));
+    loop {
+        let started = Instant::now();
+        // Clear the screen and move the cursor to the top left
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "Every {}s: workspaces list    {}\n",
+            interval.as_secs(),
+            Local::now().format("%Y-%m-%d %H:%M:%S")
+        );
+        render(
+            conn,
+            cache,
+            filesystems,
+            filter_users,
+            filter_filesystems,
+            output,
+            pagination,
+            format,
+            group_by,
+            public,
+            private_listing,
+        )?;
+        cache.refresh_in_background();
+        thread::sleep(interval.saturating_sub(started.elapsed()));
+    }
+}
+
+/// Prints the table of workspaces
+#[allow(clippy::too_many_arguments)]
+fn render(
+    conn: &Connection,
+    cache: &PropertyCache,
+    filesystems: &HashMap<String, config::Filesystem>,
+    filter_users: &Option<Vec<String>>,
+    filter_filesystems: &Option<Vec<String>>,
+    output: &Option<Vec<cli::WorkspacesColumns>>,
+    pagination: &Pagination,
+    format: cli::ListFormat,
+    group_by: Option<cli::ListGroupBy>,
+    public: bool,
+    private_listing: bool,
+) -> Result<(), Box<dyn Error>> {
+    use cli::WorkspacesColumns;
+    let jsonl = format == cli::ListFormat::Jsonl;
+    let json = jsonl || format == cli::ListFormat::Json;
+    let admin = get_current_uid() == 0;
+    // the default columns, with operator notes for admins
+    let output = output.clone().unwrap_or_else(|| {
+        if public {
+            return vec![
+                WorkspacesColumns::Name,
+                WorkspacesColumns::User,
+                WorkspacesColumns::Fs,
+                WorkspacesColumns::Mountpoint,
+                WorkspacesColumns::Description,
+            ];
+        }
+        let mut output = vec![
+            WorkspacesColumns::Name,
+            WorkspacesColumns::User,
+            WorkspacesColumns::Fs,
+            WorkspacesColumns::Size,
+            WorkspacesColumns::Expiry,
+            WorkspacesColumns::Mountpoint,
+        ];
+        if admin {
+            output.push(WorkspacesColumns::Notes);
+        }
         output
-            .iter()
-            .map(|h| Cell::new(&h.to_string()).with_style(Attr::Bold))
-            .collect(),
-    ));
+    });
+    let notes = if output.contains(&WorkspacesColumns::Notes) || (json && admin) {
+        note::latest(conn)?
+    } else {
+        HashMap::new()
+    };
 
-    let mut statement =
-        conn.prepare("SELECT filesystem, user, name, expiration_time FROM workspaces")?;
+    let json_notes = admin.then_some(&notes);
+
+    let mut table = new_table(&output);
+    // `--format json` prints everything as a single array at the end
+    let mut array = Vec::new();
+    let mut print_json = |value: json::Value| match format {
+        cli::ListFormat::Json => {
+            array.push(value);
+            Ok(())
+        }
+        _ => json::print_line(&value),
+    };
+
+    let mut statement = conn.prepare(
+        "SELECT id, filesystem, user, name, expiration_time, root, dataset, created_at, \
+                public, description \
+            FROM workspaces",
+    )?;
     let workspace_iter = statement.query_map([], |row| {
         Ok(WorkspacesRow {
-            filesystem_name: row.get(0)?,
-            user: row.get(1)?,
-            name: row.get(2)?,
-            expiration_time: row.get(3)?,
+            id: row.get(0)?,
+            filesystem_name: row.get(1)?,
+            user: row.get(2)?,
+            name: row.get(3)?,
+            expiration_time: row.get(4)?,
+            root: row.get(5)?,
+            dataset: row.get(6)?,
+            created_at: row.get(7)?,
+            public: row.get(8)?,
+            description: row.get(9)?,
         })
     })?;
 
+    let visible = if private_listing && !admin {
+        Some(visible_workspaces(conn)?)
+    } else {
+        None
+    };
+    let mut workspaces = Vec::new();
     for workspace in workspace_iter {
         let workspace = workspace?;
-        if !filter_users
+        if visible
             .as_ref()
-            .map_or(true, |us| us.contains(&workspace.user))
+            .is_some_and(|visible| !visible.contains(&workspace.id))
+            || !filter_users
+                .as_ref()
+                .is_none_or(|us| us.contains(&workspace.user))
             || !filter_filesystems
                 .as_ref()
-                .map_or(true, |fs| fs.contains(&workspace.filesystem_name))
+                .is_none_or(|fs| fs.contains(&workspace.filesystem_name))
+            || (public && !workspace.public)
         {
             continue;
         }
+        workspaces.push(workspace);
+    }
+
+    // Sorting by ZFS properties requires querying them for all workspaces.
+    // Otherwise, we can paginate first and only query the displayed ones.
+    let sort_by_zfs_property = matches!(
+        pagination.sort,
+        Some(WorkspacesColumns::Size | WorkspacesColumns::Mountpoint)
+    );
+    if !sort_by_zfs_property {
+        if let Some(column) = &pagination.sort {
+            workspaces.sort_by(|a, b| match column {
+                WorkspacesColumns::Id => a.id.cmp(&b.id),
+                WorkspacesColumns::Name => a.name.cmp(&b.name),
+                WorkspacesColumns::User => a.user.cmp(&b.user),
+                WorkspacesColumns::Fs => a.filesystem_name.cmp(&b.filesystem_name),
+                WorkspacesColumns::Created => a.created_at.cmp(&b.created_at),
+                WorkspacesColumns::Description => a.description.cmp(&b.description),
+                _ => a.expiration_time.cmp(&b.expiration_time),
+            });
+        }
+        if pagination.reverse {
+            workspaces.reverse();
+        }
+        workspaces = pagination.paginate(workspaces);
+    }
+
+    // One query per root instead of two per workspace
+    let mut below_roots: BTreeMap<(&str, &str), Vec<String>> = BTreeMap::new();
+    for workspace in &workspaces {
+        let Some(filesystem) = filesystems.get(&workspace.filesystem_name) else {
+            continue;
+        };
+        let root = filesystem.root(workspace.root.as_deref());
+        below_roots
+            .entry((&workspace.filesystem_name, root))
+            .or_default()
+            .push(to_volume_string(root, &workspace.dataset));
+    }
+    for ((filesystem_name, root), volumes) in below_roots {
+        cache.prefetch(
+            filesystem_name,
+            &filesystems[filesystem_name],
+            root,
+            &volumes,
+            &["referenced", "mountpoint"],
+        );
+    }
+
+    let mut rows = Vec::new();
+    let mut failed = 0;
+    for workspace in workspaces {
+        let filesystem = filesystems
+            .get(&workspace.filesystem_name)
+            .expect("found workspace in database without corresponding config entry");
         let volume = to_volume_string(
-            &filesystems
-                .get(&workspace.filesystem_name)
-                .expect("found workspace in database without corresponding config entry")
-                .root,
-            &workspace.user,
-            &workspace.name,
+            filesystem.root(workspace.root.as_deref()),
+            &workspace.dataset,
+        );
+        let referenced = cache.get_property::<usize>(
+            &workspace.filesystem_name,
+            filesystem,
+            &volume,
+            "referenced",
+        );
+        let mountpoint = cache.get_property::<PathBuf>(
+            &workspace.filesystem_name,
+            filesystem,
+            &volume,
+            "mountpoint",
         );
-        let referenced = zfs::get_property::<usize>(&volume, "referenced");
-        let mountpoint = zfs::get_property::<PathBuf>(&volume, "mountpoint");
-        if mountpoint.is_err() || referenced.is_err() {
-            eprintln!("Failed to get info for {}", volume);
+        // Keep the row even if the dataset is unavailable, e.g. during a storage incident
+        if referenced.is_err() || mountpoint.is_err() {
+            failed += 1;
+        }
+        // The order is already final, so there's no need to hold back the row
+        if jsonl && !sort_by_zfs_property && group_by.is_none() {
+            print_json(json_workspace(
+                filesystem,
+                &workspace,
+                referenced.ok(),
+                mountpoint.ok().as_deref(),
+                json_notes,
+            ))?;
             continue;
         }
-        table.add_row(Row::new(
-            output
-                .iter()
-                .map(|column| match column {
-                    WorkspacesColumns::Name => Cell::new(&workspace.name),
-                    WorkspacesColumns::User => Cell::new(&workspace.user),
-                    WorkspacesColumns::Fs => Cell::new(&workspace.filesystem_name),
-                    WorkspacesColumns::Expiry => {
-                        if Utc::now()
-                            > workspace.expiration_time
-                                + filesystems[&workspace.filesystem_name].expired_retention
-                        {
-                            Cell::new("deleted soon")
-                                .with_style(Attr::Bold)
-                                .with_style(Attr::ForegroundColor(color::RED))
-                        } else if Utc::now() > workspace.expiration_time {
-                            Cell::new_align(
-                                &format!(
-                                    "deleted in {:>2}d",
-                                    (workspace.expiration_time
-                                        + filesystems[&workspace.filesystem_name]
-                                            .expired_retention
-                                        - Utc::now())
-                                    .num_days()
-                                ),
-                                Alignment::RIGHT,
-                            )
+        rows.push((workspace, referenced.ok(), mountpoint.ok()));
+    }
+
+    if sort_by_zfs_property {
+        match pagination.sort {
+            Some(WorkspacesColumns::Size) => rows.sort_by_key(|(_, referenced, _)| *referenced),
+            _ => rows.sort_by(|(_, _, a), (_, _, b)| a.cmp(b)),
+        }
+        if pagination.reverse {
+            rows.reverse();
+        }
+        rows = pagination.paginate(rows);
+    }
+
+    match group_by {
+        // Groups keep the order of their workspaces, and are sorted by their name
+        Some(group_by) => {
+            let mut groups: BTreeMap<String, Vec<_>> = BTreeMap::new();
+            let mut first = true;
+            for row in rows {
+                let key = match group_by {
+                    cli::ListGroupBy::User => &row.0.user,
+                    cli::ListGroupBy::Filesystem => &row.0.filesystem_name,
+                };
+                groups.entry(key.clone()).or_default().push(row);
+            }
+            for (key, rows) in groups {
+                let size: usize = rows
+                    .iter()
+                    .filter_map(|(_, referenced, _)| *referenced)
+                    .sum();
+                if json {
+                    print_json(json::object([
+                        (group_by.to_string(), key.as_str().into()),
+                        ("count".to_owned(), rows.len().into()),
+                        ("size".to_owned(), size.into()),
+                        (
+                            "workspaces".to_owned(),
+                            json::Value::Array(
+                                rows.iter()
+                                    .map(|(workspace, referenced, mountpoint)| {
+                                        json_workspace(
+                                            &filesystems[&workspace.filesystem_name],
+                                            workspace,
+                                            *referenced,
+                                            mountpoint.as_deref(),
+                                            json_notes,
+                                        )
+                                    })
+                                    .collect(),
+                            ),
+                        ),
+                    ]))?;
+                    continue;
+                }
+                if !first {
+                    println!();
+                }
+                first = false;
+                println!(
+                    "{} {}: {} workspace(s), {}G",
+                    group_by,
+                    key,
+                    rows.len(),
+                    size / (1 << 30)
+                );
+                let mut table = new_table(&output);
+                for (workspace, referenced, mountpoint) in rows {
+                    table.add_row(table_row(
+                        &output,
+                        filesystems,
+                        &notes,
+                        &workspace,
+                        referenced,
+                        mountpoint.as_deref(),
+                    ));
+                }
+                table.printstd();
+            }
+        }
+        None => {
+            for (workspace, referenced, mountpoint) in rows {
+                if json {
+                    print_json(json_workspace(
+                        &filesystems[&workspace.filesystem_name],
+                        &workspace,
+                        referenced,
+                        mountpoint.as_deref(),
+                        json_notes,
+                    ))?;
+                    continue;
+                }
+                table.add_row(table_row(
+                    &output,
+                    filesystems,
+                    &notes,
+                    &workspace,
+                    referenced,
+                    mountpoint.as_deref(),
+                ));
+            }
+        }
+    }
+
+    if json {
+        if format == cli::ListFormat::Json {
+            json::print_line(&json::Value::Array(array))?;
+        }
+        if failed > 0 {
+            eprintln!(
+                "Warning: could not query ZFS for {} workspace(s); \
+                their size and mountpoint are null",
+                failed
+            );
+        }
+        return Ok(());
+    }
+
+    if group_by.is_none() {
+        table.printstd();
+    }
+    if failed > 0 {
+        eprintln!(
+            "Warning: could not query ZFS for {} workspace(s); \
+            their size and mountpoint are shown as `?`",
+            failed
+        );
+    }
+    Ok(())
+}
+
+/// Ids of the workspaces the current user may see with a private listing
+///
+/// These are their own, the ones they co-own, were granted rights on or share
+/// a group with, and published ones.
+pub fn visible_workspaces(conn: &Connection) -> Result<HashSet<i64>, Box<dyn Error>> {
+    let me = get_current_username()
+        .ok_or("could not look up the current user")?
+        .to_string_lossy()
+        .into_owned();
+    let mut visible = conn
+        .prepare(
+            "SELECT id FROM workspaces WHERE user = ?1 OR public \
+            UNION SELECT workspace_id FROM co_owners WHERE user = ?1 \
+            UNION SELECT workspace_id FROM grants WHERE user = ?1",
+        )?
+        .query_map((&me,), |row| row.get(0))?
+        .collect::<Result<HashSet<i64>, _>>()?;
+    let mut statement =
+        conn.prepare("SELECT id, owner_group FROM workspaces WHERE owner_group IS NOT NULL")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        if is_group_member(&row.get::<_, String>(1)?) {
+            visible.insert(row.get(0)?);
+        }
+    }
+    Ok(visible)
+}
+
+/// An empty table of workspaces with the given columns
+fn new_table(output: &[cli::WorkspacesColumns]) -> Table {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+
+    // bold title row
+    table.set_titles(Row::new(
+        output
+            .iter()
+            .map(|h| Cell::new(&h.to_string()).with_style(Attr::Bold))
+            .collect(),
+    ));
+    table
+}
+
+/// Builds the table row of a workspace
+fn table_row(
+    output: &[cli::WorkspacesColumns],
+    filesystems: &HashMap<String, config::Filesystem>,
+    notes: &HashMap<i64, (usize, Note)>,
+    workspace: &WorkspacesRow,
+    referenced: Option<usize>,
+    mountpoint: Option<&Path>,
+) -> Row {
+    use cli::WorkspacesColumns;
+    Row::new(
+        output
+            .iter()
+            .map(|column| match column {
+                WorkspacesColumns::Id => {
+                    Cell::new_align(&workspace.id.to_string(), Alignment::RIGHT)
+                }
+                WorkspacesColumns::Name => Cell::new(&workspace.name),
+                WorkspacesColumns::User => Cell::new(&workspace.user),
+                WorkspacesColumns::Fs => Cell::new(&workspace.filesystem_name),
+                WorkspacesColumns::Expiry => {
+                    if clock::now()
+                        > workspace.expiration_time
+                            + filesystems[&workspace.filesystem_name].expired_retention
+                    {
+                        Cell::new("deleted soon")
                             .with_style(Attr::Bold)
                             .with_style(Attr::ForegroundColor(color::RED))
-                        } else if workspace.expiration_time - Utc::now() < Duration::days(30) {
-                            Cell::new_align(
-                                &format!(
-                                    "expires in {:>2}d",
-                                    (workspace.expiration_time - Utc::now()).num_days()
-                                ),
-                                Alignment::RIGHT,
-                            )
-                            .with_style(Attr::ForegroundColor(color::YELLOW))
-                        } else {
-                            Cell::new_align(
-                                &format!(
-                                    "expires in {:>2}d",
-                                    (workspace.expiration_time - Utc::now()).num_days()
-                                ),
-                                Alignment::RIGHT,
-                            )
-                        }
+                    } else if clock::now() > workspace.expiration_time {
+                        Cell::new_align(
+                            &format!(
+                                "deleted in {:>2}d",
+                                (workspace.expiration_time
+                                    + filesystems[&workspace.filesystem_name].expired_retention
+                                    - clock::now())
+                                .num_days()
+                            ),
+                            Alignment::RIGHT,
+                        )
+                        .with_style(Attr::Bold)
+                        .with_style(Attr::ForegroundColor(color::RED))
+                    } else if workspace.expiration_time - clock::now() < Duration::days(30) {
+                        Cell::new_align(
+                            &format!(
+                                "expires in {:>2}d",
+                                (workspace.expiration_time - clock::now()).num_days()
+                            ),
+                            Alignment::RIGHT,
+                        )
+                        .with_style(Attr::ForegroundColor(color::YELLOW))
+                    } else {
+                        Cell::new_align(
+                            &format!(
+                                "expires in {:>2}d",
+                                (workspace.expiration_time - clock::now()).num_days()
+                            ),
+                            Alignment::RIGHT,
+                        )
                     }
-                    WorkspacesColumns::Size => Cell::new_align(
-                        &format!("{}G", referenced.as_ref().unwrap() / (1 << 30)),
-                        Alignment::RIGHT,
+                }
+                WorkspacesColumns::Created => match workspace.created_at {
+                    Some(created_at) => Cell::new(
+                        &created_at
+                            .with_timezone(&Local)
+                            .format("%Y-%m-%d")
+                            .to_string(),
                     ),
-                    WorkspacesColumns::Mountpoint => {
-                        Cell::new(mountpoint.as_ref().unwrap().to_str().unwrap())
+                    None => Cell::new("?"),
+                },
+                WorkspacesColumns::Size => match referenced {
+                    Some(referenced) => {
+                        Cell::new_align(&format!("{}G", referenced / (1 << 30)), Alignment::RIGHT)
+                    }
+                    None => Cell::new_align("?", Alignment::RIGHT),
+                },
+                WorkspacesColumns::Mountpoint => match &mountpoint {
+                    Some(mountpoint) => Cell::new(mountpoint.to_str().unwrap()),
+                    None => Cell::new("?"),
+                },
+                WorkspacesColumns::Description => {
+                    Cell::new(workspace.description.as_deref().unwrap_or(""))
+                }
+                WorkspacesColumns::Notes => match notes.get(&workspace.id) {
+                    Some((1, note)) => Cell::new(&note.text),
+                    Some((count, note)) => {
+                        Cell::new(&format!("{} (+{} more)", note.text, count - 1))
                     }
-                })
-                .collect(),
+                    None => Cell::new(""),
+                },
+            })
+            .collect(),
+    )
+}
+
+/// A workspace as a JSON object
+///
+/// Given the operator notes, i.e. for admins, their number and the latest one are added.
+fn json_workspace(
+    filesystem: &config::Filesystem,
+    workspace: &WorkspacesRow,
+    referenced: Option<usize>,
+    mountpoint: Option<&Path>,
+    notes: Option<&HashMap<i64, (usize, Note)>>,
+) -> json::Value {
+    let mut value = json::object([
+        ("id", workspace.id.into()),
+        ("name", workspace.name.as_str().into()),
+        ("user", workspace.user.as_str().into()),
+        ("filesystem", workspace.filesystem_name.as_str().into()),
+        ("size", referenced.into()),
+        (
+            "expiration_time",
+            workspace.expiration_time.to_rfc3339().into(),
+        ),
+        (
+            "deletion_time",
+            (workspace.expiration_time + filesystem.expired_retention)
+                .to_rfc3339()
+                .into(),
+        ),
+        (
+            "created_at",
+            workspace
+                .created_at
+                .map(|created_at| created_at.to_rfc3339())
+                .into(),
+        ),
+        (
+            "mountpoint",
+            mountpoint
+                .map(|mountpoint| mountpoint.to_string_lossy().into_owned())
+                .into(),
+        ),
+        ("public", workspace.public.into()),
+        ("description", workspace.description.as_deref().into()),
+    ]);
+    if let (json::Value::Object(entries), Some(notes)) = (&mut value, notes) {
+        let latest = notes.get(&workspace.id);
+        entries.push((
+            "notes".to_owned(),
+            latest.map_or(0, |(count, _)| *count).into(),
+        ));
+        entries.push((
+            "latest_note".to_owned(),
+            latest.map(|(_, note)| note.text.as_str()).into(),
         ));
     }
-
-    table.printstd();
-    Ok(())
+    value
 }
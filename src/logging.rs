@@ -0,0 +1,71 @@
+use std::{
+    ffi::CString,
+    io::{self, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use chrono::Local;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Logs to stderr, and to syslog once [`configure`]d to
+///
+/// Messages for users acting on them right away, e.g. why a command was
+/// refused, are printed directly instead.
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+/// Whether messages go to syslog, which journald picks up as well
+static SYSLOG: AtomicBool = AtomicBool::new(false);
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let syslog = SYSLOG.load(Ordering::Relaxed);
+        // Services' stderr ends up in the journal as well, so only repeat
+        // messages there for admins watching
+        if !syslog {
+            eprintln!("{}", record.args());
+        } else if io::stderr().is_terminal() {
+            eprintln!(
+                "{} {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                record.args()
+            );
+        }
+        if syslog && let Ok(message) = CString::new(record.args().to_string()) {
+            let priority = match record.level() {
+                Level::Error => libc::LOG_ERR,
+                Level::Warn => libc::LOG_WARNING,
+                Level::Info => libc::LOG_INFO,
+                Level::Debug | Level::Trace => libc::LOG_DEBUG,
+            };
+            unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logs informational messages and worse to stderr, until [`configure`]d
+/// otherwise
+pub fn init() {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(LevelFilter::Info);
+}
+
+/// Logs messages of at least `level` from now on, and with `syslog` to the
+/// system log instead of stderr
+pub fn configure(level: LevelFilter, syslog: bool) {
+    log::set_max_level(level);
+    if syslog {
+        unsafe { libc::openlog(c"workspaces".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+        SYSLOG.store(true, Ordering::Relaxed);
+    }
+}
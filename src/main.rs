@@ -1,58 +1,287 @@
+use approve::approve;
 use chrono::Utc;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use create::create;
 use db_schema::{NEWEST_DB_VERSION, UPDATE_DB};
+use du::du;
+use env::env;
+use error::WorkspacesError;
+use exclude_snapshots::exclude_snapshots;
 use expire::expire;
-use extend::extend;
+use export::export;
+use extend::{Extension, extend};
 use filesystems::filesystems;
+use info::info;
 use list::list;
 use maintain::maintain;
+use note::note;
+use offboard::offboard;
+use pending_deletions::pending_deletions;
+use profiles::profiles;
 use rename::rename;
-use rusqlite::{backup, Connection};
+use restore::restore;
+use rusqlite::{Connection, OpenFlags};
 use std::{
-    collections::HashMap, error::Error, fs, os::unix::fs::MetadataExt, path::Path, process,
+    collections::HashMap,
+    error::Error,
+    fs,
+    io::{self, IsTerminal},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process,
+    sync::OnceLock,
     time::Duration,
 };
 use users::{get_current_uid, get_current_username};
 
+mod admin_report;
+mod approve;
+mod archive;
+mod backup;
+mod banner;
+mod btrfs;
+mod cache;
+mod calendar;
+mod clean;
 mod cli;
+mod clock;
+mod completions;
 mod config;
+mod config_upgrade;
 mod create;
+mod daemon;
 mod db_schema;
+mod departed;
+mod digest;
+mod du;
+mod email;
+mod env;
+mod error;
+mod events;
+mod exclude_snapshots;
 mod expire;
+mod explain;
+mod export;
 mod extend;
 mod filesystems;
+mod fsck;
+mod grace;
+mod grants;
+mod info;
+mod init;
+mod journal;
+mod json;
 mod list;
+mod logging;
 mod maintain;
+mod metadata;
+mod modulefile;
+mod note;
+mod notify;
+mod notify_schedule;
+mod offboard;
+mod owners;
+mod ownership;
+mod pending_deletions;
+mod prefs;
+mod profiles;
+mod publish;
 mod rename;
+mod restore;
+mod schedule;
+mod simulated;
+mod snapshot;
+mod storage;
+mod tarball;
+mod telemetry;
+mod template;
+mod transfer;
+mod version;
 mod zfs;
 
+/// Exit codes of the CLI
+///
+/// Wrapper scripts depend on these, so never change or reuse a value; only
+/// append new ones.  `workspaces explain <CODE>` describes them to users.
+/// Panics exit with 101, successful runs with 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ExitCodes {
     /// The user tried executing an action they have no rights to do,
     /// i.e. expiring another user's workspace
     InsufficientPrivileges = 1,
     /// The user tried creating / extending a workspace on a disabled filesystem
-    FsDisabled,
+    FsDisabled = 2,
     /// The user tried creating / extending a workspace with too long a duration
-    TooHighDuration,
+    TooHighDuration = 3,
     /// The workspace specified by a user does not exist
-    UnknownWorkspace,
+    UnknownWorkspace = 4,
     /// The user tried to create a workspace that already exists
-    WorkspaceExists,
+    WorkspaceExists = 5,
     /// No filesystem given and no default specified in configuration file
-    NoFilesystemSpecified,
+    NoFilesystemSpecified = 6,
+    /// The user did not confirm a destructive operation
+    NotConfirmed = 7,
+    /// The user tried to rename an expired / read-only workspace without `--force`,
+    /// or to restore one already due for deletion
+    WorkspaceExpired = 8,
+    /// The filesystem given by the user is not configured
+    UnknownFilesystem = 9,
+    /// There is no creation request, archive or backup with the given id
+    UnknownId = 10,
+    /// The operation requires configuration the site doesn't have, e.g. SMTP
+    NotConfigured = 11,
+    /// Invalid command line arguments or other input, e.g. an email address
+    InvalidArgument = 12,
+    /// Any other error, e.g. a failed ZFS command or database access
+    Failure = 13,
+    /// The command could change state, but `--read-only` was given
+    ReadOnly = 14,
+    /// The user tried creating a workspace with too large a quota, or one
+    /// exceeding the space they may use in total
+    TooHighQuota = 15,
+    /// The configuration file can't be parsed, or is for a newer version
+    InvalidConfig = 16,
+    /// The user already has as many workspaces as they may have on the filesystem
+    TooManyWorkspaces = 17,
+}
+
+impl ExitCodes {
+    /// All exit codes, in order
+    const ALL: [ExitCodes; 17] = [
+        ExitCodes::InsufficientPrivileges,
+        ExitCodes::FsDisabled,
+        ExitCodes::TooHighDuration,
+        ExitCodes::UnknownWorkspace,
+        ExitCodes::WorkspaceExists,
+        ExitCodes::NoFilesystemSpecified,
+        ExitCodes::NotConfirmed,
+        ExitCodes::WorkspaceExpired,
+        ExitCodes::UnknownFilesystem,
+        ExitCodes::UnknownId,
+        ExitCodes::NotConfigured,
+        ExitCodes::InvalidArgument,
+        ExitCodes::Failure,
+        ExitCodes::ReadOnly,
+        ExitCodes::TooHighQuota,
+        ExitCodes::InvalidConfig,
+        ExitCodes::TooManyWorkspaces,
+    ];
+}
+
+/// The ZFS volume of a workspace, given its root and its dataset below it
+fn to_volume_string(root: &str, dataset: &str) -> String {
+    format!("{}/{}", root, dataset)
+}
+
+/// The dataset a workspace moves to when it's renamed or reassigned
+///
+/// Datasets named after the workspace's owner and name follow them in the
+/// layout they were created with.  Others, e.g. flat ones, stay where they are.
+fn moved_dataset(
+    dataset: &str,
+    user: &str,
+    name: &str,
+    new_user: &str,
+    new_name: &str,
+) -> Result<String, WorkspacesError> {
+    Ok(if dataset == format!("{}/{}", user, name) {
+        format!("{}/{}", new_user, new_name)
+    } else if dataset == format!("{}--{}", name, user) {
+        config::name_user_dataset(new_user, new_name)?
+    } else {
+        dataset.to_owned()
+    })
+}
+
+/// Looks up the ZFS volume of an existing workspace
+fn workspace_volume(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+) -> rusqlite::Result<String> {
+    let (root, dataset): (Option<String>, String) = conn
+        .prepare(
+            "SELECT root, dataset FROM workspaces \
+                WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        )?
+        .query_row((filesystem_name, user, name), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+    Ok(to_volume_string(filesystem.root(root.as_deref()), &dataset))
+}
+
+/// Whether the current user is a member of `group`, including as their primary group
+fn is_group_member(group: &str) -> bool {
+    let Some(me) = get_current_username() else {
+        return false;
+    };
+    users::get_user_groups(&me, users::get_current_gid())
+        .is_some_and(|groups| groups.iter().any(|g| g.name() == group))
 }
 
-fn to_volume_string(root: &str, user: &str, name: &str) -> String {
-    format!("{}/{}/{}", root, user, name)
+/// Whether the current user may change the workspace `user` owns: as its
+/// owner or a co-owner, as an admin, or as a member of the group owning it
+fn may_change(conn: &Connection, filesystem_name: &str, user: &str, name: &str) -> bool {
+    let Some(me) = get_current_username() else {
+        return get_current_uid() == 0;
+    };
+    if get_current_uid() == 0
+        || me == user
+        || owners::is_co_owner(conn, filesystem_name, user, name, &me.to_string_lossy())
+    {
+        return true;
+    }
+    conn.query_row(
+        "SELECT owner_group FROM workspaces WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name),
+        |row| row.get::<_, Option<String>>(0),
+    )
+    .ok()
+    .flatten()
+    .is_some_and(|group| is_group_member(&group))
 }
 
-/// Warns if `~USERNAME/.config/workspaces.toml` is missing or lacks a valid `email`.
-fn warn_missing_email_for_user(username: &str) {
-    use std::fs;
-    use users::{get_user_by_name, os::unix::UserExt};
+/// How often users are reminded of the same thing
+const HINT_INTERVAL: chrono::Duration = chrono::Duration::days(1);
+
+/// Whether to remind `user` of `hint` now, recording that they were
+///
+/// If that can't be recorded, e.g. as the database is busy, they are reminded
+/// anyway.
+fn hint_due(db_path: &Path, user: &str, hint: &str) -> bool {
+    let record = || -> rusqlite::Result<bool> {
+        let conn = Connection::open(db_path)?;
+        // Rather than waiting for `maintain`, remind them once more
+        conn.busy_timeout(Duration::from_secs(1))?;
+        let now = Utc::now();
+        let changed = conn.execute(
+            "INSERT INTO hints(user, hint, shown_at) VALUES(?1, ?2, ?3) \
+                ON CONFLICT(user, hint) DO UPDATE SET shown_at = excluded.shown_at \
+                WHERE unixepoch(shown_at) <= unixepoch(?4)",
+            (user, hint, now, now - HINT_INTERVAL),
+        )?;
+        Ok(changed > 0)
+    };
+    record().unwrap_or(true)
+}
 
-    let Some(user) = get_user_by_name(username) else {
+/// Warns once a day if a user's preferences are missing or lack a valid `email`.
+fn warn_missing_email_for_user(db_path: &Path, username: &str) {
+    use users::get_user_by_name;
+
+    let read = prefs::read(username);
+    // Parse and validate via existing type (ensures a valid email format)
+    if read
+        .as_ref()
+        .is_ok_and(|toml_str| toml::from_str::<crate::config::UserConfig>(toml_str).is_ok())
+        || !hint_due(db_path, username, "missing-email")
+    {
+        return;
+    }
+
+    if get_user_by_name(username).is_none() {
         eprintln!(
             "\n━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━
 ⚠️  [workspaces] Could not resolve user `{}` to check email config.
@@ -60,47 +289,137 @@ fn warn_missing_email_for_user(username: &str) {
             username
         );
         return;
-    };
+    }
 
-    let path = user.home_dir().join(".config/workspaces.toml");
-    let toml_str = match fs::read_to_string(&path) {
-        Ok(s) => s,
+    let path = prefs::location(username);
+    match read {
         Err(_) => {
             eprintln!(
                 "\n━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━
 ⚠️  [workspaces] No email configured for notifications
-    Missing file: {path}
+    Nothing found in: {path}
 
-💡  To fix this, run:
-    mkdir -p {home}/.config && echo 'email = \"you@example.org\"' > {path}
+{fix}
 ━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━\n",
-                path = path.display(),
-                home = user.home_dir().to_string_lossy()
+                fix = email_fix(username),
             );
-            return;
         }
-    };
-
-    // Parse and validate via existing type (ensures a valid email format)
-    match toml::from_str::<crate::config::UserConfig>(&toml_str) {
-        Ok(_) => {} // all good
-        Err(_) => {
+        Ok(_) => {
             eprintln!(
                 "\n━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━
 ⚠️  [workspaces] Invalid or missing `email` in config:
     {path}
 
-💡  To fix this, run:
-    echo 'email = \"you@example.org\"' > {path}
+{fix}
 ━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━\n",
-                path = path.display()
+                fix = email_fix(username),
             );
         }
     }
 }
 
+/// How to fix a missing email: `workspaces init` guides users through it,
+/// others' addresses have to be set by themselves
+fn email_fix(username: &str) -> String {
+    match get_current_username() {
+        Some(current) if current == username => {
+            "💡  To set up notifications, run:\n    workspaces init".to_owned()
+        }
+        _ => format!(
+            "💡  To fix this, run as {}:\n    workspaces email set-mine you@example.org",
+            username
+        ),
+    }
+}
+
+fn main() {
+    logging::init();
+    if let Err(e) = run() {
+        // Refusals are for the user to act on, not failures to log
+        if let Some(refusal) = e
+            .downcast_ref::<WorkspacesError>()
+            .filter(|e| !matches!(e, WorkspacesError::Failure(_)))
+        {
+            eprintln!("{}", refusal);
+            process::exit(refusal.exit_code() as i32);
+        }
+        log::error!("Error: {}", e);
+        process::exit(ExitCodes::Failure as i32);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = match cli::Args::try_parse() {
+        Ok(args) => args,
+        // Usage errors, as opposed to --help and the like
+        Err(e) if e.use_stderr() => {
+            e.print()?;
+            process::exit(ExitCodes::InvalidArgument as i32);
+        }
+        Err(e) => e.exit(),
+    };
+    let command = match (args.command, args.version) {
+        (Some(command), false) => command,
+        (None, true) => {
+            version::print_version(args.json);
+            return Ok(());
+        }
+        (command, _) => {
+            cli::Args::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    if command.is_some() {
+                        "--version cannot be used with a subcommand"
+                    } else {
+                        "a subcommand is required"
+                    },
+                )
+                .print()?;
+            process::exit(ExitCodes::InvalidArgument as i32);
+        }
+    };
+    if let Some(now) = args.now {
+        if get_current_uid() != 0 {
+            eprintln!("Only admins may use --now");
+            process::exit(ExitCodes::InsufficientPrivileges as i32);
+        }
+        // Pretending another time must never destroy or change anything for real
+        let previews = matches!(
+            command,
+            cli::Command::Maintain { dry_run: true, .. }
+                | cli::Command::Maintain { simulate: true, .. }
+        );
+        if !previews && !command.is_inspection() && !args.read_only {
+            eprintln!(
+                "--now only works with `maintain --simulate`, `maintain --dry-run`, \
+                    --read-only and commands which only show things"
+            );
+            process::exit(ExitCodes::InvalidArgument as i32);
+        }
+        clock::set(now);
+    }
+    // Has to work even without a (readable) configuration
+    if let cli::Command::Explain { code } = command {
+        return explain::explain(code);
+    }
+    if let cli::Command::Completions {
+        shell: Some(shell), ..
+    } = command
+    {
+        return completions::completions(shell);
+    }
+    // Has to work with configurations which no longer parse
+    if let cli::Command::Config {
+        command: cli::ConfigCommand::Upgrade { dry_run },
+    } = command
+    {
+        if get_current_uid() != 0 {
+            eprintln!("You are not allowed to execute this operation");
+            process::exit(ExitCodes::InsufficientPrivileges as i32);
+        }
+        return config_upgrade::upgrade_file(Path::new(config::CONFIG_PATH), dry_run);
+    }
 
-fn main() -> Result<(), Box<dyn Error>> {
     // Read config
     let config_file =
         fs::File::open(config::CONFIG_PATH).expect("could not find configuration file");
@@ -109,155 +428,972 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
     let toml_str =
         fs::read_to_string(config::CONFIG_PATH).expect("could not find configuration file");
-    let config: config::Config =
-        toml::from_str(&toml_str).expect("error parsing configuration file");
+    let mut config = match config_upgrade::load(&toml_str) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error in {}: {}", config::CONFIG_PATH, e);
+            process::exit(ExitCodes::InvalidConfig as i32);
+        }
+    };
+    config.inherit_notification_templates();
+    logging::configure(
+        config.log_level.filter(),
+        matches!(
+            command,
+            cli::Command::Maintain { .. } | cli::Command::Daemon
+        ),
+    );
 
-    let args = cli::Args::parse();
+    if let Some(telemetry) = &config.telemetry {
+        telemetry::install_panic_hook(telemetry);
+    }
 
-    // Warn for the invoking user on every CLI run
-    if let Some(me) = get_current_username() {
-        warn_missing_email_for_user(&me.to_string_lossy());
+    if args.read_only && !command.is_inspection() {
+        eprintln!("This command could change state, which --read-only forbids");
+        process::exit(ExitCodes::ReadOnly as i32);
     }
 
-    let mut conn = Connection::open(&config.db_path)?;
-    conn.pragma_update(None, "foreign_keys", true)?;
+    // Simulations run against a copy of the database, on no storage at all
+    let simulation = match &command {
+        cli::Command::Maintain {
+            simulate: true, db, ..
+        } => {
+            if get_current_uid() != 0 {
+                eprintln!("You are not allowed to execute this operation");
+                process::exit(ExitCodes::InsufficientPrivileges as i32);
+            }
+            let copy = maintain::simulation_copy(db.as_deref().unwrap_or(&config.db_path))?;
+            config.db_path = copy.clone();
+            for filesystem in config.filesystems.values_mut() {
+                filesystem.backend = config::Backend::Simulated;
+                filesystem.ssh = None;
+                filesystem.ownership_drift = config::OwnershipDrift::Ignore;
+            }
+            Some(copy)
+        }
+        _ => None,
+    };
 
-    update_database_schema_if_necessary(&mut conn)?;
+    // Overrides the filesystems' limits for this run
+    if let cli::Command::Maintain {
+        bwlimit: cli::BwLimitArgs {
+            bwlimit: Some(bwlimit),
+        },
+        ..
+    }
+    | cli::Command::Clean {
+        bwlimit: cli::BwLimitArgs {
+            bwlimit: Some(bwlimit),
+        },
+        ..
+    }
+    | cli::Command::RestoreArchive {
+        bwlimit: cli::BwLimitArgs {
+            bwlimit: Some(bwlimit),
+        },
+        ..
+    } = command
+    {
+        for s3 in config
+            .filesystems
+            .values_mut()
+            .filter_map(|filesystem| filesystem.s3_archive.as_mut())
+        {
+            s3.bwlimit = Some(bwlimit);
+        }
+    }
+
+    let mut conn = open_database(&config.db_path, args.read_only)?;
+    if config.user_preferences == config::UserPreferences::Database {
+        prefs::store_in_database(&config.db_path);
+    }
 
-    match args.command {
+    // Hints are for people at a terminal, not for cron jobs or pipelines
+    let hints = config.hints && !args.no_hints && io::stderr().is_terminal();
+
+    // Remind the invoking user, except while they fix it.  Recording the
+    // reminder writes to the database, which commands only looking at things
+    // don't.
+    if !args.read_only
+        && hints
+        && !command.is_inspection()
+        && !matches!(
+            command,
+            cli::Command::Init | cli::Command::Email { .. } | cli::Command::Prefs { .. }
+        )
+        && let Some(me) = get_current_username()
+    {
+        warn_missing_email_for_user(&config.db_path, &me.to_string_lossy());
+    }
+
+    let notifiers = notify::Notifiers::new(&config);
+
+    match command {
         cli::Command::Create {
             filesystem_name,
             workspace_name: name,
             duration,
             user,
+            modulefile,
+            visible_snapshots,
+            profile: profile_name,
+            quota,
+            group,
+            no_notify,
+            message,
+            format: cli::FormatArgs { format },
         } => {
             // Warn for target user
-            warn_missing_email_for_user(&user);
+            if hints {
+                warn_missing_email_for_user(&config.db_path, &user);
+            }
+
+            let profile = profile_name.as_ref().map(|profile_name| {
+                let Some(profile) = config.profiles.get(profile_name) else {
+                    eprintln!(
+                        "Unknown profile {}. See `workspaces profiles` for all of them.",
+                        profile_name
+                    );
+                    process::exit(ExitCodes::InvalidArgument as i32);
+                };
+                (profile_name.as_str(), profile)
+            });
+            let Some(duration) = duration.or(profile.and_then(|(_, profile)| profile.duration))
+            else {
+                eprintln!(
+                    "Profile {} has no duration, please give one with -d",
+                    profile_name.unwrap()
+                );
+                process::exit(ExitCodes::InvalidArgument as i32);
+            };
+
+            let modulefiles = if modulefile {
+                let Some(modulefiles) = config.modulefiles.as_ref() else {
+                    eprintln!(
+                        "Modulefiles are not configured. Please add a [modulefiles] block in {}",
+                        config::CONFIG_PATH
+                    );
+                    process::exit(ExitCodes::NotConfigured as i32);
+                };
+                Some(modulefiles)
+            } else {
+                None
+            };
 
             let filesystem_name = filesystem_or_default_or_exit(
-                &filesystem_name,
+                &filesystem_name.or(profile.and_then(|(_, profile)| profile.filesystem.clone())),
                 &config.filesystems,
                 &config.default_filesystem,
             );
             create(
                 &mut conn,
                 &filesystem_name,
-                &config
+                config
                     .filesystems
                     .get(&filesystem_name)
                     .expect("unknown filesystem"),
                 &user,
                 &name,
                 &duration,
-                &config.smtp, // pass SMTP
+                quota,
+                group.as_deref(),
+                &notifiers,
+                !no_notify,
+                modulefiles,
+                visible_snapshots,
+                profile,
+                (get_current_username().is_some_and(|me| me != *user))
+                    .then(create::acting_user)
+                    .as_deref(),
+                message.as_deref(),
+                format,
             )
+            .map_err(Into::into)
         }
         cli::Command::List {
             filter_users,
             filter_filesystems,
             output,
+            sort,
+            reverse,
+            limit,
+            offset,
+            watch,
+            format,
+            group_by,
+            public,
         } => list(
             &conn,
+            &cache::PropertyCache::new(&conn, config.property_cache_ttl),
             &config.filesystems,
             &filter_users,
             &filter_filesystems,
             &output,
+            &list::Pagination {
+                sort,
+                reverse,
+                offset,
+                limit,
+            },
+            watch,
+            format,
+            group_by,
+            public,
+            config.private_listing,
+        ),
+        cli::Command::Calendar {
+            days,
+            filter_users,
+            filter_filesystems,
+            format,
+            email,
+        } => calendar::calendar(
+            &conn,
+            &cache::PropertyCache::new(&conn, config.property_cache_ttl),
+            &config.filesystems,
+            days,
+            &filter_users,
+            &filter_filesystems,
+            format,
+            email.as_deref(),
+            config.smtp.as_ref(),
+            config.private_listing,
         ),
         cli::Command::Rename {
             src_workspace_name,
             dest_workspace_name,
             user,
             filesystem_name,
+            id,
+            force,
+            format: cli::FormatArgs { format },
         } => {
-            // Warn for target user
-            warn_missing_email_for_user(&user);
-
-            let filesystem_name = filesystem_or_default_or_exit(
+            // With `--id`, the only name given is the new one
+            let (src_workspace_name, dest_workspace_name) = match dest_workspace_name {
+                Some(dest_workspace_name) => (Some(src_workspace_name), dest_workspace_name),
+                None => (None, src_workspace_name),
+            };
+            let (filesystem_name, user, src_workspace_name) = workspace_or_exit(
+                &conn,
+                id,
                 &filesystem_name,
+                user,
+                src_workspace_name,
                 &config.filesystems,
                 &config.default_filesystem,
             );
+            // Warn for target user
+            if hints {
+                warn_missing_email_for_user(&config.db_path, &user);
+            }
             rename(
                 &mut conn,
                 &filesystem_name,
-                &config
+                config
                     .filesystems
                     .get(&filesystem_name)
                     .expect("unknown filesystem"),
                 &user,
                 &src_workspace_name,
                 &dest_workspace_name,
+                force,
+                &config.modulefiles,
+                format,
             )
+            .map_err(Into::into)
+        }
+        cli::Command::Info {
+            name,
+            user,
+            filesystem_name,
+            id,
+            du,
+        } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            info(
+                &conn,
+                &cache::PropertyCache::new(&conn, config.property_cache_ttl),
+                &filesystem_name,
+                config
+                    .filesystems
+                    .get(&filesystem_name)
+                    .expect("unknown filesystem"),
+                &user,
+                &name,
+                du,
+            )
+            .map_err(Into::into)
         }
         cli::Command::Extend {
             filesystem_name,
+            id,
             name,
             user,
             duration,
+            by,
+            no_notify,
+            format: cli::FormatArgs { format },
         } => {
-            // Warn for target user
-            warn_missing_email_for_user(&user);
-
-            let filesystem_name = filesystem_or_default_or_exit(
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
                 &filesystem_name,
+                user,
+                name,
                 &config.filesystems,
                 &config.default_filesystem,
             );
+            // Warn for target user
+            if hints {
+                warn_missing_email_for_user(&config.db_path, &user);
+            }
             extend(
                 &mut conn,
                 &filesystem_name,
-                &config
+                config
+                    .filesystems
+                    .get(&filesystem_name)
+                    .expect("unknown filesystem"),
+                &user,
+                &name,
+                by.map(Extension::By).or(duration.map(Extension::For)),
+                &notifiers,
+                !no_notify,
+                format,
+            )
+            .map_err(Into::into)
+        }
+        cli::Command::Restore {
+            filesystem_name,
+            id,
+            name,
+            user,
+        } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            restore(
+                &mut conn,
+                &filesystem_name,
+                config
                     .filesystems
                     .get(&filesystem_name)
                     .expect("unknown filesystem"),
                 &user,
                 &name,
-                &duration,
-                &config.smtp, // pass SMTP
             )
         }
         // Correct single Expire arm
         cli::Command::Expire {
             filesystem_name,
+            id,
             name,
             user,
             delete_on_next_clean,
+            yes,
+            reason,
+            format: cli::FormatArgs { format },
         } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
             // Warn for target user
-            warn_missing_email_for_user(&user);
+            if hints {
+                warn_missing_email_for_user(&config.db_path, &user);
+            }
+            expire(
+                &mut conn,
+                &filesystem_name,
+                config
+                    .filesystems
+                    .get(&filesystem_name)
+                    .expect("unknown filesystem"),
+                &user,
+                &name,
+                delete_on_next_clean,
+                yes,
+                reason.as_deref(),
+                &notifiers,
+                format,
+            )
+            .map_err(Into::into)
+        }
+        cli::Command::Env {
+            name,
+            user,
+            filesystem_name,
+            id,
+            shell,
+        } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            env(
+                &conn,
+                &filesystem_name,
+                config
+                    .filesystems
+                    .get(&filesystem_name)
+                    .expect("unknown filesystem"),
+                &user,
+                &name,
+                &shell,
+            )
+            .map_err(Into::into)
+        }
+        cli::Command::Du {
+            by_user,
+            filter_filesystems,
+        } => du(
+            &conn,
+            &cache::PropertyCache::new(&conn, config.property_cache_ttl),
+            &config.filesystems,
+            &filter_filesystems,
+            by_user,
+            config.private_listing,
+        ),
+        cli::Command::Filesystems {
+            output,
+            watch,
+            format,
+        } => filesystems(&config.filesystems, output, watch, format),
+        cli::Command::Profiles => profiles(&config.profiles),
+        cli::Command::Init => init::init(&mut conn, &config, &notifiers),
+        cli::Command::Events { since, follow } => {
+            events::events(&conn, since, follow, config.private_listing)
+        }
+        cli::Command::Explain { .. } => unreachable!("handled before loading the configuration"),
+        cli::Command::Completions { names, .. } => completions::names(
+            &conn,
+            &config.filesystems,
+            names.expect("the shell is handled before loading the configuration"),
+        ),
+        cli::Command::Config { .. } => unreachable!("handled before loading the configuration"),
+        cli::Command::Maintain {
+            quiet,
+            weekly_report,
+            dry_run,
+            simulate,
+            db: _,
+            bwlimit: _,
+        } => {
+            // Admins only
+            if get_current_uid() != 0 {
+                eprintln!("You are not allowed to execute this operation");
+                process::exit(ExitCodes::InsufficientPrivileges as i32);
+            }
+            let report = match (&config.admin_report, &config.smtp) {
+                (Some(report), Some(smtp)) => Some((report, smtp)),
+                _ if weekly_report => {
+                    eprintln!(
+                        "--weekly-report requires [admin_report] and [smtp] to be configured"
+                    );
+                    process::exit(ExitCodes::NotConfigured as i32);
+                }
+                _ => None,
+            };
+            let Some(_lock) = maintain::try_lock(&config.db_path)? else {
+                eprintln!("Another `workspaces maintain` run is still in progress");
+                process::exit(ExitCodes::Failure as i32);
+            };
+
+            let res = maintain(
+                &mut conn,
+                &config.filesystems,
+                &notifiers,
+                &config.modulefiles,
+                &config.departed_users,
+                quiet,
+                dry_run || simulate,
+            )
+            .and_then(|()| match report {
+                Some((report, smtp)) if weekly_report => {
+                    admin_report::send(&conn, &config.filesystems, smtp, report)
+                }
+                _ => Ok(()),
+            })
+            .inspect_err(|e| telemetry::report(&config.telemetry, &e.to_string()));
+            if let Some(dir) = simulation.as_deref().and_then(Path::parent)
+                && let Err(e) = fs::remove_dir_all(dir)
+            {
+                log::warn!(
+                    "Failed to remove the simulation's copy in {}: {}",
+                    dir.display(),
+                    e
+                );
+            }
+            res
+        }
+        cli::Command::Daemon => {
+            // Admins only
+            if get_current_uid() != 0 {
+                eprintln!("You are not allowed to execute this operation");
+                process::exit(ExitCodes::InsufficientPrivileges as i32);
+            }
+
+            daemon::daemon(&mut conn, &config, &notifiers)
+        }
+        cli::Command::Snapshot => {
+            // Admins only
+            if get_current_uid() != 0 {
+                eprintln!("You are not allowed to execute this operation");
+                process::exit(ExitCodes::InsufficientPrivileges as i32);
+            }
+
+            snapshot::snapshot(&conn, &config.filesystems, false)
+                .inspect_err(|e| telemetry::report(&config.telemetry, &e.to_string()))
+        }
+
+        cli::Command::RestoreArchive { id, duration, .. } => {
+            // Admins only
+            if get_current_uid() != 0 {
+                eprintln!("You are not allowed to execute this operation");
+                process::exit(ExitCodes::InsufficientPrivileges as i32);
+            }
+
+            archive::restore_archive(&mut conn, &config.filesystems, id, duration)
+        }
+
+        cli::Command::RestoreBackup { id, duration } => {
+            // Admins only
+            if get_current_uid() != 0 {
+                eprintln!("You are not allowed to execute this operation");
+                process::exit(ExitCodes::InsufficientPrivileges as i32);
+            }
+
+            backup::restore_backup(&mut conn, &config.filesystems, id, duration, &notifiers)
+        }
+
+        cli::Command::RecoverArchive {
+            name,
+            user,
+            filesystem_name,
+            duration,
+        } => {
+            // Admins only
+            if get_current_uid() != 0 {
+                eprintln!("You are not allowed to execute this operation");
+                process::exit(ExitCodes::InsufficientPrivileges as i32);
+            }
 
             let filesystem_name = filesystem_or_default_or_exit(
                 &filesystem_name,
                 &config.filesystems,
                 &config.default_filesystem,
             );
-            expire(
+            tarball::recover(
                 &mut conn,
                 &filesystem_name,
-                &config
+                config
                     .filesystems
                     .get(&filesystem_name)
                     .expect("unknown filesystem"),
                 &user,
                 &name,
-                delete_on_next_clean,
-                &config.smtp, // pass SMTP
+                duration,
+                &notifiers,
             )
         }
-        cli::Command::Filesystems { output } => filesystems(&config.filesystems, output),
-        cli::Command::Maintain => {
+
+        cli::Command::VerifyArchive { id, tarball } => {
             // Admins only
             if get_current_uid() != 0 {
                 eprintln!("You are not allowed to execute this operation");
                 process::exit(ExitCodes::InsufficientPrivileges as i32);
             }
 
-            maintain(&mut conn, &config.filesystems, &config.smtp)
+            if tarball {
+                tarball::verify(&conn, &config.filesystems, id)
+            } else {
+                archive::verify_archive(&conn, &config.filesystems, id)
+            }
+        }
+
+        cli::Command::RefreshCache => {
+            cache::refresh(&conn, &config.filesystems, config.property_cache_ttl)
         }
 
+        cli::Command::PendingDeletions {
+            hold,
+            release,
+            user,
+            filesystem_name,
+        } => match (hold, release) {
+            (None, None) => pending_deletions(&conn, &config.filesystems, config.private_listing),
+            (hold, release) => {
+                // Admins only
+                if get_current_uid() != 0 {
+                    eprintln!("You are not allowed to execute this operation");
+                    process::exit(ExitCodes::InsufficientPrivileges as i32);
+                }
+
+                let filesystem_name = filesystem_or_default_or_exit(
+                    &filesystem_name,
+                    &config.filesystems,
+                    &config.default_filesystem,
+                );
+                let held = hold.is_some();
+                let name = hold.or(release).unwrap();
+                pending_deletions::hold(&conn, &filesystem_name, &user, &name, held)
+                    .map_err(Into::into)
+            }
+        },
+        cli::Command::Clean {
+            name,
+            user,
+            filesystem_name,
+            id,
+            all,
+            force,
+            bwlimit: _,
+        } => {
+            let workspace = (!all).then(|| {
+                workspace_or_exit(
+                    &conn,
+                    id,
+                    &filesystem_name,
+                    user,
+                    name,
+                    &config.filesystems,
+                    &config.default_filesystem,
+                )
+            });
+            clean::clean(
+                &mut conn,
+                &config.filesystems,
+                &config.modulefiles,
+                workspace
+                    .as_ref()
+                    .map(|(filesystem_name, user, name)| (&**filesystem_name, &**user, &**name)),
+                filesystem_name.as_deref(),
+                force,
+            )
+            .map_err(Into::into)
+        }
+        cli::Command::Fsck {
+            filesystem_name,
+            adopt,
+            prune,
+        } => fsck::fsck(
+            &mut conn,
+            &config.filesystems,
+            &config.modulefiles,
+            filesystem_name.as_deref(),
+            adopt,
+            prune,
+        )
+        .map_err(Into::into),
+        cli::Command::Approve { id, reject } => {
+            // Admins only
+            if get_current_uid() != 0 {
+                eprintln!("You are not allowed to execute this operation");
+                process::exit(ExitCodes::InsufficientPrivileges as i32);
+            }
+
+            approve(
+                &mut conn,
+                &config.filesystems,
+                id,
+                reject,
+                &notifiers,
+                &config.modulefiles,
+                &config.profiles,
+            )
+        }
+
+        cli::Command::Email {
+            command: cli::EmailCommand::SetMine { address },
+        } => email::set_mine(&address),
+        cli::Command::Prefs { command } => {
+            let me = get_current_username()
+                .ok_or("could not look up the current user")?
+                .to_string_lossy()
+                .into_owned();
+            prefs::prefs(&me, command)
+        }
+        cli::Command::NotifySchedule {
+            name,
+            user,
+            filesystem_name,
+            id,
+            days,
+            reset,
+        } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            notify_schedule::notify_schedule(
+                &conn,
+                &filesystem_name,
+                config
+                    .filesystems
+                    .get(&filesystem_name)
+                    .expect("unknown filesystem"),
+                &user,
+                &name,
+                days,
+                reset,
+            )
+            .map_err(Into::into)
+        }
+        cli::Command::Export {
+            name,
+            to,
+            zstd,
+            from_snapshot,
+            user,
+            filesystem_name,
+            id,
+        } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            export(
+                &conn,
+                &filesystem_name,
+                config
+                    .filesystems
+                    .get(&filesystem_name)
+                    .expect("unknown filesystem"),
+                &user,
+                &name,
+                &to,
+                zstd,
+                from_snapshot.as_deref(),
+            )
+            .map_err(Into::into)
+        }
+        cli::Command::Offboard {
+            user,
+            successor,
+            reassign,
+            retention,
+            notify,
+            yes,
+        } => offboard(
+            &mut conn,
+            &config,
+            &user,
+            &reassign,
+            successor.as_deref(),
+            retention,
+            notify.as_deref(),
+            yes,
+            &notifiers,
+        ),
+        cli::Command::Transfer {
+            name,
+            to,
+            user,
+            filesystem_name,
+            id,
+            accept,
+        } => {
+            // The workspace's name isn't given with --id, only the recipient
+            let (name, to) = match (id, name, to) {
+                (Some(_), Some(_), Some(_)) => {
+                    eprintln!("With --id, only the user to transfer the workspace to is given");
+                    process::exit(ExitCodes::InvalidArgument as i32);
+                }
+                (Some(_), name, to) => (None, to.or(name)),
+                (None, name, to) => (name, to),
+            };
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            transfer::transfer(
+                &mut conn,
+                &config,
+                &filesystem_name,
+                &user,
+                &name,
+                to.as_deref(),
+                accept,
+            )
+            .map_err(Into::into)
+        }
+        cli::Command::Publish {
+            name,
+            user,
+            filesystem_name,
+            id,
+            description,
+            undo,
+        } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            publish::publish(
+                &conn,
+                &filesystem_name,
+                &user,
+                &name,
+                description.as_deref(),
+                !undo,
+            )
+            .map_err(Into::into)
+        }
+        cli::Command::ExcludeSnapshots {
+            name,
+            user,
+            filesystem_name,
+            id,
+            undo,
+        } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            exclude_snapshots(&conn, &filesystem_name, &user, &name, !undo).map_err(Into::into)
+        }
+        cli::Command::Note {
+            name,
+            user,
+            filesystem_name,
+            id,
+            message,
+            delete,
+        } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            note(
+                &conn,
+                &filesystem_name,
+                &user,
+                &name,
+                message.as_deref(),
+                delete,
+            )
+            .map_err(Into::into)
+        }
+        cli::Command::Owner { command } => {
+            let (cli::OwnerCommand::List {
+                name,
+                user,
+                filesystem_name,
+                id,
+            }
+            | cli::OwnerCommand::Add {
+                name,
+                user,
+                filesystem_name,
+                id,
+                ..
+            }
+            | cli::OwnerCommand::Remove {
+                name,
+                user,
+                filesystem_name,
+                id,
+                ..
+            }) = &command;
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                *id,
+                filesystem_name,
+                user.clone(),
+                name.clone(),
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            owners::owner(&conn, &filesystem_name, &user, &name, command).map_err(Into::into)
+        }
+        cli::Command::Grant {
+            grantee,
+            name,
+            user,
+            filesystem_name,
+            id,
+            right,
+            revoke,
+        } => {
+            let (filesystem_name, user, name) = workspace_or_exit(
+                &conn,
+                id,
+                &filesystem_name,
+                user,
+                name,
+                &config.filesystems,
+                &config.default_filesystem,
+            );
+            grants::grant(
+                &conn,
+                &filesystem_name,
+                &user,
+                &name,
+                &grantee,
+                right,
+                revoke,
+            )
+            .map_err(Into::into)
+        }
         cli::Command::NotifyTest { user, to } => {
             // Warn for target user
-            warn_missing_email_for_user(&user);
+            if hints {
+                warn_missing_email_for_user(&config.db_path, &user);
+            }
 
             // Admins only
             if get_current_uid() != 0 {
@@ -270,9 +1406,9 @@ fn main() -> Result<(), Box<dyn Error>> {
                     "SMTP is not configured. Please add an [smtp] block in {}",
                     config::CONFIG_PATH
                 );
-                process::exit(1);
+                process::exit(ExitCodes::NotConfigured as i32);
             };
-            maintain::notify_test(&user, to, smtp_cfg)
+            notify::notify_test(&user, to, smtp_cfg)
         }
     }
 }
@@ -309,8 +1445,110 @@ fn filesystem_or_default_or_exit(
             eprint!(" {}", name);
         }
         eprintln!();
-        process::exit(ExitCodes::UnknownWorkspace as i32);
+        process::exit(ExitCodes::UnknownFilesystem as i32);
+    }
+}
+
+/// Identifies the workspace a command is about, by its id if given
+///
+/// Otherwise, the filesystem defaults as in [`filesystem_or_default_or_exit`].
+/// Returns the filesystem, user and name of the workspace, or terminates the
+/// program if there is no workspace with the id.
+fn workspace_or_exit(
+    conn: &Connection,
+    id: Option<i64>,
+    filesystem_name: &Option<String>,
+    user: String,
+    name: Option<String>,
+    filesystems: &HashMap<String, config::Filesystem>,
+    default: &Option<String>,
+) -> (String, String, String) {
+    let Some(id) = id else {
+        let filesystem_name = filesystem_or_default_or_exit(filesystem_name, filesystems, default);
+        return (
+            filesystem_name,
+            user,
+            name.expect("name required without id"),
+        );
+    };
+    match conn.query_row(
+        "SELECT filesystem, user, name FROM workspaces WHERE id = ?1",
+        (id,),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ) {
+        Ok(workspace) => workspace,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            eprintln!("There is no workspace with id {}", id);
+            process::exit(ExitCodes::UnknownId as i32);
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(ExitCodes::Failure as i32);
+        }
+    }
+}
+
+/// Opens the database, read-only with `--read-only`
+///
+/// Missing or outdated databases are set up first, which `--read-only`
+/// forbids.  Other commands, even those which only show things, need a
+/// writable connection, e.g. to fill the property cache.
+fn open_database(db_path: &Path, read_only: bool) -> Result<Connection, Box<dyn Error>> {
+    if read_only {
+        if let Ok(conn) = Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        ) && conn.pragma_query_value(None, "user_version", |row| row.get::<_, usize>(0))?
+            == NEWEST_DB_VERSION
+        {
+            // Readers don't wait for writers in WAL mode, only for the rare
+            // recovery or checkpoint
+            conn.busy_timeout(Duration::from_secs(10))?;
+            return Ok(conn);
+        }
+        return Err(format!(
+            "{} is missing or needs an upgrade, which --read-only forbids",
+            db_path.display()
+        )
+        .into());
+    }
+
+    let mut conn = Connection::open(db_path)?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    // Wait for concurrent writers, e.g. a running `maintain`, instead of failing
+    let _ = LOCKED_DATABASE.set(db_path.to_owned());
+    conn.busy_handler(Some(wait_for_lock))?;
+
+    update_database_schema_if_necessary(&mut conn)?;
+    Ok(conn)
+}
+
+/// The database [`wait_for_lock`] waits for, to tell whether `maintain` runs
+static LOCKED_DATABASE: OnceLock<PathBuf> = OnceLock::new();
+
+/// How often a command waiting for another one's write lock retries
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// Retries after which the user is told what's going on
+const LOCK_NOTICE_ATTEMPTS: i32 = 20;
+/// Retries after which a command gives up, unless `maintain` runs
+///
+/// Other commands hold the write lock for moments, while `maintain` holds it
+/// for its whole run, which may take hours when it destroys or archives a lot.
+const LOCK_MAX_ATTEMPTS: i32 = 600;
+
+/// Busy handler waiting for the database's write lock, which commands
+/// take for their whole read-modify-write sequence
+///
+/// It waits for as long as a `maintain` run goes on.
+fn wait_for_lock(attempts: i32) -> bool {
+    if attempts == LOCK_NOTICE_ATTEMPTS {
+        eprintln!("Waiting for another workspaces command (e.g. `maintain`) to finish...");
     }
+    std::thread::sleep(LOCK_RETRY_INTERVAL);
+    attempts < LOCK_MAX_ATTEMPTS
+        || LOCKED_DATABASE
+            .get()
+            .is_some_and(|db_path| maintain::is_running(db_path))
 }
 
 fn update_database_schema_if_necessary(
@@ -342,7 +1580,7 @@ fn update_database_schema_if_necessary(
     ));
 
     let mut backup_dest_db = Connection::open(backup_path)?;
-    backup::Backup::new(source_db_conn, &mut backup_dest_db)?.run_to_completion(
+    rusqlite::backup::Backup::new(source_db_conn, &mut backup_dest_db)?.run_to_completion(
         4,
         Duration::from_millis(250),
         None,
@@ -1,519 +1,1230 @@
-use crate::{config, to_volume_string, zfs};
-use chrono::{DateTime, Duration, Local, Utc};
-use lettre::{
-    address::AddressError,
-    message::header::ContentType,
-    message::Mailbox,
-    transport::smtp::authentication::{Credentials, Mechanism},
-    transport::smtp::client::{Tls, TlsParameters},
-    Message, SmtpTransport, Transport,
+use crate::{
+    archive, backup, banner, clock, config, departed, digest,
+    events::{self, EventKind},
+    expire, grace, journal, metadata, modulefile,
+    notify::{self, Notification, NotificationError, NotificationKind, Notifiers},
+    owners, ownership, schedule, snapshot, tarball, to_volume_string,
 };
-use rusqlite::Connection;
-use std::{collections::HashMap, error::Error, fmt, fs, io};
-use users::{get_user_by_name, os::unix::UserExt};
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{Connection, OpenFlags, TransactionBehavior};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fs::{DirBuilder, File, OpenOptions},
+    io::{self, IsTerminal},
+    os::{fd::AsRawFd, unix::fs::DirBuilderExt},
+    path::{Path, PathBuf},
+    process,
+    time::Instant,
+};
+
+/// Days between warnings about snapshots pinning a lot of space
+const SNAPSHOT_WARNING_INTERVAL_DAYS: i64 = 7;
+
+/// The lock file of `maintain` runs on the database at `db_path`
+fn lock_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".maintain.lock");
+    path.into()
+}
+
+/// Tries taking the lock held for the whole of a `maintain` run, in a file
+/// next to the database
+///
+/// Returns `None` if another run holds it.  Commands waiting for the database
+/// check on the lock with [`is_running`], holding it for a moment, so it's
+/// tried a few times.  The lock is released once the returned file is
+/// dropped.
+pub fn try_lock(db_path: &Path) -> io::Result<Option<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(lock_path(db_path))?;
+    for _ in 0..LOCK_ATTEMPTS {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(Some(file));
+        }
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(error);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    Ok(None)
+}
+
+/// Times [`try_lock`] tries, 100ms apart
+const LOCK_ATTEMPTS: usize = 10;
+
+/// Whether a `maintain` run on the database at `db_path` holds its lock
+pub fn is_running(db_path: &Path) -> bool {
+    let Ok(file) = File::open(lock_path(db_path)) else {
+        return false;
+    };
+    // Taken for as long as `file` lives, which keeps runs from starting meanwhile
+    let busy = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } != 0;
+    busy && io::Error::last_os_error().raw_os_error() == Some(libc::EWOULDBLOCK)
+}
+
+/// Copies the database at `db_path` for `maintain --simulate`, into a new
+/// directory only root can read
+///
+/// Returns the path of the copy.  The caller removes its directory afterwards.
+pub fn simulation_copy(db_path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let dir = env::temp_dir().join(format!("workspaces-simulate.{}", process::id()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let copy = dir.join("workspaces.db");
+    // Unlike copying the file, this includes what is still in the WAL
+    Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?
+        .execute("VACUUM INTO ?1", (copy.to_string_lossy(),))?;
+    Ok(copy)
+}
 
 pub fn maintain(
     conn: &mut Connection,
     filesystems: &HashMap<String, config::Filesystem>,
-    smtp_config: &Option<config::SmtpConfig>,
+    notifiers: &Notifiers,
+    modulefiles: &Option<config::ModulefilesConfig>,
+    departed_users: &Option<config::DepartedUsersConfig>,
+    quiet: bool,
+    dry_run: bool,
 ) -> Result<(), Box<dyn Error>> {
-    let transaction = conn.transaction()?;
+    let interactive = !quiet && io::stderr().is_terminal();
+    let mut timing = Timing::new();
+
+    // Finish what crashed commands left behind first
+    if !dry_run {
+        journal::recover(conn, filesystems)?;
+    }
+    timing.phase("recovering interrupted commands");
+
+    // Archives and backups take a while, so they are made before taking the
+    // write lock.  Workspaces extended meanwhile aren't deleted, as they are
+    // checked again holding the lock.
+    let preserved = match dry_run {
+        true => HashSet::new(),
+        false => preserve_due(conn, filesystems)?,
+    };
+    timing.phase("archiving workspaces");
+    let mut in_use = match notifiers.any() {
+        true => check_activity(conn, filesystems)?,
+        false => HashMap::new(),
+    };
+    timing.phase("checking for recent activity");
+
+    let host = hostname::get()?.to_string_lossy().to_string();
+    // Hold the write lock for the whole run, so nothing gets extended between
+    // deciding to delete a workspace and deleting it
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    timing.phase("waiting for the database");
+    let mut progress = interactive
+        .then(|| Progress::new(&transaction))
+        .transpose()?;
+    // Notifications to send, with the workspaces to record them for
+    let mut pending = Vec::new();
+    // Expiry and deletion warnings due, merged before sending
+    let mut warnings = Vec::new();
+    // Whether each owner has left, looked up once per run
+    let departures = match departed_users {
+        Some(departed_users) => {
+            let mut usernames = HashSet::new();
+            let mut known_departed = HashSet::new();
+            let mut statement = transaction.prepare(
+                "SELECT user, MAX(owner_departed IS NOT NULL) FROM workspaces GROUP BY user",
+            )?;
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                let username: String = row.get(0)?;
+                if row.get(1)? {
+                    known_departed.insert(username.clone());
+                }
+                usernames.insert(username);
+            }
+            match departed::departures(
+                &usernames,
+                &known_departed,
+                departed_users.max_departed_fraction,
+            ) {
+                Ok(departures) => Some(departures),
+                Err(e) => {
+                    log::error!("{}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    // Volumes whose owner newly turned out to have left, with the reason
+    let mut newly_departed = Vec::new();
     {
-        let mut statement = transaction
-            .prepare("SELECT id, filesystem, user, name, expiration_time FROM workspaces")?;
+        let mut statement = transaction.prepare(
+            "SELECT id, filesystem, user, name, expiration_time, root, deletion_held, dataset, \
+                    expiry_notifications, grace_granted, mountpoint, owner_departed, owner_group \
+                FROM workspaces ORDER BY filesystem",
+        )?;
         let mut rows = statement.query([])?;
         while let Some(row) = rows.next()? {
             let workspace_id: i32 = row.get(0)?;
             let filesystem_name: String = row.get(1)?;
+            if let Some(progress) = &mut progress {
+                progress.next(&filesystem_name);
+            }
             let username: String = row.get(2)?;
             let workspace_name: String = row.get(3)?;
-            let expiration_time: DateTime<Utc> = row.get(4)?;
+            let mut expiration_time: DateTime<Utc> = row.get(4)?;
+            let root: Option<String> = row.get(5)?;
+            let deletion_held: bool = row.get(6)?;
+            let dataset: String = row.get(7)?;
+            let expiry_notifications: Option<String> = row.get(8)?;
+            let grace_granted: bool = row.get(9)?;
+            let mut mountpoint: Option<String> = row.get(10)?;
+            let owner_departed: Option<DateTime<Utc>> = row.get(11)?;
+            let owner_group: Option<String> = row.get(12)?;
 
             let filesystem = &filesystems
                 .get(&filesystem_name)
                 .expect("unknown filesystem name");
 
-            if let Some(smtp_config) = smtp_config {
-                match notify_if_necessary_(
+            let volume = to_volume_string(filesystem.root(root.as_deref()), &dataset);
+
+            if expiration_time < clock::local_now() - filesystem.expired_retention {
+                if deletion_held {
+                    log::info!("Not deleting {}, its deletion is on hold", volume);
+                    continue;
+                }
+                if dry_run {
+                    println!("Would delete {}", volume);
+                    if notifiers.any() {
+                        println!("Would notify {} about the deletion of {}", username, volume);
+                    }
+                    continue;
+                }
+                // Due only since archiving, or failed to; kept for the next run
+                if !preserved.contains(&workspace_id) {
+                    continue;
+                }
+                // Taken along with the workspace
+                let co_owners = owners::co_owners(&transaction, workspace_id.into())?;
+                let deleted = delete(
+                    &transaction,
+                    filesystem,
+                    modulefiles,
+                    &Expired {
+                        workspace_id,
+                        filesystem_name: &filesystem_name,
+                        username: &username,
+                        workspace_name: &workspace_name,
+                        root: root.as_deref(),
+                        dataset: &dataset,
+                        expiration_time,
+                    },
+                )?;
+                if !deleted {
+                    continue;
+                }
+                if notifiers.any() {
+                    let subject =
+                        format!("Your workspace {} on {} was deleted.", workspace_name, host);
+                    let body = format!(
+                        "Hello,\n\nYour workspace \"{}\" on {} has been permanently deleted.\nFilesystem: {}\nMountpoint: {}\nOwner: {}\n",
+                        workspace_name,
+                        host,
+                        filesystem_name,
+                        mountpoint.as_deref().unwrap_or("unknown"),
+                        username
+                    );
+                    let notification = Notification {
+                        filesystem,
+                        kind: NotificationKind::Deleted,
+                        username: username.clone(),
+                        values: vec![
+                            ("workspace", workspace_name),
+                            ("user", username),
+                            ("filesystem", filesystem_name),
+                            ("mountpoint", mountpoint.unwrap_or_default()),
+                            ("host", host.clone()),
+                        ],
+                        subject,
+                        body,
+                    };
+                    for co_owner in &co_owners {
+                        pending.push((None, notification.for_co_owner(co_owner)));
+                    }
+                    pending.push((None, notification));
+                }
+                continue;
+            }
+
+            // Workspaces from before mountpoints were recorded
+            if mountpoint.is_none() {
+                match filesystem
+                    .storage()
+                    .get_property::<String>(&volume, "mountpoint")
+                {
+                    Ok(looked_up) => {
+                        transaction.execute(
+                            "UPDATE workspaces SET mountpoint = ?2 WHERE id = ?1",
+                            (workspace_id, &looked_up),
+                        )?;
+                        mountpoint = Some(looked_up);
+                    }
+                    Err(e) => log::warn!("Failed to look up the mountpoint of {}: {}", volume, e),
+                }
+            }
+
+            let mut departure = None;
+            if let Some(departed_users) = departed_users
+                && let Some(departures) = &departures
+            {
+                departure = departures.get(&username).and_then(Option::as_deref);
+                match (departure, owner_departed) {
+                    (Some(reason), None) => {
+                        transaction.execute(
+                            "UPDATE workspaces SET owner_departed = ?2 WHERE id = ?1",
+                            (workspace_id, clock::now()),
+                        )?;
+                        if !quiet {
+                            println!("The owner of {} has left: {}", volume, reason);
+                        }
+                        if departed_users.action == config::DepartedUsersAction::Expire
+                            && expiration_time > clock::now()
+                        {
+                            expiration_time = clock::now();
+                            transaction.execute(
+                                "UPDATE workspaces SET expiration_time = ?2 WHERE id = ?1",
+                                (workspace_id, expiration_time),
+                            )?;
+                            events::record(
+                                &transaction,
+                                EventKind::Expire,
+                                &filesystem_name,
+                                &username,
+                                &workspace_name,
+                                Some(expiration_time),
+                                Some("owner departed"),
+                            )?;
+                        }
+                        newly_departed.push((volume.clone(), reason.to_owned()));
+                    }
+                    // The account is back, e.g. after it was renewed
+                    (None, Some(_)) => {
+                        transaction.execute(
+                            "UPDATE workspaces SET owner_departed = NULL WHERE id = ?1",
+                            (workspace_id,),
+                        )?;
+                    }
+                    _ => {}
+                }
+            }
+
+            if notifiers.any()
+                && departure.is_none()
+                && let Some((kind, due)) = due_warning(
+                    workspace_id,
+                    &expiry_notifications
+                        .as_deref()
+                        .map(schedule::from_db_string)
+                        .unwrap_or_else(|| filesystem.expiry_notifications_on_days.clone()),
+                    expiration_time,
+                    grace_granted,
+                    &transaction,
+                )
+            {
+                let mut grace_days = None;
+                if let DueWarning::First = due
+                    && let Some(grace) = filesystem.grace_extension_days
+                {
+                    // Checked before taking the lock, unless it only became due since
+                    let recently_active = in_use.remove(&workspace_id).unwrap_or_else(|| {
+                        grace::recently_active(filesystem, &volume, filesystem.grace_activity_days)
+                    });
+                    match recently_active {
+                        Ok(true) if dry_run => {
+                            println!(
+                                "Would extend {} by {} days, as it is still in use",
+                                volume,
+                                grace.num_days()
+                            );
+                            expiration_time += grace;
+                            grace_days = Some(grace.num_days());
+                        }
+                        Ok(true) => {
+                            expiration_time += grace;
+                            transaction.execute(
+                                "UPDATE workspaces SET expiration_time = ?2, grace_granted = TRUE \
+                                    WHERE id = ?1",
+                                (workspace_id, expiration_time),
+                            )?;
+                            if !quiet {
+                                println!(
+                                    "Extended {} by {} days, as it is still in use",
+                                    volume,
+                                    grace.num_days()
+                                );
+                            }
+                            if let Err(e) = metadata::write(
+                                &transaction,
+                                &filesystem_name,
+                                filesystem,
+                                &username,
+                                &workspace_name,
+                            ) {
+                                log::warn!("Failed to write workspace metadata file: {}", e);
+                            }
+                            grace_days = Some(grace.num_days());
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::warn!("Failed to check {} for recent activity: {}", volume, e)
+                        }
+                    }
+                }
+                warnings.push(Warning {
+                    workspace_id,
+                    kind,
+                    workspace_name: workspace_name.clone(),
+                    username: username.clone(),
+                    filesystem_name: filesystem_name.clone(),
+                    filesystem,
+                    mountpoint: mountpoint.clone(),
+                    expiration_time,
+                    grace_days,
+                });
+            }
+
+            if notifiers.any()
+                && departure.is_none()
+                && expiration_time >= clock::local_now()
+                && let Some(fraction) = filesystem.snapshot_space_warning
+            {
+                match snapshot_space_warning(
                     workspace_id,
                     &workspace_name,
                     &username,
-                    smtp_config,
+                    &host,
+                    &filesystem_name,
                     filesystem,
-                    expiration_time,
+                    &volume,
+                    fraction,
+                    quiet,
                     &transaction,
                 ) {
-                    user_error @ Err(
-                        NotificationError::UserConfigReadError(..)
-                            | NotificationError::UserConfigParseError(..)
-                            | NotificationError::MailboxParseError(..),
-                    ) => {
-                        eprintln!("User error while notifying {}: {:?}", username, user_error);
-                    }
-                    res => {
-                        res.expect("non-recoverable error during notification process");
+                    Ok(Some(notification)) => {
+                        pending.push((Some(vec![workspace_id]), notification))
                     }
+                    Ok(None) => {}
+                    Err(e) => log::warn!("Failed to check snapshot space of {}: {}", volume, e),
                 }
             }
 
-            let volume = to_volume_string(&filesystem.root, &username, &workspace_name);
-
-            if expiration_time < Local::now() - filesystem.expired_retention {
-                // Delete workspaces expired beyond their retention date
-                if zfs::destroy(&volume).is_err() {
-                    continue;
+            if departure.is_none()
+                && expiration_time >= clock::local_now()
+                && filesystem.ownership_drift != config::OwnershipDrift::Ignore
+                && let Some(mountpoint) = &mountpoint
+            {
+                match ownership::verify(
+                    filesystem,
+                    Path::new(mountpoint),
+                    &username,
+                    owner_group.as_deref(),
+                    !dry_run && filesystem.ownership_drift == config::OwnershipDrift::Fix,
+                ) {
+                    Ok(None) => {}
+                    Ok(Some(drift)) => {
+                        log::warn!("Ownership of {} has drifted: {}", mountpoint, drift);
+                        events::record(
+                            &transaction,
+                            EventKind::Drift,
+                            &filesystem_name,
+                            &username,
+                            &workspace_name,
+                            Some(expiration_time),
+                            Some(&drift),
+                        )?;
+                    }
+                    Err(e) => log::warn!("Failed to verify the ownership of {}: {}", volume, e),
                 }
-                transaction.execute(
-                    "DELETE FROM workspaces
-                            WHERE id = ?1",
-                    [workspace_id],
-                )?;
-                if let Some(smtp) = smtp_config {
-                    let host = hostname::get()?.to_string_lossy().to_string();
-                    let subject = format!("Your workspace {} on {} was deleted.", workspace_name, host);
-                    let body = format!(
-                        "Hello,\n\nYour workspace \"{}\" on {} has been permanently deleted.\nFilesystem: {}\nOwner: {}\n",
-                        workspace_name, host, filesystem_name, username
-                    );
-                    // Best-effort: if email fails, don't abort the cleanup run
-                    if let Err(e) = notify_event(&username, smtp, subject, body) {
-                        eprintln!("Failed to send deletion notice for {}: {}", workspace_name, e);
+            }
+
+            if expiration_time < clock::local_now() {
+                // Set recently expired workspaces to read-only, keeping a final snapshot
+                if filesystem
+                    .storage()
+                    .get_property::<String>(&volume, "readonly")?
+                    != "on"
+                {
+                    if dry_run {
+                        println!("Would set {} read-only", volume);
+                    } else {
+                        if let Err(e) = expire::final_snapshot(filesystem, &volume) {
+                            log::warn!("Failed to snapshot expiring workspace {}: {}", volume, e);
+                        }
+                        filesystem
+                            .storage()
+                            .set_property(&volume, "readonly", "on")?;
+                        events::record(
+                            &transaction,
+                            EventKind::Expire,
+                            &filesystem_name,
+                            &username,
+                            &workspace_name,
+                            Some(expiration_time),
+                            None,
+                        )?;
                     }
                 }
-            } else if expiration_time < Local::now() {
-                // Set recently expired workspaces to read-only
-                zfs::set_property(&volume, "readonly", "on")?;
+            } else if !dry_run
+                && let Err(e) = banner::update(
+                    &filesystem_name,
+                    filesystem,
+                    &volume,
+                    &workspace_name,
+                    expiration_time,
+                )
+            {
+                log::warn!("Failed to update expiry banner of {}: {}", volume, e);
             }
         }
     }
-    transaction.commit()?;
 
-    // Snapshot all remaining filesystems for which this is desired
-    for filesystem in filesystems.values() {
-        if filesystem.snapshot {
-            zfs::snapshot(&filesystem.root)?
+    timing.phase("checking workspaces");
+
+    // Same-named workspaces on several filesystems get a single message
+    pending.extend(merge_warnings(warnings).iter().map(|group| {
+        let workspace_ids = group.iter().map(|w| w.workspace_id).collect();
+        (Some(workspace_ids), warning(group, &host))
+    }));
+
+    // Co-owners get copies, which are recorded along with the owner's
+    let mut copies = Vec::new();
+    for (workspace_ids, notification) in &pending {
+        let Some(workspace_ids) = workspace_ids else {
+            continue;
+        };
+        let mut co_owners = Vec::new();
+        for workspace_id in workspace_ids {
+            co_owners.extend(owners::co_owners(&transaction, (*workspace_id).into())?);
+        }
+        co_owners.sort();
+        co_owners.dedup();
+        copies.extend(
+            co_owners
+                .iter()
+                .map(|co_owner| (Some(Vec::new()), notification.for_co_owner(co_owner))),
+        );
+    }
+    pending.extend(copies);
+
+    if dry_run {
+        for (_, notification) in &pending {
+            println!(
+                "Would notify {}: {}",
+                notification.username, notification.subject
+            );
+        }
+        if departed_users.is_some() && notifiers.smtp.is_some() && !newly_departed.is_empty() {
+            println!(
+                "Would notify the admins about {} workspaces of departed owners",
+                newly_departed.len()
+            );
         }
+        // Nothing this run changed in the database is kept
+        transaction.rollback()?;
+        return Ok(());
     }
 
+    // Sending is slow, so do it concurrently; only the database writes are serialized
+    notify::dispatch(notifiers, pending, |workspace_ids, notification, res| {
+        match (workspace_ids, res) {
+            (Some(workspace_ids), Ok(channels)) => {
+                for workspace_id in workspace_ids {
+                    notify::record(
+                        &transaction,
+                        workspace_id.into(),
+                        notification.kind,
+                        &channels,
+                        clock::now(),
+                    )
+                    .unwrap();
+                }
+            }
+            // Deletion notices, whose workspace is gone already
+            (None, Ok(channels)) if !channels.is_empty() => {
+                let value = |key| {
+                    notification
+                        .values
+                        .iter()
+                        .find(|(k, _)| *k == key)
+                        .map_or("", |(_, v)| v.as_str())
+                };
+                events::record(
+                    &transaction,
+                    EventKind::Notify,
+                    value("filesystem"),
+                    value("user"),
+                    value("workspace"),
+                    None,
+                    Some(&events::notification_detail(notification.kind, &channels)),
+                )
+                .unwrap();
+            }
+            (None, Ok(_)) => {}
+            (
+                _,
+                user_error @ Err(
+                    NotificationError::UserConfigReadError(..)
+                    | NotificationError::UserConfigParseError(..)
+                    | NotificationError::MailboxParseError(..),
+                ),
+            ) => {
+                log::warn!(
+                    "User error while notifying {}: {:?}",
+                    notification.username,
+                    user_error
+                );
+            }
+            (Some(_), res) => {
+                res.expect("non-recoverable error during notification process");
+            }
+            // Best-effort: if notifying fails, don't abort the cleanup run
+            (None, Err(e)) => {
+                log::warn!(
+                    "Failed to send deletion notice to {}: {}",
+                    notification.username,
+                    e
+                );
+            }
+        }
+    });
+    if let Err(e) = digest::send_due(&transaction, notifiers, &host) {
+        log::warn!("Failed to send digests: {}", e);
+    }
+    transaction.commit()?;
+    timing.phase("sending notifications");
+
+    if let Some(departed_users) = departed_users
+        && let Some(smtp) = notifiers.smtp
+        && !newly_departed.is_empty()
+    {
+        departed::notify_admins(
+            smtp,
+            &departed_users.notify,
+            departed_users.action,
+            &newly_departed,
+        );
+    }
+
+    // Snapshot all remaining filesystems for which this is desired
+    snapshot::snapshot(conn, filesystems, true)?;
+    timing.phase("taking snapshots");
+
+    tarball::prune(conn, filesystems)?;
+    timing.phase("removing old archives");
+
+    if interactive {
+        timing.report();
+    }
     Ok(())
 }
 
-#[derive(Debug)]
-#[allow(unused)]
-enum NotificationError {
-    UserNotFoundError(String),
-    UserConfigReadError(io::Error),
-    UserConfigParseError(toml::de::Error),
-    SmtpError(lettre::transport::smtp::Error),
-    MailboxParseError(AddressError),
-    /// Failed to build TLS parameters for the given relay host
-    TlsParametersInvalid(String),
+/// A workspace expired beyond its retention period, as far as deleting it is concerned
+pub struct Expired<'a> {
+    pub workspace_id: i32,
+    pub filesystem_name: &'a str,
+    pub username: &'a str,
+    pub workspace_name: &'a str,
+    pub root: Option<&'a str>,
+    pub dataset: &'a str,
+    pub expiration_time: DateTime<Utc>,
 }
 
-impl std::error::Error for NotificationError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        match self {
-            Self::UserNotFoundError(..) => None,
-            Self::UserConfigReadError(err) => Some(err),
-            Self::UserConfigParseError(err) => Some(err),
-            Self::SmtpError(err) => Some(err),
-            Self::MailboxParseError(err) => Some(err),
-            Self::TlsParametersInvalid(..) => None,
+/// Preserves the workspaces due for deletion as their filesystems ask for
+///
+/// Returns the ids of those which may be deleted, i.e. which were
+/// preserved or need not be.
+fn preserve_due(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+) -> Result<HashSet<i32>, Box<dyn Error>> {
+    let due = conn
+        .prepare(
+            "SELECT id, filesystem, user, name, expiration_time, root, dataset FROM workspaces \
+                WHERE NOT deletion_held",
+        )?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i32>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, DateTime<Utc>>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, String>(6)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut preserved = HashSet::new();
+    for (workspace_id, filesystem_name, username, workspace_name, expiration_time, root, dataset) in
+        due
+    {
+        let Some(filesystem) = filesystems.get(&filesystem_name) else {
+            continue;
+        };
+        if expiration_time >= clock::local_now() - filesystem.expired_retention {
+            continue;
+        }
+        let workspace = Expired {
+            workspace_id,
+            filesystem_name: &filesystem_name,
+            username: &username,
+            workspace_name: &workspace_name,
+            root: root.as_deref(),
+            dataset: &dataset,
+            expiration_time,
+        };
+        if preserve(conn, filesystem, &workspace)? {
+            preserved.insert(workspace_id);
         }
     }
+    Ok(preserved)
 }
 
-impl std::fmt::Display for NotificationError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::UserNotFoundError(username) => write!(f, "User not found: {}", username),
-            Self::UserConfigReadError(err) => write!(f, "User configuration read error: {}", err),
-            Self::UserConfigParseError(err) => {
-                write!(f, "User configuration parsing error: {}", err)
-            }
-            Self::SmtpError(err) => write!(f, "SMTP error: {}", err),
-            Self::MailboxParseError(err) => write!(f, "Mailbox parse error: {}", err),
-            Self::TlsParametersInvalid(host) => write!(
-                f,
-                "TLS parameters could not be constructed for relay host: {}",
-                host
-            ),
+/// Whether each workspace checked was recently active, by id
+type Activity = HashMap<i32, Result<bool, Box<dyn Error>>>;
+
+/// Whether the workspaces getting their first expiry warning with a grace
+/// extension are still in use
+///
+/// Looking through them takes a while, so it's done before taking the write
+/// lock.
+fn check_activity(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+) -> Result<Activity, Box<dyn Error>> {
+    let candidates = conn
+        .prepare(
+            "SELECT id, filesystem, expiration_time, root, dataset, expiry_notifications \
+                FROM workspaces WHERE NOT grace_granted",
+        )?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i32>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, DateTime<Utc>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, Option<String>>(5)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut in_use = HashMap::new();
+    for (workspace_id, filesystem_name, expiration_time, root, dataset, expiry_notifications) in
+        candidates
+    {
+        let Some(filesystem) = filesystems.get(&filesystem_name) else {
+            continue;
+        };
+        if filesystem.grace_extension_days.is_none() {
+            continue;
+        }
+        let schedule = expiry_notifications
+            .as_deref()
+            .map(schedule::from_db_string)
+            .unwrap_or_else(|| filesystem.expiry_notifications_on_days.clone());
+        if let Some((_, DueWarning::First)) =
+            due_warning(workspace_id, &schedule, expiration_time, false, conn)
+        {
+            let volume = to_volume_string(filesystem.root(root.as_deref()), &dataset);
+            in_use.insert(
+                workspace_id,
+                grace::recently_active(filesystem, &volume, filesystem.grace_activity_days),
+            );
         }
     }
+    Ok(in_use)
 }
 
-impl From<io::Error> for NotificationError {
-    fn from(value: io::Error) -> Self {
-        NotificationError::UserConfigReadError(value)
+/// Archives or backs up a workspace about to be deleted, where the
+/// filesystem asks for it
+///
+/// Returns false, having logged why, if the workspace has to be kept for now,
+/// e.g. as archiving it failed.  Takes long for large workspaces, so it's to be
+/// called without holding the write lock.
+pub fn preserve(
+    conn: &Connection,
+    filesystem: &config::Filesystem,
+    workspace: &Expired,
+) -> Result<bool, Box<dyn Error>> {
+    let volume = to_volume_string(filesystem.root(workspace.root), workspace.dataset);
+    if let Some(s3) = &filesystem.s3_archive
+        && let Err(e) = archive::archive(
+            conn,
+            workspace.filesystem_name,
+            filesystem,
+            s3,
+            filesystem.root(workspace.root),
+            workspace.dataset,
+            workspace.username,
+            workspace.workspace_name,
+        )
+    {
+        log::warn!("Failed to archive {}, keeping it for now: {}", volume, e);
+        return Ok(false);
     }
-}
-
-impl From<toml::de::Error> for NotificationError {
-    fn from(value: toml::de::Error) -> Self {
-        NotificationError::UserConfigParseError(value)
+    if let Some(backup) = &filesystem.backup
+        && let Err(e) = backup::backup(
+            conn,
+            workspace.filesystem_name,
+            filesystem,
+            backup,
+            &volume,
+            workspace.username,
+            workspace.workspace_name,
+        )
+    {
+        log::warn!("Failed to back up {}, keeping it for now: {}", volume, e);
+        return Ok(false);
+    }
+    if let Some(archive_path) = &filesystem.archive_path
+        && let Err(e) = tarball::archive(
+            conn,
+            workspace.filesystem_name,
+            filesystem,
+            archive_path,
+            &volume,
+            workspace.username,
+            workspace.workspace_name,
+        )
+    {
+        log::warn!(
+            "Failed to archive {} to {}, keeping it for now: {}",
+            volume,
+            archive_path.display(),
+            e
+        );
+        return Ok(false);
     }
+    Ok(true)
 }
 
-impl From<lettre::transport::smtp::Error> for NotificationError {
-    fn from(value: lettre::transport::smtp::Error) -> Self {
-        NotificationError::SmtpError(value)
+/// Deletes a workspace for good, which has been [`preserve`]d
+///
+/// Returns false, having logged why, if the workspace is kept for now, as
+/// deleting its dataset failed.  Nobody is notified.
+pub fn delete(
+    conn: &Connection,
+    filesystem: &config::Filesystem,
+    modulefiles: &Option<config::ModulefilesConfig>,
+    workspace: &Expired,
+) -> Result<bool, Box<dyn Error>> {
+    let volume = to_volume_string(filesystem.root(workspace.root), workspace.dataset);
+    // A previous run may have crashed right after destroying it
+    if let Err(e) = filesystem.storage().destroy(&volume)
+        && filesystem.storage().exists(&volume).unwrap_or(true)
+    {
+        log::warn!("Failed to delete {}, keeping it for now: {}", volume, e);
+        events::record(
+            conn,
+            EventKind::Failure,
+            workspace.filesystem_name,
+            workspace.username,
+            workspace.workspace_name,
+            Some(workspace.expiration_time),
+            Some(&format!("failed to delete {}: {}", volume, e)),
+        )?;
+        return Ok(false);
     }
+    conn.execute(
+        "DELETE FROM workspaces
+                WHERE id = ?1",
+        [workspace.workspace_id],
+    )?;
+    events::record(
+        conn,
+        EventKind::Delete,
+        workspace.filesystem_name,
+        workspace.username,
+        workspace.workspace_name,
+        Some(workspace.expiration_time),
+        None,
+    )?;
+    if let Some(modulefiles) = modulefiles
+        && let Err(e) = modulefile::remove(
+            modulefiles,
+            workspace.filesystem_name,
+            workspace.username,
+            workspace.workspace_name,
+        )
+    {
+        log::warn!(
+            "Failed to remove modulefile for {}: {}",
+            workspace.workspace_name,
+            e
+        );
+    }
+    Ok(true)
 }
 
-impl From<AddressError> for NotificationError {
-    fn from(value: AddressError) -> Self {
-        NotificationError::MailboxParseError(value)
-    }
+/// Progress through the workspaces of each filesystem, shown on a terminal
+struct Progress {
+    /// Number of workspaces on each filesystem
+    totals: HashMap<String, usize>,
+    filesystem: String,
+    done: usize,
 }
 
-/// Parses "host", "host:port", or "[IPv6]:port" into (host, Some(port)) or (host, None)
-fn split_host_port(input: &str) -> (&str, Option<u16>) {
-    if let Some(rest) = input.strip_prefix('[') {
-        if let Some(idx) = rest.find("]:") {
-            let host = &rest[..idx];
-            let port_str = &rest[idx + 2..];
-            if let Ok(port) = port_str.parse::<u16>() {
-                return (host, Some(port));
-            }
-            return (host, None);
-        }
+impl Progress {
+    fn new(connection: &Connection) -> rusqlite::Result<Self> {
+        Ok(Progress {
+            totals: connection
+                .prepare("SELECT filesystem, COUNT(*) FROM workspaces GROUP BY filesystem")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?,
+            filesystem: String::new(),
+            done: 0,
+        })
     }
-    if let Some((host, port_str)) = input.rsplit_once(':') {
-        if let Ok(port) = port_str.parse::<u16>() {
-            return (host, Some(port));
+
+    /// Shows that the next workspace of `filesystem_name` is being checked
+    ///
+    /// The line is overwritten until all workspaces of the filesystem are done.
+    fn next(&mut self, filesystem_name: &str) {
+        if self.filesystem != filesystem_name {
+            self.filesystem = filesystem_name.to_owned();
+            self.done = 0;
         }
+        self.done += 1;
+        let total = self
+            .totals
+            .get(filesystem_name)
+            .copied()
+            .unwrap_or(0)
+            .max(self.done);
+        eprint!(
+            "\x1b[K{}: {}/{} workspaces{}",
+            filesystem_name,
+            self.done,
+            total,
+            if self.done == total { "\n" } else { "\r" }
+        );
     }
-    (input, None)
 }
 
-fn notify_if_necessary_(
-    workspace_id: i32,
-    workspace_name: &str,
-    username: &str,
-    smtp_config: &config::SmtpConfig,
-    filesystem: &config::Filesystem,
-    expiration_time: DateTime<Utc>,
-    connection: &Connection,
-) -> Result<(), NotificationError> {
-    // Get user config
-    let user = get_user_by_name(username)
-        .ok_or(NotificationError::UserNotFoundError(username.to_owned()))?;
-    let user_config_path = user.home_dir().join(".config/workspaces.toml");
-    let toml_str =
-        fs::read_to_string(user_config_path).map_err(NotificationError::UserConfigReadError)?;
-    let user_config: config::UserConfig =
-        toml::from_str(&toml_str).map_err(NotificationError::UserConfigParseError)?;
-
-    // Send out email notifications
-    let creds = Credentials::new(
-        smtp_config.username.to_owned(),
-        smtp_config.password.to_owned(),
-    );
+/// How long each phase of a run took
+struct Timing {
+    start: Instant,
+    last: Instant,
+    phases: Vec<(&'static str, std::time::Duration)>,
+}
 
-    // Support relay as "host" or "host:port" (and "[IPv6]:port")
-    let (relay_host, relay_port) = split_host_port(&smtp_config.relay);
-    let mut builder = SmtpTransport::relay(relay_host).map_err(NotificationError::SmtpError)?;
-
-    // TLS mode: default STARTTLS; if WRAPPER and no port given, default to 465
-    let tls_mode = smtp_config.tls.unwrap_or(config::TlsMode::Starttls);
-    match (tls_mode, relay_port) {
-        (config::TlsMode::Wrapper, Some(p)) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.port(p).tls(Tls::Wrapper(params));
-        }
-        (config::TlsMode::Wrapper, None) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.port(465).tls(Tls::Wrapper(params));
-        }
-        (config::TlsMode::Starttls, Some(p)) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.port(p).tls(Tls::Required(params));
-        }
-        (config::TlsMode::Starttls, None) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.tls(Tls::Required(params));
+impl Timing {
+    fn new() -> Self {
+        let now = Instant::now();
+        Timing {
+            start: now,
+            last: now,
+            phases: Vec::new(),
         }
     }
 
-    // Optional auth mechanism override
-    if let Some(method) = smtp_config.auth {
-        let mech = match method {
-            config::AuthMethod::Plain => Mechanism::Plain,
-            config::AuthMethod::Login => Mechanism::Login,
-        };
-        builder = builder.authentication(vec![mech]);
+    /// Ends the phase named `name`, which started when the previous one ended
+    fn phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.phases.push((name, now - self.last));
+        self.last = now;
     }
 
-    let mailer = builder.credentials(creds).build();
+    fn report(&self) {
+        eprintln!("Finished in {:.1}s:", self.start.elapsed().as_secs_f64());
+        for (name, duration) in &self.phases {
+            eprintln!("  {:<32} {:>6.1}s", name, duration.as_secs_f64());
+        }
+    }
+}
 
-    let last_notification_time = connection
+/// Whether a warning is the first one about a workspace's expiry, i.e. may
+/// come with a grace extension
+enum DueWarning {
+    First,
+    Further,
+}
+
+/// Which warning, if any, is due for a workspace according to its schedule
+fn due_warning(
+    workspace_id: i32,
+    expiry_notifications: &[Duration],
+    expiration_time: DateTime<Utc>,
+    grace_granted: bool,
+    connection: &Connection,
+) -> Option<(NotificationKind, DueWarning)> {
+    let recorded = connection
         .prepare(
-            "SELECT timestamp \
+            "SELECT kind, timestamp, channel IS NOT NULL \
                 FROM notifications \
-                WHERE workspace_id = ?1 \
-                ORDER BY timestamp DESC \
-                LIMIT 1",
+                WHERE workspace_id = ?1 AND kind != 'snapshot-space-warning'",
         )
-        .map_or(None, |mut res| {
-            res.query_row((workspace_id,), |row| row.get::<_, DateTime<Utc>>(0))
-                .ok()
-        });
-
-    let duration_since_last_notification = last_notification_time.map(|t| Utc::now() - t);
-    let duration_until_expiry = expiration_time - Utc::now();
-    // Find the most recent passed notification deadline ...
-    if let Some(duration_from_expiry_when_notification_should_have_been_issued) = filesystem
-        .expiry_notifications_on_days
-        .iter()
-        .filter(|d| d > &&duration_until_expiry)
-        .next()
-    {
-        // ... and check if our last message is more recent ...
-        if duration_since_last_notification.map_or(true, |d| {
-            (Utc::now() - d)
-                < (expiration_time
-                    - *duration_from_expiry_when_notification_should_have_been_issued)
-        }) {
-            // if not, we have to notify the user!
-            let from_mailbox: Mailbox = if let Some(mb) = smtp_config.from.clone() {
-                mb
-            } else {
-                smtp_config
-                    .username
-                    .parse()
-                    .map_err(NotificationError::MailboxParseError)?
-            };
-
-            let email = Message::builder()
-                .from(from_mailbox)
-                .to(user_config.email)
-                .header(ContentType::TEXT_PLAIN);
-
-            let subject = if duration_until_expiry > Duration::days(0) {
-                format!(
-                    "Your workspace {} on {} will expire in {} days.",
-                    workspace_name,
-                    hostname::get()?.to_string_lossy(),
-                    duration_until_expiry.num_days()
-                )
-            } else {
-                format!(
-                    "Your workspace {} on {} will be deleted in {} days.",
-                    workspace_name,
-                    hostname::get()?.to_string_lossy(),
-                    (filesystem.expired_retention + duration_until_expiry).num_days()
-                )
-            };
-
-            let email = email
-                .subject(&subject)
-                .body(format!(
-                    "{}
+        .and_then(|mut statement| {
+            statement
+                .query_map((workspace_id,), |row| {
+                    Ok(schedule::Recorded {
+                        kind: row.get::<_, String>(0)?.parse().ok(),
+                        time: row.get(1)?,
+                        delivered: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .unwrap_or_default();
 
-You can extend it by logging into {} and running
-`workspaces extend -d <duration in days> {}`.
+    let kind = schedule::due_warning(
+        clock::now(),
+        expiration_time,
+        expiry_notifications,
+        &recorded,
+    )?;
+    let warned_before = recorded
+        .iter()
+        .any(|r| r.delivered && matches!(r.kind, Some(NotificationKind::ExpiryWarning)));
+    let first = matches!(kind, NotificationKind::ExpiryWarning) && !warned_before && !grace_granted;
+    Some((
+        kind,
+        if first {
+            DueWarning::First
+        } else {
+            DueWarning::Further
+        },
+    ))
+}
 
-\
-                    To disable notifications for this workspace, manually mark this workspace as expired by running
-\
-                    `workspaces expire {}`.",
-                    &subject,
-                    hostname::get()?.to_string_lossy(),
-                    workspace_name,
-                    workspace_name,
-                ))
-                .unwrap();
+/// An expiry or deletion warning due for a workspace
+struct Warning<'a> {
+    workspace_id: i32,
+    kind: NotificationKind,
+    workspace_name: String,
+    username: String,
+    filesystem_name: String,
+    filesystem: &'a config::Filesystem,
+    mountpoint: Option<String>,
+    expiration_time: DateTime<Utc>,
+    /// The grace extension the workspace just received, if any
+    grace_days: Option<i64>,
+}
 
-            mailer.send(&email).map_err(NotificationError::SmtpError)?;
-            connection
-                .execute(
-                    "INSERT INTO notifications(workspace_id, timestamp) VALUES(?1, ?2)",
-                    (workspace_id, Utc::now()),
-                )
-                .unwrap();
+impl Warning<'_> {
+    /// Days left until the workspace expires or is deleted, depending on the kind
+    fn days(&self) -> i64 {
+        match self.kind {
+            NotificationKind::ExpiryWarning => (self.expiration_time - clock::now()).num_days(),
+            _ => {
+                (self.expiration_time + self.filesystem.expired_retention - clock::now()).num_days()
+            }
         }
     }
-    Ok(())
-}
 
-/// Admin-only: send a one-off test email using SMTP config.
-/// If `to_override` is Some, send to that address; otherwise look up the
-/// target user's `~/.config/workspaces.toml` (UserConfig.email).
-pub fn notify_test(
-    target_username: &str,
-    to_override: Option<String>,
-    smtp_config: &config::SmtpConfig,
-) -> Result<(), Box<dyn Error>> {
-    // Resolve recipient
-    let to_mailbox: Mailbox = if let Some(to) = to_override {
-        to.parse().map_err(NotificationError::MailboxParseError)?
-    } else {
-        let user = get_user_by_name(target_username)
-            .ok_or(NotificationError::UserNotFoundError(target_username.to_owned()))?;
-        let user_config_path = user.home_dir().join(".config/workspaces.toml");
-        let toml_str = fs::read_to_string(user_config_path)?;
-        let user_config: config::UserConfig = toml::from_str(&toml_str)?;
-        user_config.email
-    };
+    /// Whether both would result in the same message, sent the same way
+    fn same_message(&self, other: &Warning) -> bool {
+        self.username == other.username
+            && self.workspace_name == other.workspace_name
+            && self.kind == other.kind
+            && self.grace_days == other.grace_days
+            && self.days() == other.days()
+            && self.filesystem.notification_channels == other.filesystem.notification_channels
+            && self.kind.template(&self.filesystem.notification_templates)
+                == other
+                    .kind
+                    .template(&other.filesystem.notification_templates)
+    }
+}
 
-    // Build SMTP transport
-    let creds = Credentials::new(
-        smtp_config.username.to_owned(),
-        smtp_config.password.to_owned(),
-    );
-    let (relay_host, relay_port) = split_host_port(&smtp_config.relay);
-    let mut builder = SmtpTransport::relay(relay_host)?;
-
-    // TLS mode: mirror the logic above
-    let tls_mode = smtp_config.tls.unwrap_or(config::TlsMode::Starttls);
-    match (tls_mode, relay_port) {
-        (config::TlsMode::Wrapper, Some(p)) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.port(p).tls(Tls::Wrapper(params));
-        }
-        (config::TlsMode::Wrapper, None) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.port(465).tls(Tls::Wrapper(params));
-        }
-        (config::TlsMode::Starttls, Some(p)) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.port(p).tls(Tls::Required(params));
-        }
-        (config::TlsMode::Starttls, None) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.tls(Tls::Required(params));
+/// Groups the warnings which would otherwise be sent as identical messages
+///
+/// These are the warnings about workspaces of the same name and owner on
+/// different filesystems, expiring on the same day.
+fn merge_warnings(warnings: Vec<Warning>) -> Vec<Vec<Warning>> {
+    let mut groups: Vec<Vec<Warning>> = Vec::new();
+    for warning in warnings {
+        match groups
+            .iter_mut()
+            .find(|group| group[0].same_message(&warning))
+        {
+            Some(group) => group.push(warning),
+            None => groups.push(vec![warning]),
         }
     }
+    groups
+}
 
-    if let Some(method) = smtp_config.auth {
-        let mech = match method {
-            config::AuthMethod::Plain => Mechanism::Plain,
-            config::AuthMethod::Login => Mechanism::Login,
-        };
-        builder = builder.authentication(vec![mech]);
-    }
-    let mailer = builder.credentials(creds).build();
-
-    // Determine From
-    let from_mailbox: Mailbox = if let Some(mb) = smtp_config.from.clone() {
-        mb
-    } else {
-        match smtp_config.username.parse() {
-            Ok(mb) => mb,
-            // Propagate the real parse error if username isn't an email
-            Err(e) => return Err(Box::new(e)),
-        }
+/// The message for a group of warnings from [`merge_warnings`], naming the
+/// filesystem and mountpoint of each workspace it is about
+fn warning<'a>(group: &[Warning<'a>], host: &str) -> Notification<'a> {
+    let first = &group[0];
+    let workspace_name = &first.workspace_name;
+    let days = first.days();
+    let subject = match first.kind {
+        NotificationKind::ExpiryWarning => format!(
+            "Your workspace {} on {} will expire in {} days.",
+            workspace_name, host, days
+        ),
+        _ => format!(
+            "Your workspace {} on {} will be deleted in {} days.",
+            workspace_name, host, days
+        ),
     };
 
-    let host = hostname::get()?.to_string_lossy().to_string();
-    let subject = format!("Workspaces test email from {}", host);
+    let grace = match first.grace_days {
+        Some(grace_days) => format!(
+            "\nAs it was in use recently, it was extended by {} days once.\n",
+            grace_days
+        ),
+        None => String::new(),
+    };
+    let location = |warning: &Warning| match &warning.mountpoint {
+        Some(mountpoint) => format!("{}, mounted at {}", warning.filesystem_name, mountpoint),
+        None => warning.filesystem_name.clone(),
+    };
+    let (locations, filesystem_name) = match group {
+        [warning] => (
+            format!("It is on filesystem {}.\n", location(warning)),
+            warning.filesystem_name.as_str(),
+        ),
+        _ => (
+            format!(
+                "This applies to your workspaces of this name on each of these filesystems:\n{}\n",
+                group
+                    .iter()
+                    .map(|warning| format!("  {}", location(warning)))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+            "<filesystem>",
+        ),
+    };
     let body = format!(
-        "Hello,\n\nThis is a test email sent by Workspaces on {}.\n\
-If you can read this, SMTP is configured correctly.\n",
-        host
-    );
+        "{}
+{}
+{}
+You can extend it by logging into {} and running
+`workspaces extend -f {} -d <duration in days> {}`.
 
-    let msg = Message::builder()
-        .from(from_mailbox)
-        .to(to_mailbox.clone())
-        .header(ContentType::TEXT_PLAIN)
-        .subject(subject)
-        .body(body)?;
+\
+            To disable notifications for this workspace, manually mark this workspace as expired by running
+\
+            `workspaces expire -f {} {}`.",
+        &subject,
+        grace,
+        locations,
+        host,
+        filesystem_name,
+        workspace_name,
+        filesystem_name,
+        workspace_name,
+    );
 
-    mailer.send(&msg)?;
-    println!("Sent test email to {}", to_mailbox);
-    Ok(())
+    let filesystems: Vec<&str> = group.iter().map(|w| w.filesystem_name.as_str()).collect();
+    let mountpoints: Vec<&str> = group
+        .iter()
+        .map(|w| w.mountpoint.as_deref().unwrap_or_default())
+        .collect();
+    let mut values = vec![
+        ("workspace", workspace_name.to_owned()),
+        ("user", first.username.to_owned()),
+        ("filesystem", filesystems.join(", ")),
+        ("mountpoint", mountpoints.join(", ")),
+        ("host", host.to_owned()),
+        ("days", days.to_string()),
+    ];
+    if let Some(grace_days) = first.grace_days {
+        values.push(("grace_days", grace_days.to_string()));
+    }
+    Notification {
+        filesystem: first.filesystem,
+        kind: first.kind,
+        username: first.username.to_owned(),
+        values,
+        subject,
+        body,
+    }
 }
 
+/// Warns about the snapshots of a workspace pinning more than `fraction` of
+/// its quota, or of its size if it has none
+///
+/// Owners are warned at most every [`SNAPSHOT_WARNING_INTERVAL_DAYS`] days.
+#[allow(clippy::too_many_arguments)]
+fn snapshot_space_warning<'a>(
+    workspace_id: i32,
+    workspace_name: &str,
+    username: &str,
+    host: &str,
+    filesystem_name: &str,
+    filesystem: &'a config::Filesystem,
+    volume: &str,
+    fraction: f64,
+    quiet: bool,
+    connection: &Connection,
+) -> Result<Option<Notification<'a>>, Box<dyn Error>> {
+    let warned_recently: bool = connection.query_row(
+        "SELECT EXISTS(SELECT 1 FROM notifications \
+            WHERE workspace_id = ?1 \
+                AND kind = 'snapshot-space-warning' \
+                AND unixepoch(timestamp) > unixepoch(?2))",
+        (
+            workspace_id,
+            clock::now() - Duration::days(SNAPSHOT_WARNING_INTERVAL_DAYS),
+        ),
+        |row| row.get(0),
+    )?;
+    if warned_recently {
+        return Ok(None);
+    }
 
-/// Send a one-off event email with custom subject/body to a user's address
-/// resolved from `~/.config/workspaces.toml` (UserConfig.email).
-pub fn notify_event(
-    target_username: &str,
-    smtp_config: &crate::config::SmtpConfig,
-    subject: String,
-    body: String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    use lettre::{
-        message::header::ContentType,
-        message::Mailbox,
-        transport::smtp::authentication::{Credentials, Mechanism},
-        transport::smtp::client::{Tls, TlsParameters},
-        Message, SmtpTransport, Transport,
+    let storage = filesystem.storage();
+    let pinned: usize = storage.get_property(volume, "usedbysnapshots")?;
+    let (limit, of) = match storage.get_property::<usize>(volume, "quota")? {
+        0 => (storage.get_property::<usize>(volume, "used")?, "its size"),
+        quota => (quota, "its quota"),
     };
-    use users::{get_user_by_name, os::unix::UserExt};
-    use std::fs;
-
-    // Resolve recipient from user config
-    let user = get_user_by_name(target_username)
-        .ok_or(NotificationError::UserNotFoundError(target_username.to_owned()))?;
-    let user_config_path = user.home_dir().join(".config/workspaces.toml");
-    let toml_str = fs::read_to_string(user_config_path)?;
-    let user_config: crate::config::UserConfig = toml::from_str(&toml_str)?;
-
-    // Build SMTP transport (same rules as elsewhere)
-    let creds = Credentials::new(
-        smtp_config.username.to_owned(),
-        smtp_config.password.to_owned(),
-    );
-    let (relay_host, relay_port) = split_host_port(&smtp_config.relay);
-    let mut builder = SmtpTransport::relay(relay_host)?;
-
-    let tls_mode = smtp_config.tls.unwrap_or(crate::config::TlsMode::Starttls);
-    match (tls_mode, relay_port) {
-        (crate::config::TlsMode::Wrapper, Some(p)) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.port(p).tls(Tls::Wrapper(params));
-        }
-        (crate::config::TlsMode::Wrapper, None) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.port(465).tls(Tls::Wrapper(params));
-        }
-        (crate::config::TlsMode::Starttls, Some(p)) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.port(p).tls(Tls::Required(params));
-        }
-        (crate::config::TlsMode::Starttls, None) => {
-            let params = TlsParameters::new(relay_host.to_string())
-                .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
-            builder = builder.tls(Tls::Required(params));
-        }
+    if limit == 0 || (pinned as f64) <= fraction * limit as f64 {
+        return Ok(None);
     }
 
-    if let Some(method) = smtp_config.auth {
-        let mech = match method {
-            crate::config::AuthMethod::Plain => Mechanism::Plain,
-            crate::config::AuthMethod::Login => Mechanism::Login,
-        };
-        builder = builder.authentication(vec![mech]);
+    let pinned_gib = pinned / (1 << 30);
+    let percent = pinned * 100 / limit;
+    if !quiet {
+        println!(
+            "Snapshots of {} pin {}G, {}% of {}",
+            volume, pinned_gib, percent, of
+        );
     }
-    let mailer = builder.credentials(creds).build();
-
-    // From: explicit [smtp].from if set, else username parsed as email
-    let from_mailbox: Mailbox = if let Some(mb) = smtp_config.from.clone() {
-        mb
-    } else {
-        smtp_config.username.parse().map_err(NotificationError::MailboxParseError)?
-    };
-
-    let msg = Message::builder()
-        .from(from_mailbox)
-        .to(user_config.email.clone())
-        .header(ContentType::TEXT_PLAIN)
-        .subject(subject)
-        .body(body)?;
-
-    mailer.send(&msg)?;
-    Ok(())
+    let subject = format!(
+        "Snapshots of your workspace {} on {} take up {}G",
+        workspace_name, host, pinned_gib
+    );
+    let body = format!(
+        "Hello,\n\nSnapshots of your workspace \"{}\" on {} hold on to {}G of deleted or \
+changed files, {}% of {}.\nThis space is only freed once the snapshots are deleted.\n\n\
+If the workspace holds large temporary data, leave it out of further snapshots with\n  \
+workspaces exclude-snapshots -f {} {}\nand ask an administrator to delete its existing \
+snapshots.\n",
+        workspace_name, host, pinned_gib, percent, of, filesystem_name, workspace_name
+    );
+    Ok(Some(Notification {
+        filesystem,
+        kind: NotificationKind::SnapshotSpaceWarning,
+        username: username.to_owned(),
+        values: vec![
+            ("workspace", workspace_name.to_owned()),
+            ("user", username.to_owned()),
+            ("filesystem", filesystem_name.to_owned()),
+            ("host", host.to_owned()),
+            ("size", pinned_gib.to_string()),
+            ("percent", percent.to_string()),
+        ],
+        subject,
+        body,
+    }))
 }
@@ -0,0 +1,143 @@
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+
+use crate::{config, json, to_volume_string};
+
+/// Name of the metadata file at the root of each workspace
+pub const METADATA_FILE_NAME: &str = ".workspace.json";
+
+/// (Re)writes the metadata file of a workspace, if enabled for its filesystem
+///
+/// The file is owned by root and world-readable,
+/// so jobs running inside the workspace can introspect their own deadline.
+pub fn write(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    if !filesystem.metadata_file {
+        return Ok(());
+    }
+
+    let (id, expiration_time, root, dataset): (i64, DateTime<Utc>, Option<String>, String) = conn
+        .prepare(
+            "SELECT id, expiration_time, root, dataset FROM workspaces \
+                WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        )?
+        .query_row((filesystem_name, user, name), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+
+    let volume = to_volume_string(filesystem.root(root.as_deref()), &dataset);
+    let mountpoint: PathBuf = filesystem.storage().get_property(&volume, "mountpoint")?;
+    let path = mountpoint.join(METADATA_FILE_NAME);
+
+    let metadata = json::object([
+        ("id", id.into()),
+        ("name", name.into()),
+        ("user", user.into()),
+        ("filesystem", filesystem_name.into()),
+        (
+            "mountpoint",
+            mountpoint.to_string_lossy().into_owned().into(),
+        ),
+        ("expiration_time", expiration_time.to_rfc3339().into()),
+        ("expiration_epoch", expiration_time.timestamp().into()),
+        (
+            "deletion_time",
+            (expiration_time + filesystem.expired_retention)
+                .to_rfc3339()
+                .into(),
+        ),
+    ]);
+
+    replace_file(filesystem, &path, &format!("{}\n", metadata))?;
+
+    Ok(())
+}
+
+/// Writes a root-owned, world-readable file inside a workspace, on the
+/// filesystem's host
+///
+/// Anything already at `path` is removed first.
+pub fn replace_file(
+    filesystem: &config::Filesystem,
+    path: &Path,
+    contents: &str,
+) -> Result<(), Box<dyn Error>> {
+    let storage = filesystem.storage();
+    if storage.is_remote() {
+        // `excl` creates the file anew like `create_new` does below, which
+        // doesn't follow symlinks either
+        let mut child = storage
+            .command(
+                "sh",
+                &[
+                    "-c",
+                    "rm -f -- \"$0\" && umask 022 && exec dd of=\"$0\" conv=excl status=none",
+                    &path.to_string_lossy(),
+                ],
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let written = child
+            .stdin
+            .take()
+            .expect("stdin should be piped")
+            .write_all(contents.as_bytes());
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "writing {} failed: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        return Ok(written?);
+    }
+
+    // The workspace's owner controls its contents, so never follow whatever
+    // they may have placed at our path (e.g. a symlink to a system file)
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o644)
+        .open(path)?;
+    // `mode` is subject to the umask
+    file.set_permissions(fs::Permissions::from_mode(0o644))?;
+    Ok(file.write_all(contents.as_bytes())?)
+}
+
+/// Removes a file inside a workspace on the filesystem's host, if it is there
+pub fn remove_file(filesystem: &config::Filesystem, path: &Path) -> Result<(), Box<dyn Error>> {
+    let storage = filesystem.storage();
+    if storage.is_remote() {
+        let status = storage.status("rm", &["-f", "--", &path.to_string_lossy()])?;
+        if !status.success() {
+            return Err(format!("removing {} failed with {}", path.display(), status).into());
+        }
+        return Ok(());
+    }
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
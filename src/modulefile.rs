@@ -0,0 +1,111 @@
+use std::{
+    error::Error,
+    fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{config, template};
+
+/// Modulefile used if no template is configured
+///
+/// Its values are escaped for Tcl, see [`tcl_escape`].
+const DEFAULT_TEMPLATE: &str = "#%Module1.0
+##
+## Workspace {name} of {user} on {filesystem}
+##
+module-whatis \"Workspace {name} on {filesystem}\"
+setenv WORKSPACE_DIR \"{mountpoint}\"
+setenv WORKSPACE_NAME \"{name}\"
+";
+
+/// Escapes the characters Tcl would substitute or split words on, so `text`
+/// stays literal both as a bare word and within double quotes
+fn tcl_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        if matches!(
+            character,
+            '\\' | '"' | '$' | '[' | ']' | '{' | '}' | ';' | ' ' | '\t'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+/// Location of a workspace's modulefile
+fn path(
+    modulefiles: &config::ModulefilesConfig,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> PathBuf {
+    Path::new(&template::render(
+        &modulefiles.dir,
+        &[("user", user), ("filesystem", filesystem_name)],
+    ))
+    .join(name)
+}
+
+/// Writes a modulefile for a workspace
+///
+/// Returns the path of the written file.
+pub fn write(
+    modulefiles: &config::ModulefilesConfig,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    mountpoint: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let mountpoint = mountpoint.to_string_lossy();
+    let values = [
+        ("name", name),
+        ("user", user),
+        ("filesystem", filesystem_name),
+        ("mountpoint", &mountpoint),
+    ];
+    // Custom templates, e.g. Lua ones for Lmod, get the values verbatim
+    let contents = match &modulefiles.template {
+        Some(template_path) => template::render(&fs::read_to_string(template_path)?, &values),
+        None => {
+            let escaped = values.map(|(key, value)| (key, tcl_escape(value)));
+            let escaped: Vec<(&str, &str)> = escaped
+                .iter()
+                .map(|(key, value)| (*key, value.as_str()))
+                .collect();
+            template::render(DEFAULT_TEMPLATE, &escaped)
+        }
+    };
+
+    let path = path(modulefiles, filesystem_name, user, name);
+    fs::create_dir_all(path.parent().expect("modulefile path should have a parent"))?;
+    fs::write(&path, contents)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+
+    Ok(path)
+}
+
+/// Removes a workspace's modulefile, if there is one
+pub fn remove(
+    modulefiles: &config::ModulefilesConfig,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> io::Result<()> {
+    match fs::remove_file(path(modulefiles, filesystem_name, user, name)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Whether a workspace has a modulefile
+pub fn exists(
+    modulefiles: &config::ModulefilesConfig,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+) -> bool {
+    path(modulefiles, filesystem_name, user, name).exists()
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Local, Utc};
+use rusqlite::Connection;
+use users::get_current_uid;
+
+use crate::{clock, create, error::WorkspacesError};
+
+/// A note operators attached to a workspace
+pub struct Note {
+    pub id: i64,
+    pub author: String,
+    pub time: DateTime<Utc>,
+    pub text: String,
+}
+
+impl Note {
+    /// The note as a single line, e.g. for `info`
+    pub fn describe(&self) -> String {
+        format!(
+            "#{} {} by {}: {}",
+            self.id,
+            self.time.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            self.author,
+            self.text
+        )
+    }
+}
+
+/// Attaches a note to a workspace, or deletes one of its notes
+pub fn note(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    message: Option<&str>,
+    delete: Option<i64>,
+) -> Result<(), WorkspacesError> {
+    if get_current_uid() != 0 {
+        return Err(WorkspacesError::not_allowed());
+    }
+
+    let workspace_id: i64 = match conn.query_row(
+        "SELECT id FROM workspaces WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name),
+        |row| row.get(0),
+    ) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                name,
+            ));
+        }
+        res => res,
+    }?;
+
+    if let Some(note_id) = delete {
+        let deleted = conn.execute(
+            "DELETE FROM notes WHERE id = ?1 AND workspace_id = ?2",
+            (note_id, workspace_id),
+        )?;
+        if deleted == 0 {
+            return Err(WorkspacesError::InvalidArgument(format!(
+                "Workspace {} has no note #{}",
+                name, note_id
+            )));
+        }
+        println!("Deleted note #{} of {}", note_id, name);
+        return Ok(());
+    }
+
+    let text = message.expect("either a message or a note to delete is required");
+    conn.execute(
+        "INSERT INTO notes(workspace_id, author, timestamp, text) VALUES (?1, ?2, ?3, ?4)",
+        (workspace_id, create::acting_user(), clock::now(), text),
+    )?;
+    println!("Added note #{} to {}", conn.last_insert_rowid(), name);
+    Ok(())
+}
+
+/// All notes of a workspace, oldest first
+pub fn notes(conn: &Connection, workspace_id: i64) -> rusqlite::Result<Vec<Note>> {
+    conn.prepare(
+        "SELECT id, author, timestamp, text FROM notes WHERE workspace_id = ?1 ORDER BY id",
+    )?
+    .query_map((workspace_id,), |row| {
+        Ok(Note {
+            id: row.get(0)?,
+            author: row.get(1)?,
+            time: row.get(2)?,
+            text: row.get(3)?,
+        })
+    })?
+    .collect()
+}
+
+/// The number of notes of each workspace which has any, with its latest note
+pub fn latest(conn: &Connection) -> rusqlite::Result<HashMap<i64, (usize, Note)>> {
+    conn.prepare(
+        "SELECT notes.workspace_id, counts.count, notes.id, notes.author, notes.timestamp, \
+                notes.text \
+            FROM notes \
+            JOIN (SELECT workspace_id, COUNT(*) AS count, MAX(id) AS latest \
+                    FROM notes GROUP BY workspace_id) AS counts \
+                ON notes.id = counts.latest",
+    )?
+    .query_map([], |row| {
+        Ok((
+            row.get(0)?,
+            (
+                row.get(1)?,
+                Note {
+                    id: row.get(2)?,
+                    author: row.get(3)?,
+                    time: row.get(4)?,
+                    text: row.get(5)?,
+                },
+            ),
+        ))
+    })?
+    .collect()
+}
@@ -0,0 +1,616 @@
+use crate::{config, events, json, owners, prefs, storage, template};
+use chrono::{DateTime, Utc};
+use lettre::{
+    Message, SmtpTransport, Transport,
+    address::AddressError,
+    message::header::ContentType,
+    message::{Mailbox, Mailboxes},
+    transport::smtp::authentication::{Credentials, Mechanism},
+    transport::smtp::client::{Tls, TlsParameters},
+};
+use rusqlite::Connection;
+use std::{
+    error::Error,
+    fmt, fs, io,
+    io::Write,
+    path::Path,
+    process::Stdio,
+    str::FromStr,
+    sync::{Mutex, mpsc},
+    thread,
+};
+use users::get_user_by_name;
+
+#[derive(Debug)]
+#[allow(unused)]
+pub enum NotificationError {
+    UserNotFoundError(String),
+    UserConfigReadError(io::Error),
+    UserConfigParseError(toml::de::Error),
+    SmtpError(lettre::transport::smtp::Error),
+    MailboxParseError(AddressError),
+    /// Failed to build TLS parameters for the given relay host
+    TlsParametersInvalid(String),
+    /// The email could not be assembled
+    MessageError(lettre::error::Error),
+    /// A configured notification template could not be read
+    TemplateReadError(io::Error),
+    /// Delivering to the webhook failed
+    WebhookError(String),
+}
+
+impl std::error::Error for NotificationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::UserNotFoundError(..) => None,
+            Self::UserConfigReadError(err) => Some(err),
+            Self::UserConfigParseError(err) => Some(err),
+            Self::SmtpError(err) => Some(err),
+            Self::MailboxParseError(err) => Some(err),
+            Self::TlsParametersInvalid(..) => None,
+            Self::MessageError(err) => Some(err),
+            Self::TemplateReadError(err) => Some(err),
+            Self::WebhookError(..) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for NotificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UserNotFoundError(username) => write!(f, "User not found: {}", username),
+            Self::UserConfigReadError(err) => write!(f, "User configuration read error: {}", err),
+            Self::UserConfigParseError(err) => {
+                write!(f, "User configuration parsing error: {}", err.message())
+            }
+            Self::SmtpError(err) => write!(f, "SMTP error: {}", err),
+            Self::MailboxParseError(err) => write!(f, "Mailbox parse error: {}", err),
+            Self::TlsParametersInvalid(host) => write!(
+                f,
+                "TLS parameters could not be constructed for relay host: {}",
+                host
+            ),
+            Self::MessageError(err) => write!(f, "Email construction error: {}", err),
+            Self::TemplateReadError(err) => write!(f, "Template read error: {}", err),
+            Self::WebhookError(err) => write!(f, "Webhook error: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for NotificationError {
+    fn from(value: io::Error) -> Self {
+        NotificationError::UserConfigReadError(value)
+    }
+}
+
+impl From<toml::de::Error> for NotificationError {
+    fn from(value: toml::de::Error) -> Self {
+        NotificationError::UserConfigParseError(value)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for NotificationError {
+    fn from(value: lettre::transport::smtp::Error) -> Self {
+        NotificationError::SmtpError(value)
+    }
+}
+
+impl From<AddressError> for NotificationError {
+    fn from(value: AddressError) -> Self {
+        NotificationError::MailboxParseError(value)
+    }
+}
+
+impl From<lettre::error::Error> for NotificationError {
+    fn from(value: lettre::error::Error) -> Self {
+        NotificationError::MessageError(value)
+    }
+}
+
+/// The kinds of notifications sent to workspace owners
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationKind {
+    Created,
+    Extended,
+    Expired,
+    /// The workspace was permanently deleted
+    Deleted,
+    /// The workspace is about to expire
+    ExpiryWarning,
+    /// The expired workspace is about to be deleted
+    DeletionWarning,
+    /// The workspace's snapshots pin a lot of space
+    SnapshotSpaceWarning,
+    /// An admin rejected the request to create the workspace
+    Rejected,
+}
+
+impl NotificationKind {
+    /// The filesystem's template replacing the built-in text, if any
+    pub fn template(self, templates: &config::NotificationTemplates) -> Option<&Path> {
+        match self {
+            NotificationKind::Created => templates.created.as_deref(),
+            NotificationKind::Extended => templates.extended.as_deref(),
+            NotificationKind::Expired => templates.expired.as_deref(),
+            NotificationKind::Deleted => templates.deleted.as_deref(),
+            NotificationKind::ExpiryWarning => templates.expiry_warning.as_deref(),
+            NotificationKind::DeletionWarning => templates.deletion_warning.as_deref(),
+            NotificationKind::SnapshotSpaceWarning => templates.snapshot_space_warning.as_deref(),
+            NotificationKind::Rejected => templates.rejected.as_deref(),
+        }
+    }
+}
+
+impl fmt::Display for NotificationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                NotificationKind::Created => "created",
+                NotificationKind::Extended => "extended",
+                NotificationKind::Expired => "expired",
+                NotificationKind::Deleted => "deleted",
+                NotificationKind::ExpiryWarning => "expiry-warning",
+                NotificationKind::DeletionWarning => "deletion-warning",
+                NotificationKind::SnapshotSpaceWarning => "snapshot-space-warning",
+                NotificationKind::Rejected => "rejected",
+            }
+        )
+    }
+}
+
+impl FromStr for NotificationKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "created" => Ok(NotificationKind::Created),
+            "extended" => Ok(NotificationKind::Extended),
+            "expired" => Ok(NotificationKind::Expired),
+            "deleted" => Ok(NotificationKind::Deleted),
+            "expiry-warning" => Ok(NotificationKind::ExpiryWarning),
+            "deletion-warning" => Ok(NotificationKind::DeletionWarning),
+            "snapshot-space-warning" => Ok(NotificationKind::SnapshotSpaceWarning),
+            "rejected" => Ok(NotificationKind::Rejected),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The site-wide configuration of all notification channels
+#[derive(Clone, Copy)]
+pub struct Notifiers<'a> {
+    pub smtp: Option<&'a config::SmtpConfig>,
+    pub webhook: Option<&'a config::WebhookConfig>,
+    /// Maximum number of notifications [`dispatch`] sends at once
+    pub concurrency: usize,
+    /// The site's default for batching created / extended emails
+    pub digest_frequency: config::DigestFrequency,
+}
+
+impl<'a> Notifiers<'a> {
+    pub fn new(config: &'a config::Config) -> Self {
+        Notifiers {
+            smtp: config.smtp.as_ref(),
+            webhook: config.webhook.as_ref(),
+            concurrency: config.max_concurrent_notifications,
+            digest_frequency: config.digest_frequency,
+        }
+    }
+
+    /// Whether there is any channel to send notifications over
+    pub fn any(&self) -> bool {
+        self.smtp.is_some() || self.webhook.is_some()
+    }
+}
+
+/// Notifies a workspace's owner about an event
+///
+/// The notification is sent over all of the filesystem's channels which are
+/// configured site-wide.  If the filesystem has a template for `kind`, it is
+/// rendered with `values` and replaces `subject` and `body`. Succeeds with the
+/// channels the notification was delivered over, unless all of them failed.
+pub fn notify(
+    notifiers: &Notifiers,
+    filesystem: &config::Filesystem,
+    kind: NotificationKind,
+    target_username: &str,
+    values: &[(&str, &str)],
+    subject: String,
+    body: String,
+) -> Result<Vec<config::NotificationChannel>, NotificationError> {
+    let (subject, body) = render(filesystem, kind, values, subject, body)?;
+
+    let mut delivered = Vec::new();
+    let mut error = None;
+    for channel in &filesystem.notification_channels {
+        let res = match (channel, notifiers.smtp, notifiers.webhook) {
+            (config::NotificationChannel::Email, Some(smtp_config), _) => {
+                user_recipients(target_username)
+                    .and_then(|to| send_email(smtp_config, to, subject.clone(), body.clone()))
+            }
+            (config::NotificationChannel::Webhook, _, Some(webhook)) => send_webhook(
+                &webhook.url,
+                json::object(
+                    [
+                        ("kind", kind.to_string().into()),
+                        ("user", target_username.into()),
+                        ("subject", subject.as_str().into()),
+                        ("body", body.as_str().into()),
+                    ]
+                    .into_iter()
+                    .chain(
+                        values
+                            .iter()
+                            .filter(|(k, _)| *k != "user")
+                            .map(|(k, v)| (*k, (*v).into())),
+                    ),
+                ),
+            ),
+            // Channel not configured for this site
+            _ => continue,
+        };
+        match res {
+            Ok(()) => delivered.push(*channel),
+            Err(err) => error = Some(err),
+        }
+    }
+    match error {
+        Some(err) if delivered.is_empty() => Err(err),
+        _ => Ok(delivered),
+    }
+}
+
+/// Records a notification about a workspace, for scheduling further ones
+///
+/// Without `channels`, the entry only marks that the owner needs no warnings
+/// for now, e.g. because they just created the workspace.
+pub fn record(
+    conn: &Connection,
+    workspace_id: i64,
+    kind: NotificationKind,
+    channels: &[config::NotificationChannel],
+    timestamp: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    let channel = (!channels.is_empty()).then(|| {
+        channels
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+    conn.execute(
+        "INSERT INTO notifications(workspace_id, timestamp, kind, channel) \
+            VALUES (?1, ?2, ?3, ?4)",
+        (workspace_id, timestamp, kind.to_string(), channel),
+    )?;
+    if !channels.is_empty() {
+        events::record_notification(conn, workspace_id, kind, channels)?;
+    }
+    Ok(())
+}
+
+/// A notification queued for [`dispatch`]
+#[derive(Clone)]
+pub struct Notification<'a> {
+    pub filesystem: &'a config::Filesystem,
+    pub kind: NotificationKind,
+    pub username: String,
+    pub values: Vec<(&'static str, String)>,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Notification<'_> {
+    /// The copy of the notification sent to a co-owner of its workspace
+    pub fn for_co_owner(&self, co_owner: &str) -> Self {
+        Notification {
+            username: co_owner.to_owned(),
+            body: owners::co_owner_body(&self.username, &self.body),
+            ..self.clone()
+        }
+    }
+}
+
+/// Sends notifications concurrently, at most `notifiers.concurrency` at a time
+///
+/// `on_sent` is called on the calling thread as soon as each notification was
+/// sent or has failed, so it may e.g. record it in the database.
+pub fn dispatch<'a, T: Send>(
+    notifiers: &Notifiers,
+    notifications: Vec<(T, Notification<'a>)>,
+    mut on_sent: impl FnMut(
+        T,
+        Notification<'a>,
+        Result<Vec<config::NotificationChannel>, NotificationError>,
+    ),
+) {
+    let queue = Mutex::new(notifications.into_iter());
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..notifiers.concurrency.max(1) {
+            let sender = sender.clone();
+            let queue = &queue;
+            scope.spawn(move || {
+                loop {
+                    let Some((tag, notification)) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let values: Vec<(&str, &str)> = notification
+                        .values
+                        .iter()
+                        .map(|(k, v)| (*k, v.as_str()))
+                        .collect();
+                    let res = notify(
+                        notifiers,
+                        notification.filesystem,
+                        notification.kind,
+                        &notification.username,
+                        &values,
+                        notification.subject.clone(),
+                        notification.body.clone(),
+                    );
+                    if sender.send((tag, notification, res)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+        for (tag, notification, res) in receiver {
+            on_sent(tag, notification, res);
+        }
+    });
+}
+
+/// The subject and body of a notification, from the filesystem's template if
+/// it has one for `kind`
+pub fn render(
+    filesystem: &config::Filesystem,
+    kind: NotificationKind,
+    values: &[(&str, &str)],
+    subject: String,
+    body: String,
+) -> Result<(String, String), NotificationError> {
+    match kind.template(&filesystem.notification_templates) {
+        Some(path) => {
+            let admin_contact = filesystem.admin_contact.as_deref().unwrap_or("");
+            let values: Vec<(&str, &str)> = values
+                .iter()
+                .copied()
+                .chain([("admin_contact", admin_contact)])
+                .collect();
+            render_template(path, &values)
+        }
+        None => match &filesystem.admin_contact {
+            Some(contact) => Ok((
+                subject,
+                format!(
+                    "{}\nIf you have questions, please contact {}.\n",
+                    body, contact
+                ),
+            )),
+            None => Ok((subject, body)),
+        },
+    }
+}
+
+/// Renders a template file into a subject and a body
+///
+/// The first line of the template is the subject,
+/// everything after the following blank line the body.
+fn render_template(
+    path: &Path,
+    values: &[(&str, &str)],
+) -> Result<(String, String), NotificationError> {
+    let template = fs::read_to_string(path).map_err(NotificationError::TemplateReadError)?;
+    let rendered = template::render(&template, values);
+    let (subject, body) = rendered.split_once('\n').unwrap_or((&rendered, ""));
+    Ok((
+        subject.trim().to_owned(),
+        body.strip_prefix('\n').unwrap_or(body).to_owned(),
+    ))
+}
+
+/// Who an email is sent to, and who is copied on it
+#[derive(Clone)]
+pub struct Recipients {
+    pub to: Vec<Mailbox>,
+    pub cc: Vec<Mailbox>,
+}
+
+impl From<Mailbox> for Recipients {
+    fn from(to: Mailbox) -> Self {
+        Recipients {
+            to: vec![to],
+            cc: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Recipients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Mailboxes::from(self.to.clone()))?;
+        if !self.cc.is_empty() {
+            write!(f, " (cc {})", Mailboxes::from(self.cc.clone()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Looks up a user's email addresses in their preferences
+pub fn user_recipients(username: &str) -> Result<Recipients, NotificationError> {
+    let user_config = user_config(username)?;
+    Ok(Recipients {
+        to: user_config.email,
+        cc: user_config.cc,
+    })
+}
+
+/// Reads a user's preferences, from their `~/.config/workspaces.toml` or the database
+pub fn user_config(username: &str) -> Result<config::UserConfig, NotificationError> {
+    if get_user_by_name(username).is_none() {
+        return Err(NotificationError::UserNotFoundError(username.to_owned()));
+    }
+    let toml_str = prefs::read(username).map_err(NotificationError::UserConfigReadError)?;
+    toml::from_str(&toml_str).map_err(NotificationError::UserConfigParseError)
+}
+
+/// Parses "host", "host:port", or "[IPv6]:port" into (host, Some(port)) or (host, None)
+fn split_host_port(input: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = input.strip_prefix('[')
+        && let Some(idx) = rest.find("]:")
+    {
+        let host = &rest[..idx];
+        let port_str = &rest[idx + 2..];
+        if let Ok(port) = port_str.parse::<u16>() {
+            return (host, Some(port));
+        }
+        return (host, None);
+    }
+    if let Some((host, port_str)) = input.rsplit_once(':')
+        && let Ok(port) = port_str.parse::<u16>()
+    {
+        return (host, Some(port));
+    }
+    (input, None)
+}
+
+/// Builds an SMTP transport from the configuration
+fn mailer(smtp_config: &config::SmtpConfig) -> Result<SmtpTransport, NotificationError> {
+    let creds = Credentials::new(
+        smtp_config.username.to_owned(),
+        smtp_config.password.to_owned(),
+    );
+
+    // Support relay as "host" or "host:port" (and "[IPv6]:port")
+    let (relay_host, relay_port) = split_host_port(&smtp_config.relay);
+    let mut builder = SmtpTransport::relay(relay_host).map_err(NotificationError::SmtpError)?;
+
+    // TLS mode: default STARTTLS; if WRAPPER and no port given, default to 465
+    let tls_mode = smtp_config.tls.unwrap_or(config::TlsMode::Starttls);
+    let params = TlsParameters::new(relay_host.to_string())
+        .map_err(|_| NotificationError::TlsParametersInvalid(relay_host.to_string()))?;
+    builder = match (tls_mode, relay_port) {
+        (config::TlsMode::Wrapper, Some(p)) => builder.port(p).tls(Tls::Wrapper(params)),
+        (config::TlsMode::Wrapper, None) => builder.port(465).tls(Tls::Wrapper(params)),
+        (config::TlsMode::Starttls, Some(p)) => builder.port(p).tls(Tls::Required(params)),
+        (config::TlsMode::Starttls, None) => builder.tls(Tls::Required(params)),
+    };
+
+    // Optional auth mechanism override
+    if let Some(method) = smtp_config.auth {
+        let mech = match method {
+            config::AuthMethod::Plain => Mechanism::Plain,
+            config::AuthMethod::Login => Mechanism::Login,
+        };
+        builder = builder.authentication(vec![mech]);
+    }
+
+    Ok(builder.credentials(creds).build())
+}
+
+/// Sends a plain text email
+pub fn send_email(
+    smtp_config: &config::SmtpConfig,
+    to: impl Into<Recipients>,
+    subject: String,
+    body: String,
+) -> Result<(), NotificationError> {
+    let recipients = to.into();
+    // From: explicit [smtp].from if set, else username parsed as email
+    let from_mailbox: Mailbox = if let Some(mb) = smtp_config.from.clone() {
+        mb
+    } else {
+        smtp_config
+            .username
+            .parse()
+            .map_err(NotificationError::MailboxParseError)?
+    };
+
+    let mut builder = Message::builder().from(from_mailbox);
+    for to in recipients.to {
+        builder = builder.to(to);
+    }
+    for cc in recipients.cc {
+        builder = builder.cc(cc);
+    }
+    let msg = builder
+        .header(ContentType::TEXT_PLAIN)
+        .subject(subject)
+        .body(body)?;
+
+    mailer(smtp_config)?.send(&msg)?;
+    Ok(())
+}
+
+/// POSTs `payload` to `url`
+pub fn send_webhook(url: &str, payload: json::Value) -> Result<(), NotificationError> {
+    let mut child = storage::system_command("curl")
+        // Before anything else, so the `.curlrc` of the user we run as is skipped
+        .args([
+            "-q",
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--max-time",
+            "30",
+            "--header",
+            "Content-Type: application/json",
+            "--data-binary",
+            "@-",
+        ])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| NotificationError::WebhookError(e.to_string()))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin should be piped")
+        .write_all(payload.to_string().as_bytes())
+        .map_err(|e| NotificationError::WebhookError(e.to_string()))?;
+    let output = child
+        .wait_with_output()
+        .map_err(|e| NotificationError::WebhookError(e.to_string()))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(NotificationError::WebhookError(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        )),
+    }
+}
+
+/// Admin-only: send a one-off test email using SMTP config.
+/// If `to_override` is Some, send to that address; otherwise look up the
+/// target user's `~/.config/workspaces.toml` (UserConfig.email).
+pub fn notify_test(
+    target_username: &str,
+    to_override: Option<String>,
+    smtp_config: &config::SmtpConfig,
+) -> Result<(), Box<dyn Error>> {
+    // Resolve recipient
+    let recipients = if let Some(to) = to_override {
+        to.parse::<Mailbox>()
+            .map_err(NotificationError::MailboxParseError)?
+            .into()
+    } else {
+        user_recipients(target_username)?
+    };
+
+    let host = hostname::get()?.to_string_lossy().to_string();
+    let subject = format!("Workspaces test email from {}", host);
+    let body = format!(
+        "Hello,\n\nThis is a test email sent by Workspaces on {}.\n\
+If you can read this, SMTP is configured correctly.\n",
+        host
+    );
+
+    let sent_to = recipients.to_string();
+    send_email(smtp_config, recipients, subject, body)?;
+    println!("Sent test email to {}", sent_to);
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use rusqlite::Connection;
+
+use crate::{config, error::WorkspacesError, may_change, schedule};
+
+/// Shows or changes the days a single workspace's owner is warned on
+///
+/// `days` replaces the filesystem's `expiry_notifications_on_days` for this
+/// workspace, `reset` reverts to them.
+pub fn notify_schedule(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    days: Option<Vec<i64>>,
+    reset: bool,
+) -> Result<(), WorkspacesError> {
+    if (days.is_some() || reset) && !may_change(conn, filesystem_name, user, name) {
+        return Err(WorkspacesError::not_allowed());
+    }
+
+    let current: Option<String> = match conn.query_row(
+        "SELECT expiry_notifications FROM workspaces \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name),
+        |row| row.get(0),
+    ) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                name,
+            ));
+        }
+        res => res,
+    }?;
+
+    let new = match (days, reset) {
+        (Some(days), _) => Some(schedule::to_db_string(&days)),
+        (None, true) => None,
+        (None, false) => {
+            match current {
+                Some(days) => println!(
+                    "Warnings: {} (set for this workspace)",
+                    schedule::describe(&schedule::from_db_string(&days))
+                ),
+                None => println!(
+                    "Warnings: {} (default of {})",
+                    schedule::describe(&filesystem.expiry_notifications_on_days),
+                    filesystem_name
+                ),
+            }
+            return Ok(());
+        }
+    };
+
+    conn.execute(
+        "UPDATE workspaces SET expiry_notifications = ?4 \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name, &new),
+    )?;
+    println!(
+        "Warnings: {}",
+        schedule::describe(&match &new {
+            Some(days) => schedule::from_db_string(days),
+            None => filesystem.expiry_notifications_on_days.clone(),
+        })
+    );
+    Ok(())
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, Local, Utc};
+use lettre::message::Mailbox;
+use prettytable::{Attr, Cell, Row, Table, format::FormatBuilder};
+use rusqlite::{Connection, TransactionBehavior};
+use users::{get_current_uid, get_user_by_name};
+
+use crate::{
+    cli::CommandFormat,
+    clock, config,
+    error::WorkspacesError,
+    events::{self, EventKind},
+    expire, journal, metadata, modulefile, moved_dataset,
+    notify::{self, Notifiers},
+    to_volume_string,
+};
+
+/// A workspace being handed over
+pub struct Workspace {
+    pub id: i64,
+    pub filesystem_name: String,
+    pub name: String,
+    pub expiration_time: DateTime<Utc>,
+    pub root: Option<String>,
+    pub dataset: String,
+}
+
+/// Hands a leaving user's workspaces over
+///
+/// The workspaces named in `reassign` are given to `successor`, all others
+/// are expired and deleted after `retention` (at most their filesystem's
+/// retention).  A summary is sent to `notify`, or the successor's address.
+#[allow(clippy::too_many_arguments)]
+pub fn offboard(
+    conn: &mut Connection,
+    config: &config::Config,
+    user: &str,
+    reassign: &[String],
+    successor: Option<&str>,
+    retention: Option<Duration>,
+    notify: Option<&str>,
+    yes: bool,
+    notifiers: &Notifiers,
+) -> Result<(), Box<dyn Error>> {
+    if get_current_uid() != 0 {
+        return Err(WorkspacesError::not_allowed().into());
+    }
+    if let Some(successor) = successor
+        && get_user_by_name(successor).is_none()
+    {
+        return Err(
+            WorkspacesError::InvalidArgument(format!("There is no user {}", successor)).into(),
+        );
+    }
+    let recipient = match (notify, successor) {
+        (Some(address), _) => match address.parse::<Mailbox>() {
+            Ok(mailbox) => Some(mailbox.into()),
+            Err(_) => {
+                return Err(WorkspacesError::InvalidArgument(format!(
+                    "`{}` is not a valid email address",
+                    address
+                ))
+                .into());
+            }
+        },
+        (None, Some(successor)) => notify::user_recipients(successor).ok(),
+        (None, None) => None,
+    };
+    if notify.is_some() && config.smtp.is_none() {
+        return Err(WorkspacesError::NotConfigured(format!(
+            "SMTP is not configured. Please add an [smtp] block in {}",
+            config::CONFIG_PATH
+        ))
+        .into());
+    }
+
+    let workspaces: Vec<Workspace> = conn
+        .prepare(
+            "SELECT id, filesystem, name, expiration_time, root, dataset FROM workspaces \
+                WHERE user = ?1 ORDER BY filesystem, name",
+        )?
+        .query_map((user,), |row| {
+            Ok(Workspace {
+                id: row.get(0)?,
+                filesystem_name: row.get(1)?,
+                name: row.get(2)?,
+                expiration_time: row.get(3)?,
+                root: row.get(4)?,
+                dataset: row.get(5)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    if workspaces.is_empty() {
+        println!("{} has no workspaces", user);
+        return Ok(());
+    }
+    if let Some(unknown) = reassign
+        .iter()
+        .find(|name| !workspaces.iter().any(|workspace| &workspace.name == *name))
+    {
+        return Err(WorkspacesError::UnknownWorkspace(format!(
+            "{} has no workspace {}",
+            user, unknown
+        ))
+        .into());
+    }
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["NAME", "FS", "EXPIRY", "ACTION"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+    let mut actions = HashMap::new();
+    for workspace in &workspaces {
+        let action = match successor {
+            Some(successor) if reassign.contains(&workspace.name) => {
+                format!("reassign to {}", successor)
+            }
+            _ if workspace.expiration_time < clock::now() => "already expired".to_owned(),
+            _ => "expire".to_owned(),
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&workspace.name),
+            Cell::new(&workspace.filesystem_name),
+            Cell::new(
+                &workspace
+                    .expiration_time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+            ),
+            Cell::new(&action),
+        ]));
+        actions.insert(workspace.id, action);
+    }
+    table.printstd();
+
+    if !yes && !confirm(user)? {
+        return Err(WorkspacesError::NotConfirmed(
+            "Aborted, the workspaces were left untouched".to_owned(),
+        )
+        .into());
+    }
+
+    let mut summary = Vec::new();
+    for workspace in &workspaces {
+        let filesystem = &config.filesystems[&workspace.filesystem_name];
+        let res = match successor {
+            Some(successor) if reassign.contains(&workspace.name) => reassign_workspace(
+                conn,
+                filesystem,
+                workspace,
+                user,
+                successor,
+                &config.modulefiles,
+            ),
+            _ => expire_workspace(conn, filesystem, workspace, user, retention, notifiers),
+        };
+        match res {
+            Ok(()) => summary.push(format!(
+                "{} on {}: {}",
+                workspace.name, workspace.filesystem_name, actions[&workspace.id]
+            )),
+            Err(e) => {
+                log::warn!("Failed to offboard {}: {}", workspace.name, e);
+                summary.push(format!(
+                    "{} on {}: failed to {} ({})",
+                    workspace.name, workspace.filesystem_name, actions[&workspace.id], e
+                ));
+            }
+        }
+    }
+
+    if let (Some(recipient), Some(smtp)) = (recipient, &config.smtp) {
+        let host = hostname::get()?.to_string_lossy().to_string();
+        let body = format!(
+            "Hello,\n\nThe workspaces of {} on {} were handed over:\n\n{}\n",
+            user,
+            host,
+            summary.join("\n")
+        );
+        match notify::send_email(
+            smtp,
+            recipient.clone(),
+            format!("Workspaces of {} on {} handed over", user, host),
+            body,
+        ) {
+            Ok(()) => println!("Sent a summary to {}", recipient),
+            Err(e) => log::warn!("Failed to send the summary to {}: {}", recipient, e),
+        }
+    }
+    Ok(())
+}
+
+/// Asks whether the workspaces should really be handed over
+///
+/// Fails closed if there is no terminal to ask on.
+fn confirm(user: &str) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        eprintln!(
+            "Refusing to offboard {} without confirmation; pass --yes to confirm",
+            user
+        );
+        return Ok(false);
+    }
+    eprint!("Type \"yes\" to offboard {} as shown: ", user);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
+/// Expires a workspace, shortening its retention if asked to
+fn expire_workspace(
+    conn: &mut Connection,
+    filesystem: &config::Filesystem,
+    workspace: &Workspace,
+    user: &str,
+    retention: Option<Duration>,
+    notifiers: &Notifiers,
+) -> Result<(), Box<dyn Error>> {
+    if workspace.expiration_time >= clock::now() {
+        expire::expire(
+            conn,
+            &workspace.filesystem_name,
+            filesystem,
+            user,
+            &workspace.name,
+            false,
+            true,
+            Some("the owner left"),
+            notifiers,
+            CommandFormat::Text,
+        )?;
+    }
+    if let Some(retention) = retention {
+        // Deleted once expired for longer than the filesystem's retention
+        conn.execute(
+            "UPDATE workspaces SET expiration_time = MIN(expiration_time, ?2) WHERE id = ?1",
+            (
+                workspace.id,
+                clock::now() - filesystem.expired_retention + retention,
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+/// Moves a workspace to `successor`, along with the files `user` owns in it
+pub fn reassign_workspace(
+    conn: &mut Connection,
+    filesystem: &config::Filesystem,
+    workspace: &Workspace,
+    user: &str,
+    successor: &str,
+    modulefiles: &Option<config::ModulefilesConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let root = filesystem.root(workspace.root.as_deref());
+    let dest_dataset = moved_dataset(
+        &workspace.dataset,
+        user,
+        &workspace.name,
+        successor,
+        &workspace.name,
+    )?;
+    let src_volume = to_volume_string(root, &workspace.dataset);
+    let dest_volume = to_volume_string(root, &dest_dataset);
+
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    match transaction.execute(
+        "UPDATE workspaces SET user = ?2, dataset = ?3 WHERE id = ?1",
+        (workspace.id, successor, &dest_dataset),
+    ) {
+        Ok(_) => {}
+        Err(rusqlite::Error::SqliteFailure(
+            libsqlite3_sys::Error {
+                code: libsqlite3_sys::ErrorCode::ConstraintViolation,
+                ..
+            },
+            _,
+        )) => {
+            return Err(format!("{} already has a workspace {}", successor, workspace.name).into());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let entry = journal::begin(
+        &transaction,
+        workspace.id,
+        &workspace.filesystem_name,
+        &dest_volume,
+        journal::Operation::Reassign {
+            previous_volume: src_volume.clone(),
+            previous_user: user.to_owned(),
+        },
+    )?;
+    transaction.commit()?;
+
+    let storage = filesystem.storage();
+    let renamed = if src_volume == dest_volume {
+        Ok(())
+    } else {
+        storage.rename(&src_volume, &dest_volume)
+    };
+    if journal::resolve(conn, filesystem, &entry)? == journal::Resolution::RolledBack {
+        let message = match renamed {
+            Err(e) => format!("failed to rename {} to {}: {}", src_volume, dest_volume, e),
+            Ok(()) => format!("{} is missing right after renaming it", dest_volume),
+        };
+        events::record(
+            conn,
+            EventKind::Failure,
+            &workspace.filesystem_name,
+            user,
+            &workspace.name,
+            None,
+            Some(&message),
+        )?;
+        return Err(message.into());
+    }
+
+    // Mountpoints set from the filesystem's template don't follow the new owner on their own
+    let mut mountpoint: PathBuf = storage.get_property(&dest_volume, "mountpoint")?;
+    if let Some(templated) =
+        filesystem.mountpoint(&workspace.filesystem_name, user, &workspace.name)
+        && mountpoint == Path::new(&templated)
+        && let Some(new_mountpoint) =
+            filesystem.mountpoint(&workspace.filesystem_name, successor, &workspace.name)
+    {
+        storage.set_property(&dest_volume, "mountpoint", &new_mountpoint)?;
+        mountpoint = new_mountpoint.into();
+    }
+    conn.execute(
+        "UPDATE workspaces SET mountpoint = ?2 WHERE id = ?1",
+        (workspace.id, mountpoint.to_string_lossy()),
+    )?;
+
+    let status = storage.status(
+        "chown",
+        &[
+            "-R",
+            &format!("--from={}", user),
+            successor,
+            &mountpoint.to_string_lossy(),
+        ],
+    )?;
+    if !status.success() {
+        return Err(format!("chown failed with {}", status).into());
+    }
+
+    events::record(
+        conn,
+        EventKind::Reassign,
+        &workspace.filesystem_name,
+        successor,
+        &workspace.name,
+        None,
+        Some(user),
+    )?;
+
+    if let Some(modulefiles) = modulefiles
+        && modulefile::exists(
+            modulefiles,
+            &workspace.filesystem_name,
+            user,
+            &workspace.name,
+        )
+    {
+        modulefile::remove(
+            modulefiles,
+            &workspace.filesystem_name,
+            user,
+            &workspace.name,
+        )?;
+        modulefile::write(
+            modulefiles,
+            &workspace.filesystem_name,
+            successor,
+            &workspace.name,
+            &mountpoint,
+        )?;
+    }
+    if let Err(e) = metadata::write(
+        conn,
+        &workspace.filesystem_name,
+        filesystem,
+        successor,
+        &workspace.name,
+    ) {
+        log::warn!("Failed to write workspace metadata file: {}", e);
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use rusqlite::Connection;
+use users::{get_current_uid, get_current_username, get_user_by_name};
+
+use crate::{cli, error::WorkspacesError};
+
+/// The co-owners of a workspace, who may change it and get its notifications
+/// just like its owner
+pub fn co_owners(conn: &Connection, workspace_id: i64) -> rusqlite::Result<Vec<String>> {
+    conn.prepare("SELECT user FROM co_owners WHERE workspace_id = ?1 ORDER BY user")?
+        .query_map((workspace_id,), |row| row.get(0))?
+        .collect()
+}
+
+/// Whether `me` co-owns the workspace `user` owns
+pub fn is_co_owner(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    me: &str,
+) -> bool {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM co_owners \
+            JOIN workspaces ON workspaces.id = co_owners.workspace_id \
+            WHERE filesystem = ?1 AND workspaces.user = ?2 AND name = ?3 \
+                AND co_owners.user = ?4)",
+        (filesystem_name, user, name, me),
+        |row| row.get(0),
+    )
+    .unwrap_or(false)
+}
+
+/// A notification's body as sent to a co-owner, telling them how to act on it
+pub fn co_owner_body(owner: &str, body: &str) -> String {
+    format!(
+        "{}\n\nYou get this as a co-owner of this workspace of {}; pass `-u {}` to \
+        workspaces commands about it.\n",
+        body.trim_end(),
+        owner,
+        owner
+    )
+}
+
+/// Lists, adds or removes the co-owners of a workspace
+///
+/// Only its owner and admins may change them.
+pub fn owner(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    command: cli::OwnerCommand,
+) -> Result<(), WorkspacesError> {
+    let workspace_id: i64 = match conn.query_row(
+        "SELECT id FROM workspaces WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name),
+        |row| row.get(0),
+    ) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                name,
+            ));
+        }
+        res => res,
+    }?;
+
+    if let cli::OwnerCommand::List { .. } = command {
+        let co_owners = co_owners(conn, workspace_id)?;
+        if co_owners.is_empty() {
+            println!("{} has no co-owners", name);
+        }
+        for co_owner in co_owners {
+            println!("{}", co_owner);
+        }
+        return Ok(());
+    }
+
+    if get_current_uid() != 0 && get_current_username().is_none_or(|me| me != user) {
+        return Err(WorkspacesError::InsufficientPrivileges(
+            "Only the owner of a workspace may change its co-owners".to_owned(),
+        ));
+    }
+    match &command {
+        cli::OwnerCommand::Add { co_owner, .. } => {
+            if get_user_by_name(co_owner).is_none() {
+                return Err(WorkspacesError::InvalidArgument(format!(
+                    "There is no user {}",
+                    co_owner
+                )));
+            }
+            if co_owner == user {
+                return Err(WorkspacesError::InvalidArgument(format!(
+                    "{} already owns {}",
+                    co_owner, name
+                )));
+            }
+            conn.execute(
+                "INSERT OR IGNORE INTO co_owners(workspace_id, user) VALUES (?1, ?2)",
+                (workspace_id, co_owner),
+            )?;
+            println!("{} now co-owns {}", co_owner, name);
+        }
+        cli::OwnerCommand::Remove { co_owner, .. } => {
+            let removed = conn.execute(
+                "DELETE FROM co_owners WHERE workspace_id = ?1 AND user = ?2",
+                (workspace_id, co_owner),
+            )?;
+            if removed == 0 {
+                return Err(WorkspacesError::InvalidArgument(format!(
+                    "{} is not a co-owner of {}",
+                    co_owner, name
+                )));
+            }
+            println!("{} no longer co-owns {}", co_owner, name);
+        }
+        cli::OwnerCommand::List { .. } => unreachable!("listed above"),
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::{
+    error::Error,
+    fs,
+    os::unix::{self, fs::MetadataExt, fs::PermissionsExt},
+    path::Path,
+};
+
+use users::{get_group_by_name, get_user_by_name};
+
+use crate::config;
+
+/// Owner, group and permissions of a mountpoint
+struct Stat {
+    uid: u32,
+    gid: u32,
+    mode: u32,
+}
+
+fn stat(filesystem: &config::Filesystem, mountpoint: &Path) -> Result<Stat, Box<dyn Error>> {
+    let storage = filesystem.storage();
+    if !storage.is_remote() {
+        let metadata = fs::metadata(mountpoint)?;
+        return Ok(Stat {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mode: metadata.mode() & 0o7777,
+        });
+    }
+    let output = storage
+        .host()
+        .output("stat", &["-c", "%u %g %a", &mountpoint.to_string_lossy()])?;
+    if !output.status.success() {
+        return Err(format!(
+            "stat failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [uid, gid, mode] => Ok(Stat {
+            uid: uid.parse()?,
+            gid: gid.parse()?,
+            mode: u32::from_str_radix(mode, 8)?,
+        }),
+        _ => Err(format!("unexpected output of stat: {}", stdout.trim()).into()),
+    }
+}
+
+/// Checks that a workspace's mountpoint still belongs to its owner, and to
+/// the group owning it if any, with them having full access
+///
+/// Returns what drifted, if anything.  With `fix`, the owner, group and
+/// missing permissions are restored; other permission bits are left alone,
+/// as owners may have changed them on purpose.
+pub fn verify(
+    filesystem: &config::Filesystem,
+    mountpoint: &Path,
+    user: &str,
+    owner_group: Option<&str>,
+    fix: bool,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let owner = get_user_by_name(user).ok_or("workspace owner does not exist")?;
+    let group = match owner_group {
+        Some(group) => Some(
+            get_group_by_name(group).ok_or_else(|| format!("group {} does not exist", group))?,
+        ),
+        None => None,
+    };
+    let stat = stat(filesystem, mountpoint)?;
+
+    let mut drift = Vec::new();
+    let mut required_mode = 0o700;
+    if stat.uid != owner.uid() {
+        drift.push(format!("owned by uid {} instead of {}", stat.uid, user));
+    }
+    if let Some(group) = &group {
+        if stat.gid != group.gid() {
+            drift.push(format!(
+                "group is gid {} instead of {}",
+                stat.gid,
+                group.name().to_string_lossy()
+            ));
+        }
+        required_mode |= 0o2070;
+    }
+    if stat.mode & required_mode != required_mode {
+        drift.push(format!("mode {:o} lacks {:o}", stat.mode, required_mode));
+    }
+    if drift.is_empty() {
+        return Ok(None);
+    }
+    let mut drift = drift.join(", ");
+    if !fix {
+        return Ok(Some(drift));
+    }
+
+    let gid = group.as_ref().map_or(stat.gid, |group| group.gid());
+    let mode = stat.mode | required_mode;
+    let storage = filesystem.storage();
+    if storage.is_remote() {
+        let mountpoint = mountpoint.to_string_lossy();
+        let status =
+            storage.status("chown", &[&format!("{}:{}", owner.uid(), gid), &mountpoint])?;
+        if !status.success() {
+            return Err("failed to change owner".into());
+        }
+        let status = storage.status("chmod", &[&format!("{:o}", mode), &mountpoint])?;
+        if !status.success() {
+            return Err("failed to change permissions".into());
+        }
+    } else {
+        // Changing the owner may clear the setgid bit, so set the mode afterwards
+        unix::fs::chown(mountpoint, Some(owner.uid()), Some(gid))?;
+        fs::set_permissions(mountpoint, fs::Permissions::from_mode(mode))?;
+    }
+    drift.push_str(" (fixed)");
+    Ok(Some(drift))
+}
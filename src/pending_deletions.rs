@@ -0,0 +1,152 @@
+use std::{collections::HashMap, error::Error};
+
+use chrono::{DateTime, Local, Utc};
+use prettytable::{
+    Attr, Cell, Row, Table, color,
+    format::{Alignment, FormatBuilder},
+};
+use rusqlite::Connection;
+use users::get_current_uid;
+
+use crate::{clock, config, error::WorkspacesError, list, to_volume_string};
+
+/// Prints all workspaces the next `workspaces maintain` run will destroy
+///
+/// With `private_listing`, users only get the workspaces `list` shows them.
+pub fn pending_deletions(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    private_listing: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["NAME", "USER", "FS", "SIZE", "EXPIRED", "STATUS"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    let (mut reclaimable, mut count, mut held, mut failed) = (0, 0, 0, 0);
+    let visible = if private_listing && get_current_uid() != 0 {
+        Some(list::visible_workspaces(conn)?)
+    } else {
+        None
+    };
+    let mut statement = conn.prepare(
+        "SELECT filesystem, user, name, expiration_time, root, deletion_held, dataset, id \
+            FROM workspaces ORDER BY expiration_time",
+    )?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(7)?;
+        if visible
+            .as_ref()
+            .is_some_and(|visible| !visible.contains(&id))
+        {
+            continue;
+        }
+        let filesystem_name: String = row.get(0)?;
+        let user: String = row.get(1)?;
+        let name: String = row.get(2)?;
+        let expiration_time: DateTime<Utc> = row.get(3)?;
+        let root: Option<String> = row.get(4)?;
+        let deletion_held: bool = row.get(5)?;
+        let dataset: String = row.get(6)?;
+
+        let filesystem = filesystems
+            .get(&filesystem_name)
+            .expect("found workspace in database without corresponding config entry");
+        if expiration_time >= clock::now() - filesystem.expired_retention {
+            continue;
+        }
+
+        // Including snapshots, i.e. everything that is freed by destroying it
+        let volume = to_volume_string(filesystem.root(root.as_deref()), &dataset);
+        let used = filesystem.storage().get_property::<usize>(&volume, "used");
+        let status = if deletion_held {
+            held += 1;
+            Cell::new("on hold").with_style(Attr::ForegroundColor(color::YELLOW))
+        } else {
+            count += 1;
+            match used {
+                Ok(used) => reclaimable += used,
+                Err(_) => failed += 1,
+            }
+            Cell::new("deleted next")
+                .with_style(Attr::Bold)
+                .with_style(Attr::ForegroundColor(color::RED))
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&name),
+            Cell::new(&user),
+            Cell::new(&filesystem_name),
+            match used {
+                Ok(used) => Cell::new_align(&format!("{}G", used / (1 << 30)), Alignment::RIGHT),
+                Err(_) => Cell::new_align("?", Alignment::RIGHT),
+            },
+            Cell::new(
+                &expiration_time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d")
+                    .to_string(),
+            ),
+            status,
+        ]));
+    }
+
+    table.printstd();
+    println!(
+        "\n{} workspace(s) will be deleted, reclaiming {}G{}",
+        count,
+        reclaimable / (1 << 30),
+        if held > 0 {
+            format!("; {} more on hold", held)
+        } else {
+            String::new()
+        }
+    );
+    if failed > 0 {
+        eprintln!(
+            "Warning: could not query ZFS for {} workspace(s); \
+            their size is not included above",
+            failed
+        );
+    }
+    Ok(())
+}
+
+/// Holds off or allows the deletion of a workspace past its retention period
+pub fn hold(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    held: bool,
+) -> Result<(), WorkspacesError> {
+    let updated = conn.execute(
+        "UPDATE workspaces SET deletion_held = ?4 \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name, held),
+    )?;
+    if updated == 0 {
+        return Err(WorkspacesError::unknown_workspace(
+            filesystem_name,
+            user,
+            name,
+        ));
+    }
+
+    if held {
+        println!(
+            "Holding off the deletion of workspace {} of {} on {}",
+            name, user, filesystem_name
+        );
+    } else {
+        println!(
+            "Workspace {} of {} on {} will be deleted once its retention period has elapsed",
+            name, user, filesystem_name
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use users::{get_current_gid, get_current_uid, get_user_by_name, os::unix::UserExt, switch};
+
+use crate::{cli, config, error::WorkspacesError};
+
+/// Database user preferences are kept in, unless they are kept in the users'
+/// home directories
+static DATABASE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps user preferences in the database at `db_path` from now on
+pub fn store_in_database(db_path: &Path) {
+    let _ = DATABASE.set(db_path.to_owned());
+}
+
+/// A user's `~/.config/workspaces.toml`
+pub fn home_path(username: &str) -> io::Result<PathBuf> {
+    let user = get_user_by_name(username).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such user {}", username),
+        )
+    })?;
+    Ok(user.home_dir().join(".config/workspaces.toml"))
+}
+
+/// Where a user's preferences are kept, for messages
+pub fn location(username: &str) -> String {
+    match DATABASE.get() {
+        Some(_) => "the workspaces database".to_owned(),
+        None => home_path(username).map_or_else(
+            |_| "~/.config/workspaces.toml".to_owned(),
+            |path| path.display().to_string(),
+        ),
+    }
+}
+
+/// Reads a user's preferences, as TOML
+///
+/// With preferences in the database, users who haven't stored any there yet
+/// still get the ones from their home directory.
+pub fn read(username: &str) -> io::Result<String> {
+    if let Some(db_path) = DATABASE.get() {
+        let stored = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .and_then(|conn| {
+                // Readers only wait for the rare recovery or checkpoint in WAL mode
+                conn.busy_timeout(Duration::from_secs(10))?;
+                conn.query_row(
+                    "SELECT preferences FROM user_preferences WHERE user = ?1",
+                    (username,),
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+            })
+            .map_err(io::Error::other)?;
+        if let Some(preferences) = stored {
+            return Ok(preferences);
+        }
+    }
+    read_home(username)
+}
+
+/// Reads a user's `~/.config/workspaces.toml`, if it is theirs
+///
+/// We run setuid root, so the file mustn't be a symlink or hard link to one
+/// only root may read, e.g. `/etc/shadow`.
+fn read_home(username: &str) -> io::Result<String> {
+    let user = get_user_by_name(username).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such user {}", username),
+        )
+    })?;
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(user.home_dir().join(".config/workspaces.toml"))
+        .map_err(|err| match err.raw_os_error() {
+            Some(libc::ELOOP) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "~/.config/workspaces.toml must not be a symlink",
+            ),
+            _ => err,
+        })?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.uid() != user.uid() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "~/.config/workspaces.toml must be a file owned by {}",
+                username
+            ),
+        ));
+    }
+    let mut toml_str = String::new();
+    file.read_to_string(&mut toml_str)?;
+    Ok(toml_str)
+}
+
+/// Replaces the current user's preferences
+pub fn write(username: &str, toml_str: &str) -> Result<(), Box<dyn Error>> {
+    // Make sure notifications will actually pick them up
+    toml::from_str::<config::UserConfig>(toml_str)?;
+
+    if let Some(db_path) = DATABASE.get() {
+        let conn = Connection::open(db_path)?;
+        conn.busy_handler(Some(crate::wait_for_lock))?;
+        conn.execute(
+            "INSERT INTO user_preferences(user, preferences) VALUES(?1, ?2) \
+                ON CONFLICT(user) DO UPDATE SET preferences = excluded.preferences",
+            (username, toml_str),
+        )?;
+        return Ok(());
+    }
+
+    // We run setuid root, so only touch the user's files with their own privileges
+    let _guard = switch::switch_user_group(get_current_uid(), get_current_gid())?;
+    write_atomically(&home_path(username)?, toml_str)?;
+    Ok(())
+}
+
+/// A user's preferences as a table to change, empty if they have none yet
+pub fn table(username: &str) -> Result<toml::Table, Box<dyn Error>> {
+    match read(username) {
+        Ok(toml_str) => match toml::from_str::<toml::Table>(&toml_str) {
+            Ok(table) => Ok(table),
+            // Only the message, not the offending line it quotes
+            Err(e) => Err(WorkspacesError::InvalidArgument(format!(
+                "The preferences in {} are not valid TOML, please fix or remove them first: {}",
+                location(username),
+                e.message()
+            ))
+            .into()),
+        },
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(toml::Table::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Shows or changes the current user's preferences
+pub fn prefs(username: &str, command: Option<cli::PrefsCommand>) -> Result<(), Box<dyn Error>> {
+    let mut preferences = table(username)?;
+    let (key, value) = match command {
+        None | Some(cli::PrefsCommand::Show) => {
+            println!("Stored in {}", location(username));
+            for key in cli::PrefsKey::ALL {
+                match preferences.get(key.toml_key()) {
+                    Some(toml::Value::String(value)) => println!("{}: {}", key, value),
+                    Some(value) => println!("{}: {}", key, value),
+                    None => println!("{}: (not set)", key),
+                }
+            }
+            return Ok(());
+        }
+        Some(cli::PrefsCommand::Set { key, value }) => (key, Some(value)),
+        Some(cli::PrefsCommand::Unset { key }) => (key, None),
+    };
+    match &value {
+        Some(value) => preferences.insert(key.toml_key().to_owned(), value.as_str().into()),
+        None => preferences.remove(key.toml_key()),
+    };
+    let toml_str = toml::to_string(&preferences)?;
+    if let Err(e) = toml::from_str::<config::UserConfig>(&toml_str) {
+        return Err(WorkspacesError::InvalidArgument(format!(
+            "Invalid preferences: {}",
+            e.message()
+        ))
+        .into());
+    }
+    write(username, &toml_str)?;
+    match value {
+        Some(value) => println!("Set {} to {}", key, value),
+        None => println!("Unset {}", key),
+    }
+    Ok(())
+}
+
+/// Replaces `path` with a file containing `contents`, never leaving it half-written
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("toml.tmp");
+    match fs::remove_file(&tmp_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o644)
+        .open(&tmp_path)?
+        .write_all(contents.as_bytes())?;
+    fs::rename(tmp_path, path)
+}
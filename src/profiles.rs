@@ -0,0 +1,73 @@
+use std::{collections::HashMap, error::Error};
+
+use prettytable::{
+    Attr, Cell, Row, Table,
+    format::{Alignment, FormatBuilder},
+};
+
+use crate::config;
+
+/// Prints the profiles `workspaces create --profile` accepts
+pub fn profiles(profiles: &HashMap<String, config::Profile>) -> Result<(), Box<dyn Error>> {
+    if profiles.is_empty() {
+        println!("No profiles are configured");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        [
+            "NAME",
+            "FS",
+            "DURATION",
+            "QUOTA",
+            "PROPERTIES",
+            "SKELETON",
+            "DESCRIPTION",
+        ]
+        .iter()
+        .map(|h| Cell::new(h).with_style(Attr::Bold))
+        .collect(),
+    ));
+
+    let mut names: Vec<_> = profiles.keys().collect();
+    names.sort();
+    for name in names {
+        let profile = &profiles[name];
+        table.add_row(Row::new(vec![
+            Cell::new(name),
+            Cell::new(profile.filesystem.as_deref().unwrap_or("")),
+            Cell::new_align(
+                &profile
+                    .duration
+                    .map(|duration| format!("{}d", duration.num_days()))
+                    .unwrap_or_default(),
+                Alignment::RIGHT,
+            ),
+            Cell::new_align(
+                &profile.quota.map(config::format_size).unwrap_or_default(),
+                Alignment::RIGHT,
+            ),
+            Cell::new(
+                &profile
+                    .properties
+                    .iter()
+                    .map(|(property, value)| format!("{}={}", property, value))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            Cell::new(
+                &profile
+                    .skeleton
+                    .as_ref()
+                    .map(|skeleton| skeleton.display().to_string())
+                    .unwrap_or_default(),
+            ),
+            Cell::new(&profile.description),
+        ]));
+    }
+
+    table.printstd();
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use rusqlite::Connection;
+
+use crate::{error::WorkspacesError, may_change};
+
+/// Lists a workspace to everyone with `list --public`, or stops doing so
+///
+/// A previous description is kept unless a new one is given.
+pub fn publish(
+    conn: &Connection,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    description: Option<&str>,
+    public: bool,
+) -> Result<(), WorkspacesError> {
+    if !may_change(conn, filesystem_name, user, name) {
+        return Err(WorkspacesError::not_allowed());
+    }
+
+    let updated = conn.execute(
+        "UPDATE workspaces SET public = ?4, description = COALESCE(?5, description) \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name, public, description),
+    )?;
+    if updated == 0 {
+        return Err(WorkspacesError::unknown_workspace(
+            filesystem_name,
+            user,
+            name,
+        ));
+    }
+
+    if public {
+        println!("Workspace {} is listed publicly", name);
+    } else {
+        println!("Workspace {} is no longer listed publicly", name);
+    }
+    Ok(())
+}
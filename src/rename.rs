@@ -1,11 +1,22 @@
-use std::{error::Error, process};
+use std::path::{Path, PathBuf};
 
-use rusqlite::Connection;
-use users::{get_current_uid, get_current_username};
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, TransactionBehavior};
+use users::get_current_uid;
 
-use crate::{config, to_volume_string, zfs, ExitCodes};
+use crate::{
+    cli::CommandFormat,
+    clock, config,
+    error::WorkspacesError,
+    events::{self, EventKind},
+    journal, json, may_change, modulefile, moved_dataset, to_volume_string,
+};
 
 /// Renames an existing workspace
+///
+/// Expired and read-only workspaces are only renamed with `force`.  The new
+/// name is only kept once the dataset was actually renamed.
+#[allow(clippy::too_many_arguments)]
 pub fn rename(
     conn: &mut Connection,
     filesystem_name: &str,
@@ -13,24 +24,69 @@ pub fn rename(
     user: &str,
     src_name: &str,
     dest_name: &str,
-) -> Result<(), Box<dyn Error>> {
-    if get_current_username().unwrap() != user && get_current_uid() != 0 {
-        eprintln!("You are not allowed to execute this operation");
-        process::exit(ExitCodes::InsufficientPrivileges as i32);
+    force: bool,
+    modulefiles: &Option<config::ModulefilesConfig>,
+    format: CommandFormat,
+) -> Result<(), WorkspacesError> {
+    if !may_change(conn, filesystem_name, user, src_name) {
+        return Err(WorkspacesError::not_allowed());
     }
     if filesystem.disabled && get_current_uid() != 0 {
-        eprintln!("Filesystem is disabled. Please try another filesystem.");
-        process::exit(ExitCodes::FsDisabled as i32);
+        return Err(WorkspacesError::FsDisabled(format!(
+            "Filesystem is disabled. Please try another filesystem.{}",
+            filesystem.contact_hint()
+        )));
+    }
+
+    // Take the write lock right away, a deferred upgrade fails instead of waiting
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let (workspace_id, root, src_dataset, expiration_time): (
+        i64,
+        Option<String>,
+        String,
+        DateTime<Utc>,
+    ) = match transaction
+        .prepare(
+            "SELECT id, root, dataset, expiration_time FROM workspaces \
+                WHERE filesystem = ?1 \
+                    AND user = ?2 \
+                    AND name = ?3",
+        )?
+        .query_row((filesystem_name, user, src_name), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        }) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                src_name,
+            ));
+        }
+        res => res,
+    }?;
+
+    let root = filesystem.root(root.as_deref());
+    let dest_dataset = moved_dataset(&src_dataset, user, src_name, user, dest_name)?;
+    let src_volume = to_volume_string(root, &src_dataset);
+    let dest_volume = to_volume_string(root, &dest_dataset);
+    let readonly = filesystem
+        .storage()
+        .get_property::<String>(&src_volume, "readonly")?
+        == "on";
+    if (readonly || expiration_time < clock::now()) && !force {
+        return Err(WorkspacesError::WorkspaceExpired(format!(
+            "Workspace {} is expired or read-only. Use --force to rename it anyway.",
+            src_name
+        )));
     }
 
-    let transaction = conn.transaction()?;
     match transaction.execute(
         "UPDATE workspaces \
-            SET name = ?1 \
+            SET name = ?1, dataset = ?5 \
             WHERE filesystem = ?2 \
                 AND user = ?3 \
                 AND name = ?4",
-        (dest_name, filesystem_name, user, src_name),
+        (dest_name, filesystem_name, user, src_name, &dest_dataset),
     ) {
         Ok(_) => {}
         Err(rusqlite::Error::SqliteFailure(
@@ -40,16 +96,108 @@ pub fn rename(
             },
             _,
         )) => {
-            eprintln!("The target workspace already exists");
-            process::exit(ExitCodes::WorkspaceExists as i32);
+            return Err(WorkspacesError::WorkspaceExists(
+                "The target workspace already exists".to_owned(),
+            ));
         }
         Err(_) => unreachable!(),
     }
 
-    let src_volume = to_volume_string(&filesystem.root, user, src_name);
-    let dest_volume = to_volume_string(&filesystem.root, user, dest_name);
-    zfs::rename(&src_volume, &dest_volume)?;
+    let entry = journal::begin(
+        &transaction,
+        workspace_id,
+        filesystem_name,
+        &dest_volume,
+        journal::Operation::Rename {
+            previous_volume: src_volume.clone(),
+            previous_name: src_name.to_owned(),
+            readonly,
+        },
+    )?;
     transaction.commit()?;
 
+    let storage = filesystem.storage();
+    // Datasets not named after the workspace, e.g. flat ones, stay as they are
+    let renamed = if src_volume == dest_volume {
+        Ok(())
+    } else if readonly {
+        storage
+            .set_property(&src_volume, "readonly", "off")
+            .and_then(|()| storage.rename(&src_volume, &dest_volume))
+    } else {
+        storage.rename(&src_volume, &dest_volume)
+    };
+    // Keeps the new name only if the dataset actually has it now
+    if journal::resolve(conn, filesystem, &entry)? == journal::Resolution::RolledBack {
+        let message = match renamed {
+            Err(e) => format!("failed to rename {} to {}: {}", src_volume, dest_volume, e),
+            Ok(()) => format!("{} is missing right after renaming it", dest_volume),
+        };
+        events::record(
+            conn,
+            EventKind::Failure,
+            filesystem_name,
+            user,
+            src_name,
+            None,
+            Some(&message),
+        )?;
+        return Err(message.into());
+    }
+
+    // What was done, for `--format json`
+    let mut actions = vec!["renamed"];
+    // Mountpoints set from the filesystem's template don't follow the new name on their own
+    let mut mountpoint: PathBuf = storage.get_property(&dest_volume, "mountpoint")?;
+    if let Some(templated) = filesystem.mountpoint(filesystem_name, user, src_name)
+        && mountpoint == Path::new(&templated)
+        && let Some(new_mountpoint) = filesystem.mountpoint(filesystem_name, user, dest_name)
+    {
+        storage.set_property(&dest_volume, "mountpoint", &new_mountpoint)?;
+        mountpoint = new_mountpoint.into();
+        actions.push("mountpoint");
+    }
+    conn.execute(
+        "UPDATE workspaces SET mountpoint = ?2 WHERE id = ?1",
+        (workspace_id, mountpoint.to_string_lossy()),
+    )?;
+    events::record(
+        conn,
+        EventKind::Rename,
+        filesystem_name,
+        user,
+        dest_name,
+        None,
+        Some(src_name),
+    )?;
+
+    // Move the modulefile along with the workspace
+    if let Some(modulefiles) = modulefiles
+        && modulefile::exists(modulefiles, filesystem_name, user, src_name)
+    {
+        modulefile::remove(modulefiles, filesystem_name, user, src_name)?;
+        modulefile::write(modulefiles, filesystem_name, user, dest_name, &mountpoint)?;
+        actions.push("modulefile");
+    }
+
+    if format == CommandFormat::Json {
+        json::print_line(&json::object([
+            ("action", "rename".into()),
+            ("id", workspace_id.into()),
+            ("filesystem", filesystem_name.into()),
+            ("user", user.into()),
+            ("name", dest_name.into()),
+            ("previous_name", src_name.into()),
+            (
+                "mountpoint",
+                mountpoint.to_string_lossy().into_owned().into(),
+            ),
+            ("expiration_time", expiration_time.to_rfc3339().into()),
+            (
+                "actions",
+                json::Value::Array(actions.into_iter().map(Into::into).collect()),
+            ),
+        ]))?;
+    }
     Ok(())
 }
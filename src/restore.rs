@@ -0,0 +1,137 @@
+use std::error::Error;
+
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{Connection, TransactionBehavior};
+use users::get_current_uid;
+
+use crate::{
+    banner, clock, config, create,
+    error::WorkspacesError,
+    events::{self, EventKind},
+    journal, may_change, metadata, workspace_volume,
+};
+
+/// Makes an expired workspace writable again, before it is deleted
+///
+/// The workspace expires again after the filesystem's `restore_grace_days`.
+pub fn restore(
+    conn: &mut Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    if !may_change(conn, filesystem_name, user, name) {
+        return Err(WorkspacesError::not_allowed().into());
+    }
+    if filesystem.disabled && get_current_uid() != 0 {
+        return Err(WorkspacesError::FsDisabled(format!(
+            "Filesystem is disabled. Please recreate workspace on another filesystem.{}",
+            filesystem.contact_hint()
+        ))
+        .into());
+    }
+    // A restored workspace counts against the user's limits again
+    if get_current_uid() != 0 {
+        let bytes = match filesystem.max_total_bytes_per_user {
+            Some(_) => create::footprint(
+                filesystem,
+                &workspace_volume(conn, filesystem_name, filesystem, user, name)?,
+            )?,
+            None => 0,
+        };
+        create::check_user_limits(conn, filesystem_name, filesystem, user, bytes)?;
+    }
+
+    // Take the write lock right away, so `maintain` can't delete the workspace
+    // between looking it up and restoring it
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let (workspace_id, expiration_time): (i64, DateTime<Utc>) = match transaction.query_row(
+        "SELECT id, expiration_time FROM workspaces \
+                WHERE filesystem = ?1 \
+                    AND user = ?2 \
+                    AND name = ?3",
+        (filesystem_name, user, name),
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(filesystem_name, user, name).into());
+        }
+        res => res,
+    }?;
+
+    if expiration_time > clock::now() {
+        return Err(WorkspacesError::InvalidArgument(
+            "Workspace has not expired. Use `workspaces extend` to postpone its expiry.".to_owned(),
+        )
+        .into());
+    }
+    if expiration_time < clock::now() - filesystem.expired_retention {
+        return Err(WorkspacesError::WorkspaceExpired(format!(
+            "Workspace is already due for deletion and cannot be restored anymore.{}",
+            filesystem.contact_hint()
+        ))
+        .into());
+    }
+
+    let new_expiration = clock::now() + filesystem.restore_grace_days;
+    transaction.execute(
+        "UPDATE workspaces SET expiration_time = ?2 WHERE id = ?1",
+        (workspace_id, new_expiration),
+    )?;
+
+    // `workspaces expire` may have created a faux notification in the future
+    // to silence further notifications;
+    // Remove those!
+    transaction.execute(
+        "DELETE FROM notifications \
+            WHERE workspace_id = ?1 \
+                AND channel IS NULL \
+                AND unixepoch(timestamp) > unixepoch(?2)",
+        (workspace_id, clock::now()),
+    )?;
+
+    let volume = workspace_volume(&transaction, filesystem_name, filesystem, user, name)?;
+    let entry = journal::begin(
+        &transaction,
+        workspace_id,
+        filesystem_name,
+        &volume,
+        journal::Operation::Readonly(false),
+    )?;
+    transaction.commit()?;
+
+    journal::resolve(conn, filesystem, &entry)?;
+
+    events::record(
+        conn,
+        EventKind::Restore,
+        filesystem_name,
+        user,
+        name,
+        Some(new_expiration),
+        None,
+    )?;
+
+    if let Err(e) = metadata::write(conn, filesystem_name, filesystem, user, name) {
+        log::warn!("Failed to write workspace metadata file: {}", e);
+    }
+    if let Err(e) = banner::update(
+        filesystem_name,
+        filesystem,
+        &workspace_volume(conn, filesystem_name, filesystem, user, name)?,
+        name,
+        new_expiration,
+    ) {
+        log::warn!("Failed to update expiry banner: {}", e);
+    }
+
+    println!(
+        "Workspace restored, it expires again on {}",
+        new_expiration
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+    );
+
+    Ok(())
+}
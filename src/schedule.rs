@@ -0,0 +1,276 @@
+use chrono::{DateTime, Duration, Utc};
+
+use crate::notify::NotificationKind;
+
+/// The most days before or after expiry a per-workspace warning may be due on
+pub const MAX_DAYS: i64 = 3650;
+
+/// A notification recorded for a workspace
+#[derive(Debug, Clone, Copy)]
+pub struct Recorded {
+    /// `None` for entries from before kinds were recorded
+    pub kind: Option<NotificationKind>,
+    pub time: DateTime<Utc>,
+    /// Whether it was actually sent, instead of only holding off warnings
+    pub delivered: bool,
+}
+
+/// Decides which warning, if any, is due for a workspace
+///
+/// `schedule` holds the days before expiry warnings are due on, negative ones
+/// falling into the retention period.  A warning is due once its deadline has
+/// passed, unless the owner has heard from us (or acted) since.  Missed
+/// deadlines don't add up: only the most recent one counts, so a workspace
+/// which wasn't looked at for a while gets a single warning, not one per
+/// deadline.
+///
+/// Owners who expired their own workspace don't get any further warnings,
+/// until it is extended again.
+pub fn due_warning(
+    now: DateTime<Utc>,
+    expiration_time: DateTime<Utc>,
+    schedule: &[Duration],
+    recorded: &[Recorded],
+) -> Option<NotificationKind> {
+    let expired_by_owner = recorded.iter().any(|r| {
+        matches!(r.kind, Some(NotificationKind::Expired))
+            && !r.delivered
+            && r.time >= expiration_time
+    });
+    if expired_by_owner {
+        return None;
+    }
+
+    let deadline = schedule
+        .iter()
+        .filter_map(|days| expiration_time.checked_sub_signed(*days))
+        .filter(|deadline| *deadline <= now)
+        .max()?;
+    if recorded.iter().any(|r| r.time >= deadline) {
+        return None;
+    }
+
+    Some(if now < expiration_time {
+        NotificationKind::ExpiryWarning
+    } else {
+        NotificationKind::DeletionWarning
+    })
+}
+
+/// Serializes a per-workspace schedule for the database
+pub fn to_db_string(days: &[i64]) -> String {
+    days.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parses a per-workspace schedule as stored in the database
+pub fn from_db_string(days: &str) -> Vec<Duration> {
+    let mut days: Vec<i64> = days
+        .split(',')
+        .filter_map(|day| day.trim().parse().ok())
+        .filter(|day: &i64| day.abs() <= MAX_DAYS)
+        .collect();
+    days.sort();
+    days.into_iter().map(Duration::days).collect()
+}
+
+/// Describes a schedule for humans, earliest warning first
+pub fn describe(schedule: &[Duration]) -> String {
+    if schedule.is_empty() {
+        return "none".to_owned();
+    }
+    let mut days: Vec<i64> = schedule.iter().map(Duration::num_days).collect();
+    days.sort_by(|a, b| b.cmp(a));
+    format!(
+        "{} days before expiry",
+        to_db_string(&days).replace(',', ", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn days(days: &[i64]) -> Vec<Duration> {
+        days.iter().map(|d| Duration::days(*d)).collect()
+    }
+
+    fn recorded(kind: NotificationKind, time: DateTime<Utc>, delivered: bool) -> Recorded {
+        Recorded {
+            kind: Some(kind),
+            time,
+            delivered,
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        "2026-01-15T12:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn nothing_due_before_first_deadline() {
+        let expiry = now() + Duration::days(20);
+        assert!(due_warning(now(), expiry, &days(&[0, 7, 14]), &[]).is_none());
+    }
+
+    #[test]
+    fn nothing_due_without_schedule() {
+        let expiry = now() - Duration::days(1);
+        assert!(due_warning(now(), expiry, &[], &[]).is_none());
+    }
+
+    #[test]
+    fn expiry_warning_once_deadline_passed() {
+        let expiry = now() + Duration::days(10);
+        assert!(matches!(
+            due_warning(now(), expiry, &days(&[0, 7, 14]), &[]),
+            Some(NotificationKind::ExpiryWarning)
+        ));
+    }
+
+    #[test]
+    fn deadline_is_inclusive() {
+        let expiry = now() + Duration::days(14);
+        assert!(due_warning(now(), expiry, &days(&[14]), &[]).is_some());
+    }
+
+    #[test]
+    fn no_double_send_for_same_deadline() {
+        let expiry = now() + Duration::days(10);
+        let sent = [recorded(
+            NotificationKind::ExpiryWarning,
+            now() - Duration::hours(12),
+            true,
+        )];
+        assert!(due_warning(now(), expiry, &days(&[0, 7, 14]), &sent).is_none());
+    }
+
+    #[test]
+    fn next_deadline_after_previous_warning() {
+        let expiry = now() + Duration::days(6);
+        let sent = [recorded(
+            NotificationKind::ExpiryWarning,
+            now() - Duration::days(3),
+            true,
+        )];
+        assert!(matches!(
+            due_warning(now(), expiry, &days(&[0, 7, 14]), &sent),
+            Some(NotificationKind::ExpiryWarning)
+        ));
+    }
+
+    #[test]
+    fn missed_deadlines_only_warn_once() {
+        // Both the 14 and 7 day deadlines were missed
+        let expiry = now() + Duration::days(3);
+        let schedule = days(&[0, 7, 14]);
+        assert!(due_warning(now(), expiry, &schedule, &[]).is_some());
+        let sent = [recorded(NotificationKind::ExpiryWarning, now(), true)];
+        let later = now() + Duration::hours(1);
+        assert!(due_warning(later, expiry, &schedule, &sent).is_none());
+    }
+
+    #[test]
+    fn negative_days_warn_about_deletion() {
+        let expiry = now() - Duration::days(12);
+        assert!(matches!(
+            due_warning(now(), expiry, &days(&[-20, -10, 0, 7]), &[]),
+            Some(NotificationKind::DeletionWarning)
+        ));
+    }
+
+    #[test]
+    fn negative_days_not_due_before_their_deadline() {
+        // Expired 5 days ago, the warning at expiry was already sent
+        let expiry = now() - Duration::days(5);
+        let sent = [recorded(
+            NotificationKind::DeletionWarning,
+            expiry + Duration::hours(1),
+            true,
+        )];
+        assert!(due_warning(now(), expiry, &days(&[-10, 0]), &sent).is_none());
+    }
+
+    #[test]
+    fn zero_day_deadline_warns_about_deletion() {
+        let expiry = now() - Duration::hours(1);
+        assert!(matches!(
+            due_warning(now(), expiry, &days(&[0]), &[]),
+            Some(NotificationKind::DeletionWarning)
+        ));
+    }
+
+    #[test]
+    fn schedule_order_does_not_matter() {
+        let expiry = now() + Duration::days(6);
+        let sent = [recorded(
+            NotificationKind::ExpiryWarning,
+            now() - Duration::days(3),
+            true,
+        )];
+        assert!(due_warning(now(), expiry, &days(&[14, 0, 7]), &sent).is_some());
+    }
+
+    #[test]
+    fn creation_holds_off_already_passed_deadlines() {
+        // Created an hour ago with a 5-day duration
+        let expiry = now() + Duration::days(5);
+        let marker = [recorded(
+            NotificationKind::Created,
+            now() - Duration::hours(1),
+            false,
+        )];
+        assert!(due_warning(now(), expiry, &days(&[0, 7, 14]), &marker).is_none());
+        // ... but not the ones that pass afterwards
+        let at_expiry = expiry + Duration::minutes(1);
+        assert!(due_warning(at_expiry, expiry, &days(&[0, 7, 14]), &marker).is_some());
+    }
+
+    #[test]
+    fn expired_by_owner_gets_no_deletion_warnings() {
+        // `workspaces expire` moved the expiry to now and left a marker there
+        let expiry = now() - Duration::days(15);
+        let marker = [recorded(NotificationKind::Expired, expiry, false)];
+        assert!(due_warning(now(), expiry, &days(&[-10, 0]), &marker).is_none());
+    }
+
+    #[test]
+    fn expired_by_admin_still_gets_deletion_warnings() {
+        let expiry = now() - Duration::days(15);
+        let sent = [recorded(NotificationKind::Expired, expiry, true)];
+        assert!(due_warning(now(), expiry, &days(&[-10, 0]), &sent).is_some());
+    }
+
+    #[test]
+    fn extending_lifts_owner_expiry() {
+        // Expired by the owner, then extended: the marker predates the new expiry
+        let marker_time = now() - Duration::days(2);
+        let expiry = now() + Duration::days(3);
+        let recorded = [
+            recorded(NotificationKind::Expired, marker_time, false),
+            recorded(
+                NotificationKind::Extended,
+                marker_time + Duration::hours(1),
+                false,
+            ),
+        ];
+        let deadline_passed = now() + Duration::days(2) + Duration::hours(1);
+        assert!(matches!(
+            due_warning(deadline_passed, expiry, &days(&[1]), &recorded),
+            Some(NotificationKind::ExpiryWarning)
+        ));
+    }
+
+    #[test]
+    fn legacy_entries_count_as_contact() {
+        let expiry = now() + Duration::days(6);
+        let legacy = [Recorded {
+            kind: None,
+            time: now() - Duration::hours(2),
+            delivered: false,
+        }];
+        assert!(due_warning(now(), expiry, &days(&[7]), &legacy).is_none());
+    }
+}
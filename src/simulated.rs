@@ -0,0 +1,89 @@
+use crate::storage::{Host, Result, Storage};
+use std::path::{Path, PathBuf};
+
+/// Stands in for the storage of `maintain --simulate`, touching nothing
+///
+/// Every volume exists, is read-only, has no quota and doesn't use any space,
+/// so only what the database and the configuration decide is simulated.
+/// Changes are printed instead of made.
+pub struct Simulated<'a> {
+    host: Host<'a>,
+}
+
+impl<'a> Simulated<'a> {
+    pub fn new(host: Host<'a>) -> Self {
+        Simulated { host }
+    }
+}
+
+impl Storage for Simulated<'_> {
+    fn host(&self) -> &Host<'_> {
+        &self.host
+    }
+
+    fn create(&self, volume: &str, _properties: &[(&str, &str)]) -> Result<()> {
+        println!("Would create {}", volume);
+        Ok(())
+    }
+
+    fn exists(&self, _volume: &str) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn destroy(&self, volume: &str) -> Result<()> {
+        println!("Would destroy {}", volume);
+        Ok(())
+    }
+
+    fn rename(&self, src_volume: &str, dest_volume: &str) -> Result<()> {
+        println!("Would rename {} to {}", src_volume, dest_volume);
+        Ok(())
+    }
+
+    fn property(&self, volume: &str, property: &str) -> Result<String> {
+        Ok(match property {
+            "mountpoint" => format!("/{}", volume.trim_start_matches('/')),
+            "readonly" => "on".to_owned(),
+            "snapdir" => "hidden".to_owned(),
+            _ => "0".to_owned(),
+        })
+    }
+
+    fn set_property(&self, volume: &str, property: &str, value: &str) -> Result<()> {
+        println!("Would set {}={} on {}", property, value, volume);
+        Ok(())
+    }
+
+    fn list_snapshots(&self, _volume: &str) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn snapshot_named(&self, volume: &str, snapshot_name: &str) -> Result<()> {
+        println!("Would snapshot {}@{}", volume, snapshot_name);
+        Ok(())
+    }
+
+    fn snapshot(&self, volume: &str, prefix: &str) -> Result<()> {
+        println!("Would snapshot {} with prefix {}", volume, prefix);
+        Ok(())
+    }
+
+    fn snapshot_each(&self, volumes: &[String], prefix: &str) -> Result<()> {
+        for volume in volumes {
+            self.snapshot(volume, prefix)?;
+        }
+        Ok(())
+    }
+
+    fn list_descendants(&self, volume: &str) -> Result<Vec<String>> {
+        Ok(vec![volume.to_owned()])
+    }
+
+    fn space(&self, _root: &str) -> Result<(usize, usize)> {
+        Ok((0, 0))
+    }
+
+    fn snapshot_dir(&self, mountpoint: &Path) -> PathBuf {
+        mountpoint.join(".zfs/snapshot")
+    }
+}
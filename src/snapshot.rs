@@ -0,0 +1,87 @@
+use std::{collections::HashMap, error::Error};
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{config, to_volume_string};
+
+/// How much earlier than its interval a snapshot may be taken, so timers
+/// firing a bit early don't make them slip by a whole period
+fn slack() -> Duration {
+    Duration::minutes(5)
+}
+
+/// Recursively snapshots the roots of all filesystems with `snapshot` set
+/// whose snapshot is due
+///
+/// Filesystems without `snapshot_interval_hours` are only snapshotted when
+/// `maintaining`, i.e. on each `workspaces maintain` run.  Workspaces whose
+/// snapshots were excluded are left out.
+pub fn snapshot(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    maintaining: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut names: Vec<_> = filesystems.keys().collect();
+    names.sort();
+    for filesystem_name in names {
+        let filesystem = &filesystems[filesystem_name];
+        if !filesystem.snapshot {
+            continue;
+        }
+        match filesystem.snapshot_interval_hours {
+            None if !maintaining => continue,
+            None => {}
+            Some(interval) => {
+                let last: Option<DateTime<Utc>> = conn
+                    .query_row(
+                        "SELECT time FROM snapshots WHERE filesystem = ?1",
+                        (filesystem_name,),
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if let Some(last) = last
+                    && last + interval - slack() > Utc::now()
+                {
+                    continue;
+                }
+            }
+        }
+
+        let excluded: Vec<String> = conn
+            .prepare(
+                "SELECT root, dataset FROM workspaces \
+                    WHERE filesystem = ?1 AND snapshots_excluded",
+            )?
+            .query_map((filesystem_name,), |row| {
+                let root: Option<String> = row.get(0)?;
+                Ok(to_volume_string(
+                    filesystem.root(root.as_deref()),
+                    &row.get::<_, String>(1)?,
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        for root in &filesystem.roots {
+            let storage = filesystem.storage();
+            if !excluded
+                .iter()
+                .any(|volume| volume.starts_with(&format!("{}/", root)))
+            {
+                storage.snapshot(root, &filesystem.snapshot_prefix)?;
+                continue;
+            }
+            // A recursive snapshot can't leave out single datasets, so list them
+            let volumes: Vec<String> = storage
+                .list_descendants(root)?
+                .into_iter()
+                .filter(|volume| !excluded.contains(volume))
+                .collect();
+            storage.snapshot_each(&volumes, &filesystem.snapshot_prefix)?;
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO snapshots(filesystem, time) VALUES(?1, ?2)",
+            (filesystem_name, Utc::now()),
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,432 @@
+use crate::config;
+use chrono::Utc;
+use std::{
+    io::{self, Read},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+use users::os::unix::UserExt;
+
+/// How long a timed out command gets to exit after being asked to terminate
+const TERMINATION_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+#[allow(unused)]
+pub enum Error {
+    /// An error occurring while running a command
+    Command(io::Error),
+    /// The ZFS invocation completed, but returned a non-zero code, along with
+    /// what it printed to stderr
+    ZfsStatus(process::ExitStatus, String),
+    /// Like [`Error::ZfsStatus`], for `btrfs` and the tools used along with it
+    BtrfsStatus(process::ExitStatus, String),
+    /// Error while parsing ZFS's output
+    PropertyParse(Box<dyn std::error::Error>),
+    /// The command didn't complete within the filesystem's `command_timeout`
+    Timeout(Duration),
+    /// The filesystem's backend can't do what was asked for
+    Unsupported(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Command(err) => {
+                write!(f, "Command error: {}", err)
+            }
+            Error::ZfsStatus(status, stderr) if stderr.is_empty() => {
+                write!(f, "ZFS status error: {}", status)
+            }
+            Error::ZfsStatus(status, stderr) => {
+                write!(f, "ZFS status error: {}: {}", status, stderr)
+            }
+            Error::BtrfsStatus(status, stderr) if stderr.is_empty() => {
+                write!(f, "btrfs status error: {}", status)
+            }
+            Error::BtrfsStatus(status, stderr) => {
+                write!(f, "btrfs status error: {}: {}", status, stderr)
+            }
+            Error::PropertyParse(err) => {
+                write!(f, "ZFS property parsing error: {}", err)
+            }
+            Error::Timeout(timeout) => {
+                write!(f, "Command timed out after {}s", timeout.as_secs())
+            }
+            Error::Unsupported(what) => {
+                write!(f, "Not supported by the storage backend: {}", what)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Command(err) => err.source(),
+            Error::PropertyParse(err) => err.source(),
+            Error::ZfsStatus(..)
+            | Error::BtrfsStatus(..)
+            | Error::Timeout(..)
+            | Error::Unsupported(..) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::Command(value)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Where the datasets of a filesystem live, and how they are managed
+///
+/// Volumes are named by joining a root and a dataset with `/`; for ZFS
+/// that is a dataset name, for btrfs the path of a subvolume.  Properties
+/// are named like ZFS's, which other backends map to their own concepts.
+pub trait Storage {
+    /// Runs the backend's commands
+    fn host(&self) -> &Host<'_>;
+
+    /// Creates a new volume with the given properties
+    fn create(&self, volume: &str, properties: &[(&str, &str)]) -> Result<()>;
+
+    /// Whether a volume exists, failing if that can't be told
+    fn exists(&self, volume: &str) -> Result<bool>;
+
+    /// Destroys a volume along with its snapshots
+    fn destroy(&self, volume: &str) -> Result<()>;
+
+    /// Renames a volume, creating missing parents of `dest_volume`
+    fn rename(&self, src_volume: &str, dest_volume: &str) -> Result<()>;
+
+    /// Retrieves a property, unparsed
+    fn property(&self, volume: &str, property: &str) -> Result<String>;
+
+    /// Sets a property
+    fn set_property(&self, volume: &str, property: &str, value: &str) -> Result<()>;
+
+    /// Lists the names of a volume's snapshots, oldest first
+    fn list_snapshots(&self, volume: &str) -> Result<Vec<String>>;
+
+    /// Snapshots a single volume, giving the snapshot a name
+    fn snapshot_named(&self, volume: &str, snapshot_name: &str) -> Result<()>;
+
+    /// Recursively snapshot a volume, naming the snapshot `prefix` followed by the time
+    fn snapshot(&self, volume: &str, prefix: &str) -> Result<()>;
+
+    /// Snapshots several volumes, named like [`Self::snapshot`] does
+    fn snapshot_each(&self, volumes: &[String], prefix: &str) -> Result<()>;
+
+    /// Lists a volume and all volumes below it
+    fn list_descendants(&self, volume: &str) -> Result<Vec<String>>;
+
+    /// Retrieves `properties` of a volume and all volumes below it at once,
+    /// unparsed, as volume, property and value
+    ///
+    /// Volumes whose properties can't be retrieved are left out.
+    fn properties_below(
+        &self,
+        volume: &str,
+        properties: &[&str],
+    ) -> Result<Vec<(String, String, String)>> {
+        let mut values = Vec::new();
+        for volume in self.list_descendants(volume)? {
+            for property in properties {
+                if let Ok(value) = self.property(&volume, property) {
+                    values.push((volume.clone(), property.to_string(), value));
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// Bytes used below `root` and available to it
+    fn space(&self, root: &str) -> Result<(usize, usize)>;
+
+    /// Directory the snapshots of the volume mounted at `mountpoint` can be read from
+    fn snapshot_dir(&self, mountpoint: &Path) -> PathBuf;
+}
+
+impl dyn Storage + '_ {
+    /// Retrieves a property
+    pub fn get_property<F: FromStr>(&self, volume: &str, property: &str) -> Result<F>
+    where
+        <F as FromStr>::Err: std::error::Error + 'static,
+    {
+        parse(&self.property(volume, property)?)
+    }
+
+    /// Whether commands are executed on another host
+    pub fn is_remote(&self) -> bool {
+        self.host().is_remote()
+    }
+
+    /// Builds a command running `program` with `args` on the filesystem's host
+    pub fn command(&self, program: &str, args: &[&str]) -> Command {
+        self.host().command(program, args)
+    }
+
+    /// Like [`Self::command`], but running `program` in `dir` as `uid` and `gid`
+    pub fn command_as(
+        &self,
+        uid: u32,
+        gid: u32,
+        dir: &str,
+        program: &str,
+        args: &[&str],
+    ) -> Command {
+        self.host().command_as(uid, gid, dir, program, args)
+    }
+
+    /// Runs `program` with `args` on the filesystem's host, subject to the timeout
+    ///
+    /// The output is inherited.  Used for commands other than the backend's
+    /// which operate on workspaces, e.g. `chown`.
+    pub fn status(&self, program: &str, args: &[&str]) -> Result<process::ExitStatus> {
+        self.host().status(program, args)
+    }
+}
+
+/// Runs commands for a filesystem, either locally or on a remote host via SSH
+#[derive(Clone, Copy)]
+pub struct Host<'a> {
+    remote: Option<&'a config::SshRemote>,
+    /// How long commands may run before they are terminated.  Unlimited if unset.
+    timeout: Option<Duration>,
+}
+
+/// Directories programs are run from, whatever the caller's `PATH` says
+const SYSTEM_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Builds a command running `program` from [`SYSTEM_PATH`]
+///
+/// We run setuid root, so the caller's environment is left behind rather
+/// than configuring the program, e.g. through `CURL_HOME` or proxy variables.
+/// Only `PATH` and the `HOME` of the user we run as are set; anything else a
+/// program needs is up to the caller.
+pub fn system_command(program: &str) -> Command {
+    let path = SYSTEM_PATH
+        .split(':')
+        .map(|dir| Path::new(dir).join(program))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from(program));
+    let mut command = Command::new(path);
+    command.env_clear().env("PATH", SYSTEM_PATH);
+    if let Some(user) = users::get_user_by_uid(users::get_effective_uid()) {
+        command.env("HOME", user.home_dir());
+    }
+    command
+}
+
+/// Quotes `arg` for a POSIX shell
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+impl<'a> Host<'a> {
+    pub fn new(remote: Option<&'a config::SshRemote>, timeout: Option<Duration>) -> Self {
+        Host { remote, timeout }
+    }
+
+    /// Whether commands are executed on another host
+    pub fn is_remote(&self) -> bool {
+        self.remote.is_some()
+    }
+
+    /// Builds a command running `program` with `args` on the filesystem's host
+    pub fn command(&self, program: &str, args: &[&str]) -> Command {
+        match self.remote {
+            None => {
+                let mut command = system_command(program);
+                command.args(args);
+                command
+            }
+            Some(remote) => {
+                let mut command = system_command("ssh");
+                command.args(["-o", "BatchMode=yes"]);
+                if let Some(port) = remote.port {
+                    command.args(["-p", &port.to_string()]);
+                }
+                if let Some(identity_file) = &remote.identity_file {
+                    command.arg("-i").arg(identity_file);
+                }
+                match &remote.user {
+                    Some(user) => command.arg(format!("{}@{}", user, remote.host)),
+                    None => command.arg(&remote.host),
+                };
+                // ssh passes the command to the remote user's shell
+                command.arg("--").arg(
+                    std::iter::once(program)
+                        .chain(args.iter().copied())
+                        .map(shell_quote)
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                );
+                command
+            }
+        }
+    }
+
+    /// Like [`Self::command`], but running `program` in `dir` as `uid` and
+    /// `gid`, without supplementary groups
+    ///
+    /// The directory is entered before the privileges are dropped, so it may
+    /// be below one only root can enter, e.g. one holding snapshots.
+    pub fn command_as(
+        &self,
+        uid: u32,
+        gid: u32,
+        dir: &str,
+        program: &str,
+        args: &[&str],
+    ) -> Command {
+        let (uid, gid) = (uid.to_string(), gid.to_string());
+        let setpriv = [
+            "--reuid",
+            &uid,
+            "--regid",
+            &gid,
+            "--clear-groups",
+            "--",
+            program,
+        ];
+        match self.remote {
+            None => {
+                let mut command = self.command("setpriv", &[&setpriv[..], args].concat());
+                command.current_dir(dir);
+                command
+            }
+            // ssh starts commands in the remote user's home
+            Some(_) => self.command(
+                "sh",
+                &[
+                    &["-c", "cd \"$0\" && exec setpriv \"$@\"", dir][..],
+                    &setpriv,
+                    args,
+                ]
+                .concat(),
+            ),
+        }
+    }
+
+    /// Runs `program` with `args` on the filesystem's host, subject to the timeout
+    ///
+    /// The output is inherited.
+    pub fn status(&self, program: &str, args: &[&str]) -> Result<process::ExitStatus> {
+        Ok(self.run(self.command(program, args), false)?.status)
+    }
+
+    /// Runs `program` with `args` on the filesystem's host, capturing its output
+    pub fn output(&self, program: &str, args: &[&str]) -> Result<process::Output> {
+        self.run(self.command(program, args), true)
+    }
+
+    /// Like [`Self::output`], but fails with `error` built from the exit
+    /// status and stderr if the command doesn't succeed
+    pub fn checked_output(
+        &self,
+        program: &str,
+        args: &[&str],
+        error: fn(process::ExitStatus, String) -> Error,
+    ) -> Result<process::Output> {
+        let output = self.output(program, args)?;
+        if !output.status.success() {
+            return Err(error(
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            ));
+        }
+        Ok(output)
+    }
+
+    /// Runs `command` in its own process group, terminating the whole group on timeout
+    ///
+    /// A timed out group is sent SIGTERM, then SIGKILL if it is still around
+    /// after [`TERMINATION_GRACE`], so that a local `zfs` isn't left behind
+    /// blocking the pool.  On a remote host, only the `ssh` running the
+    /// command is terminated; the command itself may carry on there.
+    fn run(&self, mut command: Command, capture: bool) -> Result<process::Output> {
+        // Commands in their own process group mustn't read from the terminal
+        command.stdin(Stdio::null()).process_group(0);
+        if capture {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let mut child = command.spawn()?;
+        // Drain the pipes while waiting, so a chatty command can't block on a full one
+        let stdout = child
+            .stdout
+            .take()
+            .map(|pipe| thread::spawn(|| read_all(pipe)));
+        let stderr = child
+            .stderr
+            .take()
+            .map(|pipe| thread::spawn(|| read_all(pipe)));
+
+        let status = match self.timeout {
+            None => child.wait()?,
+            Some(timeout) => match wait_until(&mut child, Instant::now() + timeout)? {
+                Some(status) => status,
+                None => {
+                    let group = -(child.id() as libc::pid_t);
+                    unsafe { libc::kill(group, libc::SIGTERM) };
+                    if wait_until(&mut child, Instant::now() + TERMINATION_GRACE)?.is_none() {
+                        unsafe { libc::kill(group, libc::SIGKILL) };
+                        child.wait()?;
+                    }
+                    return Err(Error::Timeout(timeout));
+                }
+            },
+        };
+        Ok(process::Output {
+            status,
+            stdout: stdout.map_or(Ok(Vec::new()), |reader| reader.join().unwrap())?,
+            stderr: stderr.map_or(Ok(Vec::new()), |reader| reader.join().unwrap())?,
+        })
+    }
+}
+
+/// Waits for `child` to exit until `deadline`, returning `None` if it didn't
+fn wait_until(
+    child: &mut process::Child,
+    deadline: Instant,
+) -> io::Result<Option<process::ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Reads a pipe to its end
+fn read_all(mut pipe: impl Read) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    pipe.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Parses a value printed by the backend's tools
+pub fn parse<F: FromStr>(value: &str) -> Result<F>
+where
+    <F as FromStr>::Err: std::error::Error + 'static,
+{
+    value.parse().map_err(|e| Error::PropertyParse(Box::new(e)))
+}
+
+/// Name of a regular snapshot taken now
+pub fn snapshot_name(prefix: &str) -> String {
+    format!(
+        "{}{}",
+        prefix,
+        Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    )
+}
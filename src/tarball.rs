@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use chrono::{DateTime, Duration, Local, Utc};
+use prettytable::{
+    Attr, Cell, Row, Table,
+    format::{Alignment, FormatBuilder},
+};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{
+    archive, banner, cli::CommandFormat, clock, config, create::create, error::WorkspacesError,
+    metadata, notify::Notifiers, workspace_volume,
+};
+
+/// Runs `program` on the filesystem's host without the filesystem's
+/// `command_timeout`, as archiving large workspaces takes a while
+fn run(
+    filesystem: &config::Filesystem,
+    program: &str,
+    args: &[&str],
+) -> Result<(), Box<dyn Error>> {
+    let output = filesystem
+        .storage()
+        .command(program, args)
+        .stdout(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// The SHA-256 of the tarball at `path` on the filesystem's host
+///
+/// Not subject to the `command_timeout` either.
+fn sha256(filesystem: &config::Filesystem, path: &str) -> Result<String, Box<dyn Error>> {
+    let output = filesystem
+        .storage()
+        .command("sha256sum", &[path])
+        .output()?;
+    archive::parse_sha256(&output)
+}
+
+/// Packs a workspace's contents into a zstd-compressed tarball in the
+/// filesystem's `archive_path`, and checks it can be read back
+///
+/// The tarball is recorded in the database with its SHA-256, so it can be
+/// recovered and verified later.
+/// Only root may read it.  If the workspace already has a tarball which
+/// reads back, e.g. when deleting it failed on a previous run, it's kept.
+pub fn archive(
+    conn: &Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    archive_path: &Path,
+    volume: &str,
+    user: &str,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    let archived: Option<String> = conn
+        .query_row(
+            "SELECT tarballs.path FROM tarballs JOIN workspaces USING(filesystem, user, name) \
+                WHERE filesystem = ?1 AND user = ?2 AND name = ?3 \
+                    AND unixepoch(tarballs.archive_time) >= unixepoch(workspaces.created_at) \
+                ORDER BY tarballs.archive_time DESC LIMIT 1",
+            (filesystem_name, user, name),
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(path) = archived
+        && run(filesystem, "tar", &["--zstd", "-tf", &path]).is_ok()
+    {
+        return Ok(());
+    }
+
+    let mountpoint: PathBuf = filesystem.storage().get_property(volume, "mountpoint")?;
+    let now = Utc::now();
+    let path = archive_path
+        .join(format!(
+            "{}-{}-{}-{}.tar.zst",
+            filesystem_name,
+            user,
+            name,
+            now.format("%Y%m%dT%H%M%S")
+        ))
+        .to_string_lossy()
+        .into_owned();
+
+    let created = run(
+        filesystem,
+        "sh",
+        &[
+            "-c",
+            "umask 077 && exec tar --zstd -C \"$0\" -cf \"$1\" .",
+            &mountpoint.to_string_lossy(),
+            &path,
+        ],
+    )
+    .and_then(|()| run(filesystem, "tar", &["--zstd", "-tf", &path]));
+    if let Err(e) = created {
+        // Don't leave a partial tarball around to be mistaken for a good one
+        if let Err(e) = run(filesystem, "rm", &["-f", &path]) {
+            log::warn!("Failed to remove incomplete archive {}: {}", path, e);
+        }
+        return Err(e);
+    }
+
+    let output = filesystem
+        .storage()
+        .host()
+        .output("stat", &["-c", "%s", &path])?;
+    let size: i64 = String::from_utf8_lossy(&output.stdout).trim().parse()?;
+    let sha256 = sha256(filesystem, &path)?;
+    conn.execute(
+        "INSERT INTO tarballs(filesystem, user, name, path, size, archive_time, sha256) \
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (filesystem_name, user, name, &path, size, now, &sha256),
+    )?;
+    Ok(())
+}
+
+/// Removes the tarballs kept longer than their filesystem's `archive_retention`
+///
+/// Failures are logged, the tarballs are then tried again on the next run.
+pub fn prune(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+) -> Result<(), Box<dyn Error>> {
+    let tarballs = conn
+        .prepare("SELECT id, filesystem, path, archive_time FROM tarballs")?
+        .query_map((), |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, DateTime<Utc>>(3)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (id, filesystem_name, path, archive_time) in tarballs {
+        let Some(filesystem) = filesystems.get(&filesystem_name) else {
+            continue;
+        };
+        let Some(retention) = filesystem.archive_retention else {
+            continue;
+        };
+        if archive_time + retention > clock::now() {
+            continue;
+        }
+        match run(filesystem, "rm", &["-f", &path]) {
+            Ok(()) => {
+                conn.execute("DELETE FROM tarballs WHERE id = ?1", (id,))?;
+            }
+            Err(e) => log::warn!("Failed to remove archive {}: {}", path, e),
+        }
+    }
+    Ok(())
+}
+
+/// Checks a tarball still matches the checksum recorded when packing it
+///
+/// Without a tarball id, all tarballs are listed instead.
+pub fn verify(
+    conn: &Connection,
+    filesystems: &HashMap<String, config::Filesystem>,
+    tarball_id: Option<i64>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(tarball_id) = tarball_id else {
+        return list(conn);
+    };
+    let Some((filesystem_name, path, recorded)): Option<(String, String, Option<String>)> = conn
+        .query_row(
+            "SELECT filesystem, path, sha256 FROM tarballs WHERE id = ?1",
+            (tarball_id,),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?
+    else {
+        return Err(WorkspacesError::UnknownId(format!(
+            "There is no tarball with id {}",
+            tarball_id
+        ))
+        .into());
+    };
+    let Some(filesystem) = filesystems.get(&filesystem_name) else {
+        return Err(WorkspacesError::NotConfigured(format!(
+            "Filesystem {} isn't configured anymore",
+            filesystem_name
+        ))
+        .into());
+    };
+    let Some(recorded) = recorded else {
+        return Err(format!(
+            "Tarball {} was packed before checksums were recorded, so it can't be verified",
+            tarball_id
+        )
+        .into());
+    };
+
+    let packed = sha256(filesystem, &path)?;
+    if packed != recorded {
+        return Err(format!(
+            "Tarball {} is damaged, its SHA-256 is {} but {} was recorded",
+            tarball_id, packed, recorded
+        )
+        .into());
+    }
+    println!(
+        "Tarball {} is intact, its SHA-256 is {}",
+        tarball_id, recorded
+    );
+    Ok(())
+}
+
+/// Prints all tarballs kept
+fn list(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().padding(0, 2).build());
+    table.set_titles(Row::new(
+        ["ID", "NAME", "USER", "FS", "SIZE", "ARCHIVED", "PATH"]
+            .iter()
+            .map(|h| Cell::new(h).with_style(Attr::Bold))
+            .collect(),
+    ));
+
+    let mut statement = conn.prepare(
+        "SELECT id, name, user, filesystem, size, archive_time, path \
+            FROM tarballs ORDER BY archive_time",
+    )?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let archive_time: DateTime<Utc> = row.get(5)?;
+        table.add_row(Row::new(vec![
+            Cell::new_align(&row.get::<_, i64>(0)?.to_string(), Alignment::RIGHT),
+            Cell::new(&row.get::<_, String>(1)?),
+            Cell::new(&row.get::<_, String>(2)?),
+            Cell::new(&row.get::<_, String>(3)?),
+            Cell::new_align(
+                &format!("{}G", row.get::<_, i64>(4)? / (1 << 30)),
+                Alignment::RIGHT,
+            ),
+            Cell::new(
+                &archive_time
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+            ),
+            Cell::new(&row.get::<_, String>(6)?),
+        ]));
+    }
+
+    table.printstd();
+    Ok(())
+}
+
+/// Recreates a deleted workspace from its most recent tarball
+#[allow(clippy::too_many_arguments)]
+pub fn recover(
+    conn: &mut Connection,
+    filesystem_name: &str,
+    filesystem: &config::Filesystem,
+    user: &str,
+    name: &str,
+    duration: Option<Duration>,
+    notifiers: &Notifiers,
+) -> Result<(), Box<dyn Error>> {
+    let Some((path, archive_time)): Option<(String, DateTime<Utc>)> = conn
+        .query_row(
+            "SELECT path, archive_time FROM tarballs \
+                WHERE filesystem = ?1 AND user = ?2 AND name = ?3 \
+                ORDER BY archive_time DESC LIMIT 1",
+            (filesystem_name, user, name),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+    else {
+        return Err(WorkspacesError::UnknownWorkspace(format!(
+            "There is no archive of workspace {} of {} on {}",
+            name, user, filesystem_name
+        ))
+        .into());
+    };
+    let duration = duration.unwrap_or(
+        filesystem
+            .default_duration
+            .unwrap_or(filesystem.max_duration),
+    );
+
+    create(
+        conn,
+        filesystem_name,
+        filesystem,
+        user,
+        name,
+        &duration,
+        None,
+        None,
+        notifiers,
+        true,
+        None,
+        false,
+        None,
+        None,
+        None,
+        CommandFormat::Text,
+    )?;
+
+    let volume = workspace_volume(conn, filesystem_name, filesystem, user, name)?;
+    let mountpoint: PathBuf = filesystem.storage().get_property(&volume, "mountpoint")?;
+    // Running as root, tar restores the files' owners and permissions as well
+    run(
+        filesystem,
+        "tar",
+        &["--zstd", "-C", &mountpoint.to_string_lossy(), "-xpf", &path],
+    )?;
+
+    // The archived metadata and banner are stale
+    let expiration_time: DateTime<Utc> = conn.query_row(
+        "SELECT expiration_time FROM workspaces \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name),
+        |row| row.get(0),
+    )?;
+    if let Err(e) = metadata::write(conn, filesystem_name, filesystem, user, name) {
+        log::warn!("Failed to write workspace metadata file: {}", e);
+    }
+    if let Err(e) = banner::update(filesystem_name, filesystem, &volume, name, expiration_time) {
+        log::warn!("Failed to update expiry banner: {}", e);
+    }
+
+    println!(
+        "Recovered the archive from {} into {}",
+        archive_time.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+        mountpoint.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use std::{env, panic};
+
+use users::get_current_username;
+
+use crate::{config, json, notify};
+
+/// Reports panics to the configured error webhook, in addition to printing them
+pub fn install_panic_hook(telemetry: &config::TelemetryConfig) {
+    let url = telemetry.error_webhook.clone();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        let message = if let Some(message) = info.payload().downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = info.payload().downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic".to_owned()
+        };
+        let location = info
+            .location()
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+        send(&url, "panic", &message, &location);
+    }));
+}
+
+/// Reports a non-recoverable error to the configured error webhook, if any
+pub fn report(telemetry: &Option<config::TelemetryConfig>, message: &str) {
+    if let Some(telemetry) = telemetry {
+        send(&telemetry.error_webhook, "error", message, "");
+    }
+}
+
+fn send(url: &str, kind: &str, message: &str, location: &str) {
+    let host = hostname::get()
+        .map(|host| host.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let user = get_current_username()
+        .map(|user| user.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let payload = json::object([
+        ("kind", kind.into()),
+        ("message", message.into()),
+        ("location", location.into()),
+        ("command", env::args().collect::<Vec<_>>().join(" ").into()),
+        ("user", user.into()),
+        ("host", host.into()),
+        ("version", env!("CARGO_PKG_VERSION").into()),
+    ]);
+    // There's nowhere left to report to if this fails
+    if let Err(e) = notify::send_webhook(url, payload) {
+        log::warn!("Failed to report error: {}", e);
+    }
+}
@@ -0,0 +1,10 @@
+/// Substitutes `{key}` placeholders in `template` with their values
+///
+/// Unknown placeholders are left untouched.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_owned(), |rendered, (key, value)| {
+            rendered.replace(&format!("{{{}}}", key), value)
+        })
+}
@@ -0,0 +1,121 @@
+use rusqlite::{Connection, OptionalExtension};
+use users::{get_current_uid, get_current_username, get_user_by_name};
+
+use crate::{
+    clock, config,
+    error::WorkspacesError,
+    offboard::{self, Workspace},
+};
+
+/// Transfers a workspace to `to`, or offers it to them
+///
+/// Admins transfer it right away.  Its owner only offers it, and the recipient
+/// takes it over with `accept`, so both of them agreed to it.
+pub fn transfer(
+    conn: &mut Connection,
+    config: &config::Config,
+    filesystem_name: &str,
+    user: &str,
+    name: &str,
+    to: Option<&str>,
+    accept: bool,
+) -> Result<(), WorkspacesError> {
+    let filesystem = &config.filesystems[filesystem_name];
+    let workspace = match conn.query_row(
+        "SELECT id, filesystem, name, expiration_time, root, dataset FROM workspaces \
+            WHERE filesystem = ?1 AND user = ?2 AND name = ?3",
+        (filesystem_name, user, name),
+        |row| {
+            Ok(Workspace {
+                id: row.get(0)?,
+                filesystem_name: row.get(1)?,
+                name: row.get(2)?,
+                expiration_time: row.get(3)?,
+                root: row.get(4)?,
+                dataset: row.get(5)?,
+            })
+        },
+    ) {
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(WorkspacesError::unknown_workspace(
+                filesystem_name,
+                user,
+                name,
+            ));
+        }
+        res => res,
+    }?;
+    let me = get_current_username()
+        .expect("couldn't get username")
+        .to_string_lossy()
+        .into_owned();
+
+    let to = if accept {
+        let recipient: Option<String> = conn
+            .query_row(
+                "SELECT recipient FROM transfer_offers WHERE workspace_id = ?1",
+                (workspace.id,),
+                |row| row.get(0),
+            )
+            .optional()?;
+        if recipient.as_deref() != Some(me.as_str()) {
+            return Err(WorkspacesError::InvalidArgument(format!(
+                "{} of {} was not offered to you",
+                name, user
+            )));
+        }
+        me
+    } else {
+        let to = to.expect("required unless accepting");
+        if get_user_by_name(to).is_none() {
+            return Err(WorkspacesError::InvalidArgument(format!(
+                "There is no user {}",
+                to
+            )));
+        }
+        if to == user {
+            return Err(WorkspacesError::InvalidArgument(format!(
+                "{} already owns {}",
+                to, name
+            )));
+        }
+        if get_current_uid() != 0 {
+            if me != user {
+                return Err(WorkspacesError::InsufficientPrivileges(
+                    "Only the owner of a workspace may transfer it".to_owned(),
+                ));
+            }
+            conn.execute(
+                "INSERT INTO transfer_offers(workspace_id, recipient, offered_at) \
+                    VALUES (?1, ?2, ?3) \
+                    ON CONFLICT(workspace_id) DO UPDATE \
+                        SET recipient = excluded.recipient, offered_at = excluded.offered_at",
+                (workspace.id, to, clock::now()),
+            )?;
+            println!(
+                "Offered {} to {}. They can take it over with:\n    \
+                workspaces transfer --accept -u {} -f {} {}",
+                name, to, user, filesystem_name, name
+            );
+            return Ok(());
+        }
+        to.to_owned()
+    };
+
+    offboard::reassign_workspace(conn, filesystem, &workspace, user, &to, &config.modulefiles)?;
+    // The new owner doesn't need any other rights on it anymore
+    conn.execute(
+        "DELETE FROM co_owners WHERE workspace_id = ?1 AND user = ?2",
+        (workspace.id, &to),
+    )?;
+    conn.execute(
+        "DELETE FROM grants WHERE workspace_id = ?1 AND user = ?2",
+        (workspace.id, &to),
+    )?;
+    conn.execute(
+        "DELETE FROM transfer_offers WHERE workspace_id = ?1",
+        (workspace.id,),
+    )?;
+    println!("Transferred {} to {}", name, to);
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use crate::{db_schema::NEWEST_DB_VERSION, json};
+
+const GIT_COMMIT: &str = env!("WORKSPACES_GIT_COMMIT");
+const BUILD_DATE: &str = env!("WORKSPACES_BUILD_DATE");
+const RUSTC_VERSION: &str = env!("WORKSPACES_RUSTC_VERSION");
+
+/// Storage backends compiled into this build
+const BACKENDS: &[&str] = &["zfs", "zfs-over-ssh", "btrfs"];
+/// Notification channels compiled into this build
+const NOTIFIERS: &[&str] = &["email", "webhook"];
+
+/// Prints the version along with information about the build
+pub fn print_version(json: bool) {
+    if json {
+        println!(
+            "{}",
+            json::object([
+                ("version", env!("CARGO_PKG_VERSION").into()),
+                ("commit", GIT_COMMIT.into()),
+                ("build_date", BUILD_DATE.into()),
+                ("rustc", RUSTC_VERSION.into()),
+                ("db_schema_version", NEWEST_DB_VERSION.into()),
+                ("backends", BACKENDS.to_vec().into()),
+                ("notifiers", NOTIFIERS.to_vec().into()),
+            ])
+        );
+        return;
+    }
+
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    println!("commit:     {}", GIT_COMMIT);
+    println!("built:      {}", BUILD_DATE);
+    println!("rustc:      {}", RUSTC_VERSION);
+    println!("db schema:  {}", NEWEST_DB_VERSION);
+    println!("backends:   {}", BACKENDS.join(", "));
+    println!("notifiers:  {}", NOTIFIERS.join(", "));
+}
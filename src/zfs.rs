@@ -1,137 +1,184 @@
-use chrono::Utc;
+use crate::storage::{Error, Host, Result, Storage, parse, snapshot_name};
 use std::{
-    io,
-    process::{self, Command},
-    str::FromStr,
+    path::{Path, PathBuf},
+    process,
 };
 
-#[derive(Debug)]
-#[allow(unused)]
-pub enum Error {
-    /// An error occurring while running a command
-    Command(io::Error),
-    /// The ZFS invocation completed, but returned a non-zero code
-    ZfsStatus(process::ExitStatus),
-    /// Error while parsing ZFS's output
-    PropertyParse(Box<dyn std::error::Error>),
+/// Manages a filesystem's datasets with ZFS
+pub struct Zfs<'a> {
+    host: Host<'a>,
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Error::Command(err) => {
-                write!(f, "Command error: {}", err)
-            }
-            Error::ZfsStatus(err) => {
-                write!(f, "ZFS status error: {}", err)
-            }
-            Error::PropertyParse(err) => {
-                write!(f, "ZFS property parsing error: {}", err)
-            }
-        }
+impl<'a> Zfs<'a> {
+    pub fn new(host: Host<'a>) -> Self {
+        Zfs { host }
     }
-}
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            Error::Command(err) => err.source(),
-            Error::PropertyParse(err) => err.source(),
-            Error::ZfsStatus(..) => None,
-        }
+    /// Runs `zfs` with `args`, failing with its error message if it doesn't succeed
+    fn run_zfs(&self, args: &[&str]) -> Result<process::Output> {
+        self.host.checked_output("zfs", args, Error::ZfsStatus)
     }
 }
 
-impl From<io::Error> for Error {
-    fn from(value: io::Error) -> Self {
-        Error::Command(value)
+impl Storage for Zfs<'_> {
+    fn host(&self) -> &Host<'_> {
+        &self.host
     }
-}
 
-type Result<T> = std::result::Result<T, Error>;
+    /// Creates a new ZFS volume with the given properties
+    fn create(&self, volume: &str, properties: &[(&str, &str)]) -> Result<()> {
+        let properties: Vec<String> = properties
+            .iter()
+            .map(|(property, value)| format!("{}={}", property, value))
+            .collect();
+        let mut args = vec!["create", "-p"];
+        for property in &properties {
+            args.extend(["-o", property]);
+        }
+        args.push(volume);
+        self.run_zfs(&args)?;
+        Ok(())
+    }
 
-/// Creates a new ZFS volume
-pub fn create(volume: &str) -> Result<()> {
-    let status = Command::new("zfs")
-        .args(["create", "-p", volume])
-        .status()?;
-    match status.success() {
-        true => Ok(()),
-        false => Err(Error::ZfsStatus(status)),
+    /// Whether a ZFS volume exists
+    ///
+    /// Fails rather than answering no if `zfs` fails for another reason, e.g.
+    /// the SSH connection to the filesystem's host.
+    fn exists(&self, volume: &str) -> Result<bool> {
+        let output = self
+            .host
+            .output("zfs", &["list", "-H", "-o", "name", volume])?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match output.status.success() {
+            true => Ok(true),
+            false if stderr.contains("dataset does not exist") => Ok(false),
+            false => Err(Error::ZfsStatus(output.status, stderr.trim().to_owned())),
+        }
     }
-}
 
-/// Destroys a ZFS volume
-pub fn destroy(volume: &str) -> Result<()> {
-    let status = Command::new("zfs")
-        .args(["destroy", "-r", volume])
-        .status()?;
-    match status.success() {
-        true => Ok(()),
-        false => Err(Error::ZfsStatus(status)),
+    /// Destroys a ZFS volume
+    fn destroy(&self, volume: &str) -> Result<()> {
+        self.run_zfs(&["destroy", "-r", volume])?;
+        Ok(())
     }
-}
 
-/// Renames a ZFS volume
-pub fn rename(src_volume: &str, dest_volume: &str) -> Result<()> {
-    let status = Command::new("zfs")
-        .args(["rename", src_volume, dest_volume])
-        .status()?;
-    match status.success() {
-        true => Ok(()),
-        false => Err(Error::ZfsStatus(status)),
+    /// Renames a ZFS volume, creating missing parents of `dest_volume`
+    fn rename(&self, src_volume: &str, dest_volume: &str) -> Result<()> {
+        self.run_zfs(&["rename", "-p", src_volume, dest_volume])?;
+        Ok(())
     }
-}
 
-/// Retrieves a ZFS property
-pub fn get_property<F: FromStr>(volume: &str, property: &str) -> Result<F>
-where
-    <F as FromStr>::Err: std::error::Error + 'static,
-{
-    let output = Command::new("zfs")
-        .args([
+    /// Retrieves a ZFS property
+    fn property(&self, volume: &str, property: &str) -> Result<String> {
+        let output = self.run_zfs(&[
             "get", "-Hp", // make zfs output easily parsable
             "-o", "value", // output only desired value
             property, volume,
-        ])
-        .output()?;
-    if !output.status.success() {
-        return Err(Error::ZfsStatus(output.status));
-    }
-    let mut info_line = String::from_utf8(output.stdout).unwrap();
-    info_line.pop(); // remove trailing newline
-    info_line
-        .parse()
-        .map_err(|e| Error::PropertyParse(Box::new(e)))
-}
+        ])?;
+        let mut info_line = String::from_utf8(output.stdout).unwrap();
+        info_line.pop(); // remove trailing newline
+        Ok(info_line)
+    }
 
-/// Sets a ZFS property
-pub fn set_property(volume: &str, property: &str, value: &str) -> Result<()> {
-    let status: process::ExitStatus = Command::new("zfs")
-        .args(["set", &format!("{}={}", property, value), volume])
-        .status()?;
+    /// Lists the names of a volume's snapshots, oldest first
+    fn list_snapshots(&self, volume: &str) -> Result<Vec<String>> {
+        let output = self.run_zfs(&[
+            "list", "-H", // make zfs output easily parsable
+            "-o", "name", "-s", "creation", "-t", "snapshot", "-d", "1", volume,
+        ])?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| {
+                line.rsplit_once('@')
+                    .map_or(line, |(_, name)| name)
+                    .to_owned()
+            })
+            .collect())
+    }
 
-    match status.success() {
-        true => Ok(()),
-        false => Err(Error::ZfsStatus(status)),
+    /// Sets a ZFS property
+    fn set_property(&self, volume: &str, property: &str, value: &str) -> Result<()> {
+        self.run_zfs(&["set", &format!("{}={}", property, value), volume])?;
+        Ok(())
     }
-}
 
-/// Recursively snapshot a volume
-pub fn snapshot(volume: &str) -> Result<()> {
-    let status = Command::new("zfs")
-        .args([
+    /// Snapshots a single volume, giving the snapshot a name
+    fn snapshot_named(&self, volume: &str, snapshot_name: &str) -> Result<()> {
+        self.run_zfs(&["snapshot", &format!("{}@{}", volume, snapshot_name)])?;
+        Ok(())
+    }
+
+    /// Recursively snapshot a volume, naming the snapshot `prefix` followed by the time
+    fn snapshot(&self, volume: &str, prefix: &str) -> Result<()> {
+        self.run_zfs(&[
             "snapshot",
             "-r",
-            &format!(
-                "{}@{}",
-                volume,
-                Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
-            ),
-        ])
-        .status()?;
-    match status.success() {
-        true => Ok(()),
-        false => Err(Error::ZfsStatus(status)),
+            &format!("{}@{}", volume, snapshot_name(prefix)),
+        ])?;
+        Ok(())
+    }
+
+    /// Atomically snapshots several volumes, named like [`Self::snapshot`] does
+    fn snapshot_each(&self, volumes: &[String], prefix: &str) -> Result<()> {
+        let name = snapshot_name(prefix);
+        let snapshots: Vec<String> = volumes
+            .iter()
+            .map(|volume| format!("{}@{}", volume, name))
+            .collect();
+        let mut args = vec!["snapshot"];
+        args.extend(snapshots.iter().map(String::as_str));
+        self.run_zfs(&args)?;
+        Ok(())
+    }
+
+    /// Lists a volume and all filesystems below it
+    fn list_descendants(&self, volume: &str) -> Result<Vec<String>> {
+        let output =
+            self.run_zfs(&["list", "-H", "-o", "name", "-t", "filesystem", "-r", volume])?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    /// Retrieves properties of a volume and all filesystems below it with a single `zfs get`
+    fn properties_below(
+        &self,
+        volume: &str,
+        properties: &[&str],
+    ) -> Result<Vec<(String, String, String)>> {
+        let properties = properties.join(",");
+        let output = self.run_zfs(&[
+            "get",
+            "-Hp", // make zfs output easily parsable
+            "-o",
+            "name,property,value",
+            "-t",
+            "filesystem",
+            "-r",
+            &properties,
+            volume,
+        ])?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                Some((
+                    fields.next()?.to_owned(),
+                    fields.next()?.to_owned(),
+                    fields.next()?.to_owned(),
+                ))
+            })
+            .collect())
+    }
+
+    fn space(&self, root: &str) -> Result<(usize, usize)> {
+        let used = self.property(root, "used")?;
+        let available = self.property(root, "available")?;
+        Ok((parse(&used)?, parse(&available)?))
+    }
+
+    fn snapshot_dir(&self, mountpoint: &Path) -> PathBuf {
+        mountpoint.join(".zfs/snapshot")
     }
 }